@@ -0,0 +1,131 @@
+/*
+    Copyright © 2016 Zetok Zalbavar <zexavexxe@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Bootstrap node daemon.
+//!
+//! Usage: `tox-bootstrapd <config file>`
+//!
+//! See [`bootstrapd`](../tox/toxcore/bootstrapd/index.html) for the config
+//! file format.
+
+extern crate rustc_serialize;
+extern crate tox;
+
+use rustc_serialize::hex::ToHex;
+
+use std::env;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::UdpSocket;
+use std::process;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tox::toxcore::bootstrapd::*;
+use tox::toxcore::crypto_core::*;
+use tox::toxcore::dht_node::DhtNode;
+use tox::toxcore::lan_discovery::LanDiscoverySender;
+use tox::toxcore::network::Sockets;
+
+
+fn fail(msg: &str) -> ! {
+    let _ = writeln!(io::stderr(), "{}", msg);
+    process::exit(1)
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn main() {
+    let path = match env::args().nth(1) {
+        Some(p) => p,
+        None => fail("Usage: tox-bootstrapd <config file>"),
+    };
+    let mut json = String::new();
+    if let Err(e) = File::open(&path).and_then(|mut f| f.read_to_string(&mut json)) {
+        fail(&format!("Failed to read config file {}: {}", path, e));
+    }
+    let config = match parse_config(&json) {
+        Some(c) => c,
+        None => fail(&format!("Invalid config file {}", path)),
+    };
+
+    let (pk, sk) = match load_or_create_keys(&config.keys_file) {
+        Ok(keys) => keys,
+        Err(e) => fail(&format!("Failed to load keys from {}: {}",
+                                config.keys_file, e)),
+    };
+    let PublicKey(ref pk_bytes) = pk;
+    println!("Public Key: {}", pk_bytes.to_hex().to_uppercase());
+    println!("MOTD: {}", config.motd);
+
+    let mut sockets = Vec::with_capacity(config.bind.len());
+    for addr in &config.bind {
+        // broadcast is needed for LAN discovery
+        let socket = UdpSocket::bind(addr)
+            .and_then(|s| s.set_nonblocking(true).map(|_| s))
+            .and_then(|s| s.set_broadcast(true).map(|_| s));
+        match socket {
+            Ok(s) => {
+                println!("Listening on {}", addr);
+                sockets.push(s);
+            },
+            Err(e) => println!("Failed to bind to {}: {}", addr, e),
+        }
+    }
+    if sockets.is_empty() {
+        fail("Failed to bind to any address");
+    }
+    let sockets = Sockets(sockets);
+
+    let mut node = DhtNode::new(&pk, &sk);
+    node.motd = config.motd.clone();
+    node.version = DAEMON_VERSION;
+    node.add_bootstrap_nodes(&config.nodes);
+    if config.lan_discovery {
+        let mut sender = LanDiscoverySender::new(config.bind[0].port());
+        sender.add_local_interfaces();
+        node.lan_discovery = Some(sender);
+        println!("LAN discovery enabled");
+    }
+
+    let mut buf = [0; 2048];
+    loop {
+        let now = now();
+        for socket in &sockets.0 {
+            loop {
+                match socket.recv_from(&mut buf) {
+                    Ok((len, addr)) => {
+                        node.handle_packet(socket, &buf[..len], addr, now);
+                    },
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(e) => {
+                        println!("Failed to receive: {}", e);
+                        break
+                    },
+                }
+            }
+        }
+        node.send_requests(&sockets, now);
+        thread::sleep(Duration::from_millis(50));
+    }
+}
@@ -0,0 +1,115 @@
+/*
+    Copyright © 2016 Zetok Zalbavar <zexavexxe@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! DHT crawler.
+//!
+//! Usage: `tox-crawler <nodes.json> [json|csv]`
+//!
+//! Crawls the network starting from nodes in the `nodes.json`, and prints
+//! report in the requested format (JSON by default) to stdout. Progress is
+//! printed to stderr.
+
+extern crate tox;
+
+use std::env;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::UdpSocket;
+use std::process;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tox::toxcore::crypto_core::*;
+use tox::toxcore::dht_bootstrap::parse_nodes_json;
+use tox::toxcore::dht_crawler::*;
+use tox::toxcore::network::{Sockets, Transport};
+
+
+fn fail(msg: &str) -> ! {
+    let _ = writeln!(io::stderr(), "{}", msg);
+    process::exit(1)
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn main() {
+    let usage = "Usage: tox-crawler <nodes.json> [json|csv]";
+    let path = match env::args().nth(1) {
+        Some(p) => p,
+        None => fail(usage),
+    };
+    let csv = match env::args().nth(2) {
+        None => false,
+        Some(ref f) if f == "json" => false,
+        Some(ref f) if f == "csv" => true,
+        Some(_) => fail(usage),
+    };
+
+    let mut json = String::new();
+    if let Err(e) = File::open(&path).and_then(|mut f| f.read_to_string(&mut json)) {
+        fail(&format!("Failed to read {}: {}", path, e));
+    }
+    let nodes = match parse_nodes_json(&json) {
+        Some(n) => n,
+        None => fail(&format!("Invalid nodes file {}", path)),
+    };
+
+    let mut sockets = Vec::new();
+    for addr in &["0.0.0.0:0", "[::]:0"] {
+        match UdpSocket::bind(addr).and_then(|s| s.set_nonblocking(true).map(|_| s)) {
+            Ok(s) => sockets.push(s),
+            Err(e) => { let _ = writeln!(io::stderr(), "Failed to bind to {}: {}",
+                                         addr, e); },
+        }
+    }
+    if sockets.is_empty() {
+        fail("Failed to bind to any address");
+    }
+    let sockets = Sockets(sockets);
+
+    let (pk, sk) = gen_keypair();
+    let mut crawler = DhtCrawler::new(&pk, &sk, &nodes, 0);
+    let mut buf = [0; 2048];
+    while !crawler.is_finished() {
+        let now = now();
+        while let Ok((len, addr)) = sockets.recv_from(&mut buf) {
+            crawler.handle_packet(&buf[..len], addr);
+        }
+        crawler.send_requests(&sockets, now);
+        let report = crawler.report();
+        let _ = write!(io::stderr(), "\rFound: {}, unique PKs: {}, reachable: {}",
+                       report.len(), crawler.unique_pks(),
+                       report.iter()
+                           .filter(|n| n.reachability == Reachability::Reachable)
+                           .count());
+        thread::sleep(Duration::from_millis(50));
+    }
+    let _ = writeln!(io::stderr(), "");
+
+    let report = crawler.report();
+    if csv {
+        print!("{}", report_to_csv(&report));
+    } else {
+        println!("{}", report_to_json(&report));
+    }
+}
@@ -0,0 +1,91 @@
+/*
+    Copyright © 2016 Zetok Zalbavar <zexavexxe@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Print routing table of a node as JSON.
+//!
+//! Usage: `tox-kbucket <own PK> <saved nodes file>`
+//!
+//! Nodes saved with [`save_nodes()`]
+//! (../tox/toxcore/dht_bootstrap/fn.save_nodes.html) are put into a
+//! `Kbucket` for the own PK (64 hex characters), and its [`snapshot`]
+//! (../tox/toxcore/dht/struct.KbucketSnapshot.html) is printed. Diversity
+//! limits aren't applied, so that no saved node is dropped. Since saved
+//! nodes carry no timing information, their last seen time, whether they're
+//! good, and RTT are unknown.
+
+extern crate rustc_serialize;
+extern crate tox;
+
+use rustc_serialize::hex::FromHex;
+
+use std::env;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use tox::toxcore::crypto_core::*;
+use tox::toxcore::dht::*;
+use tox::toxcore::dht_bootstrap::load_nodes;
+
+
+fn fail(msg: &str) -> ! {
+    let _ = writeln!(io::stderr(), "{}", msg);
+    process::exit(1)
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn main() {
+    let usage = "Usage: tox-kbucket <own PK> <saved nodes file>";
+    let (pk, path) = match (env::args().nth(1), env::args().nth(2)) {
+        (Some(pk), Some(path)) => (pk, path),
+        _ => fail(usage),
+    };
+    let pk = pk.from_hex().ok()
+        .and_then(|pk| if pk.len() == PUBLICKEYBYTES {
+            PublicKey::from_slice(&pk)
+        } else {
+            None
+        });
+    let pk = match pk {
+        Some(pk) => pk,
+        None => fail("Invalid PK, 64 hex characters expected"),
+    };
+
+    let mut bytes = Vec::new();
+    if let Err(e) = File::open(&path).and_then(|mut f| f.read_to_end(&mut bytes)) {
+        fail(&format!("Failed to read {}: {}", path, e));
+    }
+
+    let now = now();
+    let mut kbucket = Kbucket::new(KBUCKET_MAX_ENTRIES as u8, &pk);
+    kbucket.limits = DiversityLimits::none();
+    for node in load_nodes(&bytes) {
+        if !kbucket.try_add(&Node::new(&node, 0)) {
+            let _ = writeln!(io::stderr(), "Node {} doesn't fit into Kbucket",
+                             node.saddr);
+        }
+    }
+    println!("{}", kbucket.snapshot(now).to_json_string());
+}
@@ -129,15 +129,35 @@ extern crate sodiumoxide;
 
 extern crate ip;
 
+extern crate rustc_serialize;
+
 
 /// Core Tox module. Provides an API on top of which other modules and
 /// applications may be build.
 #[warn(missing_docs)]
 pub mod toxcore {
     pub mod binary_io;
+    pub mod bootstrap_info;
+    pub mod bootstrapd;
+    pub mod congestion;
+    pub mod crypto_buffer;
+    pub mod crypto_connection;
     pub mod crypto_core;
     pub mod dht;
+    pub mod dht_bootstrap;
+    pub mod dht_crawler;
+    pub mod dht_friend;
+    pub mod dht_lookup;
+    pub mod dht_node;
+    pub mod hole_punching;
+    pub mod lan_discovery;
+    pub mod net_crypto;
     pub mod network;
+    pub mod onion;
+    pub mod onion_announce;
+    pub mod onion_client;
+    pub mod onion_friend;
+    pub mod onion_paths;
 }
 
 
@@ -147,7 +167,26 @@ mod toxcore_tests {
     extern crate rustc_serialize;
 
     mod binary_io_tests;
+    mod bootstrap_info_tests;
+    mod bootstrapd_tests;
+    mod congestion_tests;
+    mod crypto_buffer_tests;
+    mod crypto_connection_tests;
     mod crypto_core_tests;
     mod dht_tests;
+    mod dht_bootstrap_tests;
+    mod dht_crawler_tests;
+    mod dht_friend_tests;
+    mod dht_lookup_tests;
+    mod dht_node_tests;
+    mod hole_punching_tests;
+    mod lan_discovery_tests;
+    mod net_crypto_tests;
     mod network_tests;
+    mod onion_tests;
+    mod onion_announce_tests;
+    mod onion_client_tests;
+    mod onion_friend_tests;
+    mod onion_paths_tests;
+    mod sim_network;
 }
@@ -0,0 +1,116 @@
+/*
+    Copyright © 2016 Zetok Zalbavar <zexavexxe@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Querying bootstrap nodes for their version and message of the day, as
+//! status sites do.
+//!
+//! [`query_bootstrap_info()`](./fn.query_bootstrap_info.html) does the query
+//! in a separate thread, and result can be received from the returned
+//! channel once it's done.
+
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use toxcore::binary_io::*;
+use toxcore::dht::*;
+use toxcore::network::Transport;
+
+
+/// Send [`BootstrapInfoRequest`](../dht/struct.BootstrapInfoRequest.html)
+/// to `addr` with `transport`.
+pub fn send_bootstrap_info_request<T: Transport>(transport: &T,
+                                                 addr: SocketAddr)
+    -> io::Result<()>
+{
+    debug!(target: "BootstrapInfo", "Requesting BootstrapInfo from {}", addr);
+    transport.send_to(&BootstrapInfoRequest.to_bytes(), addr).map(|_| ())
+}
+
+/// Receive a packet with `transport`, and parse it as [`BootstrapInfo`]
+/// (../dht/struct.BootstrapInfo.html) from `addr`.
+///
+/// Returns `None` if packet came from other address, or it couldn't be
+/// parsed, so that caller can check its deadline before receiving the next
+/// one. Returns an error if receiving fails, e.g. because socket's read
+/// timeout was reached.
+pub fn recv_bootstrap_info<T: Transport>(transport: &T, addr: SocketAddr)
+    -> io::Result<Option<BootstrapInfo>>
+{
+    let mut buf = [0; 2048];
+    let (len, src) = try!(transport.recv_from(&mut buf));
+    if src != addr {
+        trace!(target: "BootstrapInfo", "Ignoring packet from {}", src);
+        return Ok(None)
+    }
+    Ok(BootstrapInfo::from_bytes(&buf[..len]))
+}
+
+/// Query bootstrap node at `addr` for its [`BootstrapInfo`]
+/// (../dht/struct.BootstrapInfo.html), blocking for at most `timeout`.
+///
+/// Binds a new UDP socket on a random port to do so. Returns an error of
+/// kind `TimedOut` if node doesn't answer in time.
+pub fn query_bootstrap_info_blocking(addr: SocketAddr, timeout: Duration)
+    -> io::Result<BootstrapInfo>
+{
+    let bind_addr = match addr {
+        SocketAddr::V4(_) => "0.0.0.0:0",
+        SocketAddr::V6(_) => "[::]:0",
+    };
+    let socket = try!(UdpSocket::bind(bind_addr));
+    try!(send_bootstrap_info_request(&socket, addr));
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(io::Error::new(io::ErrorKind::TimedOut,
+                                      "no BootstrapInfo received"))
+        }
+        try!(socket.set_read_timeout(Some(deadline - now)));
+        match recv_bootstrap_info(&socket, addr) {
+            Ok(Some(info)) => return Ok(info),
+            Ok(None) => {},
+            // depending on platform, read timeout is one of these
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock
+                       || e.kind() == io::ErrorKind::TimedOut => {},
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Query bootstrap node at `addr` for its [`BootstrapInfo`]
+/// (../dht/struct.BootstrapInfo.html) without blocking.
+///
+/// Query is done in a new thread as with [`query_bootstrap_info_blocking()`]
+/// (./fn.query_bootstrap_info_blocking.html); its result is sent over the
+/// returned channel after at most `timeout`.
+pub fn query_bootstrap_info(addr: SocketAddr, timeout: Duration)
+    -> Receiver<io::Result<BootstrapInfo>>
+{
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        // receiver might have been dropped, nothing to do then
+        let _ = tx.send(query_bootstrap_info_blocking(addr, timeout));
+    });
+    rx
+}
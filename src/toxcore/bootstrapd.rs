@@ -0,0 +1,261 @@
+/*
+    Copyright © 2016 Zetok Zalbavar <zexavexxe@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Configuration and persistent keys of the bootstrap node daemon,
+//! `tox-bootstrapd`.
+//!
+//! Config file is a JSON object:
+//!
+//! ```json
+//! {
+//!     "keys_file": "/var/lib/tox-bootstrapd/keys",
+//!     "motd": "tox-bootstrapd",
+//!     "addresses": ["0.0.0.0", "::"],
+//!     "ports": [33445, 3389],
+//!     "enable_lan_discovery": true,
+//!     "nodes": [
+//!         {
+//!             "ipv4": "1.2.3.4",
+//!             "ipv6": "-",
+//!             "port": 33445,
+//!             "public_key": "<64 hex characters>"
+//!         }
+//!     ]
+//! }
+//! ```
+//!
+//! All fields are optional. `nodes` are bootstrap peers in the same format
+//! as in `nodes.json`, thus they can be copied from there.
+
+use rustc_serialize::json::Json;
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::str::FromStr;
+
+use toxcore::crypto_core::*;
+use toxcore::dht::PackedNode;
+use toxcore::dht_bootstrap::parse_nodes_json;
+use toxcore::network::PORT_MIN;
+
+
+/// Version of the daemon, served to the status sites in [`BootstrapInfo`]
+/// (../dht/struct.BootstrapInfo.html). Same `YYYYMMDDVV` format as
+/// `tox-bootstrapd` of toxcore uses.
+pub const DAEMON_VERSION: u32 = 2016101900;
+
+/// Default path of the keys file.
+pub const DEFAULT_KEYS_FILE: &'static str = "tox-bootstrapd.keys";
+
+/// Default message of the day.
+pub const DEFAULT_MOTD: &'static str = "tox-bootstrapd";
+
+/// Size of the keys file – Public Key followed by Secret Key.
+pub const KEYS_FILE_SIZE: usize = PUBLICKEYBYTES + SECRETKEYBYTES;
+
+
+/// Configuration of the bootstrap daemon.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Config {
+    /// Path to the file with the keypair.
+    pub keys_file: String,
+    /// Message of the day.
+    pub motd: String,
+    /// Addresses to bind to – every configured address with every
+    /// configured port.
+    pub bind: Vec<SocketAddr>,
+    /// Nodes to bootstrap from.
+    pub nodes: Vec<PackedNode>,
+    /// Whether nodes in the local network should be discovered.
+    pub lan_discovery: bool,
+}
+
+/// Parse [`Config`](./struct.Config.html) from `json`.
+///
+/// Missing fields are set to defaults: [`DEFAULT_KEYS_FILE`]
+/// (./constant.DEFAULT_KEYS_FILE.html), [`DEFAULT_MOTD`]
+/// (./constant.DEFAULT_MOTD.html), address `::` and port [`PORT_MIN`]
+/// (../network/constant.PORT_MIN.html), no bootstrap nodes, LAN discovery
+/// enabled.
+///
+/// Returns `None` if `json` can't be parsed, or if some field has a wrong
+/// type or invalid value.
+pub fn parse_config(json: &str) -> Option<Config> {
+    debug!(target: "Bootstrapd", "Parsing config.");
+    let config = match Json::from_str(json) {
+        Ok(ref c) if c.is_object() => c.clone(),
+        Ok(_) => {
+            debug!("Config is not a JSON object!");
+            return None
+        },
+        Err(e) => {
+            debug!("Failed to parse config: {}", e);
+            return None
+        },
+    };
+
+    let string = |name: &str, default: &str| match config.find(name) {
+        Some(v) => v.as_string().map(|s| s.to_owned()),
+        None => Some(default.to_owned()),
+    };
+    let keys_file = match string("keys_file", DEFAULT_KEYS_FILE) {
+        Some(k) => k,
+        None => {
+            debug!("Invalid keys_file!");
+            return None
+        },
+    };
+    let motd = match string("motd", DEFAULT_MOTD) {
+        Some(m) => m,
+        None => {
+            debug!("Invalid motd!");
+            return None
+        },
+    };
+
+    let lan_discovery = match config.find("enable_lan_discovery") {
+        Some(l) => match l.as_boolean() {
+            Some(l) => l,
+            None => {
+                debug!("Invalid enable_lan_discovery: {:?}", l);
+                return None
+            },
+        },
+        None => true,
+    };
+
+    let addresses: Vec<IpAddr> = match config.find("addresses") {
+        Some(a) => {
+            let parsed: Option<Vec<IpAddr>> = a.as_array().and_then(|a| a.iter()
+                .map(|ip| ip.as_string().and_then(|ip| IpAddr::from_str(ip).ok()))
+                .collect());
+            match parsed {
+                Some(a) => a,
+                None => {
+                    debug!("Invalid addresses: {:?}", a);
+                    return None
+                },
+            }
+        },
+        None => vec![IpAddr::from_str("::").unwrap()],
+    };
+
+    let ports: Vec<u16> = match config.find("ports") {
+        Some(p) => {
+            let parsed: Option<Vec<u16>> = p.as_array().and_then(|p| p.iter()
+                .map(|port| match port.as_u64() {
+                    Some(port) if port > 0 && port <= ::std::u16::MAX as u64 =>
+                        Some(port as u16),
+                    _ => None,
+                })
+                .collect());
+            match parsed {
+                Some(p) => p,
+                None => {
+                    debug!("Invalid ports: {:?}", p);
+                    return None
+                },
+            }
+        },
+        None => vec![PORT_MIN],
+    };
+
+    let mut bind = Vec::with_capacity(addresses.len() * ports.len());
+    for ip in &addresses {
+        for port in &ports {
+            bind.push(SocketAddr::new(*ip, *port));
+        }
+    }
+
+    let nodes = match config.find("nodes") {
+        Some(_) => match parse_nodes_json(json) {
+            Some(n) => n,
+            None => {
+                debug!("Invalid nodes!");
+                return None
+            },
+        },
+        None => vec![],
+    };
+
+    Some(Config {
+        keys_file: keys_file,
+        motd: motd,
+        bind: bind,
+        nodes: nodes,
+        lan_discovery: lan_discovery,
+    })
+}
+
+
+/// Load keypair from the file at `path`, or generate new keypair and save
+/// it there if file doesn't exist.
+///
+/// File contains Public Key followed by Secret Key. Fails if file has a
+/// size different from [`KEYS_FILE_SIZE`](./constant.KEYS_FILE_SIZE.html).
+///
+/// New file is readable only by its owner.
+pub fn load_or_create_keys<P: AsRef<Path>>(path: P)
+    -> io::Result<(PublicKey, SecretKey)>
+{
+    let path = path.as_ref();
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    set_owner_only(&mut options);
+    match options.open(path) {
+        Ok(mut file) => {
+            debug!(target: "Bootstrapd", "Generating new keys: {:?}", path);
+            let (pk, sk) = gen_keypair();
+            let PublicKey(ref pk_bytes) = pk;
+            let SecretKey(ref sk_bytes) = sk;
+            try!(file.write_all(pk_bytes));
+            try!(file.write_all(sk_bytes));
+            return Ok((pk, sk))
+        },
+        Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {},
+        Err(e) => return Err(e),
+    }
+
+    debug!(target: "Bootstrapd", "Loading keys: {:?}", path);
+    let mut bytes = Vec::with_capacity(KEYS_FILE_SIZE);
+    try!(File::open(path).and_then(|mut f| f.read_to_end(&mut bytes)));
+    if bytes.len() != KEYS_FILE_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  "keys file has a wrong size"))
+    }
+    let pk = PublicKey::from_slice(&bytes[..PUBLICKEYBYTES]);
+    let sk = SecretKey::from_slice(&bytes[PUBLICKEYBYTES..]);
+    match (pk, sk) {
+        (Some(pk), Some(sk)) => Ok((pk, sk)),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData,
+                                "keys file can't be parsed")),
+    }
+}
+
+#[cfg(unix)]
+fn set_owner_only(options: &mut OpenOptions) {
+    options.mode(0o600);
+}
+
+#[cfg(not(unix))]
+fn set_owner_only(_options: &mut OpenOptions) {}
@@ -0,0 +1,344 @@
+/*
+    Copyright © 2016 Zetok Zalbavar <zexavexxe@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+
+//! Congestion control of lossless data sent over crypto connections.
+//!
+//! Controller decides whether a new lossless packet can be sent, or a lost
+//! one resent, based on packets that were sent, and packets that left the
+//! send buffer after the peer received them. Lossy packets aren't limited.
+//!
+//! [`ToxcoreCongestion`](./struct.ToxcoreCongestion.html) is the default,
+//! and behaves like the toxcore one. [`Ledbat`](./struct.Ledbat.html) yields
+//! to other traffic, and is meant for bulk transfers, e.g. of files.
+//!
+//! Time is in milliseconds.
+
+use std::collections::VecDeque;
+
+
+/// Controller of the rate at which lossless packets are sent.
+pub trait CongestionControl {
+    /// Whether a new lossless packet can be sent at `now`, with `queue`
+    /// packets in the send buffer waiting for acknowledgement.
+    fn can_send(&mut self, queue: usize, now: u64) -> bool;
+
+    /// Whether a lost or requested packet can be resent at `now`, with
+    /// `queue` packets in the send buffer waiting for acknowledgement.
+    /// Resent packets use the same budget as new ones, so that resending
+    /// doesn't burst when many packets are lost.
+    fn can_resend(&mut self, queue: usize, now: u64) -> bool;
+
+    /// Lossless packet was sent at `now`. If it was `resent`, it was
+    /// probably lost.
+    fn on_sent(&mut self, resent: bool, now: u64);
+
+    /// `count` packets left the send buffer at `now`, since the peer
+    /// received them. `rtt` is the round-trip time of one of them, if it
+    /// could be measured.
+    fn on_acked(&mut self, count: usize, rtt: Option<u64>, now: u64);
+
+    /// Current send rate in packets per second.
+    fn send_rate(&self) -> f64;
+}
+
+
+/// Metrics of lossless data sent over a connection.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CongestionMetrics {
+    /// Current send rate in packets per second.
+    pub send_rate: f64,
+    /// Number of packets in the send buffer, waiting for acknowledgement.
+    pub queue_depth: usize,
+    /// Smoothed round-trip time in milliseconds, if it was measured.
+    pub rtt: Option<u64>,
+}
+
+
+/// Length in milliseconds of an interval, in which packets that left the
+/// send buffer are counted.
+pub const PACKET_COUNTER_AVERAGE_INTERVAL: u64 = 50;
+
+/// Number of intervals over which rate of packets leaving the send buffer
+/// is averaged.
+pub const CONGESTION_QUEUE_ARRAY_SIZE: usize = 12;
+
+/// Minimum send rate in packets per second.
+pub const CRYPTO_PACKET_MIN_RATE: f64 = 4.0;
+
+/// Queue length below which send rate isn't reduced because of the
+/// queue, and number of packets that can be sent in a burst.
+pub const CRYPTO_MIN_QUEUE_LENGTH: usize = 64;
+
+/// Send rate is reduced when it would take more than this number of
+/// seconds to empty the queue at the current rate.
+pub const SEND_QUEUE_RATIO: f64 = 2.0;
+
+/// Send rate grows only if no packet was resent for this number of
+/// milliseconds.
+pub const CONGESTION_EVENT_TIMEOUT: u64 = 1000;
+
+
+/// Congestion controller that works like the one in toxcore.
+///
+/// Every [`PACKET_COUNTER_AVERAGE_INTERVAL`]
+/// (./constant.PACKET_COUNTER_AVERAGE_INTERVAL.html) milliseconds speed at
+/// which packets left the send buffer is computed. Send rate is set to
+/// this speed increased by 20%, if no packet was resent recently, or
+/// decreased by 10% otherwise. If the queue is long enough that it would
+/// take more than [`SEND_QUEUE_RATIO`](./constant.SEND_QUEUE_RATIO.html)
+/// seconds to empty it, send rate is decreased in proportion.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ToxcoreCongestion {
+    rate: f64,
+    /// Number of packets that can be sent right now.
+    allowance: f64,
+    last_refill: Option<u64>,
+    interval_start: Option<u64>,
+    /// Number of packets that left the send buffer in the last intervals.
+    left: VecDeque<usize>,
+    left_current: usize,
+    last_congestion: Option<u64>,
+}
+
+impl ToxcoreCongestion {
+    /// Create new `ToxcoreCongestion` with the minimum rate, that allows
+    /// to send [`CRYPTO_MIN_QUEUE_LENGTH`]
+    /// (./constant.CRYPTO_MIN_QUEUE_LENGTH.html) packets right away.
+    pub fn new() -> Self {
+        ToxcoreCongestion {
+            rate: CRYPTO_PACKET_MIN_RATE,
+            allowance: CRYPTO_MIN_QUEUE_LENGTH as f64,
+            last_refill: None,
+            interval_start: None,
+            left: VecDeque::with_capacity(CONGESTION_QUEUE_ARRAY_SIZE),
+            left_current: 0,
+            last_congestion: None,
+        }
+    }
+
+    fn update(&mut self, queue: usize, now: u64) {
+        if let Some(t) = self.last_refill {
+            let elapsed = now.saturating_sub(t) as f64 / 1000.0;
+            self.allowance = (self.allowance + self.rate * elapsed)
+                .min(CRYPTO_MIN_QUEUE_LENGTH as f64);
+        }
+        self.last_refill = Some(now);
+
+        let start = match self.interval_start {
+            Some(s) => s,
+            None => {
+                self.interval_start = Some(now);
+                return
+            },
+        };
+        if start + PACKET_COUNTER_AVERAGE_INTERVAL > now {
+            return
+        }
+        // intervals without any calls count as intervals without packets
+        let intervals = (now - start) / PACKET_COUNTER_AVERAGE_INTERVAL;
+        for i in 0..intervals.min(CONGESTION_QUEUE_ARRAY_SIZE as u64) {
+            let left = if i == 0 { self.left_current } else { 0 };
+            if self.left.len() == CONGESTION_QUEUE_ARRAY_SIZE {
+                self.left.pop_front();
+            }
+            self.left.push_back(left);
+        }
+        self.left_current = 0;
+        self.interval_start = Some(start + intervals * PACKET_COUNTER_AVERAGE_INTERVAL);
+
+        let total: usize = self.left.iter().sum();
+        let speed = (1000.0 * total as f64
+            / (CONGESTION_QUEUE_ARRAY_SIZE as u64 * PACKET_COUNTER_AVERAGE_INTERVAL) as f64)
+            .max(CRYPTO_PACKET_MIN_RATE);
+        let ratio = queue as f64 / speed;
+        let congested = self.last_congestion
+            .map_or(false, |t| t + CONGESTION_EVENT_TIMEOUT > now);
+        self.rate = if ratio > SEND_QUEUE_RATIO && queue > CRYPTO_MIN_QUEUE_LENGTH {
+            speed * SEND_QUEUE_RATIO / ratio
+        } else if congested {
+            speed * 0.9
+        } else {
+            speed * 1.2
+        };
+        self.rate = self.rate.max(CRYPTO_PACKET_MIN_RATE);
+        trace!(target: "Congestion", "Send rate is {} packets/s.", self.rate);
+    }
+}
+
+impl CongestionControl for ToxcoreCongestion {
+    fn can_send(&mut self, queue: usize, now: u64) -> bool {
+        self.update(queue, now);
+        self.allowance >= 1.0
+    }
+
+    fn can_resend(&mut self, queue: usize, now: u64) -> bool {
+        self.can_send(queue, now)
+    }
+
+    fn on_sent(&mut self, resent: bool, now: u64) {
+        self.allowance = (self.allowance - 1.0).max(0.0);
+        if resent {
+            self.last_congestion = Some(now);
+        }
+    }
+
+    fn on_acked(&mut self, count: usize, _rtt: Option<u64>, _now: u64) {
+        self.left_current += count;
+    }
+
+    fn send_rate(&self) -> f64 {
+        self.rate
+    }
+}
+
+
+/// Queuing delay in milliseconds that [`Ledbat`](./struct.Ledbat.html)
+/// aims for.
+pub const LEDBAT_TARGET: u64 = 100;
+
+/// Maximum growth of the window of [`Ledbat`](./struct.Ledbat.html) in
+/// packets per round-trip time.
+pub const LEDBAT_GAIN: f64 = 1.0;
+
+/// Initial window of [`Ledbat`](./struct.Ledbat.html) in packets.
+pub const LEDBAT_INITIAL_WINDOW: f64 = 4.0;
+
+/// Minimum window of [`Ledbat`](./struct.Ledbat.html) in packets.
+pub const LEDBAT_MIN_WINDOW: f64 = 2.0;
+
+/// Number of minutes over which the base delay is remembered by
+/// [`Ledbat`](./struct.Ledbat.html).
+pub const LEDBAT_BASE_HISTORY: usize = 10;
+
+
+/// Delay-based congestion controller, like [LEDBAT]
+/// (https://tools.ietf.org/html/rfc6817).
+///
+/// Window of packets that can wait for acknowledgement grows while the
+/// queuing delay, i.e. round-trip time above the lowest one seen recently,
+/// is below [`LEDBAT_TARGET`](./constant.LEDBAT_TARGET.html), and shrinks
+/// when it's above, so that connection yields to other traffic before
+/// losses happen. Window is halved when a packet is lost, at most once per
+/// round-trip time. Resent packets are paced at the window per round-trip
+/// time.
+///
+/// Since only round-trip times are known, they are used instead of one-way
+/// delays.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Ledbat {
+    window: f64,
+    /// Lowest round-trip time in each of the last minutes.
+    base: VecDeque<(u64, u64)>,
+    last_rtt: Option<u64>,
+    last_loss: Option<u64>,
+    /// Number of packets that can be resent right now.
+    allowance: f64,
+    last_refill: Option<u64>,
+}
+
+impl Ledbat {
+    /// Create new `Ledbat` with [`LEDBAT_INITIAL_WINDOW`]
+    /// (./constant.LEDBAT_INITIAL_WINDOW.html).
+    pub fn new() -> Self {
+        Ledbat {
+            window: LEDBAT_INITIAL_WINDOW,
+            base: VecDeque::with_capacity(LEDBAT_BASE_HISTORY),
+            last_rtt: None,
+            last_loss: None,
+            allowance: LEDBAT_INITIAL_WINDOW,
+            last_refill: None,
+        }
+    }
+
+    /// Number of packets that can wait for acknowledgement.
+    pub fn window(&self) -> f64 {
+        self.window
+    }
+
+    /// Lowest round-trip time seen recently, if any was measured.
+    pub fn base_delay(&self) -> Option<u64> {
+        self.base.iter().map(|&(_, rtt)| rtt).min()
+    }
+
+    fn add_base_delay(&mut self, rtt: u64, now: u64) {
+        let minute = now / 60000;
+        if let Some(last) = self.base.back_mut() {
+            if last.0 == minute {
+                last.1 = last.1.min(rtt);
+                return
+            }
+        }
+        while self.base.front().map_or(false,
+                |&(m, _)| m + LEDBAT_BASE_HISTORY as u64 <= minute) {
+            self.base.pop_front();
+        }
+        self.base.push_back((minute, rtt));
+    }
+}
+
+impl CongestionControl for Ledbat {
+    fn can_send(&mut self, queue: usize, _now: u64) -> bool {
+        (queue as f64) < self.window
+    }
+
+    fn can_resend(&mut self, _queue: usize, now: u64) -> bool {
+        if let Some(t) = self.last_refill {
+            let elapsed = now.saturating_sub(t) as f64 / 1000.0;
+            self.allowance = (self.allowance + self.send_rate() * elapsed)
+                .min(self.window);
+        }
+        self.last_refill = Some(now);
+        self.allowance >= 1.0
+    }
+
+    fn on_sent(&mut self, resent: bool, now: u64) {
+        if !resent {
+            return
+        }
+        self.allowance = (self.allowance - 1.0).max(0.0);
+        let rtt = self.last_rtt.unwrap_or(0);
+        if self.last_loss.map_or(true, |t| t + rtt <= now) {
+            self.window = (self.window / 2.0).max(LEDBAT_MIN_WINDOW);
+            self.last_loss = Some(now);
+            trace!(target: "Congestion", "Loss; window is {}.", self.window);
+        }
+    }
+
+    fn on_acked(&mut self, count: usize, rtt: Option<u64>, now: u64) {
+        let rtt = match rtt {
+            Some(rtt) => rtt,
+            None => return,
+        };
+        self.add_base_delay(rtt, now);
+        self.last_rtt = Some(rtt);
+        let queuing_delay = rtt - self.base_delay().unwrap_or(rtt);
+        let off_target = (LEDBAT_TARGET as f64 - queuing_delay as f64)
+            / LEDBAT_TARGET as f64;
+        self.window += LEDBAT_GAIN * off_target * count as f64 / self.window;
+        self.window = self.window.max(LEDBAT_MIN_WINDOW);
+    }
+
+    fn send_rate(&self) -> f64 {
+        match self.last_rtt {
+            Some(rtt) => self.window * 1000.0 / rtt.max(1) as f64,
+            None => self.window,
+        }
+    }
+}
@@ -0,0 +1,309 @@
+/*
+    Copyright © 2016 Zetok Zalbavar <zexavexxe@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+
+//! Buffers of lossless packets sent over a crypto connection.
+//!
+//! Packets are numbered sequentially, with numbers wrapping around. Sent
+//! packets are kept in [`SendBuffer`](./struct.SendBuffer.html) until the
+//! peer reports that it has received them; received packets are kept in
+//! [`RecvBuffer`](./struct.RecvBuffer.html) until all packets before them
+//! were received, so that they can be delivered in order.
+//!
+//! Receiver reports missing packets with a packet request, that lists
+//! them in a compact form – each byte is the distance from the previous
+//! missing packet (or from the packet before the start of the buffer, for
+//! the first one). Distance of `255` without a missing packet is written as
+//! `0`, and counting starts again. E.g. if packets `1`, `3` and `300` are
+//! missing, and the buffer starts at `1`, the request is `[1, 2, 0, 42]`.
+
+use std::collections::VecDeque;
+
+
+/// Maximum number of packets that a buffer can hold.
+pub const CRYPTO_PACKET_BUFFER_SIZE: u32 = 32768;
+
+
+/// Lossless packet waiting in [`SendBuffer`](./struct.SendBuffer.html) for
+/// the peer to receive it.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SentPacket {
+    /// Data of the packet, starting with its ID.
+    pub data: Vec<u8>,
+    /// Time when packet was sent the last time.
+    pub sent: u64,
+    /// Whether packet was sent more than once.
+    pub resent: bool,
+    /// Whether peer requested the packet to be sent again.
+    pub requested: bool,
+}
+
+
+/// Buffer of sent lossless packets, that weren't acknowledged by the peer
+/// yet.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SendBuffer {
+    start: u32,
+    /// Packets by their number after the `start`. Packets that peer
+    /// received out of order are removed before the `start` moves past
+    /// them.
+    packets: VecDeque<Option<SentPacket>>,
+    /// Number of packets that weren't removed.
+    count: usize,
+}
+
+impl SendBuffer {
+    /// Create new empty `SendBuffer`.
+    pub fn new() -> Self {
+        SendBuffer { start: 0, packets: VecDeque::new(), count: 0 }
+    }
+
+    /// Number of the first packet that wasn't acknowledged.
+    pub fn start(&self) -> u32 {
+        self.start
+    }
+
+    /// Number that the next added packet will get.
+    pub fn end(&self) -> u32 {
+        self.start.wrapping_add(self.packets.len() as u32)
+    }
+
+    /// Number of packets from the start to the end of the buffer.
+    pub fn len(&self) -> usize {
+        self.packets.len()
+    }
+
+    /// Whether buffer has no packets.
+    pub fn is_empty(&self) -> bool {
+        self.packets.is_empty()
+    }
+
+    /// Number of packets in the buffer, without those that peer received
+    /// out of order.
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// Whether buffer can't hold any more packets.
+    pub fn is_full(&self) -> bool {
+        self.packets.len() as u32 >= CRYPTO_PACKET_BUFFER_SIZE
+    }
+
+    /// Add packet with `data` sent at `now`. Returns number of the packet,
+    /// or `None` if the buffer is full.
+    pub fn push(&mut self, data: Vec<u8>, now: u64) -> Option<u32> {
+        if self.is_full() {
+            debug!(target: "CryptoBuffer", "SendBuffer is full.");
+            return None
+        }
+        let number = self.end();
+        self.packets.push_back(Some(SentPacket {
+            data: data,
+            sent: now,
+            resent: false,
+            requested: false,
+        }));
+        self.count += 1;
+        Some(number)
+    }
+
+    /// Get packet with `number`.
+    pub fn get(&self, number: u32) -> Option<&SentPacket> {
+        self.index(number).and_then(|i| self.packets[i].as_ref())
+    }
+
+    /// Get mutable packet with `number`.
+    pub fn get_mut(&mut self, number: u32) -> Option<&mut SentPacket> {
+        match self.index(number) {
+            Some(i) => self.packets[i].as_mut(),
+            None => None,
+        }
+    }
+
+    /// Numbers of all packets in the buffer, in order.
+    pub fn numbers(&self) -> Vec<u32> {
+        let start = self.start;
+        self.packets.iter().enumerate()
+            .filter(|&(_, p)| p.is_some())
+            .map(|(i, _)| start.wrapping_add(i as u32))
+            .collect()
+    }
+
+    /// Remove packets with numbers before `buffer_start`, that peer has
+    /// received. Returns removed packets.
+    ///
+    /// `buffer_start` that is outside of the buffer is ignored.
+    pub fn ack(&mut self, buffer_start: u32) -> Vec<SentPacket> {
+        let count = buffer_start.wrapping_sub(self.start) as usize;
+        if count > self.packets.len() {
+            debug!(target: "CryptoBuffer", "Acknowledged number is out of buffer.");
+            return Vec::new()
+        }
+        self.start = buffer_start;
+        let acked: Vec<SentPacket> = self.packets.drain(..count)
+            .filter_map(|p| p).collect();
+        self.count -= acked.len();
+        acked
+    }
+
+    /// Handle packet `request` in the compact form, without the ID of the
+    /// message. Packets that peer didn't request up to the last requested
+    /// one were received, and are removed.
+    ///
+    /// Returns numbers of requested packets that are in the buffer.
+    pub fn handle_request(&mut self, request: &[u8]) -> Vec<u32> {
+        let mut requested = Vec::new();
+        let mut request = request.iter();
+        let mut next = match request.next() {
+            Some(&b) => b,
+            None => return requested,
+        };
+        let mut n: u8 = 1;
+        for i in 0..self.packets.len() {
+            if n == next {
+                if self.packets[i].is_some() {
+                    requested.push(self.start.wrapping_add(i as u32));
+                }
+                n = 0;
+                next = match request.next() {
+                    Some(&b) => b,
+                    None => break,
+                };
+            } else if self.packets[i].take().is_some() {
+                self.count -= 1;
+            }
+            if n == 255 {
+                // `next` is `0` here, since any other value would've matched
+                n = 1;
+                next = match request.next() {
+                    Some(&b) => b,
+                    None => break,
+                };
+            } else {
+                n += 1;
+            }
+        }
+        requested
+    }
+
+    fn index(&self, number: u32) -> Option<usize> {
+        let index = number.wrapping_sub(self.start) as usize;
+        if index < self.packets.len() {
+            Some(index)
+        } else {
+            None
+        }
+    }
+}
+
+
+/// Buffer of received lossless packets, that can't be delivered until all
+/// packets before them are received.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RecvBuffer {
+    start: u32,
+    packets: VecDeque<Option<Vec<u8>>>,
+}
+
+impl RecvBuffer {
+    /// Create new empty `RecvBuffer`.
+    pub fn new() -> Self {
+        RecvBuffer { start: 0, packets: VecDeque::new() }
+    }
+
+    /// Number of the first packet that wasn't received yet.
+    pub fn start(&self) -> u32 {
+        self.start
+    }
+
+    /// Number after the last received packet.
+    pub fn end(&self) -> u32 {
+        self.start.wrapping_add(self.packets.len() as u32)
+    }
+
+    /// Whether some packets before the last received one are missing.
+    pub fn has_missing(&self) -> bool {
+        !self.packets.is_empty()
+    }
+
+    /// Whether packet with `number` was received, but not delivered yet.
+    pub fn contains(&self, number: u32) -> bool {
+        let index = number.wrapping_sub(self.start) as usize;
+        self.packets.get(index).map_or(false, |p| p.is_some())
+    }
+
+    /// Add packet with `number` and `data`.
+    ///
+    /// Returns `false` if packet was already received, or its number is too
+    /// far ahead of the start of the buffer.
+    pub fn insert(&mut self, number: u32, data: Vec<u8>) -> bool {
+        let index = number.wrapping_sub(self.start);
+        if index >= CRYPTO_PACKET_BUFFER_SIZE {
+            trace!(target: "CryptoBuffer", "Packet {} is out of RecvBuffer.",
+                   number);
+            return false
+        }
+        let index = index as usize;
+        while self.packets.len() <= index {
+            self.packets.push_back(None);
+        }
+        if self.packets[index].is_some() {
+            trace!(target: "CryptoBuffer", "Packet {} was already received.",
+                   number);
+            return false
+        }
+        self.packets[index] = Some(data);
+        true
+    }
+
+    /// Take the next packet, if all packets before it were received.
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        match self.packets.front() {
+            Some(&Some(_)) => {},
+            _ => return None,
+        }
+        self.start = self.start.wrapping_add(1);
+        self.packets.pop_front().and_then(|p| p)
+    }
+
+    /// Packet request in the compact form, without the ID of the message,
+    /// listing missing packets. Request is cut to `max_size` bytes.
+    pub fn packet_request(&self, max_size: usize) -> Vec<u8> {
+        let mut result = Vec::new();
+        let mut n: u8 = 1;
+        for packet in &self.packets {
+            if result.len() >= max_size {
+                break
+            }
+            if packet.is_none() {
+                result.push(n);
+                n = 0;
+            } else if n == 255 {
+                result.push(0);
+                n = 0;
+            }
+            n += 1;
+        }
+        // zeros after the last missing packet don't carry any information
+        while result.last() == Some(&0) {
+            result.pop();
+        }
+        result
+    }
+}
@@ -0,0 +1,1107 @@
+/*
+    Copyright © 2016 Zetok Zalbavar <zexavexxe@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+
+//! Crypto connections between friends, established over UDP.
+//!
+//! Side that initiates connection first requests a cookie from the peer's
+//! DHT PK, and then sends [`CryptoHandshake`]
+//! (../net_crypto/struct.CryptoHandshake.html) with that cookie. Peer
+//! answers with its own handshake, using the cookie from the received one.
+//! Each side generates a new session keypair, and after both handshakes are
+//! received, session key is computed from own session SK and peer's
+//! session PK.
+//!
+//! Both sides may initiate connection at the same time – handshake from
+//! the peer is accepted in any state, and since both handshakes carry
+//! session PKs, connection ends up being the same.
+//!
+//! Status of a connection:
+//!
+//! ```text
+//! CookieRequesting ──> HandshakeSending ──> NotConfirmed ──> Established
+//!        │                                       ^
+//!        └───────── handshake received ──────────┘
+//! ```
+//!
+//! Connection that didn't get established in time, or established one that
+//! received nothing from the peer for [`CRYPTO_CONNECTION_TIMEOUT`]
+//! (./constant.CRYPTO_CONNECTION_TIMEOUT.html) milliseconds, becomes
+//! `TimedOut`. Established connection sends a packet request at least every
+//! [`CRYPTO_KEEPALIVE_INTERVAL`](./constant.CRYPTO_KEEPALIVE_INTERVAL.html)
+//! milliseconds, so that it doesn't time out on the peer's side. Connection
+//! closed by either side becomes `Killed`.
+//!
+//! Until it's established, last packet is resent every
+//! [`CRYPTO_SEND_PACKET_INTERVAL`](./constant.CRYPTO_SEND_PACKET_INTERVAL.html)
+//! milliseconds, and connection times out after
+//! [`MAX_NUM_SENDPACKET_TRIES`](./constant.MAX_NUM_SENDPACKET_TRIES.html)
+//! attempts.
+//!
+//! Once session key is known, [`CryptoMessage`](./struct.CryptoMessage.html)s
+//! can be sent over the connection. Lossless messages are numbered, kept
+//! in the [`SendBuffer`](../crypto_buffer/struct.SendBuffer.html) and resent
+//! until the peer acknowledges them, and they are delivered in order. Lossy
+//! messages are sent once, and delivered as they arrive. Connection becomes
+//! established when the first data from the peer is received.
+//!
+//! Receiver of lossless messages acknowledges them with a packet request,
+//! that lists packets missing from its receive buffer, and sender resends
+//! exactly those. Packets that weren't acknowledged or requested within the
+//! retransmission timeout, computed from the round-trip time by
+//! [`RttEstimator`](./struct.RttEstimator.html), are resent as well.
+//!
+//! New lossless messages can be sent, and lost ones resent, only as fast as
+//! the [`CongestionControl`](../congestion/trait.CongestionControl.html) of
+//! the connection allows, [`ToxcoreCongestion`]
+//! (../congestion/struct.ToxcoreCongestion.html) by default.
+//!
+//! Unlike the DHT, time is in milliseconds, since data sent over the
+//! connection needs finer resolution than seconds.
+
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+
+use toxcore::binary_io::*;
+use toxcore::congestion::*;
+use toxcore::crypto_buffer::*;
+use toxcore::crypto_core::*;
+use toxcore::dht::PacketKind;
+use toxcore::net_crypto::*;
+use toxcore::network::Transport;
+
+
+/// Interval in milliseconds between resending cookie requests and
+/// handshakes.
+pub const CRYPTO_SEND_PACKET_INTERVAL: u64 = 1000;
+
+/// Number of times cookie request or handshake is sent before connection
+/// times out.
+pub const MAX_NUM_SENDPACKET_TRIES: u8 = 8;
+
+/// Retransmission timeout in milliseconds before round-trip time is
+/// measured.
+pub const CRYPTO_INITIAL_RTO: u64 = 1000;
+
+/// Minimum retransmission timeout in milliseconds.
+pub const CRYPTO_MIN_RTO: u64 = 200;
+
+/// Maximum retransmission timeout in milliseconds.
+pub const CRYPTO_MAX_RTO: u64 = 10000;
+
+/// Interval in milliseconds after which established connection sends a
+/// packet request, if it sent nothing else.
+pub const CRYPTO_KEEPALIVE_INTERVAL: u64 = 8000;
+
+/// Number of milliseconds after which established connection that received
+/// nothing from the peer times out.
+pub const CRYPTO_CONNECTION_TIMEOUT: u64 = 4 * CRYPTO_KEEPALIVE_INTERVAL;
+
+/// Maximum number of connections that aren't established yet. Above it,
+/// connections initiated by peers are rejected.
+pub const MAX_PENDING_CONNECTIONS: usize = 16;
+
+/// Interval in milliseconds between repeated packet requests, while some
+/// lossless packets are missing.
+pub const PACKET_REQUEST_INTERVAL: u64 = 200;
+
+
+/// Own keys used by crypto connections.
+#[derive(Clone)]
+pub struct ConnectionKeys {
+    /// Own long-term PK.
+    pub real_pk: PublicKey,
+    /// Own long-term SK.
+    pub real_sk: SecretKey,
+    /// Own DHT PK.
+    pub dht_pk: PublicKey,
+    /// Own DHT SK.
+    pub dht_sk: SecretKey,
+}
+
+
+/// Status of a [`CryptoConnection`](./struct.CryptoConnection.html).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ConnectionStatus {
+    /// Cookie request is being sent to the peer.
+    CookieRequesting,
+    /// Own handshake is being sent, peer's handshake wasn't received yet.
+    HandshakeSending,
+    /// Handshakes were exchanged, but no data from the peer was received
+    /// yet.
+    NotConfirmed,
+    /// Data from the peer was received with the session key.
+    Established,
+    /// Peer didn't answer in time.
+    TimedOut,
+    /// Connection was closed by either side.
+    Killed,
+}
+
+
+/// Estimator of the round-trip time and the retransmission timeout, as in
+/// [RFC 6298](https://tools.ietf.org/html/rfc6298).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RttEstimator {
+    srtt: Option<u64>,
+    rttvar: u64,
+    rto: u64,
+}
+
+impl RttEstimator {
+    /// Create new `RttEstimator` without any samples, with timeout of
+    /// [`CRYPTO_INITIAL_RTO`](./constant.CRYPTO_INITIAL_RTO.html).
+    pub fn new() -> Self {
+        RttEstimator { srtt: None, rttvar: 0, rto: CRYPTO_INITIAL_RTO }
+    }
+
+    /// Smoothed round-trip time in milliseconds, if it was measured.
+    pub fn srtt(&self) -> Option<u64> {
+        self.srtt
+    }
+
+    /// Current retransmission timeout in milliseconds.
+    pub fn rto(&self) -> u64 {
+        self.rto
+    }
+
+    /// Add round-trip time sample `rtt`.
+    pub fn add_sample(&mut self, rtt: u64) {
+        let srtt = match self.srtt {
+            Some(srtt) => {
+                let diff = if srtt > rtt { srtt - rtt } else { rtt - srtt };
+                self.rttvar = (3 * self.rttvar + diff) / 4;
+                (7 * srtt + rtt) / 8
+            },
+            None => {
+                self.rttvar = rtt / 2;
+                rtt
+            },
+        };
+        self.srtt = Some(srtt);
+        self.rto = clamp_rto(srtt + 4 * self.rttvar);
+    }
+
+    /// Double the timeout after a packet wasn't acknowledged in time.
+    pub fn backoff(&mut self) {
+        self.rto = clamp_rto(self.rto * 2);
+    }
+}
+
+fn clamp_rto(rto: u64) -> u64 {
+    if rto < CRYPTO_MIN_RTO {
+        CRYPTO_MIN_RTO
+    } else if rto > CRYPTO_MAX_RTO {
+        CRYPTO_MAX_RTO
+    } else {
+        rto
+    }
+}
+
+
+/// Message sent over a [`CryptoConnection`](./struct.CryptoConnection.html).
+///
+/// Serialized form:
+///
+/// Length   | Contents
+/// -------- | --------
+/// `1`      | ID of the message
+/// variable | Data
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CryptoMessage {
+    /// ID of the message. Messages with IDs from [`PACKET_ID_LOSSLESS_START`]
+    /// (../net_crypto/constant.PACKET_ID_LOSSLESS_START.html) to
+    /// [`PACKET_ID_LOSSLESS_END`]
+    /// (../net_crypto/constant.PACKET_ID_LOSSLESS_END.html) are lossless,
+    /// from [`PACKET_ID_LOSSY_START`]
+    /// (../net_crypto/constant.PACKET_ID_LOSSY_START.html) to
+    /// [`PACKET_ID_LOSSY_END`](../net_crypto/constant.PACKET_ID_LOSSY_END.html)
+    /// lossy, and the rest are reserved.
+    pub id: u8,
+    /// Data of the message.
+    pub data: Vec<u8>,
+}
+
+impl CryptoMessage {
+    /// Create new `CryptoMessage`.
+    pub fn new(id: u8, data: &[u8]) -> Self {
+        CryptoMessage { id: id, data: data.to_vec() }
+    }
+
+    /// Whether message is delivered reliably and in order.
+    pub fn is_lossless(&self) -> bool {
+        self.id >= PACKET_ID_LOSSLESS_START && self.id <= PACKET_ID_LOSSLESS_END
+    }
+
+    /// Whether message is sent only once, and may be lost.
+    pub fn is_lossy(&self) -> bool {
+        self.id >= PACKET_ID_LOSSY_START && self.id <= PACKET_ID_LOSSY_END
+    }
+}
+
+impl ToBytes for CryptoMessage {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut result = Vec::with_capacity(1 + self.data.len());
+        result.push(self.id);
+        result.extend_from_slice(&self.data);
+        result
+    }
+}
+
+impl FromBytes<CryptoMessage> for CryptoMessage {
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.is_empty() {
+            debug!(target: "CryptoConnection", "Empty CryptoMessage!");
+            return None
+        }
+        Some(CryptoMessage::new(bytes[0], &bytes[1..]))
+    }
+}
+
+
+/// Connection with a peer, identified by its long-term PK.
+pub struct CryptoConnection {
+    keys: ConnectionKeys,
+    /// Long-term PK of the peer.
+    pub peer_real_pk: PublicKey,
+    /// DHT PK of the peer.
+    pub peer_dht_pk: PublicKey,
+    /// Address of the peer.
+    pub addr: SocketAddr,
+    status: ConnectionStatus,
+    session_pk: PublicKey,
+    session_sk: SecretKey,
+    /// Nonce that we use to encrypt the next data packet.
+    sent_nonce: Nonce,
+    peer_session_pk: Option<PublicKey>,
+    /// Nonce that peer used to encrypt data, moved forward as packets
+    /// arrive.
+    peer_nonce: Option<Nonce>,
+    session_key: Option<PrecomputedKey>,
+    echo_id: u64,
+    /// Cookie request or handshake that is resent until connection is
+    /// established.
+    packet: Option<Vec<u8>>,
+    last_sent: Option<u64>,
+    tries: u8,
+    send_buffer: SendBuffer,
+    recv_buffer: RecvBuffer,
+    received: VecDeque<CryptoMessage>,
+    /// Whether lossless packet was received since the last packet request
+    /// was sent.
+    ack_needed: bool,
+    last_request: Option<u64>,
+    /// When data packet was sent last time.
+    last_data_sent: Option<u64>,
+    /// When valid data packet was received last time.
+    last_received: Option<u64>,
+    rtt: RttEstimator,
+    congestion: Box<CongestionControl>,
+}
+
+impl CryptoConnection {
+    /// Create new connection to the peer with `peer_real_pk` and
+    /// `peer_dht_pk`, that is reachable at `addr`. Connection starts with
+    /// requesting a cookie.
+    pub fn new(keys: &ConnectionKeys, peer_real_pk: &PublicKey,
+               peer_dht_pk: &PublicKey, addr: SocketAddr) -> Self {
+        debug!(target: "CryptoConnection", "Creating new CryptoConnection.");
+        let (session_pk, session_sk) = gen_keypair();
+        let echo_id = random_u64();
+        let payload = CookieRequestPayload {
+            real_pk: keys.real_pk,
+            echo_id: echo_id,
+        };
+        let request = CookieRequest::new(&keys.dht_pk, &keys.dht_sk,
+                                         peer_dht_pk, &payload);
+        CryptoConnection {
+            keys: keys.clone(),
+            peer_real_pk: *peer_real_pk,
+            peer_dht_pk: *peer_dht_pk,
+            addr: addr,
+            status: ConnectionStatus::CookieRequesting,
+            session_pk: session_pk,
+            session_sk: session_sk,
+            sent_nonce: gen_nonce(),
+            peer_session_pk: None,
+            peer_nonce: None,
+            session_key: None,
+            echo_id: echo_id,
+            packet: Some(request.to_bytes()),
+            last_sent: None,
+            tries: 0,
+            send_buffer: SendBuffer::new(),
+            recv_buffer: RecvBuffer::new(),
+            received: VecDeque::new(),
+            ack_needed: false,
+            last_request: None,
+            last_data_sent: None,
+            last_received: None,
+            rtt: RttEstimator::new(),
+            congestion: Box::new(ToxcoreCongestion::new()),
+        }
+    }
+
+    /// Status of the connection.
+    pub fn status(&self) -> ConnectionStatus {
+        self.status
+    }
+
+    /// Whether handshakes were exchanged, and messages can be sent.
+    pub fn is_connected(&self) -> bool {
+        self.status == ConnectionStatus::NotConfirmed
+            || self.status == ConnectionStatus::Established
+    }
+
+    /// Whether connection timed out or was killed.
+    pub fn is_closed(&self) -> bool {
+        self.status == ConnectionStatus::TimedOut
+            || self.status == ConnectionStatus::Killed
+    }
+
+    /// Buffer of sent lossless packets, that peer hasn't received yet.
+    pub fn send_buffer(&self) -> &SendBuffer {
+        &self.send_buffer
+    }
+
+    /// Buffer of received lossless packets, that can't be delivered yet.
+    pub fn recv_buffer(&self) -> &RecvBuffer {
+        &self.recv_buffer
+    }
+
+    /// Round-trip time estimate of the connection.
+    pub fn rtt(&self) -> &RttEstimator {
+        &self.rtt
+    }
+
+    /// Replace controller of the rate at which lossless messages are sent,
+    /// e.g. with [`Ledbat`](../congestion/struct.Ledbat.html) for bulk
+    /// transfers.
+    pub fn set_congestion_control(&mut self, congestion: Box<CongestionControl>) {
+        self.congestion = congestion;
+    }
+
+    /// Current send rate and queue depth of lossless messages.
+    pub fn metrics(&self) -> CongestionMetrics {
+        CongestionMetrics {
+            send_rate: self.congestion.send_rate(),
+            queue_depth: self.send_buffer.count(),
+            rtt: self.rtt.srtt(),
+        }
+    }
+
+    /// Own session PK.
+    pub fn session_pk(&self) -> &PublicKey {
+        &self.session_pk
+    }
+
+    /// Session PK of the peer, if its handshake was received.
+    pub fn peer_session_pk(&self) -> Option<&PublicKey> {
+        self.peer_session_pk.as_ref()
+    }
+
+    /// Key used to encrypt and decrypt data, if handshakes were exchanged.
+    pub fn session_key(&self) -> Option<&PrecomputedKey> {
+        self.session_key.as_ref()
+    }
+
+    /// Get packet that should be sent to the peer at `now`, if any.
+    ///
+    /// Cookie request or handshake is resent every
+    /// [`CRYPTO_SEND_PACKET_INTERVAL`]
+    /// (./constant.CRYPTO_SEND_PACKET_INTERVAL.html) milliseconds until
+    /// connection is established. After [`MAX_NUM_SENDPACKET_TRIES`]
+    /// (./constant.MAX_NUM_SENDPACKET_TRIES.html) unanswered attempts
+    /// connection times out. Established connection times out if nothing
+    /// was received for [`CRYPTO_CONNECTION_TIMEOUT`]
+    /// (./constant.CRYPTO_CONNECTION_TIMEOUT.html).
+    pub fn next_packet(&mut self, now: u64) -> Option<Vec<u8>> {
+        self.check_timeout(now);
+        if self.status == ConnectionStatus::Established || self.is_closed() {
+            return None
+        }
+        match self.last_sent {
+            Some(t) if t + CRYPTO_SEND_PACKET_INTERVAL > now => return None,
+            _ => {},
+        }
+        if self.tries >= MAX_NUM_SENDPACKET_TRIES {
+            debug!(target: "CryptoConnection", "Connection timed out.");
+            self.status = ConnectionStatus::TimedOut;
+            self.packet = None;
+            return None
+        }
+        self.tries += 1;
+        self.last_sent = Some(now);
+        self.packet.clone()
+    }
+
+    /// Handle `CookieResponse`, and start sending handshake with the
+    /// received cookie. Cookie for the peer is issued by `issuer`.
+    ///
+    /// Returns `false` if connection doesn't request a cookie, or response
+    /// can't be decrypted, or doesn't carry the expected echo ID.
+    pub fn handle_cookie_response(&mut self, response: &CookieResponse,
+                                  issuer: &CookieIssuer, now: u64) -> bool {
+        if self.status != ConnectionStatus::CookieRequesting {
+            debug!(target: "CryptoConnection", "Unexpected CookieResponse.");
+            return false
+        }
+        let payload = match response.get_payload(&self.keys.dht_sk,
+                                                 &self.peer_dht_pk) {
+            Some(p) => p,
+            None => return false,
+        };
+        if payload.echo_id != self.echo_id {
+            debug!(target: "CryptoConnection", "Wrong echo ID in CookieResponse.");
+            return false
+        }
+        self.send_handshake(&payload.cookie, issuer, now);
+        self.status = ConnectionStatus::HandshakeSending;
+        true
+    }
+
+    /// Handle `CryptoHandshake` with the `cookie` it carries, that was
+    /// already checked by `issuer`.
+    ///
+    /// Session key is computed from the peer's session PK. If connection
+    /// was still requesting a cookie, own handshake is sent with the cookie
+    /// that peer sent. If peer's session PK has changed, i.e. peer has
+    /// restarted the connection, own session is restarted as well.
+    ///
+    /// Returns `false` if handshake isn't from the peer, or can't be
+    /// decrypted.
+    pub fn handle_handshake(&mut self, handshake: &CryptoHandshake,
+                            cookie: &Cookie, issuer: &CookieIssuer,
+                            now: u64) -> bool {
+        if cookie.real_pk != self.peer_real_pk {
+            debug!(target: "CryptoConnection", "Handshake from a wrong peer.");
+            return false
+        }
+        let payload = match handshake.get_payload(&self.keys.real_sk,
+                                                  &self.peer_real_pk) {
+            Some(p) => p,
+            None => return false,
+        };
+        match self.peer_session_pk {
+            Some(pk) if pk == payload.session_pk => {
+                trace!(target: "CryptoConnection", "Duplicate handshake.");
+                return true
+            },
+            Some(_) => {
+                debug!(target: "CryptoConnection",
+                       "Peer restarted session; restarting own.");
+                let (session_pk, session_sk) = gen_keypair();
+                self.session_pk = session_pk;
+                self.session_sk = session_sk;
+                self.sent_nonce = gen_nonce();
+                self.send_buffer = SendBuffer::new();
+                self.recv_buffer = RecvBuffer::new();
+                self.ack_needed = false;
+                self.last_request = None;
+                self.send_handshake(&payload.other_cookie, issuer, now);
+            },
+            None => if self.status == ConnectionStatus::CookieRequesting {
+                self.send_handshake(&payload.other_cookie, issuer, now);
+            },
+        }
+        self.peer_dht_pk = cookie.dht_pk;
+        self.peer_session_pk = Some(payload.session_pk);
+        self.peer_nonce = Some(payload.base_nonce);
+        self.session_key = Some(encrypt_precompute(&payload.session_pk,
+                                                   &self.session_sk));
+        self.status = ConnectionStatus::NotConfirmed;
+        true
+    }
+
+    /// Encrypt `message` into a data packet. Lossless message is added to
+    /// the send buffer, to be resent until the peer acknowledges it.
+    ///
+    /// Returns `None` if handshakes weren't exchanged, ID of the message is
+    /// reserved, message is too big, the send buffer is full, or congestion
+    /// control doesn't allow to send a lossless message yet.
+    pub fn send_message(&mut self, message: &CryptoMessage, now: u64)
+        -> Option<Vec<u8>>
+    {
+        if !self.is_connected() {
+            debug!(target: "CryptoConnection", "Can't send, not connected.");
+            return None
+        }
+        let data = message.to_bytes();
+        if data.len() > MAX_CRYPTO_DATA_SIZE {
+            debug!(target: "CryptoConnection", "Message is too big.");
+            return None
+        }
+        let number = if message.is_lossless() {
+            let queue = self.send_buffer.count();
+            if !self.congestion.can_send(queue, now) {
+                debug!(target: "CryptoConnection", "Can't send, congested.");
+                return None
+            }
+            let number = match self.send_buffer.push(data.clone(), now) {
+                Some(n) => n,
+                None => return None,
+            };
+            self.congestion.on_sent(false, now);
+            number
+        } else if message.is_lossy() {
+            self.send_buffer.end()
+        } else {
+            debug!(target: "CryptoConnection", "Message has reserved ID {}.",
+                   message.id);
+            return None
+        };
+        let packet = self.encrypt_data(number, &data);
+        if packet.is_some() {
+            self.last_data_sent = Some(now);
+        }
+        packet
+    }
+
+    /// Take the next received message. Lossless messages are returned in
+    /// the order they were sent.
+    pub fn recv_message(&mut self) -> Option<CryptoMessage> {
+        self.received.pop_front()
+    }
+
+    /// Handle `CryptoData` from the peer. Connection becomes established,
+    /// lossless packets that peer received are removed from the send buffer,
+    /// and received messages are queued for [`recv_message()`]
+    /// (#method.recv_message).
+    ///
+    /// Returns `false` if handshakes weren't exchanged, or packet can't be
+    /// decrypted with the session key.
+    pub fn handle_data(&mut self, packet: &CryptoData, now: u64) -> bool {
+        if !self.is_connected() {
+            debug!(target: "CryptoConnection", "Unexpected CryptoData.");
+            return false
+        }
+        let mut nonce = match self.peer_nonce {
+            Some(n) => n,
+            None => return false,
+        };
+        let diff = packet.nonce_last_bytes.wrapping_sub(nonce_last_bytes(&nonce));
+        increment_nonce_be(&mut nonce, diff);
+        let payload = match self.session_key {
+            Some(ref key) => match packet.get_payload(key, &nonce) {
+                Some(p) => p,
+                None => return false,
+            },
+            None => return false,
+        };
+        if diff > DATA_NUM_THRESHOLD * 2 {
+            if let Some(ref mut peer_nonce) = self.peer_nonce {
+                increment_nonce_be(peer_nonce, DATA_NUM_THRESHOLD);
+            }
+        }
+        self.last_received = Some(now);
+        if self.status == ConnectionStatus::NotConfirmed {
+            debug!(target: "CryptoConnection", "Connection established.");
+            self.status = ConnectionStatus::Established;
+            self.packet = None;
+        }
+        self.handle_ack(payload.buffer_start, now);
+        self.handle_message(payload.packet_number, payload.data, now);
+        true
+    }
+
+    /// Packets that should be sent at `now`: lossless packets that peer
+    /// requested, or that weren't acknowledged within the retransmission
+    /// timeout, as many as congestion control allows, and a packet request,
+    /// if lossless packets were received since the last one, or some are
+    /// still missing after
+    /// [`PACKET_REQUEST_INTERVAL`](./constant.PACKET_REQUEST_INTERVAL.html),
+    /// or established connection sent nothing for
+    /// [`CRYPTO_KEEPALIVE_INTERVAL`](./constant.CRYPTO_KEEPALIVE_INTERVAL.html).
+    pub fn data_packets(&mut self, now: u64) -> Vec<Vec<u8>> {
+        let mut packets = Vec::new();
+        self.check_timeout(now);
+        if !self.is_connected() {
+            return packets
+        }
+        let request_due = self.ack_needed || (self.recv_buffer.has_missing()
+            && self.last_request.map_or(true, |t| t + PACKET_REQUEST_INTERVAL <= now));
+        let mut timed_out = false;
+        let queue = self.send_buffer.count();
+        let rto = self.rtt.rto();
+        // requested packets go first, then those that were sent the longest
+        // time ago, so that none waits forever when resending is limited
+        let mut due: Vec<(bool, u64, u32)> = {
+            let send_buffer = &self.send_buffer;
+            send_buffer.numbers().into_iter()
+                .filter_map(|n| send_buffer.get(n).map(|p| (!p.requested, p.sent, n)))
+                .filter(|&(not_requested, sent, _)| !not_requested || sent + rto <= now)
+                .collect()
+        };
+        due.sort();
+        for (_, _, number) in due {
+            if !self.congestion.can_resend(queue, now) {
+                trace!(target: "CryptoConnection", "Resending is congested.");
+                break
+            }
+            let data = match self.send_buffer.get_mut(number) {
+                Some(p) => {
+                    timed_out |= !p.requested;
+                    p.sent = now;
+                    p.resent = true;
+                    p.requested = false;
+                    p.data.clone()
+                },
+                None => continue,
+            };
+            trace!(target: "CryptoConnection", "Resending packet {}.", number);
+            self.congestion.on_sent(true, now);
+            if let Some(packet) = self.encrypt_data(number, &data) {
+                packets.push(packet);
+            }
+        }
+        if timed_out {
+            self.rtt.backoff();
+        }
+        let keepalive_due = self.status == ConnectionStatus::Established
+            && packets.is_empty()
+            && self.last_data_sent.map_or(true, |t| t + CRYPTO_KEEPALIVE_INTERVAL <= now);
+        if request_due || keepalive_due {
+            let mut request = vec![PACKET_ID_REQUEST];
+            request.extend(self.recv_buffer.packet_request(MAX_CRYPTO_DATA_SIZE - 1));
+            let number = self.send_buffer.end();
+            if let Some(packet) = self.encrypt_data(number, &request) {
+                packets.push(packet);
+            }
+            self.ack_needed = false;
+            self.last_request = Some(now);
+        }
+        if !packets.is_empty() {
+            self.last_data_sent = Some(now);
+        }
+        packets
+    }
+
+    /// Close the connection. Returns packet that tells the peer about it,
+    /// if handshakes were exchanged.
+    pub fn kill(&mut self) -> Option<Vec<u8>> {
+        let packet = if self.is_connected() {
+            let number = self.send_buffer.end();
+            self.encrypt_data(number, &[PACKET_ID_KILL])
+        } else {
+            None
+        };
+        self.status = ConnectionStatus::Killed;
+        self.packet = None;
+        packet
+    }
+
+    /// Time out established connection that received nothing for
+    /// [`CRYPTO_CONNECTION_TIMEOUT`](./constant.CRYPTO_CONNECTION_TIMEOUT.html).
+    fn check_timeout(&mut self, now: u64) {
+        if self.status != ConnectionStatus::Established {
+            return
+        }
+        if self.last_received.map_or(false, |t| t + CRYPTO_CONNECTION_TIMEOUT <= now) {
+            debug!(target: "CryptoConnection", "Peer is silent; connection timed out.");
+            self.status = ConnectionStatus::TimedOut;
+        }
+    }
+
+    /// Remove packets that peer has received from the send buffer. Packet
+    /// that was sent only once gives a round-trip time sample.
+    fn handle_ack(&mut self, buffer_start: u32, now: u64) {
+        let acked = self.send_buffer.ack(buffer_start);
+        if acked.is_empty() {
+            return
+        }
+        let sample = acked.iter().rev().find(|p| !p.resent)
+            .map(|p| now.saturating_sub(p.sent));
+        if let Some(rtt) = sample {
+            self.rtt.add_sample(rtt);
+        }
+        self.congestion.on_acked(acked.len(), sample, now);
+    }
+
+    /// Mark packets that peer requested to be resent, unless they were
+    /// sent less than a round-trip time ago, and thus may be still on the
+    /// way.
+    fn handle_request(&mut self, request: &[u8], now: u64) {
+        let count = self.send_buffer.count();
+        let requested = self.send_buffer.handle_request(request);
+        let received = count - self.send_buffer.count();
+        if received > 0 {
+            self.congestion.on_acked(received, None, now);
+        }
+        let rtt = self.rtt.srtt().unwrap_or(0);
+        for number in requested {
+            if let Some(p) = self.send_buffer.get_mut(number) {
+                if p.sent + rtt <= now {
+                    p.requested = true;
+                }
+            }
+        }
+    }
+
+    fn handle_message(&mut self, number: u32, data: Vec<u8>, now: u64) {
+        match data[0] {
+            PACKET_ID_REQUEST => self.handle_request(&data[1..], now),
+            PACKET_ID_KILL => {
+                debug!(target: "CryptoConnection", "Connection killed by peer.");
+                self.status = ConnectionStatus::Killed;
+                self.packet = None;
+            },
+            id if id >= PACKET_ID_LOSSLESS_START && id <= PACKET_ID_LOSSLESS_END => {
+                self.recv_buffer.insert(number, data);
+                // acknowledge duplicates as well, so that peer stops resending
+                self.ack_needed = true;
+                while let Some(data) = self.recv_buffer.pop() {
+                    if let Some(message) = CryptoMessage::from_bytes(&data) {
+                        self.received.push_back(message);
+                    }
+                }
+            },
+            id if id >= PACKET_ID_LOSSY_START && id <= PACKET_ID_LOSSY_END => {
+                if let Some(message) = CryptoMessage::from_bytes(&data) {
+                    self.received.push_back(message);
+                }
+            },
+            id => trace!(target: "CryptoConnection",
+                         "Ignoring message with reserved ID {}.", id),
+        }
+    }
+
+    /// Encrypt `data` with the next nonce into a `CryptoData` packet with
+    /// packet `number`.
+    fn encrypt_data(&mut self, number: u32, data: &[u8]) -> Option<Vec<u8>> {
+        let payload = CryptoDataPayload {
+            buffer_start: self.recv_buffer.start(),
+            packet_number: number,
+            data: data.to_vec(),
+        };
+        let packet = match self.session_key {
+            Some(ref key) => CryptoData::new(key, &self.sent_nonce, &payload),
+            None => return None,
+        };
+        increment_nonce_be(&mut self.sent_nonce, 1);
+        Some(packet.to_bytes())
+    }
+
+    /// Make handshake with `cookie` the packet to be sent immediately.
+    fn send_handshake(&mut self, cookie: &EncryptedCookie,
+                      issuer: &CookieIssuer, now: u64) {
+        let payload = HandshakePayload {
+            base_nonce: self.sent_nonce,
+            session_pk: self.session_pk,
+            cookie_hash: cookie_hash(cookie),
+            other_cookie: issuer.issue(&self.peer_real_pk, &self.peer_dht_pk,
+                                       now / 1000),
+        };
+        let handshake = CryptoHandshake::new(&self.keys.real_sk,
+                                             &self.peer_real_pk, cookie,
+                                             &payload);
+        self.packet = Some(handshake.to_bytes());
+        self.last_sent = None;
+        self.tries = 0;
+    }
+}
+
+
+/// Manager of crypto connections – answers cookie requests, and dispatches
+/// cookie responses and handshakes to the connections.
+///
+/// Connection initiated by a peer is created only if callback set with
+/// [`on_new_connection()`](#method.on_new_connection) accepts it, and there
+/// are less than [`MAX_PENDING_CONNECTIONS`]
+/// (./constant.MAX_PENDING_CONNECTIONS.html) connections that aren't
+/// established yet.
+pub struct NetCrypto {
+    keys: ConnectionKeys,
+    issuer: CookieIssuer,
+    connections: Vec<CryptoConnection>,
+    on_new_connection: Option<Box<FnMut(&PublicKey, &PublicKey) -> bool>>,
+}
+
+impl NetCrypto {
+    /// Create new `NetCrypto` with own keys and no connections.
+    pub fn new(keys: &ConnectionKeys) -> Self {
+        debug!(target: "NetCrypto", "Creating new NetCrypto.");
+        NetCrypto {
+            keys: keys.clone(),
+            issuer: CookieIssuer::new(),
+            connections: Vec::new(),
+            on_new_connection: None,
+        }
+    }
+
+    /// Set callback that will be called with long-term PK and DHT PK of a
+    /// peer without a connection that sent a handshake. Connection is
+    /// created only if callback returns `true`, e.g. if peer is a friend.
+    ///
+    /// Without a callback, connections initiated by peers are rejected.
+    pub fn on_new_connection(&mut self,
+                             callback: Box<FnMut(&PublicKey, &PublicKey) -> bool>) {
+        self.on_new_connection = Some(callback);
+    }
+
+    /// Own keys.
+    pub fn keys(&self) -> &ConnectionKeys {
+        &self.keys
+    }
+
+    /// All connections, including closed ones.
+    pub fn connections(&self) -> &[CryptoConnection] {
+        &self.connections
+    }
+
+    /// Get connection with the peer with long-term `pk`.
+    pub fn connection(&self, pk: &PublicKey) -> Option<&CryptoConnection> {
+        self.connections.iter().find(|c| c.peer_real_pk == *pk)
+    }
+
+    /// Get mutable connection with the peer with long-term `pk`.
+    pub fn connection_mut(&mut self, pk: &PublicKey)
+        -> Option<&mut CryptoConnection>
+    {
+        self.connections.iter_mut().find(|c| c.peer_real_pk == *pk)
+    }
+
+    /// Start connecting to the peer with `real_pk` and `dht_pk`, reachable
+    /// at `addr`. Closed connection with the peer is replaced.
+    ///
+    /// Returns `false` if there already is a connection with the peer.
+    pub fn add_connection(&mut self, real_pk: &PublicKey, dht_pk: &PublicKey,
+                          addr: SocketAddr) -> bool {
+        let closed = self.connection(real_pk).map(|c| c.is_closed());
+        match closed {
+            Some(true) => {
+                self.remove_connection(real_pk);
+            },
+            Some(false) => return false,
+            None => {},
+        }
+        let connection = CryptoConnection::new(&self.keys, real_pk, dht_pk, addr);
+        self.connections.push(connection);
+        true
+    }
+
+    /// Remove connection with the peer with long-term `pk`. Returns `false`
+    /// if there was no such connection.
+    pub fn remove_connection(&mut self, pk: &PublicKey) -> bool {
+        let len = self.connections.len();
+        self.connections.retain(|c| c.peer_real_pk != *pk);
+        len != self.connections.len()
+    }
+
+    /// Close connection with the peer with long-term `pk`, telling the peer
+    /// about it with `transport`, and remove it. Returns `false` if there
+    /// was no such connection.
+    pub fn kill_connection<T: Transport>(&mut self, transport: &T,
+                                         pk: &PublicKey) -> bool {
+        match self.connection_mut(pk) {
+            Some(connection) => if let Some(packet) = connection.kill() {
+                send_bytes(transport, &packet, connection.addr);
+            },
+            None => return false,
+        }
+        self.remove_connection(pk)
+    }
+
+    /// Send `message` to the peer with long-term `pk` with `transport`.
+    ///
+    /// Returns `false` if there is no connection with the peer, or message
+    /// can't be sent over it.
+    pub fn send_message<T: Transport>(&mut self, transport: &T, pk: &PublicKey,
+                                      message: &CryptoMessage, now: u64) -> bool {
+        let connection = match self.connection_mut(pk) {
+            Some(c) => c,
+            None => return false,
+        };
+        match connection.send_message(message, now) {
+            Some(packet) => send_bytes(transport, &packet, connection.addr),
+            None => false,
+        }
+    }
+
+    /// Send packets that are due at `now` with `transport`. Returns number
+    /// of sent packets.
+    ///
+    /// Closed connections are kept, so that their status can be checked,
+    /// until they are removed.
+    pub fn send_packets<T: Transport>(&mut self, transport: &T, now: u64)
+        -> usize
+    {
+        let mut sent = 0;
+        for connection in &mut self.connections {
+            if send_next(transport, connection, now) {
+                sent += 1;
+            }
+            for packet in connection.data_packets(now) {
+                if send_bytes(transport, &packet, connection.addr) {
+                    sent += 1;
+                }
+            }
+        }
+        sent
+    }
+
+    /// Handle packet received from `addr`. Cookie requests are answered
+    /// with `transport`. If handshake is received from a peer without a
+    /// connection, new connection is created, if it's accepted. Data is
+    /// passed to the connection with the peer at `addr`.
+    ///
+    /// Returns `false` if packet couldn't be parsed or decrypted, or wasn't
+    /// expected.
+    pub fn handle_packet<T: Transport>(&mut self, transport: &T, bytes: &[u8],
+                                       addr: SocketAddr, now: u64) -> bool {
+        trace!(target: "NetCrypto", "Handling packet from {}", addr);
+        match PacketKind::from_bytes(bytes) {
+            Some(PacketKind::CookieReq) =>
+                self.handle_cookie_request(transport, bytes, addr, now),
+            Some(PacketKind::CookieResp) =>
+                self.handle_cookie_response(transport, bytes, now),
+            Some(PacketKind::CryptoHs) =>
+                self.handle_handshake(transport, bytes, addr, now),
+            Some(PacketKind::CryptoData) =>
+                self.handle_data(bytes, addr, now),
+            _ => {
+                debug!(target: "NetCrypto", "Not a NetCrypto packet from {}",
+                       addr);
+                false
+            },
+        }
+    }
+
+    fn handle_cookie_request<T: Transport>(&self, transport: &T, bytes: &[u8],
+                                           addr: SocketAddr, now: u64) -> bool {
+        let request = match CookieRequest::from_bytes(bytes) {
+            Some(r) => r,
+            None => return false,
+        };
+        match self.issuer.handle_request(&self.keys.dht_sk, &request, now / 1000) {
+            Some(response) => {
+                send_bytes(transport, &response.to_bytes(), addr);
+                true
+            },
+            None => false,
+        }
+    }
+
+    fn handle_cookie_response<T: Transport>(&mut self, transport: &T,
+                                            bytes: &[u8], now: u64) -> bool {
+        let response = match CookieResponse::from_bytes(bytes) {
+            Some(r) => r,
+            None => return false,
+        };
+        let issuer = &self.issuer;
+        match self.connections.iter_mut()
+            .position(|c| c.status() == ConnectionStatus::CookieRequesting
+                          && c.handle_cookie_response(&response, issuer, now)) {
+            Some(pos) => {
+                send_next(transport, &mut self.connections[pos], now);
+                true
+            },
+            None => {
+                debug!(target: "NetCrypto", "Unexpected CookieResponse.");
+                false
+            },
+        }
+    }
+
+    fn handle_handshake<T: Transport>(&mut self, transport: &T, bytes: &[u8],
+                                      addr: SocketAddr, now: u64) -> bool {
+        let handshake = match CryptoHandshake::from_bytes(bytes) {
+            Some(h) => h,
+            None => return false,
+        };
+        let cookie = match self.issuer.check(&handshake.cookie, now / 1000) {
+            Some(c) => c,
+            None => return false,
+        };
+        if self.connection(&cookie.real_pk).map_or(true, |c| c.is_closed()) {
+            if !self.accept_connection(&cookie.real_pk, &cookie.dht_pk) {
+                debug!(target: "NetCrypto", "Rejected connection from {}", addr);
+                return false
+            }
+            debug!(target: "NetCrypto", "Accepting new connection from {}", addr);
+            self.remove_connection(&cookie.real_pk);
+            let connection = CryptoConnection::new(&self.keys, &cookie.real_pk,
+                                                   &cookie.dht_pk, addr);
+            self.connections.push(connection);
+        }
+        let issuer = &self.issuer;
+        let connection = match self.connections.iter_mut()
+            .find(|c| c.peer_real_pk == cookie.real_pk) {
+            Some(c) => c,
+            None => return false,
+        };
+        if !connection.handle_handshake(&handshake, &cookie, issuer, now) {
+            return false
+        }
+        connection.addr = addr;
+        send_next(transport, connection, now);
+        true
+    }
+
+    fn accept_connection(&mut self, real_pk: &PublicKey, dht_pk: &PublicKey)
+        -> bool
+    {
+        let pending = self.connections.iter()
+            .filter(|c| !c.is_closed() && c.status() != ConnectionStatus::Established)
+            .count();
+        if pending >= MAX_PENDING_CONNECTIONS {
+            debug!(target: "NetCrypto", "Too many pending connections.");
+            return false
+        }
+        match self.on_new_connection {
+            Some(ref mut callback) => callback(real_pk, dht_pk),
+            None => false,
+        }
+    }
+
+    fn handle_data(&mut self, bytes: &[u8], addr: SocketAddr, now: u64) -> bool {
+        let packet = match CryptoData::from_bytes(bytes) {
+            Some(p) => p,
+            None => return false,
+        };
+        let handled = self.connections.iter_mut()
+            .any(|c| c.addr == addr && c.handle_data(&packet, now));
+        if !handled {
+            debug!(target: "NetCrypto", "Unexpected CryptoData from {}", addr);
+        }
+        handled
+    }
+}
+
+/// Send next packet of the `connection`, if it's due. Returns `false` if
+/// nothing was sent.
+fn send_next<T: Transport>(transport: &T, connection: &mut CryptoConnection,
+                           now: u64) -> bool {
+    match connection.next_packet(now) {
+        Some(packet) => send_bytes(transport, &packet, connection.addr),
+        None => false,
+    }
+}
+
+fn send_bytes<T: Transport>(transport: &T, bytes: &[u8], addr: SocketAddr)
+    -> bool
+{
+    match transport.send_to(bytes, addr) {
+        Ok(_) => true,
+        Err(e) => {
+            debug!(target: "NetCrypto", "Failed to send packet to {}: {}",
+                   addr, e);
+            false
+        },
+    }
+}
@@ -45,6 +45,16 @@ pub fn random_u64() -> u64 {
 }
 
 
+/// Generate a new random symmetric key, used to encrypt data that only we
+/// should be able to decrypt, e.g. onion return paths.
+pub fn new_symmetric_key() -> PrecomputedKey {
+    trace!("Generating new symmetric key");
+    let mut key = [0; PRECOMPUTEDKEYBYTES];
+    randombytes_into(&mut key);
+    PrecomputedKey(key)
+}
+
+
 /// Check if Tox public key `PUBLICKEYBYTES` is valid. Should be used only for
 /// input validation.
 ///
@@ -26,7 +26,11 @@
 //! * ..
 
 use ip::*; // ← won't be needed with Rust 1.7, since it finally got stabilized
+use rustc_serialize::hex::ToHex;
+use rustc_serialize::json::{Json, ToJson};
 use std::cmp::{Ord, Ordering};
+use std::collections::BTreeMap;
+use std::fmt;
 use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
 
 use toxcore::binary_io::*;
@@ -79,6 +83,8 @@ pub enum PacketKind {
     OnionResp2    = 141,
     /// Onion Response 1.
     OnionResp1    = 142,
+    /// [`BootstrapInfo`](./struct.BootstrapInfo.html) request or response.
+    BootstrapInfo = 240,
 }
 
 /// Parse first byte from provided `bytes` as `PacketKind`.
@@ -114,6 +120,7 @@ impl FromBytes<PacketKind> for PacketKind {
             140 => Some(PacketKind::OnionResp3),
             141 => Some(PacketKind::OnionResp2),
             142 => Some(PacketKind::OnionResp1),
+            240 => Some(PacketKind::BootstrapInfo),
             _   => {
                 debug!("Byte can't be parsed as PacketKind!");
                 None
@@ -123,6 +130,146 @@ impl FromBytes<PacketKind> for PacketKind {
 }
 
 
+/// Request for [`BootstrapInfo`](./struct.BootstrapInfo.html) of a
+/// bootstrap node. Unencrypted.
+///
+/// Packet type [`PacketKind::BootstrapInfo`](./enum.PacketKind.html).
+///
+/// Serialized form:
+///
+/// Length | Contents
+/// ------ | --------
+/// `1`    | `0xF0`
+/// `77`   | Padding, ignored
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct BootstrapInfoRequest;
+
+/// Size of serialized [`BootstrapInfoRequest`]
+/// (./struct.BootstrapInfoRequest.html) in bytes.
+pub const BOOTSTRAP_INFO_REQUEST_SIZE: usize = 78;
+
+/// Serialization of `BootstrapInfoRequest`; padding is filled with zeros.
+impl ToBytes for BootstrapInfoRequest {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut result = vec![0; BOOTSTRAP_INFO_REQUEST_SIZE];
+        result[0] = PacketKind::BootstrapInfo as u8;
+        result
+    }
+}
+
+/// De-serialization of `BootstrapInfoRequest`. Fails if bytes have length
+/// different from [`BOOTSTRAP_INFO_REQUEST_SIZE`]
+/// (./constant.BOOTSTRAP_INFO_REQUEST_SIZE.html), or packet kind is wrong.
+impl FromBytes<BootstrapInfoRequest> for BootstrapInfoRequest {
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        debug!(target: "BootstrapInfo", "De-serializing BootstrapInfoRequest.");
+        if bytes.len() != BOOTSTRAP_INFO_REQUEST_SIZE
+            || PacketKind::from_bytes(bytes) != Some(PacketKind::BootstrapInfo) {
+            debug!("Not a BootstrapInfoRequest!");
+            return None
+        }
+        Some(BootstrapInfoRequest)
+    }
+}
+
+
+/// Version and message of the day of a bootstrap node, sent in response to
+/// [`BootstrapInfoRequest`](./struct.BootstrapInfoRequest.html).
+/// Unencrypted.
+///
+/// Packet type [`PacketKind::BootstrapInfo`](./enum.PacketKind.html).
+///
+/// Serialized form:
+///
+/// Length      | Contents
+/// ----------- | --------
+/// `1`         | `0xF0`
+/// `4`         | Version, big endian
+/// `[0, 256]`  | MOTD, padded with or terminated by `0` bytes
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BootstrapInfo {
+    /// Version of the bootstrap node.
+    pub version: u32,
+    /// Message of the day.
+    pub motd: String,
+}
+
+/// Minimal size of serialized [`BootstrapInfo`](./struct.BootstrapInfo.html)
+/// in bytes.
+pub const BOOTSTRAP_INFO_MIN_SIZE: usize = 5;
+
+/// Maximum length of [`BootstrapInfo`](./struct.BootstrapInfo.html) MOTD in
+/// bytes, including the terminating `0`.
+pub const BOOTSTRAP_INFO_MAX_MOTD_LENGTH: usize = 256;
+
+impl BootstrapInfo {
+    /// Create new `BootstrapInfo`. MOTD is truncated, so that it would fit
+    /// in [`BOOTSTRAP_INFO_MAX_MOTD_LENGTH`]
+    /// (./constant.BOOTSTRAP_INFO_MAX_MOTD_LENGTH.html) with the terminating
+    /// `0`.
+    pub fn new(version: u32, motd: &str) -> Self {
+        let mut len = ::std::cmp::min(motd.len(), BOOTSTRAP_INFO_MAX_MOTD_LENGTH - 1);
+        while !motd.is_char_boundary(len) {
+            len -= 1;
+        }
+        BootstrapInfo { version: version, motd: motd[..len].to_owned() }
+    }
+}
+
+/// Serialization of `BootstrapInfo`. MOTD is terminated by `0`.
+impl ToBytes for BootstrapInfo {
+    fn to_bytes(&self) -> Vec<u8> {
+        debug!(target: "BootstrapInfo", "Serializing BootstrapInfo into bytes.");
+        trace!(target: "BootstrapInfo", "With BootstrapInfo: {:?}", self);
+        let mut result = Vec::with_capacity(BOOTSTRAP_INFO_MIN_SIZE
+                                            + self.motd.len() + 1);
+        result.push(PacketKind::BootstrapInfo as u8);
+        for n in 0..4 {
+            result.push((self.version >> (24 - 8 * n)) as u8);
+        }
+        result.extend_from_slice(self.motd.as_bytes());
+        result.push(0);
+        result
+    }
+}
+
+/// De-serialization of `BootstrapInfo`. MOTD ends at the first `0` byte;
+/// invalid UTF-8 in it is replaced.
+///
+/// Fails if less than [`BOOTSTRAP_INFO_MIN_SIZE`]
+/// (./constant.BOOTSTRAP_INFO_MIN_SIZE.html) bytes are provided, or MOTD is
+/// longer than [`BOOTSTRAP_INFO_MAX_MOTD_LENGTH`]
+/// (./constant.BOOTSTRAP_INFO_MAX_MOTD_LENGTH.html).
+///
+/// **Note that [`BootstrapInfoRequest`](./struct.BootstrapInfoRequest.html)
+/// can be parsed as `BootstrapInfo` too**, same as in toxcore, packets are
+/// told apart only by who is expected to send them.
+impl FromBytes<BootstrapInfo> for BootstrapInfo {
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        debug!(target: "BootstrapInfo", "De-serializing bytes into BootstrapInfo.");
+        trace!(target: "BootstrapInfo", "With bytes: {:?}", bytes);
+        if bytes.len() < BOOTSTRAP_INFO_MIN_SIZE
+            || bytes.len() > BOOTSTRAP_INFO_MIN_SIZE + BOOTSTRAP_INFO_MAX_MOTD_LENGTH
+            || PacketKind::from_bytes(bytes) != Some(PacketKind::BootstrapInfo) {
+            debug!("Not a BootstrapInfo!");
+            return None
+        }
+        let version = bytes[1..BOOTSTRAP_INFO_MIN_SIZE].iter()
+            .fold(0, |acc, &b| acc << 8 | b as u32);
+        let motd = &bytes[BOOTSTRAP_INFO_MIN_SIZE..];
+        let motd = match motd.iter().position(|&b| b == 0) {
+            Some(end) => &motd[..end],
+            None => motd,
+        };
+        Some(BootstrapInfo {
+            version: version,
+            motd: String::from_utf8_lossy(motd).into_owned(),
+        })
+    }
+}
+
+
+
 /// Type of [`Ping`](./struct.Ping.html) packet. Either a request or response.
 ///
 /// * `0` – if ping is a request;
@@ -1058,6 +1205,284 @@ impl FromBytes<DhtPacket> for DhtPacket {
 }
 
 
+/// Packet ID of NAT ping in the payload of [`DhtRequest`]
+/// (./struct.DhtRequest.html).
+pub const NAT_PING_PACKET_ID: u8 = 0xfe;
+
+/// Types of payloads that can be put in the encrypted part of [`DhtRequest`]
+/// (./struct.DhtRequest.html).
+///
+/// Serialized form:
+///
+/// Length      | Contents
+/// ----------- | --------
+/// `1`         | `u8` packet ID
+/// variable    | Data
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DhtRequestT {
+    /// NAT ping request or response, with packet ID `0xfe`.
+    ///
+    /// Data has the same form as [`Ping`](./struct.Ping.html). Used by
+    /// friends to check whether they can reach each other.
+    NatPing(Ping),
+}
+
+impl ToBytes for DhtRequestT {
+    fn to_bytes(&self) -> Vec<u8> {
+        debug!(target: "DhtRequestT", "Serializing DhtRequestT into bytes.");
+        trace!(target: "DhtRequestT", "With DhtRequestT: {:?}", self);
+        match *self {
+            DhtRequestT::NatPing(ref p) => {
+                let mut result = vec![NAT_PING_PACKET_ID];
+                result.extend_from_slice(&p.to_bytes());
+                result
+            },
+        }
+    }
+}
+
+/// De-serialize bytes into `DhtRequestT`. Returns `None` if packet ID is
+/// unknown, or data can't be parsed.
+impl FromBytes<DhtRequestT> for DhtRequestT {
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        debug!(target: "DhtRequestT", "De-serializing bytes into DhtRequestT.");
+        trace!(target: "DhtRequestT", "With bytes: {:?}", bytes);
+        if bytes.is_empty() {
+            debug!("There are 0 bytes!");
+            return None
+        }
+
+        match bytes[0] {
+            NAT_PING_PACKET_ID => Ping::from_bytes(&bytes[1..])
+                                      .map(DhtRequestT::NatPing),
+            _ => {
+                debug!("Unknown DhtRequestT packet ID!");
+                None
+            },
+        }
+    }
+}
+
+
+/// DHT Request packet, used to send data to a node via a node that has the
+/// receiver in its close list.
+///
+/// Packet type `32`.
+///
+/// Length      | Contents
+/// ----------- | --------
+/// `1`         | `u8` [`PacketKind::DhtReq`](./enum.PacketKind.html)
+/// `32`        | Receiver DHT Public Key
+/// `32`        | Sender DHT Public Key
+/// `24`        | Random nonce
+/// variable    | Encrypted payload – [`DhtRequestT`](./enum.DhtRequestT.html)
+///
+/// Node that receives `DhtRequest` not meant for it, and has receiver in its
+/// close list, forwards the packet unchanged to the receiver.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DhtRequest {
+    /// Public key of receiver.
+    pub receiver_pk: PublicKey,
+    /// Public key of sender.
+    pub sender_pk: PublicKey,
+    nonce: Nonce,
+    payload: Vec<u8>,
+}
+
+/// Minimal size of [`DhtRequest`](./struct.DhtRequest.html) in bytes.
+pub const DHT_REQUEST_MIN_SIZE: usize = 1 // packet type, plain
+                                      + PUBLICKEYBYTES * 2
+                                      + NONCEBYTES
+                                      + MACBYTES
+                                      + 1 // payload packet ID
+                                      + PING_SIZE; // smallest payload
+
+/// Where received [`DhtRequest`](./struct.DhtRequest.html) should go.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DhtRequestRoute {
+    /// Request is meant for us.
+    ToSelf,
+    /// Request should be forwarded unchanged to given address.
+    Forward(SocketAddr),
+    /// Receiver is not known; request should be dropped.
+    Drop,
+}
+
+impl DhtRequest {
+    /// Create new `DhtRequest` to the `receiver_pk`. `symmetric_key` should
+    /// be precomputed from receiver's PK and own SK.
+    pub fn new(symmetric_key: &PrecomputedKey, own_public_key: &PublicKey,
+               receiver_pk: &PublicKey, nonce: &Nonce, payload: DhtRequestT)
+        -> Self
+    {
+        debug!(target: "DhtRequest", "Creating new DhtRequest.");
+        trace!(target: "DhtRequest", "With args: symmetric_key: <secret>,
+        own_public_key: {:?}, receiver_pk: {:?}, nonce: {:?}, payload: {:?}",
+        own_public_key, receiver_pk, nonce, &payload);
+
+        DhtRequest {
+            receiver_pk: *receiver_pk,
+            sender_pk: *own_public_key,
+            nonce: *nonce,
+            payload: seal_precomputed(&payload.to_bytes(), nonce, symmetric_key),
+        }
+    }
+
+    /// Decrypt payload and try to parse it as `DhtRequestT`.
+    ///
+    /// Returns `None` in case of failure.
+    pub fn get_payload(&self, own_secret_key: &SecretKey) -> Option<DhtRequestT> {
+        debug!(target: "DhtRequest", "Getting payload from DhtRequest.");
+        trace!(target: "DhtRequest", "With DhtRequest: {:?}", self);
+        match open(&self.payload, &self.nonce, &self.sender_pk, own_secret_key) {
+            Ok(d) => DhtRequestT::from_bytes(&d),
+            Err(_) => {
+                debug!("Decrypting DhtRequest failed!");
+                None
+            },
+        }
+    }
+
+    /// Decide what to do with received `DhtRequest` – whether it's meant for
+    /// us, should be forwarded to a node from `kbucket`, or dropped.
+    pub fn route(&self, own_pk: &PublicKey, kbucket: &Kbucket) -> DhtRequestRoute {
+        trace!(target: "DhtRequest", "Routing DhtRequest to {:?}",
+               self.receiver_pk);
+        if &self.receiver_pk == own_pk {
+            return DhtRequestRoute::ToSelf
+        }
+        match kbucket.get_node(&self.receiver_pk) {
+            Some(node) => DhtRequestRoute::Forward(node.node.saddr),
+            None => {
+                debug!(target: "DhtRequest", "Receiver is unknown; dropping.");
+                DhtRequestRoute::Drop
+            },
+        }
+    }
+}
+
+/// Serialize `DhtRequest` into bytes.
+impl ToBytes for DhtRequest {
+    fn to_bytes(&self) -> Vec<u8> {
+        debug!(target: "DhtRequest", "Serializing DhtRequest into bytes.");
+        trace!(target: "DhtRequest", "With DhtRequest: {:?}", self);
+        let mut result = Vec::with_capacity(DHT_REQUEST_MIN_SIZE);
+        result.push(PacketKind::DhtReq as u8);
+
+        let PublicKey(receiver_pk) = self.receiver_pk;
+        result.extend_from_slice(&receiver_pk);
+        let PublicKey(sender_pk) = self.sender_pk;
+        result.extend_from_slice(&sender_pk);
+
+        let Nonce(nonce) = self.nonce;
+        result.extend_from_slice(&nonce);
+
+        result.extend_from_slice(&self.payload);
+        trace!("Resulting bytes: {:?}", &result);
+        result
+    }
+}
+
+/// De-serialize bytes into `DhtRequest`.
+impl FromBytes<DhtRequest> for DhtRequest {
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        debug!(target: "DhtRequest", "De-serializing bytes into DhtRequest.");
+        trace!(target: "DhtRequest", "With bytes: {:?}", bytes);
+
+        if bytes.len() < DHT_REQUEST_MIN_SIZE {
+            debug!("Failed; less bytes than DHT_REQUEST_MIN_SIZE!");
+            return None
+        }
+
+        if PacketKind::from_bytes(bytes) != Some(PacketKind::DhtReq) {
+            debug!("Failed: not a DHT Request packet!");
+            return None
+        }
+
+        const SENDER_POS: usize = 1 + PUBLICKEYBYTES;
+        const NONCE_POS: usize = SENDER_POS + PUBLICKEYBYTES;
+        const PAYLOAD_POS: usize = NONCE_POS + NONCEBYTES;
+        let receiver_pk = PublicKey::from_slice(&bytes[1..SENDER_POS]);
+        let sender_pk = PublicKey::from_slice(&bytes[SENDER_POS..NONCE_POS]);
+        let nonce = Nonce::from_slice(&bytes[NONCE_POS..PAYLOAD_POS]);
+
+        match (receiver_pk, sender_pk, nonce) {
+            (Some(receiver_pk), Some(sender_pk), Some(nonce)) => Some(DhtRequest {
+                receiver_pk: receiver_pk,
+                sender_pk: sender_pk,
+                nonce: nonce,
+                payload: bytes[PAYLOAD_POS..].to_vec(),
+            }),
+            _ => {
+                debug!("Failed; de-serializing PKs or nonce!");
+                None
+            },
+        }
+    }
+}
+
+
+/// XOR distance between two `PublicKey`s, as used by Kademlia.
+///
+/// Distance is a 256-bit big-endian number; the more leading bits PKs share,
+/// the smaller the distance between them. Distances are ordered
+/// numerically, thus they can be computed once and used e.g. as a sort key.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct XorDistance(pub [u8; PUBLICKEYBYTES]);
+
+impl XorDistance {
+    /// Compute distance between `pk1` and `pk2`.
+    pub fn new(&PublicKey(ref pk1): &PublicKey,
+               &PublicKey(ref pk2): &PublicKey) -> Self {
+        let mut distance = [0; PUBLICKEYBYTES];
+        for i in 0..PUBLICKEYBYTES {
+            distance[i] = pk1[i] ^ pk2[i];
+        }
+        XorDistance(distance)
+    }
+
+    /// Check whether distance is `0`, i.e. PKs are equal.
+    pub fn is_zero(&self) -> bool {
+        self.0.iter().all(|&b| b == 0)
+    }
+
+    /// Number of leading zero bits, i.e. number of leading bits that PKs
+    /// share. Returns `256` if distance is `0`.
+    pub fn leading_zeros(&self) -> u32 {
+        let mut zeros = 0;
+        for &byte in &self.0 {
+            zeros += byte.leading_zeros();
+            if byte != 0 {
+                break
+            }
+        }
+        zeros
+    }
+
+    /// Index of the [`Kbucket`](./struct.Kbucket.html) bucket that node at
+    /// this distance from own PK belongs to, which is the number of leading
+    /// bits that PKs share.
+    ///
+    /// Returns `None` if distance is `0`.
+    pub fn bucket_index(&self) -> Option<u8> {
+        match self.leading_zeros() {
+            n if n < PUBLICKEYBYTES as u32 * 8 => Some(n as u8),
+            _ => None,
+        }
+    }
+}
+
+/// Distance as a hex-encoded number.
+impl fmt::Display for XorDistance {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for byte in &self.0 {
+            try!(write!(f, "{:02x}", byte));
+        }
+        Ok(())
+    }
+}
+
+
 /// Trait for functionality related to distance between `PublicKey`s.
 pub trait Distance {
     /// Check whether distance between PK1 and own PK is smaller than distance
@@ -1066,22 +1491,28 @@ pub trait Distance {
     fn distance(&self, &PublicKey, &PublicKey) -> Ordering;
 }
 
+/// Comparison of [`XorDistance`](./struct.XorDistance.html)s.
 impl Distance for PublicKey {
-    fn distance(&self,
-                &PublicKey(ref pk1): &PublicKey,
-                &PublicKey(ref pk2): &PublicKey) -> Ordering {
-
+    fn distance(&self, pk1: &PublicKey, pk2: &PublicKey) -> Ordering {
         trace!(target: "Distance", "Comparing distance between PKs.");
-        let &PublicKey(own) = self;
-        for i in 0..PUBLICKEYBYTES {
-            if pk1[i] != pk2[i] {
-                return Ord::cmp(&(own[i] ^ pk1[i]), &(own[i] ^ pk2[i]))
-            }
-        }
-        Ordering::Equal
+        Ord::cmp(&XorDistance::new(self, pk1), &XorDistance::new(self, pk2))
     }
 }
 
+/// Get up to `count` of `nodes` closest to the `pk`, closest first.
+///
+/// Distances are computed only once for each node.
+pub fn closest_nodes<'a, I>(pk: &PublicKey, nodes: I, count: usize)
+    -> Vec<PackedNode>
+    where I: IntoIterator<Item=&'a PackedNode>
+{
+    let mut nodes: Vec<(XorDistance, PackedNode)> = nodes.into_iter()
+        .map(|n| (XorDistance::new(pk, &n.pk), *n))
+        .collect();
+    nodes.sort_by(|a, b| a.0.cmp(&b.0));
+    nodes.into_iter().take(count).map(|(_, n)| n).collect()
+}
+
 
 /// DHT Node and its associated info.
 // TODO: move it up ↑
@@ -1099,13 +1530,26 @@ pub struct Node {
     pub id: u64,
     /// Packed Node that Node contains.
     pub node: PackedNode,
+    /// Round-trip time in milliseconds of the last answered
+    /// [`Ping`](./struct.Ping.html), if known.
+    pub rtt: Option<u64>,
+    /// Time in seconds when a valid packet from the node was last received,
+    /// if known.
+    pub last_seen: Option<u64>,
 }
 
+/// Number of seconds after which node that didn't send any valid packet is
+/// considered to be timed out.
+///
+/// Value to add to current time when setting [`Node`](./struct.Node.html)
+/// timeout.
+pub const NODE_TIMEOUT: u64 = 122;
+
 impl Node {
-    /// Create a new `Node`. New node has `req`, `resp` and `id` values set to
-    /// `0`.
+    /// Create a new `Node`. New node has `id` set to `0`, and unknown `rtt`
+    /// and `last_seen`.
     pub fn new(pn: &PackedNode, timeout: u64) -> Self {
-        Node { timeout: timeout, id: 0, node: *pn }
+        Node { timeout: timeout, id: 0, node: *pn, rtt: None, last_seen: None }
     }
 
     /// Set the ID of last [`Ping`](./struct.Ping.html) request sent.
@@ -1120,30 +1564,15 @@ impl Node {
 }
 
 
-/// Calculate the `k-bucket` index of a PK compared to "own" PK.
+/// Calculate the `k-bucket` index of a PK compared to "own" PK, i.e.
+/// [`XorDistance::bucket_index()`](./struct.XorDistance.html#method.bucket_index)
+/// of their distance.
 ///
 /// Falis (returns `None`) if supplied keys are the same.
-pub fn kbucket_index(&PublicKey(ref own_pk): &PublicKey,
-                     &PublicKey(ref other_pk): &PublicKey) -> Option<u8> {
-
+pub fn kbucket_index(own_pk: &PublicKey, other_pk: &PublicKey) -> Option<u8> {
     debug!(target: "KBucketIndex", "Calculating KBucketIndex for PKs.");
     trace!(target: "KBucketIndex", "With PK1: {:?}; PK2: {:?}", own_pk, other_pk);
-    let mut index = 0;
-
-    for byte in 0..PUBLICKEYBYTES {
-        for bit in 0..8 {
-            let shift = 7 - bit;
-            if (own_pk[byte] >> shift) & 0b1 != (other_pk[byte] >> shift) & 0b1 {
-                return Some(index)
-            } else {
-                index = match index.checked_add(1) {
-                    Some(n) => n,
-                    None => return None,
-                };
-            }
-        }
-    }
-    None  // PKs are equal
+    XorDistance::new(own_pk, other_pk).bucket_index()
 }
 
 /// Structure for holding up to [`BUCKET_SIZE`](./constant.BUCKET_SIZE.html)
@@ -1219,6 +1648,138 @@ impl<'a> Bucket<'a> {
         debug!("Node is too distant to add to bucket.");
         false
     }
+
+    /// Replace node that has the same PK as supplied `node`, e.g. to update
+    /// its timeout. Known `rtt` and `last_seen` are kept if `node` doesn't
+    /// have them.
+    ///
+    /// Returns `false` if there is no node with such PK in the bucket.
+    pub fn refresh(&mut self, node: &Node) -> bool {
+        trace!(target: "Bucket", "Refreshing node: {:?}", node);
+        for n in &mut self.nodes {
+            if n.pk() == node.pk() {
+                let rtt = node.rtt.or(n.rtt);
+                let last_seen = node.last_seen.or(n.last_seen);
+                *n = *node;
+                n.rtt = rtt;
+                n.last_seen = last_seen;
+                return true
+            }
+        }
+        false
+    }
+
+    /// Remove node with `pk` from the bucket, returning it.
+    ///
+    /// Returns `None` if there is no node with such PK in the bucket.
+    pub fn remove(&mut self, pk: &PublicKey) -> Option<Node> {
+        trace!(target: "Bucket", "Removing node with PK: {:?}", pk);
+        match self.nodes.iter().position(|n| n.pk() == pk) {
+            Some(pos) => Some(self.nodes.remove(pos)),
+            None => None,
+        }
+    }
+
+    /// Nodes held by the bucket, sorted from the closest to the furthest.
+    pub fn nodes(&self) -> &[Node] {
+        &self.nodes
+    }
+
+    /// Number of nodes in the bucket.
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Check whether there are no nodes in the bucket.
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+}
+
+
+/// Limits on the number of nodes from the same network or with similar PKs
+/// held by [`Kbucket`](./struct.Kbucket.html), to make it harder to fill it
+/// with nodes controlled by a single party.
+///
+/// Nodes are in the same subnet if they share an IPv4 `/24` or IPv6 `/48`
+/// prefix.
+///
+/// Nodes in a bucket with index `i` share the first `i + 1` bits of their
+/// PKs. Their PK prefix is formed by the next [`pk_prefix_bits`]
+/// (#structfield.pk_prefix_bits) bits. Nodes from different buckets never
+/// have the same PK prefix, thus it's limited only per bucket.
+///
+/// Node that would exceed any of the limits is added only if its bucket has
+/// room for it, and it will be the first one to be removed when another node
+/// needs the room.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct DiversityLimits {
+    /// Maximum number of nodes from the same subnet in a single bucket.
+    pub subnet_per_bucket: usize,
+    /// Maximum number of nodes from the same subnet in the whole `Kbucket`.
+    pub subnet_per_table: usize,
+    /// Number of bits of the PK prefix. `0` disables PK prefix limit.
+    pub pk_prefix_bits: usize,
+    /// Maximum number of nodes with the same PK prefix in a single bucket.
+    pub pk_prefix_per_bucket: usize,
+}
+
+/// Default [`DiversityLimits::subnet_per_bucket`]
+/// (./struct.DiversityLimits.html).
+pub const SUBNET_PER_BUCKET: usize = 2;
+/// Default [`DiversityLimits::subnet_per_table`]
+/// (./struct.DiversityLimits.html).
+pub const SUBNET_PER_TABLE: usize = 8;
+/// Default [`DiversityLimits::pk_prefix_bits`]
+/// (./struct.DiversityLimits.html).
+pub const PK_PREFIX_BITS: usize = 8;
+/// Default [`DiversityLimits::pk_prefix_per_bucket`]
+/// (./struct.DiversityLimits.html).
+pub const PK_PREFIX_PER_BUCKET: usize = 2;
+
+impl Default for DiversityLimits {
+    fn default() -> Self {
+        DiversityLimits {
+            subnet_per_bucket: SUBNET_PER_BUCKET,
+            subnet_per_table: SUBNET_PER_TABLE,
+            pk_prefix_bits: PK_PREFIX_BITS,
+            pk_prefix_per_bucket: PK_PREFIX_PER_BUCKET,
+        }
+    }
+}
+
+impl DiversityLimits {
+    /// Limits that never restrict adding nodes.
+    pub fn none() -> Self {
+        DiversityLimits {
+            subnet_per_bucket: ::std::usize::MAX,
+            subnet_per_table: ::std::usize::MAX,
+            pk_prefix_bits: 0,
+            pk_prefix_per_bucket: ::std::usize::MAX,
+        }
+    }
+}
+
+/// Subnet of the address – IPv4 `/24` or IPv6 `/48`. IPv4-mapped IPv6
+/// addresses are treated as IPv4.
+fn subnet(addr: &SocketAddr) -> (bool, [u16; 3]) {
+    let ipv4 = match *addr {
+        SocketAddr::V4(ref a) => a.ip().octets(),
+        SocketAddr::V6(ref a) => {
+            let s = a.ip().segments();
+            if s[..5] != [0, 0, 0, 0, 0] || s[5] != 0xffff {
+                return (true, [s[0], s[1], s[2]])
+            }
+            [(s[6] >> 8) as u8, s[6] as u8, (s[7] >> 8) as u8, s[7] as u8]
+        },
+    };
+    (false, [(ipv4[0] as u16) << 8 | ipv4[1] as u16, ipv4[2] as u16, 0])
+}
+
+/// Whether addresses are in the same subnet, as defined by
+/// [`DiversityLimits`](./struct.DiversityLimits.html).
+pub fn same_subnet(a: &SocketAddr, b: &SocketAddr) -> bool {
+    subnet(a) == subnet(b)
 }
 
 
@@ -1236,6 +1797,8 @@ impl<'a> Bucket<'a> {
 pub struct Kbucket<'a> {
     /// Number of buckets held.
     pub k: u8,
+    /// Limits on nodes from the same network.
+    pub limits: DiversityLimits,
     pk: PublicKey,
     list: Vec<Bucket<'a>>,
 }
@@ -1246,8 +1809,296 @@ pub struct Kbucket<'a> {
 // TODO: ↓ perhaps s/usize/u8/ ?
 pub const KBUCKET_MAX_ENTRIES: usize = ::std::u8::MAX as usize;
 
-// TODO: k-bucket methods
-//        * creating new kbucket without any buckets (is it needed?)
-//        * creating new kbucket from nodes, automatically creating buckets
-//          - with a switch to decide whether only 1 bucket should be used
-//            (for friend's close nodes) or many (for own close nodes)
+impl<'a> Kbucket<'a> {
+    /// Create a new `Kbucket` with `n` empty buckets for nodes close to the
+    /// `pk`, with default [`DiversityLimits`](./struct.DiversityLimits.html).
+    pub fn new(n: u8, pk: &'a PublicKey) -> Self {
+        trace!(target: "Kbucket", "Creating new Kbucket with k: {}; PK: {:?}",
+               n, pk);
+        let mut list = Vec::with_capacity(n as usize);
+        for i in 0..n {
+            list.push(Bucket::new(pk, i));
+        }
+        Kbucket {
+            k: n,
+            limits: DiversityLimits::default(),
+            pk: *pk,
+            list: list,
+        }
+    }
+
+    /// Try to add node to the bucket with matching [`kbucket_index()`]
+    /// (./fn.kbucket_index.html). If node already is in the `Kbucket`, it's
+    /// refreshed.
+    ///
+    /// Node that would exceed [`limits`](#structfield.limits) is added only
+    /// if its bucket isn't full. If bucket is full, node within limits
+    /// replaces the furthest node that exceeds them, if there is such.
+    ///
+    /// Returns `false` if node wasn't added, e.g. because its bucket is full
+    /// of closer nodes, or its index is out of range of `Kbucket`.
+    pub fn try_add(&mut self, node: &Node) -> bool {
+        debug!(target: "Kbucket", "Trying to add node to Kbucket.");
+        trace!(target: "Kbucket", "With node: {:?}", node);
+        let i = match kbucket_index(&self.pk, node.pk()) {
+            Some(i) if (i as usize) < self.list.len() => i as usize,
+            _ => {
+                debug!("Failed to add node; index out of range!");
+                return false
+            },
+        };
+        if self.list[i].refresh(node) {
+            return true
+        }
+
+        let full = self.list[i].len() >= BUCKET_SIZE;
+        if self.exceeds_limits(i, &node.node) {
+            if full {
+                debug!(target: "Kbucket", "Node {} rejected; exceeds diversity \
+                       limits and bucket {} is full.", node.node.saddr, i);
+                return false
+            }
+            debug!(target: "Kbucket", "Node {} exceeds diversity limits, but \
+                   bucket {} has room.", node.node.saddr, i);
+        } else if full {
+            let evict = self.list[i].nodes().iter().rev()
+                .find(|n| self.exceeds_limits(i, &n.node))
+                .map(|n| *n.pk());
+            if let Some(pk) = evict {
+                debug!(target: "Kbucket", "Removing node exceeding diversity \
+                       limits to make room.");
+                self.list[i].remove(&pk);
+            }
+        }
+        self.list[i].try_add(node)
+    }
+
+    /// Whether `node` exceeds `limits` in the bucket with index `i`, not
+    /// counting node itself.
+    fn exceeds_limits(&self, i: usize, node: &PackedNode) -> bool {
+        let others = |n: &&Node| n.pk() != &node.pk;
+        let in_subnet = |n: &&Node| same_subnet(&n.node.saddr, &node.saddr);
+
+        let bucket_subnet = self.list[i].nodes().iter()
+            .filter(&others).filter(&in_subnet).count();
+        if bucket_subnet >= self.limits.subnet_per_bucket {
+            trace!(target: "Kbucket", "Too many nodes from subnet in bucket.");
+            return true
+        }
+
+        let table_subnet = self.list.iter()
+            .flat_map(|b| b.nodes().iter())
+            .filter(&others).filter(&in_subnet).count();
+        if table_subnet >= self.limits.subnet_per_table {
+            trace!(target: "Kbucket", "Too many nodes from subnet in Kbucket.");
+            return true
+        }
+
+        let bits = self.limits.pk_prefix_bits;
+        if bits > 0 {
+            // bits before the prefix are shared by all nodes in the bucket
+            let same_prefix = self.list[i].nodes().iter().filter(&others)
+                .filter(|n| match kbucket_index(n.pk(), &node.pk) {
+                    Some(diff) => diff as usize >= i + 1 + bits,
+                    None => true,
+                })
+                .count();
+            if same_prefix >= self.limits.pk_prefix_per_bucket {
+                trace!(target: "Kbucket", "Too many nodes with PK prefix.");
+                return true
+            }
+        }
+        false
+    }
+
+    /// Get up to `count` nodes closest to the `pk`, closest first.
+    ///
+    /// Only nodes which timeout is bigger than `now` are returned.
+    pub fn get_closest(&self, pk: &PublicKey, count: usize, now: u64)
+        -> Vec<PackedNode>
+    {
+        trace!(target: "Kbucket", "Getting {} closest nodes to {:?}",
+               count, pk);
+        let good = self.list.iter()
+            .flat_map(|b| b.nodes().iter())
+            .filter(|n| n.timeout > now)
+            .map(|n| &n.node);
+        closest_nodes(pk, good, count)
+    }
+
+    /// Get node with given `pk`, if `Kbucket` has it.
+    pub fn get_node(&self, pk: &PublicKey) -> Option<&Node> {
+        match kbucket_index(&self.pk, pk) {
+            Some(i) if (i as usize) < self.list.len() =>
+                self.list[i as usize].nodes().iter().find(|n| n.pk() == pk),
+            _ => None,
+        }
+    }
+
+    /// Number of nodes which timeout is bigger than `now`.
+    pub fn good_nodes(&self, now: u64) -> usize {
+        self.list.iter()
+            .map(|b| b.nodes().iter().filter(|n| n.timeout > now).count())
+            .fold(0, |acc, n| acc + n)
+    }
+
+    /// Get all nodes which timeout is bigger than `now`.
+    pub fn good_packed_nodes(&self, now: u64) -> Vec<PackedNode> {
+        self.list.iter()
+            .flat_map(|b| b.nodes().iter())
+            .filter(|n| n.timeout > now)
+            .map(|n| n.node)
+            .collect()
+    }
+
+    /// Total number of nodes held.
+    pub fn len(&self) -> usize {
+        self.list.iter().fold(0, |acc, b| acc + b.len())
+    }
+
+    /// Check whether there are no nodes in the `Kbucket`.
+    pub fn is_empty(&self) -> bool {
+        self.list.iter().all(|b| b.is_empty())
+    }
+
+    /// Take a snapshot of all buckets, e.g. to inspect node's view of the
+    /// DHT. `now` is used to tell which nodes are good. Whether node is good
+    /// is unknown if it was never seen.
+    pub fn snapshot(&self, now: u64) -> KbucketSnapshot {
+        trace!(target: "Kbucket", "Taking snapshot of Kbucket.");
+        let buckets = self.list.iter().enumerate().map(|(i, b)| BucketSnapshot {
+            index: i as u8,
+            nodes: b.nodes().iter().map(|n| NodeSnapshot {
+                node: n.node,
+                distance: XorDistance::new(&self.pk, n.pk()),
+                last_seen: n.last_seen,
+                good: n.last_seen.map(|_| n.timeout > now),
+                rtt: n.rtt,
+            }).collect(),
+        }).collect();
+        KbucketSnapshot { pk: self.pk, time: now, buckets: buckets }
+    }
+}
+
+
+/// Snapshot of a [`Kbucket`](./struct.Kbucket.html), returned by
+/// [`Kbucket::snapshot()`](./struct.Kbucket.html#method.snapshot).
+///
+/// Can be serialized into JSON:
+///
+/// ```json
+/// {
+///     "public_key": "<own PK, 64 hex characters>",
+///     "time": 1476871200,
+///     "buckets": [
+///         {
+///             "index": 0,
+///             "nodes": [
+///                 {
+///                     "public_key": "<64 hex characters>",
+///                     "ip": "1.2.3.4",
+///                     "port": 33445,
+///                     "family": "ipv4",
+///                     "distance": "<64 hex characters>",
+///                     "last_seen": 1476871100,
+///                     "good": true,
+///                     "rtt": 35
+///                 }
+///             ]
+///         }
+///     ]
+/// }
+/// ```
+///
+/// `last_seen`, `good` and `rtt` are `null` when they're not known.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct KbucketSnapshot {
+    /// PK that nodes in the `Kbucket` are close to.
+    pub pk: PublicKey,
+    /// Time in seconds when snapshot was taken.
+    pub time: u64,
+    /// All buckets, including empty ones.
+    pub buckets: Vec<BucketSnapshot>,
+}
+
+/// Snapshot of a single bucket in [`KbucketSnapshot`]
+/// (./struct.KbucketSnapshot.html).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BucketSnapshot {
+    /// Index of the bucket in the `Kbucket`.
+    pub index: u8,
+    /// Nodes in the bucket, sorted from the closest to the furthest.
+    pub nodes: Vec<NodeSnapshot>,
+}
+
+/// Snapshot of a node in [`BucketSnapshot`](./struct.BucketSnapshot.html).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct NodeSnapshot {
+    /// PK, address and address family of the node.
+    pub node: PackedNode,
+    /// Distance to the PK of the `Kbucket`.
+    pub distance: XorDistance,
+    /// Time in seconds when a valid packet from the node was last received,
+    /// if known.
+    pub last_seen: Option<u64>,
+    /// Whether node hasn't timed out, if node was seen.
+    pub good: Option<bool>,
+    /// Round-trip time in milliseconds, if known.
+    pub rtt: Option<u64>,
+}
+
+impl KbucketSnapshot {
+    /// Serialize snapshot into pretty-printed JSON.
+    pub fn to_json_string(&self) -> String {
+        self.to_json().pretty().to_string()
+    }
+}
+
+impl ToJson for KbucketSnapshot {
+    fn to_json(&self) -> Json {
+        let PublicKey(ref pk) = self.pk;
+        let mut object = BTreeMap::new();
+        object.insert("public_key".to_owned(), Json::String(pk.to_hex().to_uppercase()));
+        object.insert("time".to_owned(), Json::U64(self.time));
+        object.insert("buckets".to_owned(), self.buckets.to_json());
+        Json::Object(object)
+    }
+}
+
+impl ToJson for BucketSnapshot {
+    fn to_json(&self) -> Json {
+        let mut object = BTreeMap::new();
+        object.insert("index".to_owned(), Json::U64(self.index as u64));
+        object.insert("nodes".to_owned(), self.nodes.to_json());
+        Json::Object(object)
+    }
+}
+
+impl ToJson for NodeSnapshot {
+    fn to_json(&self) -> Json {
+        let PublicKey(ref pk) = self.node.pk;
+        let family = match self.node.saddr {
+            SocketAddr::V4(_) => "ipv4",
+            SocketAddr::V6(_) => "ipv6",
+        };
+        let mut object = BTreeMap::new();
+        object.insert("public_key".to_owned(), Json::String(pk.to_hex().to_uppercase()));
+        object.insert("ip".to_owned(), Json::String(self.node.saddr.ip().to_string()));
+        object.insert("port".to_owned(), Json::U64(self.node.saddr.port() as u64));
+        object.insert("family".to_owned(), Json::String(family.to_owned()));
+        object.insert("distance".to_owned(), Json::String(self.distance.to_string()));
+        object.insert("last_seen".to_owned(), match self.last_seen {
+            Some(last_seen) => Json::U64(last_seen),
+            None => Json::Null,
+        });
+        object.insert("good".to_owned(), match self.good {
+            Some(good) => Json::Boolean(good),
+            None => Json::Null,
+        });
+        object.insert("rtt".to_owned(), match self.rtt {
+            Some(rtt) => Json::U64(rtt),
+            None => Json::Null,
+        });
+        Json::Object(object)
+    }
+}
@@ -0,0 +1,290 @@
+/*
+    Copyright © 2016 Zetok Zalbavar <zexavexxe@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Bootstrapping into the DHT from a list of known nodes.
+//!
+//! List of nodes can be parsed from the commonly used `nodes.json` format
+//! with [`parse_nodes_json()`](./fn.parse_nodes_json.html).
+//!
+//! Good nodes known in the current run can be saved with
+//! [`save_nodes()`](./fn.save_nodes.html) and used to bootstrap in the next
+//! run after [`load_nodes()`](./fn.load_nodes.html), so that well-known
+//! bootstrap nodes are needed only for the first run.
+
+use rustc_serialize::hex::FromHex;
+use rustc_serialize::json::Json;
+
+use std::cmp;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::str::FromStr;
+
+use toxcore::binary_io::*;
+use toxcore::crypto_core::*;
+use toxcore::dht::*;
+use toxcore::dht_friend::DhtFriends;
+use toxcore::network::Transport;
+
+
+/// Number of seconds to wait before retrying to bootstrap from a node for
+/// the first time. Each next retry doubles the delay.
+pub const BOOTSTRAP_RETRY_BASE: u64 = 2;
+
+/// Maximum number of seconds between retries to bootstrap from a node.
+pub const BOOTSTRAP_RETRY_MAX: u64 = 64;
+
+/// Default number of good nodes in `Kbucket` after which bootstrapping is
+/// considered to be done.
+pub const BOOTSTRAP_ENOUGH_NODES: usize = BUCKET_SIZE;
+
+
+/// Node that is used to bootstrap, with info when it should be asked next
+/// time.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct BootstrapNode {
+    node: PackedNode,
+    attempts: u32,
+    next_attempt: u64,
+}
+
+
+/// Bootstrapping from the list of nodes.
+///
+/// Each node from the list is sent [`GetNodes`](../dht/struct.GetNodes.html)
+/// request for own PK; requests are retried with exponential backoff until
+/// `Kbucket` has enough good nodes.
+///
+/// `Bootstrap` doesn't handle responses – received `SendNodes` should be
+/// added to the `Kbucket` as usual.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Bootstrap {
+    nodes: Vec<BootstrapNode>,
+    /// Number of good nodes in `Kbucket` after which bootstrapping is done.
+    pub enough_nodes: usize,
+}
+
+impl Bootstrap {
+    /// Create new `Bootstrap` from the list of `(SocketAddr, PublicKey)`.
+    pub fn new(nodes: &[(SocketAddr, PublicKey)]) -> Self {
+        debug!(target: "Bootstrap", "Creating new Bootstrap.");
+        let nodes: Vec<PackedNode> = nodes.iter()
+            .map(|&(saddr, ref pk)| PackedNode::new(true, saddr, pk))
+            .collect();
+        Bootstrap::from_packed_nodes(&nodes)
+    }
+
+    /// Create new `Bootstrap` from the list of `PackedNode`s, e.g. the ones
+    /// parsed with [`parse_nodes_json()`](./fn.parse_nodes_json.html).
+    pub fn from_packed_nodes(nodes: &[PackedNode]) -> Self {
+        trace!(target: "Bootstrap", "With nodes: {:?}", nodes);
+        Bootstrap {
+            nodes: nodes.iter().map(|n| BootstrapNode {
+                node: *n,
+                attempts: 0,
+                next_attempt: 0,
+            }).collect(),
+            enough_nodes: BOOTSTRAP_ENOUGH_NODES,
+        }
+    }
+
+    /// Add more nodes to bootstrap from, e.g. hard-coded ones after nodes
+    /// that were saved in the previous run. Nodes that already are in the
+    /// list are skipped.
+    pub fn add_nodes(&mut self, nodes: &[PackedNode]) {
+        trace!(target: "Bootstrap", "Adding nodes: {:?}", nodes);
+        for node in nodes {
+            if !self.nodes.iter().any(|n| &n.node == node) {
+                self.nodes.push(BootstrapNode {
+                    node: *node,
+                    attempts: 0,
+                    next_attempt: 0,
+                });
+            }
+        }
+    }
+
+    /// Check whether `Kbucket` has enough good nodes for bootstrapping to be
+    /// done.
+    pub fn is_done(&self, kbucket: &Kbucket, now: u64) -> bool {
+        kbucket.good_nodes(now) >= self.enough_nodes
+    }
+
+    /// Get `GetNodes` requests for `own_pk` that should be sent now.
+    ///
+    /// Returns an empty `Vec` if bootstrapping is done, or no node should be
+    /// asked again yet.
+    pub fn next_requests(&mut self, own_pk: &PublicKey, kbucket: &Kbucket,
+                         now: u64) -> Vec<(PackedNode, GetNodes)> {
+        trace!(target: "Bootstrap", "Getting next bootstrap requests.");
+        let mut result = Vec::new();
+        if self.is_done(kbucket, now) {
+            return result
+        }
+
+        for node in &mut self.nodes {
+            if node.next_attempt > now {
+                continue
+            }
+            // `min()` on shift prevents overflow
+            let delay = BOOTSTRAP_RETRY_BASE << cmp::min(node.attempts, 16);
+            node.next_attempt = now + cmp::min(delay, BOOTSTRAP_RETRY_MAX);
+            node.attempts = node.attempts.saturating_add(1);
+            result.push((node.node, GetNodes::new(own_pk)));
+        }
+        result
+    }
+
+    /// Send due `GetNodes` requests with `transport`, encrypting them with
+    /// own keypair.
+    ///
+    /// Returns number of requests that were sent successfully.
+    pub fn send_requests<T: Transport>(&mut self, transport: &T,
+                                       own_pk: &PublicKey,
+                                       own_sk: &SecretKey,
+                                       kbucket: &Kbucket,
+                                       now: u64) -> usize {
+        let mut sent = 0;
+        for (node, request) in self.next_requests(own_pk, kbucket, now) {
+            let precomp = encrypt_precompute(&node.pk, own_sk);
+            let packet = DhtPacket::new(&precomp, own_pk, &gen_nonce(),
+                                        request.as_packet());
+            match transport.send_to(&packet.to_bytes(), node.saddr) {
+                Ok(_) => sent += 1,
+                Err(e) => debug!(target: "Bootstrap",
+                                 "Failed to send GetNodes to {}: {}",
+                                 node.saddr, e),
+            }
+        }
+        sent
+    }
+}
+
+
+/// Get nodes that should be saved for bootstrapping in the next run – good
+/// nodes from the `kbucket` and from close nodes of `friends`, without
+/// duplicates.
+pub fn nodes_to_save(kbucket: &Kbucket, friends: &DhtFriends, now: u64)
+    -> Vec<PackedNode>
+{
+    let mut result = kbucket.good_packed_nodes(now);
+    for node in friends.good_packed_nodes(now) {
+        if !result.iter().any(|n| n.pk == node.pk) {
+            result.push(node);
+        }
+    }
+    debug!(target: "Bootstrap", "Got {} nodes to save.", result.len());
+    result
+}
+
+/// Serialize `nodes` in the format of the toxcore state section with DHT
+/// nodes, i.e. as concatenated [`PackedNode`](../dht/struct.PackedNode.html)s.
+pub fn save_nodes(nodes: &[PackedNode]) -> Vec<u8> {
+    debug!(target: "Bootstrap", "Serializing {} nodes.", nodes.len());
+    let mut result = Vec::with_capacity(nodes.len() * PACKED_NODE_IPV6_SIZE);
+    for node in nodes {
+        result.extend_from_slice(&node.to_bytes());
+    }
+    result
+}
+
+/// De-serialize nodes saved with [`save_nodes()`](./fn.save_nodes.html).
+///
+/// Parsing stops at the first node that can't be parsed; nodes before it
+/// are returned. Returns an empty `Vec` if there are no nodes.
+pub fn load_nodes(bytes: &[u8]) -> Vec<PackedNode> {
+    debug!(target: "Bootstrap", "De-serializing saved nodes.");
+    match PackedNode::from_bytes_multiple(bytes) {
+        Some(nodes) => nodes,
+        None => {
+            debug!(target: "Bootstrap", "No saved nodes could be loaded.");
+            vec![]
+        },
+    }
+}
+
+
+/// Parse nodes from the `nodes.json` format, as served by e.g.
+/// https://nodes.tox.chat/json.
+///
+/// For every node with valid `public_key` and `port`, a `PackedNode` is
+/// created for its `ipv4` address and another one for `ipv6` address, if
+/// they are valid IP addresses. Nodes with `status_udp` set to `false` are
+/// skipped.
+///
+/// Returns `None` if `json` can't be parsed, or has no `nodes` list.
+pub fn parse_nodes_json(json: &str) -> Option<Vec<PackedNode>> {
+    debug!(target: "Bootstrap", "Parsing nodes.json.");
+    let json = match Json::from_str(json) {
+        Ok(j) => j,
+        Err(e) => {
+            debug!("Failed to parse JSON: {}", e);
+            return None
+        },
+    };
+    let nodes = match json.find("nodes").and_then(|n| n.as_array()) {
+        Some(n) => n,
+        None => {
+            debug!("JSON has no nodes list!");
+            return None
+        },
+    };
+
+    let mut result = Vec::new();
+    for node in nodes {
+        if let Some(false) = node.find("status_udp").and_then(|s| s.as_boolean()) {
+            trace!("Skipping node with status_udp false: {:?}", node);
+            continue
+        }
+
+        let pk = node.find("public_key")
+            .and_then(|pk| pk.as_string())
+            .and_then(|pk| pk.from_hex().ok())
+            .and_then(|pk| if pk.len() == PUBLICKEYBYTES {
+                PublicKey::from_slice(&pk)
+            } else {
+                None
+            });
+        let pk = match pk {
+            Some(pk) => pk,
+            None => {
+                debug!("Skipping node with invalid PK: {:?}", node);
+                continue
+            },
+        };
+
+        let port = match node.find("port").and_then(|p| p.as_u64()) {
+            Some(p) if p > 0 && p <= ::std::u16::MAX as u64 => p as u16,
+            _ => {
+                debug!("Skipping node with invalid port: {:?}", node);
+                continue
+            },
+        };
+
+        let address = |name: &str| node.find(name).and_then(|a| a.as_string());
+        if let Some(Ok(ip)) = address("ipv4").map(Ipv4Addr::from_str) {
+            let saddr = SocketAddr::V4(SocketAddrV4::new(ip, port));
+            result.push(PackedNode::new(true, saddr, &pk));
+        }
+        if let Some(Ok(ip)) = address("ipv6").map(Ipv6Addr::from_str) {
+            let saddr = SocketAddr::V6(SocketAddrV6::new(ip, port, 0, 0));
+            result.push(PackedNode::new(true, saddr, &pk));
+        }
+    }
+    trace!("Parsed nodes: {:?}", &result);
+    Some(result)
+}
@@ -0,0 +1,403 @@
+/*
+    Copyright © 2016 Zetok Zalbavar <zexavexxe@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Crawler that maps the DHT network.
+//!
+//! Starting from bootstrap nodes, [`DhtCrawler`](./struct.DhtCrawler.html)
+//! sends [`GetNodes`](../dht/struct.GetNodes.html) for a random PK to every
+//! node it learns about, and records whether the node answered with
+//! [`SendNodes`](../dht/struct.SendNodes.html). Each unique pair of PK and
+//! address is queried separately.
+//!
+//! Crawler doesn't do any IO on its own; received packets are passed to
+//! [`handle_packet()`](./struct.DhtCrawler.html#method.handle_packet), and
+//! requests are sent with [`send_requests()`]
+//! (./struct.DhtCrawler.html#method.send_requests), which should be called
+//! periodically until crawling [`is_finished()`]
+//! (./struct.DhtCrawler.html#method.is_finished).
+//!
+//! Results can be written as JSON with [`report_to_json()`]
+//! (./fn.report_to_json.html) or as CSV with [`report_to_csv()`]
+//! (./fn.report_to_csv.html).
+
+use rustc_serialize::hex::ToHex;
+use rustc_serialize::json::{Json, ToJson};
+
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+
+use toxcore::binary_io::*;
+use toxcore::crypto_core::*;
+use toxcore::dht::*;
+use toxcore::network::Transport;
+
+
+/// Default number of `GetNodes` requests that can wait for response at the
+/// same time.
+pub const CRAWLER_PARALLELISM: usize = 32;
+
+/// Number of seconds after which request that wasn't answered is
+/// considered to be lost.
+pub const CRAWLER_REQUEST_TIMEOUT: u64 = 5;
+
+/// Number of `GetNodes` requests sent to a node before it's considered
+/// unreachable.
+pub const CRAWLER_MAX_TRIES: u8 = 2;
+
+
+/// Whether crawled node has answered.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Reachability {
+    /// Node wasn't queried yet, or response is still awaited.
+    Unknown,
+    /// Node has answered `GetNodes`.
+    Reachable,
+    /// Node didn't answer any of [`CRAWLER_MAX_TRIES`]
+    /// (./constant.CRAWLER_MAX_TRIES.html) requests.
+    Unreachable,
+}
+
+impl Reachability {
+    /// Name of the reachability used in reports.
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Reachability::Unknown => "unknown",
+            Reachability::Reachable => "reachable",
+            Reachability::Unreachable => "unreachable",
+        }
+    }
+}
+
+
+/// Node found by the crawler.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct CrawledNode {
+    /// PK and address of the node.
+    pub node: PackedNode,
+    /// Whether node has answered.
+    pub reachability: Reachability,
+    /// Number of nodes that node has sent in its `SendNodes`.
+    pub nodes_returned: usize,
+}
+
+
+/// State of a crawled node.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum QueryState {
+    /// `GetNodes` wasn't sent to the node yet, or has to be resent.
+    NotQueried,
+    /// Waiting for response to `GetNodes` with given `id`, sent at given
+    /// time.
+    Waiting(u64, u64),
+    /// Node has responded.
+    Responded,
+    /// Node didn't respond to any request.
+    Failed,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct Entry {
+    node: PackedNode,
+    state: QueryState,
+    tries: u8,
+    nodes_returned: usize,
+}
+
+
+/// Crawler of the DHT network.
+pub struct DhtCrawler {
+    pk: PublicKey,
+    sk: SecretKey,
+    parallelism: usize,
+    /// In order of discovery.
+    entries: Vec<Entry>,
+}
+
+impl DhtCrawler {
+    /// Create new crawler with own keypair, starting from bootstrap
+    /// `nodes`.
+    ///
+    /// `parallelism` – how many requests can wait for response at the same
+    /// time. If `0` is supplied, [`CRAWLER_PARALLELISM`]
+    /// (./constant.CRAWLER_PARALLELISM.html) is used.
+    pub fn new(pk: &PublicKey, sk: &SecretKey, nodes: &[PackedNode],
+               parallelism: usize) -> Self
+    {
+        debug!(target: "DhtCrawler", "Creating new DhtCrawler.");
+        trace!(target: "DhtCrawler", "With nodes: {:?}", nodes);
+        let mut crawler = DhtCrawler {
+            pk: *pk,
+            sk: sk.clone(),
+            parallelism: if parallelism == 0 { CRAWLER_PARALLELISM }
+                         else { parallelism },
+            entries: Vec::with_capacity(nodes.len()),
+        };
+        for node in nodes {
+            crawler.add_node(node);
+        }
+        crawler
+    }
+
+    /// Add node to be crawled. Returns `false` if node with the same PK and
+    /// address is already known, or node has own PK.
+    pub fn add_node(&mut self, node: &PackedNode) -> bool {
+        if node.pk == self.pk
+            || self.entries.iter().any(|e| e.node.pk == node.pk
+                                           && e.node.saddr == node.saddr) {
+            return false
+        }
+        trace!(target: "DhtCrawler", "New node: {:?}", node);
+        self.entries.push(Entry {
+            node: *node,
+            state: QueryState::NotQueried,
+            tries: 0,
+            nodes_returned: 0,
+        });
+        true
+    }
+
+    /// Whether crawling has finished, i.e. all known nodes either answered
+    /// or are unreachable.
+    pub fn is_finished(&self) -> bool {
+        self.entries.iter().all(|e| match e.state {
+            QueryState::Responded | QueryState::Failed => true,
+            _ => false,
+        })
+    }
+
+    /// Get new requests that should be sent, each for a random PK. Requests
+    /// that waited for response for longer than [`CRAWLER_REQUEST_TIMEOUT`]
+    /// (./constant.CRAWLER_REQUEST_TIMEOUT.html) are resent, until node was
+    /// asked [`CRAWLER_MAX_TRIES`](./constant.CRAWLER_MAX_TRIES.html) times.
+    ///
+    /// `now` – current time in seconds.
+    pub fn next_requests(&mut self, now: u64) -> Vec<(PackedNode, GetNodes)> {
+        trace!(target: "DhtCrawler", "Getting next requests.");
+        for e in &mut self.entries {
+            if let QueryState::Waiting(_, sent) = e.state {
+                if sent + CRAWLER_REQUEST_TIMEOUT <= now {
+                    debug!(target: "DhtCrawler", "Request timed out: {:?}",
+                           e.node);
+                    e.state = if e.tries < CRAWLER_MAX_TRIES {
+                        QueryState::NotQueried
+                    } else {
+                        QueryState::Failed
+                    };
+                }
+            }
+        }
+
+        let mut in_flight = self.entries.iter().filter(|e| match e.state {
+            QueryState::Waiting(..) => true,
+            _ => false,
+        }).count();
+        let mut result = Vec::new();
+        for e in &mut self.entries {
+            if in_flight >= self.parallelism {
+                break
+            }
+            if e.state == QueryState::NotQueried {
+                let request = GetNodes::new(&gen_keypair().0);
+                e.state = QueryState::Waiting(request.id, now);
+                e.tries += 1;
+                result.push((e.node, request));
+                in_flight += 1;
+            }
+        }
+        result
+    }
+
+    /// Handle `SendNodes` response from `from`. New nodes from the response
+    /// are added to be crawled.
+    ///
+    /// Returns `false` if response wasn't expected.
+    pub fn handle_response(&mut self, from: &PackedNode, response: &SendNodes)
+        -> bool
+    {
+        debug!(target: "DhtCrawler", "Handling SendNodes response.");
+        trace!(target: "DhtCrawler", "From: {:?}; response: {:?}",
+               from, response);
+        let found = self.entries.iter_mut().find(|e| {
+            e.node.pk == from.pk && e.node.saddr == from.saddr && match e.state {
+                QueryState::Waiting(id, _) => id == response.id,
+                _ => false,
+            }
+        });
+        match found {
+            Some(e) => {
+                e.state = QueryState::Responded;
+                e.nodes_returned = response.nodes.len();
+            },
+            None => {
+                debug!(target: "DhtCrawler", "Unexpected response; ignoring.");
+                return false
+            },
+        }
+        for node in &response.nodes {
+            self.add_node(node);
+        }
+        true
+    }
+
+    /// Send due `GetNodes` requests with `transport`.
+    ///
+    /// Returns number of requests that were sent successfully.
+    pub fn send_requests<T: Transport>(&mut self, transport: &T, now: u64)
+        -> usize
+    {
+        let mut sent = 0;
+        for (node, request) in self.next_requests(now) {
+            let precomp = encrypt_precompute(&node.pk, &self.sk);
+            let packet = DhtPacket::new(&precomp, &self.pk, &gen_nonce(),
+                                        request.as_packet());
+            match transport.send_to(&packet.to_bytes(), node.saddr) {
+                Ok(_) => sent += 1,
+                Err(e) => debug!(target: "DhtCrawler",
+                                 "Failed to send GetNodes to {}: {}",
+                                 node.saddr, e),
+            }
+        }
+        sent
+    }
+
+    /// Handle packet received from `addr`.
+    ///
+    /// Returns `false` if packet isn't an expected `SendNodes`.
+    pub fn handle_packet(&mut self, bytes: &[u8], addr: SocketAddr) -> bool {
+        let packet = match DhtPacket::from_bytes(bytes) {
+            Some(p) => p,
+            None => {
+                debug!(target: "DhtCrawler", "Not a DHT packet from {}", addr);
+                return false
+            },
+        };
+        match packet.get_packet(&self.sk) {
+            Some(DPacketT::SendNodes(response)) => {
+                let from = PackedNode::new(true, addr, &packet.sender_pk);
+                self.handle_response(&from, &response)
+            },
+            Some(_) => {
+                debug!(target: "DhtCrawler", "Not a SendNodes from {}", addr);
+                false
+            },
+            None => {
+                debug!(target: "DhtCrawler", "Failed to decrypt packet from {}",
+                       addr);
+                false
+            },
+        }
+    }
+
+    /// All nodes found so far, in order of discovery.
+    pub fn report(&self) -> Vec<CrawledNode> {
+        self.entries.iter().map(|e| CrawledNode {
+            node: e.node,
+            reachability: match e.state {
+                QueryState::Responded => Reachability::Reachable,
+                QueryState::Failed => Reachability::Unreachable,
+                _ => Reachability::Unknown,
+            },
+            nodes_returned: e.nodes_returned,
+        }).collect()
+    }
+
+    /// Number of unique PKs found so far.
+    pub fn unique_pks(&self) -> usize {
+        let mut pks: Vec<&[u8]> = self.entries.iter()
+            .map(|e| { let PublicKey(ref pk) = e.node.pk; &pk[..] })
+            .collect();
+        pks.sort();
+        pks.dedup();
+        pks.len()
+    }
+}
+
+
+/// Hex representation of the PK, uppercase – same as in `nodes.json`.
+fn pk_to_hex(&PublicKey(ref pk): &PublicKey) -> String {
+    pk.to_hex().to_uppercase()
+}
+
+impl ToJson for CrawledNode {
+    fn to_json(&self) -> Json {
+        let mut object = BTreeMap::new();
+        object.insert("public_key".to_owned(), Json::String(pk_to_hex(&self.node.pk)));
+        object.insert("ip".to_owned(), Json::String(self.node.saddr.ip().to_string()));
+        object.insert("port".to_owned(), Json::U64(self.node.saddr.port() as u64));
+        object.insert("status".to_owned(),
+                      Json::String(self.reachability.as_str().to_owned()));
+        object.insert("nodes_returned".to_owned(),
+                      Json::U64(self.nodes_returned as u64));
+        Json::Object(object)
+    }
+}
+
+/// Serialize crawled `nodes` into JSON object with a `nodes` list and
+/// summary counts:
+///
+/// ```json
+/// {
+///     "total": 2,
+///     "unique_pks": 1,
+///     "reachable": 1,
+///     "nodes": [
+///         {
+///             "public_key": "<64 hex characters>",
+///             "ip": "1.2.3.4",
+///             "port": 33445,
+///             "status": "reachable",
+///             "nodes_returned": 4
+///         },
+///         ...
+///     ]
+/// }
+/// ```
+///
+/// `status` is one of `reachable`, `unreachable` or `unknown`.
+pub fn report_to_json(nodes: &[CrawledNode]) -> String {
+    let mut pks: Vec<String> = nodes.iter().map(|n| pk_to_hex(&n.node.pk)).collect();
+    pks.sort();
+    pks.dedup();
+    let reachable = nodes.iter()
+        .filter(|n| n.reachability == Reachability::Reachable)
+        .count();
+
+    let mut object = BTreeMap::new();
+    object.insert("total".to_owned(), Json::U64(nodes.len() as u64));
+    object.insert("unique_pks".to_owned(), Json::U64(pks.len() as u64));
+    object.insert("reachable".to_owned(), Json::U64(reachable as u64));
+    object.insert("nodes".to_owned(),
+                  Json::Array(nodes.iter().map(|n| n.to_json()).collect()));
+    Json::Object(object).pretty().to_string()
+}
+
+/// Serialize crawled `nodes` into CSV with a header line:
+///
+/// ```text
+/// public_key,ip,port,status,nodes_returned
+/// ```
+pub fn report_to_csv(nodes: &[CrawledNode]) -> String {
+    let mut result = String::from("public_key,ip,port,status,nodes_returned\n");
+    for n in nodes {
+        result.push_str(&format!("{},{},{},{},{}\n", pk_to_hex(&n.node.pk),
+                                 n.node.saddr.ip(), n.node.saddr.port(),
+                                 n.reachability.as_str(), n.nodes_returned));
+    }
+    result
+}
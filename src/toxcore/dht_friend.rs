@@ -0,0 +1,547 @@
+/*
+    Copyright © 2016 Zetok Zalbavar <zexavexxe@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Tracking of DHT nodes close to friends' DHT PKs.
+//!
+//! For each friend up to [`BUCKET_SIZE`](../dht/constant.BUCKET_SIZE.html)
+//! nodes closest to friend's DHT PK are kept, and regularly asked for nodes
+//! close to friend. Once some node sends a `PackedNode` with friend's PK,
+//! friend's address is known.
+
+use std::cmp::Ordering;
+use std::net::SocketAddr;
+
+use toxcore::binary_io::*;
+use toxcore::crypto_core::*;
+use toxcore::dht::*;
+use toxcore::hole_punching::HolePunch;
+use toxcore::network::Transport;
+
+
+/// Interval in seconds between sending [`GetNodes`]
+/// (../dht/struct.GetNodes.html) requests for friend's PK to nodes close to
+/// the friend.
+pub const FRIEND_GET_NODES_INTERVAL: u64 = 20;
+
+/// Number of seconds after which the request that wasn't answered is
+/// forgotten.
+pub const FRIEND_REQUEST_TIMEOUT: u64 = 10;
+
+/// Interval in seconds between sending NAT ping requests to a friend.
+pub const NAT_PING_INTERVAL: u64 = 3;
+
+
+/// `GetNodes` request waiting for response.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct PendingRequest {
+    node_pk: PublicKey,
+    id: u64,
+    sent: u64,
+}
+
+
+/// Friend's DHT PK and nodes close to it.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DhtFriend {
+    /// DHT PK of the friend.
+    pub pk: PublicKey,
+    /// Sorted by distance to friend's PK, closest first.
+    close_nodes: Vec<Node>,
+    pending: Vec<PendingRequest>,
+    last_get_nodes: Option<u64>,
+    /// Last known address of the friend, if any.
+    pub addr: Option<SocketAddr>,
+    /// ID of the last NAT ping request sent to the friend.
+    nat_ping_id: u64,
+    /// When the last NAT ping request was sent.
+    nat_ping_sent: Option<u64>,
+    /// When the last valid NAT ping response was received.
+    nat_pong_received: Option<u64>,
+    /// Friend's addresses reported by close nodes, with PKs of the nodes
+    /// that reported them.
+    reported_addrs: Vec<(PublicKey, SocketAddr)>,
+    hole_punch: HolePunch,
+}
+
+impl DhtFriend {
+    /// Create a new `DhtFriend` with friend's DHT `pk`, without any close
+    /// nodes.
+    pub fn new(pk: &PublicKey) -> Self {
+        trace!(target: "DhtFriend", "Creating new DhtFriend with PK: {:?}", pk);
+        DhtFriend {
+            pk: *pk,
+            close_nodes: Vec::with_capacity(BUCKET_SIZE),
+            pending: Vec::new(),
+            last_get_nodes: None,
+            addr: None,
+            nat_ping_id: 0,
+            nat_ping_sent: None,
+            nat_pong_received: None,
+            reported_addrs: Vec::new(),
+            hole_punch: HolePunch::new(),
+        }
+    }
+
+    /// Nodes closest to the friend, closest first.
+    pub fn close_nodes(&self) -> &[Node] {
+        &self.close_nodes
+    }
+
+    /// Try to add node to the friend's close nodes. If node is already there,
+    /// it's refreshed.
+    ///
+    /// Node is added if there are less than [`BUCKET_SIZE`]
+    /// (../dht/constant.BUCKET_SIZE.html) close nodes, or if it's closer than
+    /// the furthest one, which is then removed.
+    ///
+    /// Friend itself is never added. Returns `true` if node was added.
+    pub fn try_add_node(&mut self, node: &Node) -> bool {
+        trace!(target: "DhtFriend", "Trying to add node: {:?}", node);
+        if node.pk() == &self.pk {
+            return false
+        }
+
+        let mut pos = self.close_nodes.len();
+        for (n, close) in self.close_nodes.iter_mut().enumerate() {
+            match self.pk.distance(node.pk(), close.pk()) {
+                Ordering::Less => {
+                    pos = n;
+                    break
+                },
+                Ordering::Equal => {
+                    *close = *node;
+                    return true
+                },
+                Ordering::Greater => {},
+            }
+        }
+        if pos == BUCKET_SIZE {
+            debug!(target: "DhtFriend", "Node is too far from friend.");
+            return false
+        }
+        self.close_nodes.insert(pos, *node);
+        self.close_nodes.truncate(BUCKET_SIZE);
+        true
+    }
+
+    /// Get `GetNodes` requests for friend's PK that should be sent to the
+    /// close nodes that haven't timed out.
+    ///
+    /// Requests are returned every [`FRIEND_GET_NODES_INTERVAL`]
+    /// (./constant.FRIEND_GET_NODES_INTERVAL.html) seconds.
+    pub fn next_requests(&mut self, now: u64) -> Vec<(PackedNode, GetNodes)> {
+        self.pending.retain(|p| p.sent + FRIEND_REQUEST_TIMEOUT > now);
+        self.close_nodes.retain(|n| n.timeout > now);
+
+        match self.last_get_nodes {
+            Some(t) if t + FRIEND_GET_NODES_INTERVAL > now => return vec![],
+            _ => {},
+        }
+        if self.close_nodes.is_empty() {
+            return vec![]
+        }
+        self.last_get_nodes = Some(now);
+
+        let mut result = Vec::with_capacity(self.close_nodes.len());
+        for node in &self.close_nodes {
+            let request = GetNodes::new(&self.pk);
+            self.pending.push(PendingRequest {
+                node_pk: *node.pk(),
+                id: request.id,
+                sent: now,
+            });
+            result.push((node.node, request));
+        }
+        result
+    }
+
+    /// Check whether there is a request to node `from` with given `id`.
+    fn is_pending(&self, from: &PublicKey, id: u64) -> bool {
+        self.pending.iter().any(|p| &p.node_pk == from && p.id == id)
+    }
+
+    /// Handle `SendNodes` response from the node with `from` PK.
+    ///
+    /// Returned nodes are added to the close nodes; if one of the returned
+    /// nodes is the friend, friend's address is returned.
+    ///
+    /// Responses to requests that weren't sent are ignored.
+    pub fn handle_send_nodes(&mut self, from: &PublicKey, response: &SendNodes,
+                             now: u64) -> Option<SocketAddr> {
+        debug!(target: "DhtFriend", "Handling SendNodes response.");
+        trace!(target: "DhtFriend", "From: {:?}; response: {:?}",
+               from, response);
+        if !self.is_pending(from, response.id) {
+            debug!(target: "DhtFriend", "Unexpected response; ignoring.");
+            return None
+        }
+        self.pending.retain(|p| !(&p.node_pk == from && p.id == response.id));
+
+        // node has responded, thus it's still alive
+        for node in &mut self.close_nodes {
+            if node.pk() == from {
+                node.timeout = now + NODE_TIMEOUT;
+            }
+        }
+
+        let mut found = None;
+        for node in &response.nodes {
+            if node.pk == self.pk {
+                debug!(target: "DhtFriend", "Found friend's address: {}",
+                       node.saddr);
+                self.addr = Some(node.saddr);
+                found = Some(node.saddr);
+                self.reported_addrs.retain(|&(ref pk, _)| pk != from);
+                self.reported_addrs.push((*from, node.saddr));
+            } else {
+                self.try_add_node(&Node::new(node, now + NODE_TIMEOUT));
+            }
+        }
+        found
+    }
+
+    /// Get new NAT ping request for the friend, if [`NAT_PING_INTERVAL`]
+    /// (./constant.NAT_PING_INTERVAL.html) has passed since the last one.
+    pub fn nat_ping_request(&mut self, now: u64) -> Option<Ping> {
+        match self.nat_ping_sent {
+            Some(t) if t + NAT_PING_INTERVAL > now => None,
+            _ => {
+                let ping = Ping::new();
+                self.nat_ping_id = ping.id;
+                self.nat_ping_sent = Some(now);
+                Some(ping)
+            },
+        }
+    }
+
+    /// Handle NAT ping response from the friend.
+    ///
+    /// Returns `false` if it's not a response to the last request.
+    pub fn handle_nat_ping_response(&mut self, ping: &Ping, now: u64) -> bool {
+        trace!(target: "DhtFriend", "Handling NAT ping response: {:?}", ping);
+        if ping.is_request() || self.nat_ping_sent.is_none()
+            || ping.id != self.nat_ping_id {
+            debug!(target: "DhtFriend", "Unexpected NAT ping response.");
+            return false
+        }
+        self.nat_pong_received = Some(now);
+        true
+    }
+
+    /// Whether friend has responded to a NAT ping recently, i.e. within the
+    /// last 2 [`NAT_PING_INTERVAL`](./constant.NAT_PING_INTERVAL.html)s.
+    pub fn is_nat_ping_confirmed(&self, now: u64) -> bool {
+        match self.nat_pong_received {
+            Some(t) => t + NAT_PING_INTERVAL * 2 >= now,
+            None => false,
+        }
+    }
+
+    /// Friend's addresses, as reported by close nodes.
+    pub fn reported_addrs(&self) -> Vec<SocketAddr> {
+        self.reported_addrs.iter().map(|&(_, a)| a).collect()
+    }
+
+    /// Get addresses that should be pinged to punch a hole to the friend.
+    ///
+    /// Holes are punched only when friend responds to NAT pings, and there is
+    /// no direct connection to the friend yet.
+    pub fn punch_holes(&mut self, now: u64) -> Vec<SocketAddr> {
+        if !self.is_nat_ping_confirmed(now) {
+            return vec![]
+        }
+        let reported = self.reported_addrs();
+        self.hole_punch.next_addrs(&reported, now)
+    }
+
+    /// Mark friend as directly reachable at `addr`, e.g. after friend has
+    /// answered a ping sent there. Stops hole punching.
+    pub fn hole_punched(&mut self, addr: SocketAddr) {
+        debug!(target: "DhtFriend", "Friend is reachable at {}", addr);
+        self.addr = Some(addr);
+        self.hole_punch.done();
+    }
+
+    /// Whether hole to the friend has been punched.
+    pub fn is_hole_punched(&self) -> bool {
+        self.hole_punch.is_done()
+    }
+
+    /// Send `DhtRequest` with `payload` to the friend through all of its
+    /// close nodes.
+    ///
+    /// Returns number of nodes that request was sent to.
+    pub fn send_request<T: Transport>(&self, transport: &T, own_pk: &PublicKey,
+                                      own_sk: &SecretKey, payload: DhtRequestT)
+        -> usize
+    {
+        let precomp = encrypt_precompute(&self.pk, own_sk);
+        let request = DhtRequest::new(&precomp, own_pk, &self.pk, &gen_nonce(),
+                                      payload).to_bytes();
+        let mut sent = 0;
+        for node in &self.close_nodes {
+            match transport.send_to(&request, node.node.saddr) {
+                Ok(_) => sent += 1,
+                Err(e) => debug!(target: "DhtFriend",
+                                 "Failed to send DhtRequest to {}: {}",
+                                 node.node.saddr, e),
+            }
+        }
+        sent
+    }
+}
+
+
+/// List of friends which close nodes are tracked.
+pub struct DhtFriends {
+    friends: Vec<DhtFriend>,
+    on_friend_addr: Option<Box<FnMut(&PublicKey, SocketAddr)>>,
+}
+
+impl DhtFriends {
+    /// Create an empty list of friends.
+    pub fn new() -> Self {
+        DhtFriends { friends: Vec::new(), on_friend_addr: None }
+    }
+
+    /// Set callback that will be called with friend's DHT PK and address
+    /// whenever friend's address is found.
+    pub fn on_friend_addr(&mut self,
+                          callback: Box<FnMut(&PublicKey, SocketAddr)>) {
+        self.on_friend_addr = Some(callback);
+    }
+
+    /// Add friend with DHT `pk`. Close nodes for friend are taken from the
+    /// `kbucket`.
+    ///
+    /// Returns `false` if friend was already added.
+    pub fn add_friend(&mut self, pk: &PublicKey, kbucket: &Kbucket, now: u64)
+        -> bool
+    {
+        debug!(target: "DhtFriends", "Adding friend: {:?}", pk);
+        if self.get(pk).is_some() {
+            return false
+        }
+        let mut friend = DhtFriend::new(pk);
+        for node in kbucket.get_closest(pk, BUCKET_SIZE, now) {
+            friend.try_add_node(&Node::new(&node, now + NODE_TIMEOUT));
+        }
+        self.friends.push(friend);
+        true
+    }
+
+    /// Remove friend with DHT `pk`. Returns `false` if there was no such
+    /// friend.
+    pub fn remove_friend(&mut self, pk: &PublicKey) -> bool {
+        debug!(target: "DhtFriends", "Removing friend: {:?}", pk);
+        let len = self.friends.len();
+        self.friends.retain(|f| &f.pk != pk);
+        len != self.friends.len()
+    }
+
+    /// Get friend with DHT `pk`.
+    pub fn get(&self, pk: &PublicKey) -> Option<&DhtFriend> {
+        self.friends.iter().find(|f| &f.pk == pk)
+    }
+
+    /// Get mutable friend with DHT `pk`.
+    pub fn get_mut(&mut self, pk: &PublicKey) -> Option<&mut DhtFriend> {
+        self.friends.iter_mut().find(|f| &f.pk == pk)
+    }
+
+    /// Get all friends.
+    pub fn friends(&self) -> &[DhtFriend] {
+        &self.friends
+    }
+
+    /// Get close nodes of all friends which timeout is bigger than `now`.
+    /// Nodes close to multiple friends are returned only once.
+    pub fn good_packed_nodes(&self, now: u64) -> Vec<PackedNode> {
+        let mut result: Vec<PackedNode> = Vec::new();
+        for friend in &self.friends {
+            for node in friend.close_nodes().iter().filter(|n| n.timeout > now) {
+                if !result.iter().any(|n| n.pk == node.node.pk) {
+                    result.push(node.node);
+                }
+            }
+        }
+        result
+    }
+
+    /// Add node to the close nodes of every friend it's close enough to.
+    ///
+    /// Returns `true` if node was added to any friend.
+    pub fn try_add_node(&mut self, node: &Node) -> bool {
+        self.friends.iter_mut()
+            .fold(false, |added, f| f.try_add_node(node) || added)
+    }
+
+    /// Get `GetNodes` requests that should be sent for all friends.
+    pub fn next_requests(&mut self, now: u64) -> Vec<(PackedNode, GetNodes)> {
+        let mut result = Vec::new();
+        for friend in &mut self.friends {
+            result.extend(friend.next_requests(now));
+        }
+        result
+    }
+
+    /// Send due `GetNodes` requests with `transport`, encrypting them with
+    /// own keypair.
+    ///
+    /// Returns number of requests that were sent successfully.
+    pub fn send_requests<T: Transport>(&mut self, transport: &T,
+                                       own_pk: &PublicKey,
+                                       own_sk: &SecretKey,
+                                       now: u64) -> usize {
+        let mut sent = 0;
+        for (node, request) in self.next_requests(now) {
+            let precomp = encrypt_precompute(&node.pk, own_sk);
+            let packet = DhtPacket::new(&precomp, own_pk, &gen_nonce(),
+                                        request.as_packet());
+            match transport.send_to(&packet.to_bytes(), node.saddr) {
+                Ok(_) => sent += 1,
+                Err(e) => debug!(target: "DhtFriends",
+                                 "Failed to send GetNodes to {}: {}",
+                                 node.saddr, e),
+            }
+        }
+        sent
+    }
+
+    /// Send NAT ping requests to every friend which wasn't pinged in the
+    /// last [`NAT_PING_INTERVAL`](./constant.NAT_PING_INTERVAL.html).
+    ///
+    /// Returns number of friends that were pinged.
+    pub fn send_nat_pings<T: Transport>(&mut self, transport: &T,
+                                        own_pk: &PublicKey,
+                                        own_sk: &SecretKey,
+                                        now: u64) -> usize {
+        let mut pinged = 0;
+        for friend in &mut self.friends {
+            if friend.close_nodes.is_empty() {
+                continue
+            }
+            if let Some(ping) = friend.nat_ping_request(now) {
+                let payload = DhtRequestT::NatPing(ping);
+                if friend.send_request(transport, own_pk, own_sk, payload) > 0 {
+                    pinged += 1;
+                }
+            }
+        }
+        pinged
+    }
+
+    /// Send ping requests to the addresses guessed by hole punching, for
+    /// every friend that responds to NAT pings.
+    ///
+    /// Returns number of pings sent successfully.
+    pub fn send_punches<T: Transport>(&mut self, transport: &T,
+                                      own_pk: &PublicKey,
+                                      own_sk: &SecretKey,
+                                      now: u64) -> usize {
+        let mut sent = 0;
+        for friend in &mut self.friends {
+            let addrs = friend.punch_holes(now);
+            if addrs.is_empty() {
+                continue
+            }
+            let precomp = encrypt_precompute(&friend.pk, own_sk);
+            for addr in addrs {
+                let packet = DhtPacket::new(&precomp, own_pk, &gen_nonce(),
+                                            Ping::new().as_packet());
+                match transport.send_to(&packet.to_bytes(), addr) {
+                    Ok(_) => sent += 1,
+                    Err(e) => debug!(target: "DhtFriends",
+                                     "Failed to punch {}: {}", addr, e),
+                }
+            }
+        }
+        sent
+    }
+
+    /// Handle valid ping (request or response) received directly from the
+    /// node with `pk` at `addr`. If node is a friend, hole to it is
+    /// considered to be punched.
+    ///
+    /// Returns `false` if `pk` isn't a friend.
+    pub fn handle_ping(&mut self, pk: &PublicKey, addr: SocketAddr) -> bool {
+        match self.friends.iter_mut().find(|f| &f.pk == pk) {
+            Some(friend) => {
+                if !friend.is_hole_punched() {
+                    friend.hole_punched(addr);
+                }
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Handle NAT ping received from the `sender` in a `DhtRequest`.
+    ///
+    /// Requests from friends are answered through friend's close nodes;
+    /// responses are passed to the friend.
+    ///
+    /// Returns `false` if `sender` is not a friend, or the response was not
+    /// expected.
+    pub fn handle_nat_ping<T: Transport>(&mut self, transport: &T,
+                                         own_pk: &PublicKey,
+                                         own_sk: &SecretKey,
+                                         sender: &PublicKey,
+                                         ping: &Ping,
+                                         now: u64) -> bool {
+        debug!(target: "DhtFriends", "Handling NAT ping from {:?}", sender);
+        let friend = match self.friends.iter_mut().find(|f| &f.pk == sender) {
+            Some(f) => f,
+            None => {
+                debug!(target: "DhtFriends", "NAT ping not from a friend.");
+                return false
+            },
+        };
+        match ping.response() {
+            Some(resp) => {
+                let payload = DhtRequestT::NatPing(resp);
+                friend.send_request(transport, own_pk, own_sk, payload);
+                true
+            },
+            None => friend.handle_nat_ping_response(ping, now),
+        }
+    }
+
+    /// Handle `SendNodes` response from the node with `from` PK, passing it
+    /// to the friend for which the request was sent.
+    ///
+    /// If friend's address was found, callback set with [`on_friend_addr()`]
+    /// (#method.on_friend_addr) is called.
+    ///
+    /// Returns `false` if response doesn't match any sent request.
+    pub fn handle_send_nodes(&mut self, from: &PublicKey, response: &SendNodes,
+                             now: u64) -> bool {
+        let friend = match self.friends.iter_mut()
+                                       .find(|f| f.is_pending(from, response.id)) {
+            Some(f) => f,
+            None => return false,
+        };
+        if let Some(addr) = friend.handle_send_nodes(from, response, now) {
+            if let Some(ref mut callback) = self.on_friend_addr {
+                callback(&friend.pk, addr);
+            }
+        }
+        true
+    }
+}
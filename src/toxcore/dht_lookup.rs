@@ -0,0 +1,277 @@
+/*
+    Copyright © 2016 Zetok Zalbavar <zexavexxe@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Iterative lookup of DHT nodes closest to a given PK.
+//!
+//! Lookup doesn't do any networking by itself – it only tells which
+//! [`GetNodes`](../dht/struct.GetNodes.html) requests should be sent where,
+//! and has to be fed with received [`SendNodes`]
+//! (../dht/struct.SendNodes.html) responses.
+//!
+//! Lookup is finished when all of the closest nodes in the shortlist have
+//! responded, i.e. when responses stopped bringing closer nodes.
+
+use std::cmp::Ordering;
+
+use toxcore::crypto_core::*;
+use toxcore::dht::*;
+
+
+/// Default number of `GetNodes` requests that can wait for response at the
+/// same time.
+pub const LOOKUP_PARALLELISM: usize = 3;
+
+/// Number of closest nodes that lookup is looking for.
+pub const LOOKUP_SHORTLIST_SIZE: usize = BUCKET_SIZE;
+
+/// Number of seconds after which node that didn't respond to `GetNodes` is
+/// considered to be failed.
+pub const LOOKUP_REQUEST_TIMEOUT: u64 = 5;
+
+
+/// State of a node in the lookup shortlist.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum CandidateState {
+    /// `GetNodes` wasn't sent to the node yet.
+    NotQueried,
+    /// Waiting for response to `GetNodes` with given `id`, sent at given
+    /// time.
+    Waiting(u64, u64),
+    /// Node has responded.
+    Responded,
+    /// Node didn't respond in time.
+    Failed,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct Candidate {
+    node: PackedNode,
+    state: CandidateState,
+}
+
+
+/// Iterative lookup of nodes closest to the `target` PK.
+///
+/// Usage:
+///
+/// * call [`next_requests()`](#method.next_requests) and send returned
+///   `GetNodes` requests to the returned nodes;
+/// * pass `SendNodes` responses to [`handle_response()`]
+///   (#method.handle_response);
+/// * repeat until [`is_finished()`](#method.is_finished) is `true`.
+///
+/// When lookup finishes, callback set with [`on_done()`](#method.on_done)
+/// is called with the closest nodes that have been found.
+pub struct NodeLookup {
+    target: PublicKey,
+    parallelism: usize,
+    /// Sorted by distance to the `target`, closest first.
+    candidates: Vec<Candidate>,
+    finished: bool,
+    on_done: Option<Box<FnMut(&PublicKey, &[PackedNode])>>,
+}
+
+impl NodeLookup {
+    /// Create a new lookup of nodes closest to the `target`, starting from
+    /// supplied `nodes`.
+    ///
+    /// `parallelism` – how many requests can wait for response at the same
+    /// time. If `0` is supplied, [`LOOKUP_PARALLELISM`]
+    /// (./constant.LOOKUP_PARALLELISM.html) is used.
+    pub fn new(target: &PublicKey, nodes: &[PackedNode], parallelism: usize)
+        -> Self
+    {
+        debug!(target: "NodeLookup", "Creating new NodeLookup.");
+        trace!(target: "NodeLookup", "With target: {:?}; nodes: {:?}",
+               target, nodes);
+        let mut lookup = NodeLookup {
+            target: *target,
+            parallelism: if parallelism == 0 { LOOKUP_PARALLELISM }
+                         else { parallelism },
+            candidates: Vec::with_capacity(nodes.len()),
+            finished: false,
+            on_done: None,
+        };
+        for node in nodes {
+            lookup.add_candidate(node);
+        }
+        lookup
+    }
+
+    /// PK which closest nodes are looked up.
+    pub fn target(&self) -> &PublicKey {
+        &self.target
+    }
+
+    /// Set callback that will be called once lookup is finished, with the
+    /// target PK and the closest nodes found.
+    pub fn on_done(&mut self, callback: Box<FnMut(&PublicKey, &[PackedNode])>) {
+        self.on_done = Some(callback);
+    }
+
+    /// Whether lookup has finished.
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Add node to the shortlist, keeping it sorted by distance to the
+    /// target. Returns `false` if node already was in the shortlist, or node
+    /// is the target itself.
+    fn add_candidate(&mut self, node: &PackedNode) -> bool {
+        if node.pk == self.target {
+            // target's own address isn't "closest node" in the lookup
+            // meaning – it's not asked for its closest nodes
+            return false
+        }
+        let mut pos = self.candidates.len();
+        for (n, c) in self.candidates.iter().enumerate() {
+            match self.target.distance(&node.pk, &c.node.pk) {
+                Ordering::Less => {
+                    pos = n;
+                    break
+                },
+                Ordering::Equal => return false,
+                Ordering::Greater => {},
+            }
+        }
+        self.candidates.insert(pos, Candidate {
+            node: *node,
+            state: CandidateState::NotQueried,
+        });
+        true
+    }
+
+    /// Number of requests that wait for response.
+    fn in_flight(&self) -> usize {
+        self.candidates.iter().filter(|c| match c.state {
+            CandidateState::Waiting(..) => true,
+            _ => false,
+        }).count()
+    }
+
+    /// Get new requests that should be sent, and mark requests that waited
+    /// for response for longer than [`LOOKUP_REQUEST_TIMEOUT`]
+    /// (./constant.LOOKUP_REQUEST_TIMEOUT.html) as failed.
+    ///
+    /// `now` – current time in seconds.
+    ///
+    /// Returns an empty `Vec` if lookup is finished, or no more requests can
+    /// be sent at the moment.
+    pub fn next_requests(&mut self, now: u64) -> Vec<(PackedNode, GetNodes)> {
+        trace!(target: "NodeLookup", "Getting next requests for lookup.");
+        let mut result = Vec::new();
+        if self.finished {
+            return result
+        }
+
+        for c in &mut self.candidates {
+            if let CandidateState::Waiting(_, sent) = c.state {
+                if sent + LOOKUP_REQUEST_TIMEOUT <= now {
+                    debug!(target: "NodeLookup", "Node timed out: {:?}",
+                           c.node);
+                    c.state = CandidateState::Failed;
+                }
+            }
+        }
+
+        let mut in_flight = self.in_flight();
+        let mut considered = 0;
+        for c in &mut self.candidates {
+            if considered == LOOKUP_SHORTLIST_SIZE
+                || in_flight == self.parallelism {
+                break
+            }
+            match c.state {
+                CandidateState::Failed => continue,
+                CandidateState::NotQueried => {
+                    let request = GetNodes::new(&self.target);
+                    c.state = CandidateState::Waiting(request.id, now);
+                    result.push((c.node, request));
+                    in_flight += 1;
+                },
+                _ => {},
+            }
+            considered += 1;
+        }
+
+        if in_flight == 0 {
+            self.finish();
+        }
+        result
+    }
+
+    /// Handle `SendNodes` response from the node with PK `from`.
+    ///
+    /// Returns `false` if response wasn't expected, e.g. it has wrong ping
+    /// ID, or it came after timeout.
+    pub fn handle_response(&mut self, from: &PublicKey, response: &SendNodes)
+        -> bool
+    {
+        debug!(target: "NodeLookup", "Handling SendNodes response.");
+        trace!(target: "NodeLookup", "From: {:?}; response: {:?}",
+               from, response);
+        if self.finished {
+            return false
+        }
+
+        let mut found = false;
+        for c in &mut self.candidates {
+            if c.node.pk != *from {
+                continue
+            }
+            if let CandidateState::Waiting(id, _) = c.state {
+                if id == response.id {
+                    c.state = CandidateState::Responded;
+                    found = true;
+                }
+            }
+            break
+        }
+        if !found {
+            debug!(target: "NodeLookup", "Unexpected response; ignoring.");
+            return false
+        }
+
+        for node in &response.nodes {
+            self.add_candidate(node);
+        }
+        true
+    }
+
+    /// Closest nodes that responded to the `GetNodes`, up to
+    /// [`LOOKUP_SHORTLIST_SIZE`](./constant.LOOKUP_SHORTLIST_SIZE.html).
+    ///
+    /// Closest node is the first.
+    pub fn closest(&self) -> Vec<PackedNode> {
+        self.candidates.iter()
+            .filter(|c| c.state == CandidateState::Responded)
+            .take(LOOKUP_SHORTLIST_SIZE)
+            .map(|c| c.node)
+            .collect()
+    }
+
+    fn finish(&mut self) {
+        debug!(target: "NodeLookup", "Lookup finished.");
+        self.finished = true;
+        let closest = self.closest();
+        if let Some(ref mut callback) = self.on_done {
+            callback(&self.target, &closest);
+        }
+    }
+}
@@ -0,0 +1,449 @@
+/*
+    Copyright © 2016 Zetok Zalbavar <zexavexxe@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! DHT node that answers requests of other nodes and keeps its `Kbucket`
+//! filled – the core of a bootstrap node.
+//!
+//! [`DhtNode`](./struct.DhtNode.html) doesn't do any IO on its own; received
+//! packets are passed to [`handle_packet()`]
+//! (./struct.DhtNode.html#method.handle_packet), and requests of the node
+//! itself are sent with [`send_requests()`]
+//! (./struct.DhtNode.html#method.send_requests), which should be called
+//! periodically.
+//!
+//! With [`lan_discovery`](./struct.DhtNode.html#structfield.lan_discovery)
+//! set, node finds other nodes in the local network without any bootstrap
+//! nodes.
+
+use std::net::SocketAddr;
+use std::time::Instant;
+
+use toxcore::binary_io::*;
+use toxcore::crypto_core::*;
+use toxcore::dht::*;
+use toxcore::dht_bootstrap::Bootstrap;
+use toxcore::lan_discovery::{LanDiscovery, LanDiscoverySender,
+                             handle_lan_discovery};
+use toxcore::network::Transport;
+use toxcore::onion::{OnionRelay, OnionResponse3, split_onion_return};
+use toxcore::onion_announce::{AnnounceRequest, AnnounceServer, OnionDataRequest};
+
+
+/// Interval in seconds between pinging nodes in the `Kbucket`.
+pub const PING_INTERVAL: u64 = 60;
+
+/// Interval in seconds between sending `GetNodes` for own PK to random nodes
+/// in the `Kbucket`.
+pub const GET_NODES_INTERVAL: u64 = 20;
+
+/// Number of seconds after which request that wasn't answered is
+/// forgotten.
+pub const REQUEST_TIMEOUT: u64 = 5;
+
+/// Maximum number of nodes waiting to be pinged.
+pub const MAX_TO_PING: usize = 32;
+
+
+/// Request sent by the node, waiting for a response.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct PendingRequest {
+    node: PackedNode,
+    id: u64,
+    sent: u64,
+    /// Used to measure round-trip time, since `sent` has too low precision.
+    sent_at: Instant,
+}
+
+
+/// DHT node that answers [`Ping`](../dht/struct.Ping.html) and
+/// [`GetNodes`](../dht/struct.GetNodes.html) requests, relays onion
+/// requests and responses of others with [`OnionRelay`]
+/// (../onion/struct.OnionRelay.html), and stores onion announcements with
+/// [`AnnounceServer`](../onion_announce/struct.AnnounceServer.html).
+///
+/// Nodes are added to the `Kbucket` only when they answer a ping or a
+/// `GetNodes` request, since source address of a request can be spoofed.
+/// Nodes that send requests, and nodes from received [`SendNodes`]
+/// (../dht/struct.SendNodes.html) are pinged before being added.
+pub struct DhtNode<'a> {
+    pk: PublicKey,
+    sk: SecretKey,
+    /// Nodes close to own PK.
+    pub kbucket: Kbucket<'a>,
+    /// Message of the day, served in [`BootstrapInfo`]
+    /// (../dht/struct.BootstrapInfo.html), e.g. to the status sites.
+    pub motd: String,
+    /// Version served in [`BootstrapInfo`](../dht/struct.BootstrapInfo.html).
+    pub version: u32,
+    bootstrap: Bootstrap,
+    onion: OnionRelay,
+    /// Onion announcements of clients with PKs close to own PK.
+    pub announce: AnnounceServer,
+    /// Sender of [`LanDiscovery`](../lan_discovery/struct.LanDiscovery.html)
+    /// packets. LAN discovery is disabled if it's `None`, and received
+    /// `LanDiscovery` packets are ignored.
+    pub lan_discovery: Option<LanDiscoverySender>,
+    /// Nodes from `SendNodes` that should be pinged.
+    to_ping: Vec<PackedNode>,
+    pending_pings: Vec<PendingRequest>,
+    pending_get_nodes: Vec<PendingRequest>,
+    last_ping: Option<u64>,
+    last_get_nodes: Option<u64>,
+}
+
+impl<'a> DhtNode<'a> {
+    /// Create new `DhtNode` with own keypair, with no known nodes, empty
+    /// MOTD and version `0`.
+    pub fn new(pk: &'a PublicKey, sk: &SecretKey) -> Self {
+        debug!(target: "DhtNode", "Creating new DhtNode.");
+        DhtNode {
+            pk: *pk,
+            sk: sk.clone(),
+            kbucket: Kbucket::new(KBUCKET_MAX_ENTRIES as u8, pk),
+            motd: String::new(),
+            version: 0,
+            bootstrap: Bootstrap::from_packed_nodes(&[]),
+            onion: OnionRelay::new(),
+            announce: AnnounceServer::new(pk),
+            lan_discovery: None,
+            to_ping: Vec::new(),
+            pending_pings: Vec::new(),
+            pending_get_nodes: Vec::new(),
+            last_ping: None,
+            last_get_nodes: None,
+        }
+    }
+
+    /// Own DHT PK.
+    pub fn pk(&self) -> &PublicKey {
+        &self.pk
+    }
+
+    /// Add nodes to bootstrap from.
+    pub fn add_bootstrap_nodes(&mut self, nodes: &[PackedNode]) {
+        self.bootstrap.add_nodes(nodes);
+    }
+
+    /// Handle packet received from `addr`, answering it with `transport` if
+    /// it's a request. [`BootstrapInfoRequest`]
+    /// (../dht/struct.BootstrapInfoRequest.html) is answered with
+    /// [`BootstrapInfo`](../dht/struct.BootstrapInfo.html) with own version
+    /// and MOTD.
+    ///
+    /// Returns `false` if packet couldn't be parsed or decrypted, or wasn't
+    /// expected.
+    pub fn handle_packet<T: Transport>(&mut self, transport: &T, bytes: &[u8],
+                                       addr: SocketAddr, now: u64) -> bool {
+        trace!(target: "DhtNode", "Handling packet from {}", addr);
+        if BootstrapInfoRequest::from_bytes(bytes).is_some() {
+            let info = BootstrapInfo::new(self.version, &self.motd);
+            self.send_bytes(transport, &info.to_bytes(), addr);
+            return true
+        }
+        match PacketKind::from_bytes(bytes) {
+            Some(PacketKind::OnionReq0) | Some(PacketKind::OnionReq1) |
+            Some(PacketKind::OnionReq2) | Some(PacketKind::OnionResp3) |
+            Some(PacketKind::OnionResp2) | Some(PacketKind::OnionResp1) =>
+                return self.onion.handle_packet(transport, &self.sk, bytes, addr),
+            Some(PacketKind::AnnReq) =>
+                return self.handle_announce_request(transport, bytes, addr, now),
+            Some(PacketKind::OnionDataReq) =>
+                return self.handle_onion_data_request(transport, bytes, now),
+            Some(PacketKind::LanDisc) =>
+                return self.handle_lan_discovery(transport, bytes, addr, now),
+            _ => {},
+        }
+        let packet = match DhtPacket::from_bytes(bytes) {
+            Some(p) => p,
+            None => {
+                debug!(target: "DhtNode", "Not a DHT packet from {}", addr);
+                return false
+            },
+        };
+        let node = PackedNode::new(true, addr, &packet.sender_pk);
+        match packet.get_packet(&self.sk) {
+            Some(DPacketT::Ping(ping)) => if ping.is_request() {
+                self.handle_ping_request(transport, &packet, &node, now)
+            } else {
+                self.handle_ping_response(&ping, &node, now)
+            },
+            Some(DPacketT::GetNodes(request)) =>
+                self.handle_get_nodes(transport, &request, &node, now),
+            Some(DPacketT::SendNodes(response)) =>
+                self.handle_send_nodes(&response, &node, now),
+            None => {
+                debug!(target: "DhtNode", "Failed to decrypt packet from {}",
+                       addr);
+                false
+            },
+        }
+    }
+
+    /// Announce request comes from the third node of the path, followed by
+    /// its `OnionReturn`; response is sent back to it in `OnionResponse3`.
+    fn handle_announce_request<T: Transport>(&mut self, transport: &T,
+                                             bytes: &[u8], addr: SocketAddr,
+                                             now: u64) -> bool {
+        let (request, onion_return) = match split_onion_return(bytes) {
+            Some((r, onion_return)) => match AnnounceRequest::from_bytes(r) {
+                Some(r) => (r, onion_return),
+                None => return false,
+            },
+            None => return false,
+        };
+        let response = match self.announce.handle_request(&self.sk, &request,
+                                                          &onion_return, addr,
+                                                          &self.kbucket, now) {
+            Some(r) => r,
+            None => return false,
+        };
+        let response = OnionResponse3 {
+            onion_return: onion_return,
+            payload: response.to_bytes(),
+        };
+        self.send_bytes(transport, &response.to_bytes(), addr)
+    }
+
+    /// Onion data request is passed to the announced client through the
+    /// return path stored with its announcement. Return path of the sender
+    /// that came with the request is not needed.
+    fn handle_onion_data_request<T: Transport>(&self, transport: &T,
+                                               bytes: &[u8], now: u64) -> bool {
+        let request = match split_onion_return(bytes) {
+            Some((r, _)) => OnionDataRequest::from_bytes(r),
+            None => None,
+        };
+        match request.and_then(|r| self.announce.handle_data_request(&r, now)) {
+            Some((addr, response)) =>
+                self.send_bytes(transport, &response.to_bytes(), addr),
+            None => false,
+        }
+    }
+
+    /// `LanDiscovery` is answered with `GetNodes`, and the sender is added
+    /// to the `Kbucket` when it responds.
+    fn handle_lan_discovery<T: Transport>(&mut self, transport: &T,
+                                          bytes: &[u8], addr: SocketAddr,
+                                          now: u64) -> bool {
+        if self.lan_discovery.is_none() {
+            debug!(target: "DhtNode", "LAN discovery is disabled.");
+            return false
+        }
+        let packet = match LanDiscovery::from_bytes(bytes) {
+            Some(p) => p,
+            None => return false,
+        };
+        match handle_lan_discovery(transport, &self.pk, &self.sk, &packet, addr) {
+            Some((node, request)) => {
+                self.pending_get_nodes.push(PendingRequest {
+                    node: node,
+                    id: request.id,
+                    sent: now,
+                    sent_at: Instant::now(),
+                });
+                true
+            },
+            None => false,
+        }
+    }
+
+    fn handle_ping_request<T: Transport>(&mut self, transport: &T,
+                                         packet: &DhtPacket, node: &PackedNode,
+                                         now: u64) -> bool {
+        let precomp = encrypt_precompute(&node.pk, &self.sk);
+        let response = match packet.ping_resp(&self.sk, &precomp, &self.pk) {
+            Some(r) => r,
+            None => return false,
+        };
+        self.send(transport, &response, node.saddr);
+        self.add_to_ping(node, now);
+        true
+    }
+
+    fn handle_ping_response(&mut self, ping: &Ping, node: &PackedNode,
+                            now: u64) -> bool {
+        let pos = self.pending_pings.iter()
+            .position(|p| p.node.pk == node.pk && p.id == ping.id);
+        let pending = match pos {
+            Some(pos) => self.pending_pings.remove(pos),
+            None => {
+                debug!(target: "DhtNode", "Unexpected ping response from {}",
+                       node.saddr);
+                return false
+            },
+        };
+        let elapsed = pending.sent_at.elapsed();
+        let mut new = Node::new(node, now + NODE_TIMEOUT);
+        new.rtt = Some(elapsed.as_secs() * 1000
+                       + elapsed.subsec_nanos() as u64 / 1_000_000);
+        new.last_seen = Some(now);
+        self.kbucket.try_add(&new);
+        true
+    }
+
+    fn handle_get_nodes<T: Transport>(&mut self, transport: &T,
+                                      request: &GetNodes, node: &PackedNode,
+                                      now: u64) -> bool {
+        let nodes: Vec<PackedNode> = self.kbucket.get_closest(&request.pk, 4, now)
+            .into_iter()
+            .filter(|n| n.pk != node.pk)
+            .collect();
+        if let Some(response) = SendNodes::from_request(request, nodes) {
+            let precomp = encrypt_precompute(&node.pk, &self.sk);
+            let packet = DhtPacket::new(&precomp, &self.pk, &gen_nonce(),
+                                        response.as_packet());
+            self.send(transport, &packet, node.saddr);
+        }
+        self.add_to_ping(node, now);
+        true
+    }
+
+    fn handle_send_nodes(&mut self, response: &SendNodes, node: &PackedNode,
+                         now: u64) -> bool {
+        let len = self.pending_get_nodes.len();
+        self.pending_get_nodes
+            .retain(|p| !(p.node.pk == node.pk && p.id == response.id));
+        if len == self.pending_get_nodes.len() {
+            debug!(target: "DhtNode", "Unexpected SendNodes from {}",
+                   node.saddr);
+            return false
+        }
+        let mut sender = Node::new(node, now + NODE_TIMEOUT);
+        sender.last_seen = Some(now);
+        self.kbucket.try_add(&sender);
+
+        for new in &response.nodes {
+            self.add_to_ping(new, now);
+        }
+        true
+    }
+
+    /// Queue `node` to be pinged with the next requests, unless it's
+    /// already queued, or it's in the `Kbucket` and hasn't timed out.
+    fn add_to_ping(&mut self, node: &PackedNode, now: u64) {
+        if node.pk == self.pk || self.to_ping.len() >= MAX_TO_PING
+            || self.to_ping.iter().any(|n| n.pk == node.pk) {
+            return
+        }
+        match self.kbucket.get_node(&node.pk) {
+            Some(n) if n.timeout > now => {},
+            _ => self.to_ping.push(*node),
+        }
+    }
+
+    /// Send periodic requests of the node with `transport`:
+    ///
+    /// * `GetNodes` to bootstrap nodes, until bootstrapping is done
+    /// * pings to the nodes that sent requests, and to the nodes from
+    ///   received `SendNodes`
+    /// * pings to all nodes in the `Kbucket` every [`PING_INTERVAL`]
+    ///   (./constant.PING_INTERVAL.html)
+    /// * `GetNodes` for own PK to a random node in the `Kbucket` every
+    ///   [`GET_NODES_INTERVAL`](./constant.GET_NODES_INTERVAL.html)
+    /// * `LanDiscovery` to broadcast addresses every
+    ///   [`LAN_DISCOVERY_INTERVAL`]
+    ///   (../lan_discovery/constant.LAN_DISCOVERY_INTERVAL.html), if LAN
+    ///   discovery is enabled
+    ///
+    /// Returns number of packets sent.
+    pub fn send_requests<T: Transport>(&mut self, transport: &T, now: u64)
+        -> usize
+    {
+        self.pending_pings.retain(|p| p.sent + REQUEST_TIMEOUT > now);
+        self.pending_get_nodes.retain(|p| p.sent + REQUEST_TIMEOUT > now);
+
+        let mut get_nodes = self.bootstrap.next_requests(&self.pk, &self.kbucket,
+                                                         now);
+        let mut to_ping: Vec<PackedNode> = self.to_ping.drain(..).collect();
+
+        let ping_due = match self.last_ping {
+            Some(t) => t + PING_INTERVAL <= now,
+            None => true,
+        };
+        if ping_due {
+            self.last_ping = Some(now);
+            to_ping.extend(self.kbucket.good_packed_nodes(now));
+        }
+
+        let get_nodes_due = match self.last_get_nodes {
+            Some(t) => t + GET_NODES_INTERVAL <= now,
+            None => true,
+        };
+        if get_nodes_due {
+            let nodes = self.kbucket.good_packed_nodes(now);
+            if !nodes.is_empty() {
+                self.last_get_nodes = Some(now);
+                let node = nodes[random_u32() as usize % nodes.len()];
+                get_nodes.push((node, GetNodes::new(&self.pk)));
+            }
+        }
+
+        let mut sent = 0;
+        for node in to_ping {
+            let ping = Ping::new();
+            self.pending_pings.push(PendingRequest {
+                node: node,
+                id: ping.id,
+                sent: now,
+                sent_at: Instant::now(),
+            });
+            let precomp = encrypt_precompute(&node.pk, &self.sk);
+            let packet = DhtPacket::new(&precomp, &self.pk, &gen_nonce(),
+                                        ping.as_packet());
+            if self.send(transport, &packet, node.saddr) {
+                sent += 1;
+            }
+        }
+        for (node, request) in get_nodes {
+            self.pending_get_nodes.push(PendingRequest {
+                node: node,
+                id: request.id,
+                sent: now,
+                sent_at: Instant::now(),
+            });
+            let precomp = encrypt_precompute(&node.pk, &self.sk);
+            let packet = DhtPacket::new(&precomp, &self.pk, &gen_nonce(),
+                                        request.as_packet());
+            if self.send(transport, &packet, node.saddr) {
+                sent += 1;
+            }
+        }
+        if let Some(ref mut sender) = self.lan_discovery {
+            sent += sender.send(transport, &self.pk, now);
+        }
+        sent
+    }
+
+    fn send<T: Transport>(&self, transport: &T, packet: &DhtPacket,
+                          addr: SocketAddr) -> bool {
+        self.send_bytes(transport, &packet.to_bytes(), addr)
+    }
+
+    fn send_bytes<T: Transport>(&self, transport: &T, bytes: &[u8],
+                                addr: SocketAddr) -> bool {
+        match transport.send_to(bytes, addr) {
+            Ok(_) => true,
+            Err(e) => {
+                debug!(target: "DhtNode", "Failed to send packet to {}: {}",
+                       addr, e);
+                false
+            },
+        }
+    }
+}
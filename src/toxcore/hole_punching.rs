@@ -0,0 +1,196 @@
+/*
+    Copyright © 2016 Zetok Zalbavar <zexavexxe@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! UDP hole punching for friends behind NAT.
+//!
+//! Nodes close to a friend report the address from which they see the
+//! friend. If all of them report the same port, friend is behind a cone NAT
+//! (or no NAT at all) and only that port needs to be pinged. Otherwise
+//! friend's NAT is symmetric, and its external port has to be guessed –
+//! ports around the reported ones are swept sequentially, and after
+//! [`MAX_NORMAL_PUNCHING_TRIES`](./constant.MAX_NORMAL_PUNCHING_TRIES.html)
+//! random ports are tried as well.
+//!
+//! Pings to the guessed addresses should be sent by both friends at the same
+//! time, which is ensured by punching only after friend has answered NAT ping.
+
+use std::net::SocketAddr;
+
+use toxcore::crypto_core::random_u32;
+
+
+/// Interval in seconds between punching attempts.
+pub const PUNCH_INTERVAL: u64 = 3;
+
+/// Number of ports pinged in a single punching attempt, in a single sweep.
+pub const MAX_PUNCHING_PORTS: u32 = 48;
+
+/// Number of attempts with only sequential sweep around reported ports,
+/// after which random ports are pinged as well.
+pub const MAX_NORMAL_PUNCHING_TRIES: u32 = 5;
+
+/// Lowest port that random sweep will try.
+pub const RANDOM_PORT_MIN: u16 = 1024;
+
+
+/// State of hole punching to a single friend.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct HolePunch {
+    /// Position in the sequential sweep.
+    index: u32,
+    /// Number of punching attempts made.
+    tries: u32,
+    /// When the last attempt was made.
+    last_punch: Option<u64>,
+    /// Whether hole has been punched.
+    done: bool,
+}
+
+impl HolePunch {
+    /// Create new `HolePunch` with no attempts made.
+    pub fn new() -> Self {
+        HolePunch::default()
+    }
+
+    /// Number of punching attempts made.
+    pub fn tries(&self) -> u32 {
+        self.tries
+    }
+
+    /// Whether hole has been punched.
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    /// Mark hole as punched; no more addresses will be returned until
+    /// [`reset()`](#method.reset).
+    pub fn done(&mut self) {
+        debug!(target: "HolePunch", "Hole punched.");
+        self.done = true;
+    }
+
+    /// Reset to the state without any attempts made, e.g. after connection
+    /// to the friend was lost.
+    pub fn reset(&mut self) {
+        *self = HolePunch::new();
+    }
+
+    /// Get addresses that should be pinged in this punching attempt, based on
+    /// the addresses of friend that were `reported` by nodes close to it.
+    ///
+    /// Returns an empty `Vec` if hole was already punched, nothing was
+    /// reported, or [`PUNCH_INTERVAL`](./constant.PUNCH_INTERVAL.html) hasn't
+    /// passed since the last attempt.
+    pub fn next_addrs(&mut self, reported: &[SocketAddr], now: u64)
+        -> Vec<SocketAddr>
+    {
+        trace!(target: "HolePunch", "Getting addresses to punch with reported:
+               {:?}", reported);
+        if self.done {
+            return vec![]
+        }
+        match self.last_punch {
+            Some(t) if t + PUNCH_INTERVAL > now => return vec![],
+            _ => {},
+        }
+
+        let (base, ports) = match most_common_ip(reported) {
+            Some(ip) => {
+                let mut ports: Vec<u16> = reported.iter()
+                    .filter(|a| same_ip(a, &ip))
+                    .map(|a| a.port())
+                    .collect();
+                ports.sort();
+                ports.dedup();
+                (ip, ports)
+            },
+            None => return vec![],
+        };
+        self.last_punch = Some(now);
+
+        let mut result = Vec::new();
+        if ports.len() == 1 {
+            // cone NAT – port is known
+            result.push(with_port(&base, ports[0]));
+        } else {
+            // symmetric NAT – try reported ports once, then ports around
+            // them, alternating above and below them
+            let num = ports.len() as u32;
+            let top = self.index + MAX_PUNCHING_PORTS;
+            for i in self.index..top {
+                let port = if i < num {
+                    ports[i as usize]
+                } else {
+                    let j = i - num;
+                    let port = ports[((j / 2) % num) as usize];
+                    let delta = (j / (2 * num) + 1) as u16;
+                    if j % 2 == 0 { port.wrapping_add(delta) }
+                    else { port.wrapping_sub(delta) }
+                };
+                if port != 0 {
+                    result.push(with_port(&base, port));
+                }
+            }
+            self.index = top;
+        }
+
+        if self.tries > MAX_NORMAL_PUNCHING_TRIES {
+            let range = (::std::u16::MAX - RANDOM_PORT_MIN) as u32 + 1;
+            for _ in 0..MAX_PUNCHING_PORTS {
+                let port = RANDOM_PORT_MIN + (random_u32() % range) as u16;
+                result.push(with_port(&base, port));
+            }
+        }
+
+        self.tries += 1;
+        debug!(target: "HolePunch", "Punching {} addresses; try {}.",
+               result.len(), self.tries);
+        result
+    }
+}
+
+
+/// Copy of `addr` with port set to `port`.
+fn with_port(addr: &SocketAddr, port: u16) -> SocketAddr {
+    let mut addr = *addr;
+    addr.set_port(port);
+    addr
+}
+
+/// Whether addresses have the same IP, regardless of the port.
+fn same_ip(a: &SocketAddr, b: &SocketAddr) -> bool {
+    with_port(a, 0) == with_port(b, 0)
+}
+
+/// Get IP that appears in the `addrs` most often, as an address with port
+/// `0`. If multiple IPs appear the same number of times, the first one is
+/// returned.
+///
+/// Returns `None` if `addrs` is empty.
+pub fn most_common_ip(addrs: &[SocketAddr]) -> Option<SocketAddr> {
+    let mut best: Option<(SocketAddr, usize)> = None;
+    for addr in addrs {
+        let count = addrs.iter().filter(|a| same_ip(a, addr)).count();
+        match best {
+            Some((_, c)) if c >= count => {},
+            _ => best = Some((with_port(addr, 0), count)),
+        }
+    }
+    best.map(|(a, _)| a)
+}
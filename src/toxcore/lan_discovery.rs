@@ -0,0 +1,345 @@
+/*
+    Copyright © 2016 Zetok Zalbavar <zexavexxe@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! LAN discovery – finding DHT nodes in the local network without any
+//! bootstrap nodes.
+//!
+//! [`LanDiscovery`](./struct.LanDiscovery.html) packets are periodically
+//! broadcasted by [`LanDiscoverySender`](./struct.LanDiscoverySender.html).
+//! Node that receives such packet from a LAN address should send
+//! `GetNodes` request to the sender.
+
+use std::fs::File;
+use std::io::Read;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use toxcore::binary_io::*;
+use toxcore::crypto_core::*;
+use toxcore::dht::*;
+use toxcore::network::{PORT_MIN, Transport};
+
+
+/// Interval in seconds between sending `LanDiscovery` packets.
+pub const LAN_DISCOVERY_INTERVAL: u64 = 10;
+
+
+/// LAN discovery packet, containing DHT PK of the sender.
+///
+/// Packet type [`PacketKind::LanDisc`](./enum.PacketKind.html).
+///
+/// Serialized form:
+///
+/// Length | Contents
+/// ------ | --------
+/// `1`    | `33`
+/// `32`   | DHT Public Key
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct LanDiscovery {
+    /// DHT Public Key of the sender.
+    pub pk: PublicKey,
+}
+
+/// Size of serialized [`LanDiscovery`](./struct.LanDiscovery.html) in bytes.
+pub const LAN_DISCOVERY_SIZE: usize = 1 + PUBLICKEYBYTES;
+
+impl LanDiscovery {
+    /// Create new `LanDiscovery` with own DHT PK.
+    pub fn new(pk: &PublicKey) -> Self {
+        LanDiscovery { pk: *pk }
+    }
+
+    /// Get `GetNodes` request that should be sent to the node which has
+    /// sent `LanDiscovery` from `addr`, together with the node.
+    ///
+    /// Returns `None` if packet was sent by self, or if `addr` is not a LAN
+    /// address.
+    pub fn request(&self, own_pk: &PublicKey, addr: SocketAddr)
+        -> Option<(PackedNode, GetNodes)>
+    {
+        debug!(target: "LanDiscovery", "Creating request for LanDiscovery.");
+        trace!(target: "LanDiscovery", "With LanDiscovery: {:?}, from: {}",
+               self, addr);
+        if &self.pk == own_pk {
+            debug!(target: "LanDiscovery", "Own LanDiscovery; ignoring.");
+            return None
+        }
+        if !is_lan(&addr.ip()) {
+            debug!(target: "LanDiscovery", "Not from a LAN address: {}", addr);
+            return None
+        }
+        Some((PackedNode::new(true, addr, &self.pk), GetNodes::new(own_pk)))
+    }
+}
+
+/// Serialization of `LanDiscovery`. Resulting length should be
+/// [`LAN_DISCOVERY_SIZE`](./constant.LAN_DISCOVERY_SIZE.html).
+impl ToBytes for LanDiscovery {
+    fn to_bytes(&self) -> Vec<u8> {
+        debug!(target: "LanDiscovery", "Serializing LanDiscovery into bytes.");
+        trace!(target: "LanDiscovery", "With LanDiscovery: {:?}", self);
+        let mut result = Vec::with_capacity(LAN_DISCOVERY_SIZE);
+        result.push(PacketKind::LanDisc as u8);
+        let PublicKey(pk) = self.pk;
+        result.extend_from_slice(&pk);
+        trace!("Resulting bytes: {:?}", &result);
+        result
+    }
+}
+
+/// De-serialization of bytes into `LanDiscovery`. Fails if less than
+/// [`LAN_DISCOVERY_SIZE`](./constant.LAN_DISCOVERY_SIZE.html) bytes are
+/// provided, or packet kind is not `LanDisc`.
+impl FromBytes<LanDiscovery> for LanDiscovery {
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        debug!(target: "LanDiscovery", "De-serializing bytes into LanDiscovery.");
+        trace!(target: "LanDiscovery", "With bytes: {:?}", bytes);
+
+        if bytes.len() < LAN_DISCOVERY_SIZE {
+            debug!("Failed; less bytes than LAN_DISCOVERY_SIZE!");
+            return None
+        }
+
+        if PacketKind::from_bytes(bytes) != Some(PacketKind::LanDisc) {
+            debug!("Failed: not a LanDiscovery packet!");
+            return None
+        }
+
+        match PublicKey::from_slice(&bytes[1..LAN_DISCOVERY_SIZE]) {
+            Some(pk) => Some(LanDiscovery { pk: pk }),
+            None => {
+                debug!("Failed; de-serializing PK!");
+                None
+            },
+        }
+    }
+}
+
+
+/// Periodic sending of [`LanDiscovery`](./struct.LanDiscovery.html) packets.
+///
+/// Packets are sent to the IPv4 broadcast address `255.255.255.255`, to the
+/// IPv6 all-nodes multicast group `ff02::1`, and to the broadcast addresses
+/// of added interfaces.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LanDiscoverySender {
+    /// Addresses to which packets are sent.
+    addrs: Vec<IpAddr>,
+    /// Port to which packets are sent.
+    port: u16,
+    /// When packets were sent last time.
+    last_sent: Option<u64>,
+}
+
+impl LanDiscoverySender {
+    /// Create new `LanDiscoverySender`, sending packets to the `port`, or
+    /// [`PORT_MIN`](../network/constant.PORT_MIN.html) if `port` is `0`.
+    pub fn new(port: u16) -> Self {
+        let port = if port == 0 { PORT_MIN } else { port };
+        LanDiscoverySender {
+            addrs: vec![IpAddr::V4(Ipv4Addr::new(255, 255, 255, 255)),
+                        IpAddr::V6(Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1))],
+            port: port,
+            last_sent: None,
+        }
+    }
+
+    /// Add interface with IPv4 `ip` and `netmask`, so that packets will be
+    /// sent to its broadcast address as well.
+    pub fn add_interface(&mut self, ip: Ipv4Addr, netmask: Ipv4Addr) {
+        self.add_broadcast(ipv4_broadcast(ip, netmask));
+    }
+
+    /// Add broadcast addresses of all local interfaces, as read from the
+    /// system routing table.
+    ///
+    /// Only Linux is supported, on other systems nothing is added.
+    ///
+    /// Returns number of added addresses.
+    pub fn add_local_interfaces(&mut self) -> usize {
+        let broadcasts = interface_broadcasts();
+        let before = self.addrs.len();
+        for addr in broadcasts {
+            self.add_broadcast(addr);
+        }
+        self.addrs.len() - before
+    }
+
+    fn add_broadcast(&mut self, addr: Ipv4Addr) {
+        let addr = IpAddr::V4(addr);
+        if !self.addrs.contains(&addr) {
+            debug!(target: "LanDiscovery", "Adding broadcast address: {}", addr);
+            self.addrs.push(addr);
+        }
+    }
+
+    /// Addresses to which `LanDiscovery` packets are sent.
+    pub fn broadcast_addrs(&self) -> Vec<SocketAddr> {
+        self.addrs.iter().map(|&ip| SocketAddr::new(ip, self.port)).collect()
+    }
+
+    /// Send `LanDiscovery` with own PK to all broadcast addresses, if
+    /// [`LAN_DISCOVERY_INTERVAL`](./constant.LAN_DISCOVERY_INTERVAL.html)
+    /// has passed since packets were sent last time.
+    ///
+    /// Socket of `transport` must have broadcast enabled, see
+    /// [`Transport`](../network/trait.Transport.html).
+    ///
+    /// Returns number of addresses that packet was sent to successfully.
+    pub fn send<T: Transport>(&mut self, transport: &T, own_pk: &PublicKey,
+                              now: u64) -> usize {
+        match self.last_sent {
+            Some(t) if t + LAN_DISCOVERY_INTERVAL > now => return 0,
+            _ => {},
+        }
+        self.last_sent = Some(now);
+
+        let packet = LanDiscovery::new(own_pk).to_bytes();
+        let mut sent = 0;
+        for addr in self.broadcast_addrs() {
+            // sending can fail e.g. when socket is bound only to IPv4
+            match transport.send_to(&packet, addr) {
+                Ok(_) => sent += 1,
+                Err(e) => debug!(target: "LanDiscovery",
+                                 "Failed to send LanDiscovery to {}: {}",
+                                 addr, e),
+            }
+        }
+        if sent == 0 {
+            warn!(target: "LanDiscovery", "Failed to send LanDiscovery to any \
+                   address; is broadcast enabled on the socket?");
+        }
+        sent
+    }
+}
+
+
+/// Handle `LanDiscovery` received from `addr`, sending `GetNodes` request
+/// to the sender with `transport`.
+///
+/// Returns the request and the node it was sent to, or `None` if no request
+/// was sent.
+pub fn handle_lan_discovery<T: Transport>(transport: &T,
+                                          own_pk: &PublicKey,
+                                          own_sk: &SecretKey,
+                                          packet: &LanDiscovery,
+                                          addr: SocketAddr)
+    -> Option<(PackedNode, GetNodes)>
+{
+    let (node, request) = match packet.request(own_pk, addr) {
+        Some(r) => r,
+        None => return None,
+    };
+    let precomp = encrypt_precompute(&node.pk, own_sk);
+    let dht_packet = DhtPacket::new(&precomp, own_pk, &gen_nonce(),
+                                    request.as_packet());
+    match transport.send_to(&dht_packet.to_bytes(), node.saddr) {
+        Ok(_) => Some((node, request)),
+        Err(e) => {
+            debug!(target: "LanDiscovery", "Failed to send GetNodes to {}: {}",
+                   node.saddr, e);
+            None
+        },
+    }
+}
+
+
+/// Whether `ip` belongs to a local network.
+///
+/// IPv4: loopback, private (`10.0.0.0/8`, `172.16.0.0/12`, `192.168.0.0/16`),
+/// link-local (`169.254.0.0/16`) and CGNAT (`100.64.0.0/10`) addresses.
+///
+/// IPv6: loopback, link-local (`fe80::/10`), unique local (`fc00::/7`) and
+/// IPv4-mapped LAN addresses.
+pub fn is_lan(ip: &IpAddr) -> bool {
+    match *ip {
+        IpAddr::V4(ip) => {
+            let o = ip.octets();
+            o[0] == 127 || o[0] == 10
+                || (o[0] == 172 && o[1] >= 16 && o[1] <= 31)
+                || (o[0] == 192 && o[1] == 168)
+                || (o[0] == 169 && o[1] == 254)
+                || (o[0] == 100 && (o[1] & 0xc0) == 64)
+        },
+        IpAddr::V6(ip) => {
+            let s = ip.segments();
+            if s[..5] == [0, 0, 0, 0, 0] && s[5] == 0xffff {
+                let mapped = Ipv4Addr::new((s[6] >> 8) as u8, s[6] as u8,
+                                           (s[7] >> 8) as u8, s[7] as u8);
+                return is_lan(&IpAddr::V4(mapped))
+            }
+            ip == Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)
+                || (s[0] & 0xffc0) == 0xfe80
+                || (s[0] & 0xfe00) == 0xfc00
+        },
+    }
+}
+
+/// Broadcast address of the IPv4 network with `ip` and `netmask`.
+pub fn ipv4_broadcast(ip: Ipv4Addr, netmask: Ipv4Addr) -> Ipv4Addr {
+    let ip = ip.octets();
+    let mask = netmask.octets();
+    Ipv4Addr::new(ip[0] | !mask[0], ip[1] | !mask[1],
+                  ip[2] | !mask[2], ip[3] | !mask[3])
+}
+
+/// Get broadcast addresses of local interfaces from the routing table
+/// `/proc/net/route`.
+///
+/// Returns an empty `Vec` if routing table can't be read, e.g. on systems
+/// other than Linux.
+pub fn interface_broadcasts() -> Vec<Ipv4Addr> {
+    let mut table = String::new();
+    match File::open("/proc/net/route")
+        .and_then(|mut f| f.read_to_string(&mut table)) {
+        Ok(_) => parse_route_table(&table),
+        Err(e) => {
+            debug!(target: "LanDiscovery", "Failed to read routing table: {}", e);
+            vec![]
+        },
+    }
+}
+
+/// Parse broadcast addresses of local networks from the contents of
+/// `/proc/net/route`.
+///
+/// Destination and mask in the table are hex-encoded in host byte order.
+/// Default routes (with mask `0`) are skipped.
+pub fn parse_route_table(table: &str) -> Vec<Ipv4Addr> {
+    let mut result = Vec::new();
+    // first line is a header
+    for line in table.lines().skip(1) {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 8 {
+            continue
+        }
+        let dst = u32::from_str_radix(fields[1], 16);
+        let mask = u32::from_str_radix(fields[7], 16);
+        let (dst, mask) = match (dst, mask) {
+            (Ok(d), Ok(m)) if m != 0 => (d, m),
+            _ => continue,
+        };
+        let broadcast = Ipv4Addr::from(u32::from_be(dst) | !u32::from_be(mask));
+        trace!(target: "LanDiscovery", "Interface {} broadcast: {}",
+               fields[0], broadcast);
+        if !result.contains(&broadcast) {
+            result.push(broadcast);
+        }
+    }
+    result
+}
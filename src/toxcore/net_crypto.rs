@@ -0,0 +1,919 @@
+/*
+    Copyright © 2016 Zetok Zalbavar <zexavexxe@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Packets and state used to establish encrypted connections between
+//! friends, and to transfer data over them.
+//!
+//! https://toktok.github.io/spec.html#net-crypto
+//!
+//! Connection starts with a [`CookieRequest`](./struct.CookieRequest.html),
+//! encrypted with the DHT keys. Response carries a cookie, that has to be
+//! sent back in the handshake. Cookie is encrypted with a key known only
+//! to its issuer, which proves that the other side can receive packets on
+//! its address, without issuer keeping any state.
+//!
+//! [`CryptoHandshake`](./struct.CryptoHandshake.html), encrypted with the
+//! long-term keys, carries a session PK and a base nonce, that are used to
+//! encrypt data sent over the connection.
+//!
+//! Data is sent in [`CryptoData`](./struct.CryptoData.html) packets. Nonce
+//! is incremented as a big-endian number after each sent packet, and only
+//! its last two bytes are sent along with the packet.
+
+use sodiumoxide::crypto::hash::sha512;
+
+use toxcore::binary_io::*;
+use toxcore::crypto_core::*;
+use toxcore::dht::PacketKind;
+
+
+/// Number of seconds for which issued cookie is valid.
+pub const COOKIE_TIMEOUT: u64 = 15;
+
+/// Size in bytes of serialized [`Cookie`](./struct.Cookie.html).
+pub const COOKIE_SIZE: usize = 8 // time
+                             + PUBLICKEYBYTES * 2;
+
+/// Size in bytes of serialized [`EncryptedCookie`]
+/// (./struct.EncryptedCookie.html).
+pub const ENCRYPTED_COOKIE_SIZE: usize = NONCEBYTES + COOKIE_SIZE + MACBYTES;
+
+/// Size in bytes of serialized [`CookieRequestPayload`]
+/// (./struct.CookieRequestPayload.html).
+pub const COOKIE_REQUEST_PAYLOAD_SIZE: usize = PUBLICKEYBYTES
+                                             + 32 // padding
+                                             + 8; // echo ID
+
+/// Size in bytes of serialized [`CookieRequest`](./struct.CookieRequest.html).
+pub const COOKIE_REQUEST_SIZE: usize = 1 // packet kind
+                                     + PUBLICKEYBYTES
+                                     + NONCEBYTES
+                                     + COOKIE_REQUEST_PAYLOAD_SIZE
+                                     + MACBYTES;
+
+/// Size in bytes of serialized [`CookieResponsePayload`]
+/// (./struct.CookieResponsePayload.html).
+pub const COOKIE_RESPONSE_PAYLOAD_SIZE: usize = ENCRYPTED_COOKIE_SIZE + 8;
+
+/// Size in bytes of serialized [`CookieResponse`]
+/// (./struct.CookieResponse.html).
+pub const COOKIE_RESPONSE_SIZE: usize = 1 // packet kind
+                                      + NONCEBYTES
+                                      + COOKIE_RESPONSE_PAYLOAD_SIZE
+                                      + MACBYTES;
+
+/// Size in bytes of serialized [`HandshakePayload`]
+/// (./struct.HandshakePayload.html).
+pub const HANDSHAKE_PAYLOAD_SIZE: usize = NONCEBYTES
+                                        + PUBLICKEYBYTES
+                                        + sha512::DIGESTBYTES
+                                        + ENCRYPTED_COOKIE_SIZE;
+
+/// Size in bytes of serialized [`CryptoHandshake`]
+/// (./struct.CryptoHandshake.html).
+pub const CRYPTO_HANDSHAKE_SIZE: usize = 1 // packet kind
+                                       + ENCRYPTED_COOKIE_SIZE
+                                       + NONCEBYTES
+                                       + HANDSHAKE_PAYLOAD_SIZE
+                                       + MACBYTES;
+
+/// Maximum size in bytes of a `CryptoData` packet.
+pub const MAX_CRYPTO_PACKET_SIZE: usize = 1400;
+
+/// Minimum size in bytes of serialized [`CryptoData`]
+/// (./struct.CryptoData.html), without any data.
+pub const CRYPTO_DATA_MIN_SIZE: usize = 1 // packet kind
+                                      + 2 // last bytes of nonce
+                                      + 4 // buffer start
+                                      + 4 // packet number
+                                      + MACBYTES;
+
+/// Maximum size in bytes of data, including its ID, that can be sent in a
+/// single `CryptoData` packet.
+pub const MAX_CRYPTO_DATA_SIZE: usize = MAX_CRYPTO_PACKET_SIZE - CRYPTO_DATA_MIN_SIZE;
+
+/// Data in `CryptoData` is padded with zeros, so that size of the packet
+/// is a multiple of this number, counting from the maximum size.
+pub const CRYPTO_MAX_PADDING: usize = 8;
+
+/// Nonce of the peer is moved forward by this number after a packet with
+/// a difference larger than twice this number is received, so that packets
+/// reordered by less than this number can still be decrypted.
+pub const DATA_NUM_THRESHOLD: u16 = 21845;
+
+/// ID of padding.
+pub const PACKET_ID_PADDING: u8 = 0;
+/// ID of the packet request.
+pub const PACKET_ID_REQUEST: u8 = 1;
+/// ID of the message that closes connection.
+pub const PACKET_ID_KILL: u8 = 2;
+/// First ID of lossless messages.
+pub const PACKET_ID_LOSSLESS_START: u8 = 16;
+/// Last ID of lossless messages.
+pub const PACKET_ID_LOSSLESS_END: u8 = 191;
+/// First ID of lossy messages.
+pub const PACKET_ID_LOSSY_START: u8 = 192;
+/// Last ID of lossy messages.
+pub const PACKET_ID_LOSSY_END: u8 = 254;
+
+
+/// Check that `bytes` have exactly `size` bytes, and that the first of
+/// them is `kind`.
+fn check_packet(bytes: &[u8], kind: PacketKind, size: usize) -> bool {
+    if bytes.len() != size || PacketKind::from_bytes(bytes) != Some(kind) {
+        debug!(target: "NetCrypto", "Not a {:?} packet!", kind);
+        return false
+    }
+    true
+}
+
+
+/// Content of a cookie, that only its issuer can decrypt.
+///
+/// Serialized form:
+///
+/// Length | Contents
+/// ------ | --------
+/// `8`    | Time in seconds when cookie was issued
+/// `32`   | Long-term PK of the requester
+/// `32`   | DHT PK of the requester
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Cookie {
+    /// Time in seconds when cookie was issued.
+    pub time: u64,
+    /// Long-term PK of the requester.
+    pub real_pk: PublicKey,
+    /// DHT PK of the requester.
+    pub dht_pk: PublicKey,
+}
+
+impl Cookie {
+    /// Whether cookie is valid at `now`, i.e. it was issued no longer than
+    /// [`COOKIE_TIMEOUT`](./constant.COOKIE_TIMEOUT.html) seconds ago,
+    /// inclusive, like in toxcore.
+    pub fn is_valid(&self, now: u64) -> bool {
+        self.time <= now && now <= self.time + COOKIE_TIMEOUT
+    }
+
+    /// Encrypt cookie with issuer's symmetric `key`.
+    pub fn encrypt(&self, key: &PrecomputedKey) -> EncryptedCookie {
+        let nonce = gen_nonce();
+        EncryptedCookie {
+            nonce: nonce,
+            payload: encrypt_data_symmetric(key, &nonce, &self.to_bytes()),
+        }
+    }
+}
+
+impl ToBytes for Cookie {
+    fn to_bytes(&self) -> Vec<u8> {
+        let PublicKey(ref real_pk) = self.real_pk;
+        let PublicKey(ref dht_pk) = self.dht_pk;
+        let mut result = Vec::with_capacity(COOKIE_SIZE);
+        result.extend_from_slice(&u64_to_array(self.time));
+        result.extend_from_slice(real_pk);
+        result.extend_from_slice(dht_pk);
+        result
+    }
+}
+
+impl FromBytes<Cookie> for Cookie {
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != COOKIE_SIZE {
+            debug!(target: "NetCrypto", "Wrong size of Cookie!");
+            return None
+        }
+        const DHT_POS: usize = 8 + PUBLICKEYBYTES;
+        let mut time = [0; 8];
+        time.copy_from_slice(&bytes[..8]);
+        let real_pk = PublicKey::from_slice(&bytes[8..DHT_POS]);
+        let dht_pk = PublicKey::from_slice(&bytes[DHT_POS..]);
+        match (real_pk, dht_pk) {
+            (Some(real_pk), Some(dht_pk)) => Some(Cookie {
+                time: array_to_u64(&time),
+                real_pk: real_pk,
+                dht_pk: dht_pk,
+            }),
+            _ => None,
+        }
+    }
+}
+
+
+/// [`Cookie`](./struct.Cookie.html) encrypted with issuer's symmetric key.
+///
+/// Serialized form:
+///
+/// Length | Contents
+/// ------ | --------
+/// `24`   | Nonce
+/// `88`   | Encrypted `Cookie`
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct EncryptedCookie {
+    /// Nonce used to encrypt the cookie.
+    pub nonce: Nonce,
+    /// Encrypted cookie.
+    pub payload: Vec<u8>,
+}
+
+impl EncryptedCookie {
+    /// Decrypt cookie with issuer's symmetric `key`.
+    ///
+    /// Returns `None` if decryption fails.
+    pub fn get_payload(&self, key: &PrecomputedKey) -> Option<Cookie> {
+        match decrypt_data_symmetric(key, &self.nonce, &self.payload) {
+            Ok(bytes) => Cookie::from_bytes(&bytes),
+            Err(_) => {
+                debug!(target: "NetCrypto", "Decrypting Cookie failed!");
+                None
+            },
+        }
+    }
+}
+
+impl ToBytes for EncryptedCookie {
+    fn to_bytes(&self) -> Vec<u8> {
+        let Nonce(ref nonce) = self.nonce;
+        let mut result = Vec::with_capacity(ENCRYPTED_COOKIE_SIZE);
+        result.extend_from_slice(nonce);
+        result.extend_from_slice(&self.payload);
+        result
+    }
+}
+
+impl FromBytes<EncryptedCookie> for EncryptedCookie {
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != ENCRYPTED_COOKIE_SIZE {
+            debug!(target: "NetCrypto", "Wrong size of EncryptedCookie!");
+            return None
+        }
+        Nonce::from_slice(&bytes[..NONCEBYTES]).map(|nonce| EncryptedCookie {
+            nonce: nonce,
+            payload: bytes[NONCEBYTES..].to_vec(),
+        })
+    }
+}
+
+
+/// Decrypted payload of [`CookieRequest`](./struct.CookieRequest.html).
+///
+/// Serialized form:
+///
+/// Length | Contents
+/// ------ | --------
+/// `32`   | Long-term PK of the sender
+/// `32`   | Padding, zeros
+/// `8`    | Echo ID, returned unchanged in the response
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct CookieRequestPayload {
+    /// Long-term PK of the sender.
+    pub real_pk: PublicKey,
+    /// Data returned unchanged in the response, to match it with the
+    /// request.
+    pub echo_id: u64,
+}
+
+impl ToBytes for CookieRequestPayload {
+    fn to_bytes(&self) -> Vec<u8> {
+        let PublicKey(ref real_pk) = self.real_pk;
+        let mut result = Vec::with_capacity(COOKIE_REQUEST_PAYLOAD_SIZE);
+        result.extend_from_slice(real_pk);
+        result.extend_from_slice(&[0; 32]);
+        result.extend_from_slice(&u64_to_array(self.echo_id));
+        result
+    }
+}
+
+impl FromBytes<CookieRequestPayload> for CookieRequestPayload {
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != COOKIE_REQUEST_PAYLOAD_SIZE {
+            debug!(target: "NetCrypto", "Wrong size of CookieRequestPayload!");
+            return None
+        }
+        const ECHO_POS: usize = PUBLICKEYBYTES + 32;
+        let mut echo_id = [0; 8];
+        echo_id.copy_from_slice(&bytes[ECHO_POS..]);
+        PublicKey::from_slice(&bytes[..PUBLICKEYBYTES])
+            .map(|real_pk| CookieRequestPayload {
+                real_pk: real_pk,
+                echo_id: array_to_u64(&echo_id),
+            })
+    }
+}
+
+
+/// Request for a cookie, sent to the DHT PK of a friend.
+///
+/// Packet kind [`PacketKind::CookieReq`](../dht/enum.PacketKind.html).
+///
+/// Serialized form:
+///
+/// Length | Contents
+/// ------ | --------
+/// `1`    | `0x18`
+/// `32`   | DHT PK of the sender
+/// `24`   | Nonce
+/// `88`   | [`CookieRequestPayload`](./struct.CookieRequestPayload.html), encrypted with the DHT keys
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CookieRequest {
+    /// DHT PK of the sender.
+    pub dht_pk: PublicKey,
+    /// Nonce used to encrypt the payload.
+    pub nonce: Nonce,
+    /// Encrypted payload.
+    pub payload: Vec<u8>,
+}
+
+impl CookieRequest {
+    /// Create new `CookieRequest` for the node with DHT `receiver_pk`,
+    /// encrypted with own DHT keypair.
+    pub fn new(own_dht_pk: &PublicKey, own_dht_sk: &SecretKey,
+               receiver_pk: &PublicKey, payload: &CookieRequestPayload) -> Self {
+        let nonce = gen_nonce();
+        let precomp = encrypt_precompute(receiver_pk, own_dht_sk);
+        CookieRequest {
+            dht_pk: *own_dht_pk,
+            nonce: nonce,
+            payload: encrypt_data_symmetric(&precomp, &nonce, &payload.to_bytes()),
+        }
+    }
+
+    /// Decrypt payload with own DHT SK.
+    ///
+    /// Returns `None` if decryption or parsing fails.
+    pub fn get_payload(&self, own_dht_sk: &SecretKey) -> Option<CookieRequestPayload> {
+        match open(&self.payload, &self.nonce, &self.dht_pk, own_dht_sk) {
+            Ok(p) => CookieRequestPayload::from_bytes(&p),
+            Err(_) => {
+                debug!(target: "NetCrypto", "Decrypting CookieRequest failed!");
+                None
+            },
+        }
+    }
+}
+
+impl ToBytes for CookieRequest {
+    fn to_bytes(&self) -> Vec<u8> {
+        let PublicKey(ref dht_pk) = self.dht_pk;
+        let Nonce(ref nonce) = self.nonce;
+        let mut result = Vec::with_capacity(COOKIE_REQUEST_SIZE);
+        result.push(PacketKind::CookieReq as u8);
+        result.extend_from_slice(dht_pk);
+        result.extend_from_slice(nonce);
+        result.extend_from_slice(&self.payload);
+        result
+    }
+}
+
+/// De-serialization of `CookieRequest`. Fails if packet kind is wrong, or
+/// size isn't [`COOKIE_REQUEST_SIZE`](./constant.COOKIE_REQUEST_SIZE.html).
+impl FromBytes<CookieRequest> for CookieRequest {
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        debug!(target: "NetCrypto", "De-serializing CookieRequest.");
+        if !check_packet(bytes, PacketKind::CookieReq, COOKIE_REQUEST_SIZE) {
+            return None
+        }
+        const NONCE_POS: usize = 1 + PUBLICKEYBYTES;
+        const PAYLOAD_POS: usize = NONCE_POS + NONCEBYTES;
+        let dht_pk = PublicKey::from_slice(&bytes[1..NONCE_POS]);
+        let nonce = Nonce::from_slice(&bytes[NONCE_POS..PAYLOAD_POS]);
+        match (dht_pk, nonce) {
+            (Some(dht_pk), Some(nonce)) => Some(CookieRequest {
+                dht_pk: dht_pk,
+                nonce: nonce,
+                payload: bytes[PAYLOAD_POS..].to_vec(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+
+/// Decrypted payload of [`CookieResponse`](./struct.CookieResponse.html).
+///
+/// Serialized form:
+///
+/// Length | Contents
+/// ------ | --------
+/// `112`  | [`EncryptedCookie`](./struct.EncryptedCookie.html)
+/// `8`    | Echo ID from the request
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CookieResponsePayload {
+    /// Cookie to be sent back in the handshake.
+    pub cookie: EncryptedCookie,
+    /// Echo ID from the request.
+    pub echo_id: u64,
+}
+
+impl ToBytes for CookieResponsePayload {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut result = self.cookie.to_bytes();
+        result.extend_from_slice(&u64_to_array(self.echo_id));
+        result
+    }
+}
+
+impl FromBytes<CookieResponsePayload> for CookieResponsePayload {
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != COOKIE_RESPONSE_PAYLOAD_SIZE {
+            debug!(target: "NetCrypto", "Wrong size of CookieResponsePayload!");
+            return None
+        }
+        let mut echo_id = [0; 8];
+        echo_id.copy_from_slice(&bytes[ENCRYPTED_COOKIE_SIZE..]);
+        EncryptedCookie::from_bytes(&bytes[..ENCRYPTED_COOKIE_SIZE])
+            .map(|cookie| CookieResponsePayload {
+                cookie: cookie,
+                echo_id: array_to_u64(&echo_id),
+            })
+    }
+}
+
+
+/// Response to [`CookieRequest`](./struct.CookieRequest.html).
+///
+/// Packet kind [`PacketKind::CookieResp`](../dht/enum.PacketKind.html).
+///
+/// Serialized form:
+///
+/// Length | Contents
+/// ------ | --------
+/// `1`    | `0x19`
+/// `24`   | Nonce
+/// `136`  | [`CookieResponsePayload`](./struct.CookieResponsePayload.html), encrypted with the DHT keys
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CookieResponse {
+    /// Nonce used to encrypt the payload.
+    pub nonce: Nonce,
+    /// Encrypted payload.
+    pub payload: Vec<u8>,
+}
+
+impl CookieResponse {
+    /// Create new `CookieResponse` for the node with DHT `receiver_pk`,
+    /// encrypted with own DHT SK.
+    pub fn new(own_dht_sk: &SecretKey, receiver_pk: &PublicKey,
+               payload: &CookieResponsePayload) -> Self {
+        let nonce = gen_nonce();
+        let precomp = encrypt_precompute(receiver_pk, own_dht_sk);
+        CookieResponse {
+            nonce: nonce,
+            payload: encrypt_data_symmetric(&precomp, &nonce, &payload.to_bytes()),
+        }
+    }
+
+    /// Decrypt payload with own DHT SK and DHT PK of the node that request
+    /// was sent to.
+    ///
+    /// Returns `None` if decryption or parsing fails.
+    pub fn get_payload(&self, own_dht_sk: &SecretKey, sender_pk: &PublicKey)
+        -> Option<CookieResponsePayload>
+    {
+        match open(&self.payload, &self.nonce, sender_pk, own_dht_sk) {
+            Ok(p) => CookieResponsePayload::from_bytes(&p),
+            Err(_) => {
+                debug!(target: "NetCrypto", "Decrypting CookieResponse failed!");
+                None
+            },
+        }
+    }
+}
+
+impl ToBytes for CookieResponse {
+    fn to_bytes(&self) -> Vec<u8> {
+        let Nonce(ref nonce) = self.nonce;
+        let mut result = Vec::with_capacity(COOKIE_RESPONSE_SIZE);
+        result.push(PacketKind::CookieResp as u8);
+        result.extend_from_slice(nonce);
+        result.extend_from_slice(&self.payload);
+        result
+    }
+}
+
+/// De-serialization of `CookieResponse`. Fails if packet kind is wrong, or
+/// size isn't [`COOKIE_RESPONSE_SIZE`](./constant.COOKIE_RESPONSE_SIZE.html).
+impl FromBytes<CookieResponse> for CookieResponse {
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        debug!(target: "NetCrypto", "De-serializing CookieResponse.");
+        if !check_packet(bytes, PacketKind::CookieResp, COOKIE_RESPONSE_SIZE) {
+            return None
+        }
+        const PAYLOAD_POS: usize = 1 + NONCEBYTES;
+        Nonce::from_slice(&bytes[1..PAYLOAD_POS]).map(|nonce| CookieResponse {
+            nonce: nonce,
+            payload: bytes[PAYLOAD_POS..].to_vec(),
+        })
+    }
+}
+
+
+/// SHA-512 hash of the serialized `cookie`, that handshake carries in its
+/// payload.
+pub fn cookie_hash(cookie: &EncryptedCookie) -> sha512::Digest {
+    sha512::hash(&cookie.to_bytes())
+}
+
+
+/// Decrypted payload of [`CryptoHandshake`](./struct.CryptoHandshake.html).
+///
+/// Serialized form:
+///
+/// Length | Contents
+/// ------ | --------
+/// `24`   | Base nonce, that the sender will use to encrypt data
+/// `32`   | Session PK of the sender
+/// `64`   | SHA-512 hash of the cookie sent along with the payload
+/// `112`  | Cookie issued by the sender, for the receiver's handshake
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct HandshakePayload {
+    /// Base nonce, that the sender will use to encrypt data.
+    pub base_nonce: Nonce,
+    /// Session PK of the sender.
+    pub session_pk: PublicKey,
+    /// Hash of the cookie sent along with the payload.
+    pub cookie_hash: sha512::Digest,
+    /// Cookie issued by the sender, that receiver should use in its own
+    /// handshake.
+    pub other_cookie: EncryptedCookie,
+}
+
+impl ToBytes for HandshakePayload {
+    fn to_bytes(&self) -> Vec<u8> {
+        let Nonce(ref base_nonce) = self.base_nonce;
+        let PublicKey(ref session_pk) = self.session_pk;
+        let sha512::Digest(ref cookie_hash) = self.cookie_hash;
+        let mut result = Vec::with_capacity(HANDSHAKE_PAYLOAD_SIZE);
+        result.extend_from_slice(base_nonce);
+        result.extend_from_slice(session_pk);
+        result.extend_from_slice(cookie_hash);
+        result.extend_from_slice(&self.other_cookie.to_bytes());
+        result
+    }
+}
+
+impl FromBytes<HandshakePayload> for HandshakePayload {
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != HANDSHAKE_PAYLOAD_SIZE {
+            debug!(target: "NetCrypto", "Wrong size of HandshakePayload!");
+            return None
+        }
+        const PK_POS: usize = NONCEBYTES;
+        const HASH_POS: usize = PK_POS + PUBLICKEYBYTES;
+        const COOKIE_POS: usize = HASH_POS + sha512::DIGESTBYTES;
+        let base_nonce = Nonce::from_slice(&bytes[..PK_POS]);
+        let session_pk = PublicKey::from_slice(&bytes[PK_POS..HASH_POS]);
+        let cookie_hash = sha512::Digest::from_slice(&bytes[HASH_POS..COOKIE_POS]);
+        let other_cookie = EncryptedCookie::from_bytes(&bytes[COOKIE_POS..]);
+        match (base_nonce, session_pk, cookie_hash, other_cookie) {
+            (Some(base_nonce), Some(session_pk), Some(cookie_hash),
+             Some(other_cookie)) => Some(HandshakePayload {
+                base_nonce: base_nonce,
+                session_pk: session_pk,
+                cookie_hash: cookie_hash,
+                other_cookie: other_cookie,
+            }),
+            _ => None,
+        }
+    }
+}
+
+
+/// Handshake that establishes session keys of a connection.
+///
+/// Packet kind [`PacketKind::CryptoHs`](../dht/enum.PacketKind.html).
+///
+/// Serialized form:
+///
+/// Length | Contents
+/// ------ | --------
+/// `1`    | `0x1a`
+/// `112`  | Cookie issued by the receiver
+/// `24`   | Nonce
+/// `248`  | [`HandshakePayload`](./struct.HandshakePayload.html), encrypted with the long-term keys
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CryptoHandshake {
+    /// Cookie issued by the receiver.
+    pub cookie: EncryptedCookie,
+    /// Nonce used to encrypt the payload.
+    pub nonce: Nonce,
+    /// Encrypted payload.
+    pub payload: Vec<u8>,
+}
+
+impl CryptoHandshake {
+    /// Create new `CryptoHandshake` for the peer with long-term
+    /// `receiver_pk`, with `cookie` it issued, encrypted with own long-term
+    /// SK.
+    ///
+    /// `cookie_hash` of the `payload` should be the hash of the `cookie`,
+    /// as returned by [`cookie_hash()`](./fn.cookie_hash.html).
+    pub fn new(own_real_sk: &SecretKey, receiver_pk: &PublicKey,
+               cookie: &EncryptedCookie, payload: &HandshakePayload) -> Self {
+        let nonce = gen_nonce();
+        let precomp = encrypt_precompute(receiver_pk, own_real_sk);
+        CryptoHandshake {
+            cookie: cookie.clone(),
+            nonce: nonce,
+            payload: encrypt_data_symmetric(&precomp, &nonce, &payload.to_bytes()),
+        }
+    }
+
+    /// Decrypt payload with own long-term SK and long-term PK of the
+    /// sender, that should be taken from the cookie.
+    ///
+    /// Returns `None` if decryption or parsing fails, or if the payload
+    /// carries a wrong hash of the cookie.
+    pub fn get_payload(&self, own_real_sk: &SecretKey, sender_pk: &PublicKey)
+        -> Option<HandshakePayload>
+    {
+        let payload = match open(&self.payload, &self.nonce, sender_pk, own_real_sk) {
+            Ok(p) => HandshakePayload::from_bytes(&p),
+            Err(_) => {
+                debug!(target: "NetCrypto", "Decrypting CryptoHandshake failed!");
+                return None
+            },
+        };
+        match payload {
+            Some(ref p) if p.cookie_hash != cookie_hash(&self.cookie) => {
+                debug!(target: "NetCrypto", "Wrong cookie hash in CryptoHandshake!");
+                None
+            },
+            p => p,
+        }
+    }
+}
+
+impl ToBytes for CryptoHandshake {
+    fn to_bytes(&self) -> Vec<u8> {
+        let Nonce(ref nonce) = self.nonce;
+        let mut result = Vec::with_capacity(CRYPTO_HANDSHAKE_SIZE);
+        result.push(PacketKind::CryptoHs as u8);
+        result.extend_from_slice(&self.cookie.to_bytes());
+        result.extend_from_slice(nonce);
+        result.extend_from_slice(&self.payload);
+        result
+    }
+}
+
+/// De-serialization of `CryptoHandshake`. Fails if packet kind is wrong, or
+/// size isn't [`CRYPTO_HANDSHAKE_SIZE`]
+/// (./constant.CRYPTO_HANDSHAKE_SIZE.html).
+impl FromBytes<CryptoHandshake> for CryptoHandshake {
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        debug!(target: "NetCrypto", "De-serializing CryptoHandshake.");
+        if !check_packet(bytes, PacketKind::CryptoHs, CRYPTO_HANDSHAKE_SIZE) {
+            return None
+        }
+        const NONCE_POS: usize = 1 + ENCRYPTED_COOKIE_SIZE;
+        const PAYLOAD_POS: usize = NONCE_POS + NONCEBYTES;
+        let cookie = EncryptedCookie::from_bytes(&bytes[1..NONCE_POS]);
+        let nonce = Nonce::from_slice(&bytes[NONCE_POS..PAYLOAD_POS]);
+        match (cookie, nonce) {
+            (Some(cookie), Some(nonce)) => Some(CryptoHandshake {
+                cookie: cookie,
+                nonce: nonce,
+                payload: bytes[PAYLOAD_POS..].to_vec(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+
+/// Last two bytes of the `nonce`, as a big-endian number. They are sent
+/// along with [`CryptoData`](./struct.CryptoData.html).
+pub fn nonce_last_bytes(nonce: &Nonce) -> u16 {
+    let Nonce(ref bytes) = *nonce;
+    (bytes[NONCEBYTES - 2] as u16) << 8 | bytes[NONCEBYTES - 1] as u16
+}
+
+/// Increment `nonce` by `num`, treating it as a big-endian number, like
+/// toxcore does for [`CryptoData`](./struct.CryptoData.html).
+pub fn increment_nonce_be(nonce: &mut Nonce, num: u16) {
+    let Nonce(ref mut bytes) = *nonce;
+    let mut carry = num as u32;
+    for byte in bytes.iter_mut().rev() {
+        if carry == 0 {
+            break
+        }
+        let sum = *byte as u32 + (carry & 0xff);
+        *byte = sum as u8;
+        carry = (carry >> 8) + (sum >> 8);
+    }
+}
+
+fn u32_to_be_array(num: u32) -> [u8; 4] {
+    [(num >> 24) as u8, (num >> 16) as u8, (num >> 8) as u8, num as u8]
+}
+
+fn be_array_to_u32(bytes: &[u8]) -> u32 {
+    (bytes[0] as u32) << 24 | (bytes[1] as u32) << 16
+        | (bytes[2] as u32) << 8 | bytes[3] as u32
+}
+
+
+/// Decrypted payload of [`CryptoData`](./struct.CryptoData.html).
+///
+/// Serialized form:
+///
+/// Length   | Contents
+/// -------- | --------
+/// `4`      | Start of the sender's receive buffer, big-endian
+/// `4`      | Packet number if data is lossless, or end of the sender's send buffer, big-endian
+/// variable | Padding, zeros
+/// variable | Data, starting with its non-zero ID
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CryptoDataPayload {
+    /// Number of the first packet that sender hasn't received yet.
+    pub buffer_start: u32,
+    /// Number of the packet if data is lossless, or end of sender's send
+    /// buffer otherwise.
+    pub packet_number: u32,
+    /// Data, starting with its ID.
+    pub data: Vec<u8>,
+}
+
+impl ToBytes for CryptoDataPayload {
+    fn to_bytes(&self) -> Vec<u8> {
+        let padding = MAX_CRYPTO_DATA_SIZE.saturating_sub(self.data.len())
+            % CRYPTO_MAX_PADDING;
+        let mut result = Vec::with_capacity(8 + padding + self.data.len());
+        result.extend_from_slice(&u32_to_be_array(self.buffer_start));
+        result.extend_from_slice(&u32_to_be_array(self.packet_number));
+        result.extend(vec![PACKET_ID_PADDING; padding]);
+        result.extend_from_slice(&self.data);
+        result
+    }
+}
+
+/// De-serialization of `CryptoDataPayload`. Padding is skipped. Fails if
+/// there is no data after the padding.
+impl FromBytes<CryptoDataPayload> for CryptoDataPayload {
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < 8 {
+            debug!(target: "NetCrypto", "CryptoDataPayload is too short!");
+            return None
+        }
+        let data_pos = match bytes[8..].iter().position(|&b| b != PACKET_ID_PADDING) {
+            Some(pos) => 8 + pos,
+            None => {
+                debug!(target: "NetCrypto", "No data in CryptoDataPayload!");
+                return None
+            },
+        };
+        Some(CryptoDataPayload {
+            buffer_start: be_array_to_u32(&bytes[..4]),
+            packet_number: be_array_to_u32(&bytes[4..8]),
+            data: bytes[data_pos..].to_vec(),
+        })
+    }
+}
+
+
+/// Data sent over an established connection.
+///
+/// Packet kind [`PacketKind::CryptoData`](../dht/enum.PacketKind.html).
+///
+/// Serialized form:
+///
+/// Length   | Contents
+/// -------- | --------
+/// `1`      | `0x1b`
+/// `2`      | Last two bytes of the nonce, big-endian
+/// variable | [`CryptoDataPayload`](./struct.CryptoDataPayload.html), encrypted with the session key
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CryptoData {
+    /// Last two bytes of the nonce used to encrypt the payload.
+    pub nonce_last_bytes: u16,
+    /// Encrypted payload.
+    pub payload: Vec<u8>,
+}
+
+impl CryptoData {
+    /// Create new `CryptoData` with `payload` encrypted with `session_key`
+    /// and `nonce`.
+    pub fn new(session_key: &PrecomputedKey, nonce: &Nonce,
+               payload: &CryptoDataPayload) -> Self {
+        CryptoData {
+            nonce_last_bytes: nonce_last_bytes(nonce),
+            payload: encrypt_data_symmetric(session_key, nonce, &payload.to_bytes()),
+        }
+    }
+
+    /// Decrypt payload with `session_key` and full `nonce`, that should end
+    /// with [`nonce_last_bytes`](#structfield.nonce_last_bytes).
+    ///
+    /// Returns `None` if decryption or parsing fails.
+    pub fn get_payload(&self, session_key: &PrecomputedKey, nonce: &Nonce)
+        -> Option<CryptoDataPayload>
+    {
+        match decrypt_data_symmetric(session_key, nonce, &self.payload) {
+            Ok(bytes) => CryptoDataPayload::from_bytes(&bytes),
+            Err(_) => {
+                debug!(target: "NetCrypto", "Decrypting CryptoData failed!");
+                None
+            },
+        }
+    }
+}
+
+impl ToBytes for CryptoData {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut result = Vec::with_capacity(3 + self.payload.len());
+        result.push(PacketKind::CryptoData as u8);
+        result.push((self.nonce_last_bytes >> 8) as u8);
+        result.push(self.nonce_last_bytes as u8);
+        result.extend_from_slice(&self.payload);
+        result
+    }
+}
+
+/// De-serialization of `CryptoData`. Fails if packet kind is wrong, or
+/// size isn't between [`CRYPTO_DATA_MIN_SIZE`]
+/// (./constant.CRYPTO_DATA_MIN_SIZE.html) and [`MAX_CRYPTO_PACKET_SIZE`]
+/// (./constant.MAX_CRYPTO_PACKET_SIZE.html).
+impl FromBytes<CryptoData> for CryptoData {
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < CRYPTO_DATA_MIN_SIZE || bytes.len() > MAX_CRYPTO_PACKET_SIZE {
+            debug!(target: "NetCrypto", "Wrong size of CryptoData!");
+            return None
+        }
+        if bytes[0] != PacketKind::CryptoData as u8 {
+            debug!(target: "NetCrypto", "Wrong packet kind of CryptoData!");
+            return None
+        }
+        Some(CryptoData {
+            nonce_last_bytes: (bytes[1] as u16) << 8 | bytes[2] as u16,
+            payload: bytes[3..].to_vec(),
+        })
+    }
+}
+
+
+/// Issuer of cookies – answers cookie requests with cookies encrypted with
+/// its own random symmetric key, and checks cookies that come back in
+/// handshakes.
+pub struct CookieIssuer {
+    key: PrecomputedKey,
+}
+
+impl CookieIssuer {
+    /// Create new `CookieIssuer` with a new random key.
+    pub fn new() -> Self {
+        debug!(target: "NetCrypto", "Creating new CookieIssuer.");
+        CookieIssuer { key: new_symmetric_key() }
+    }
+
+    /// Issue a cookie for the node with `real_pk` and `dht_pk` at `now`.
+    pub fn issue(&self, real_pk: &PublicKey, dht_pk: &PublicKey, now: u64)
+        -> EncryptedCookie
+    {
+        Cookie { time: now, real_pk: *real_pk, dht_pk: *dht_pk }.encrypt(&self.key)
+    }
+
+    /// Decrypt cookie issued by us, and check that it's still valid at
+    /// `now`.
+    ///
+    /// Returns `None` if cookie wasn't issued by us, or it expired.
+    pub fn check(&self, cookie: &EncryptedCookie, now: u64) -> Option<Cookie> {
+        match cookie.get_payload(&self.key) {
+            Some(c) if c.is_valid(now) => Some(c),
+            Some(_) => {
+                debug!(target: "NetCrypto", "Cookie expired!");
+                None
+            },
+            None => None,
+        }
+    }
+
+    /// Answer `request` with a cookie for the sender.
+    ///
+    /// Returns `None` if request can't be decrypted with own DHT SK.
+    pub fn handle_request(&self, own_dht_sk: &SecretKey, request: &CookieRequest,
+                          now: u64) -> Option<CookieResponse> {
+        debug!(target: "NetCrypto", "Handling CookieRequest.");
+        request.get_payload(own_dht_sk).map(|payload| {
+            let response = CookieResponsePayload {
+                cookie: self.issue(&payload.real_pk, &request.dht_pk, now),
+                echo_id: payload.echo_id,
+            };
+            CookieResponse::new(own_dht_sk, &request.dht_pk, &response)
+        })
+    }
+}
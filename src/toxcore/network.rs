@@ -22,7 +22,8 @@
 //! Networking part of the toxcore.
 
 
-use std::net::UdpSocket;
+use std::io;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
 
 
 /// Minimum port wchich Tox will try to bind to.
@@ -32,6 +33,8 @@ pub const PORT_MAX: u16 = 33545;
 
 /// Bind to an UDP socket on `0.0.0.0` with a port in range [`PORT_MIN`]
 /// (./constant.PORT_MIN.html):[`PORT_MAX`](./constant.PORT_MAX.html).
+/// Broadcast is enabled on the socket, so that it can be used for LAN
+/// discovery.
 ///
 /// Returns `None` if failed to bind to port within range.
 // TODO: perhaps use closure as an argument with 2 ports provided;
@@ -45,6 +48,9 @@ pub fn bind_udp() -> Option<UdpSocket> {
         match UdpSocket::bind(&format!("[::]:{}", port)[..]) {
             Ok(s) => {
                 debug!(target: "Port", "Bind to port {} successful.", port);
+                if let Err(e) = s.set_broadcast(true) {
+                    debug!(target: "Port", "Failed to enable broadcast: {}", e);
+                }
                 return Some(s)
             },
             Err(e) => trace!(target: "Port", "Bind to port {} unsuccessful: {}",
@@ -54,3 +60,72 @@ pub fn bind_udp() -> Option<UdpSocket> {
     error!(target: "Port", "Failed to bind to any port in range!");
     None  // loop ended without "early" return – failed to bind
 }
+
+
+/// Something that can send and receive UDP datagrams.
+///
+/// Implemented for `UdpSocket`; allows to use parts of toxcore that send
+/// packets with something else than a real socket, e.g. in tests.
+///
+/// Sockets used for LAN discovery must have broadcast enabled with
+/// `UdpSocket::set_broadcast()`, otherwise sending to IPv4 broadcast
+/// addresses fails.
+pub trait Transport {
+    /// Send `buf` to the `addr`. Returns number of bytes sent.
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize>;
+
+    /// Receive a datagram into `buf`. Returns number of bytes read and the
+    /// address of the sender.
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)>;
+}
+
+impl Transport for UdpSocket {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        UdpSocket::send_to(self, buf, addr)
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        UdpSocket::recv_from(self, buf)
+    }
+}
+
+
+/// Several UDP sockets, e.g. bound to all configured addresses, used as a
+/// single `Transport`.
+///
+/// Packets are sent with the first socket of the same IP version as the
+/// destination, or as IPv4-mapped address with the first socket if there is
+/// no IPv4 socket. Receiving polls the sockets in order, thus they should be
+/// non-blocking.
+pub struct Sockets(pub Vec<UdpSocket>);
+
+impl Transport for Sockets {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        let same_version = self.0.iter().find(|s| match (s.local_addr(), addr) {
+            (Ok(SocketAddr::V4(_)), SocketAddr::V4(_)) |
+            (Ok(SocketAddr::V6(_)), SocketAddr::V6(_)) => true,
+            _ => false,
+        });
+        match (same_version, addr) {
+            (Some(socket), _) => socket.send_to(buf, addr),
+            // IPv6 socket may be able to send to IPv4-mapped address
+            (None, SocketAddr::V4(a)) if !self.0.is_empty() => {
+                let mapped = SocketAddr::new(IpAddr::V6(a.ip().to_ipv6_mapped()),
+                                             a.port());
+                self.0[0].send_to(buf, mapped)
+            },
+            _ => Err(io::Error::new(io::ErrorKind::AddrNotAvailable,
+                                    "no socket to send with")),
+        }
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        for socket in &self.0 {
+            match socket.recv_from(buf) {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {},
+                r => return r,
+            }
+        }
+        Err(io::Error::new(io::ErrorKind::WouldBlock, "no packets to receive"))
+    }
+}
@@ -0,0 +1,983 @@
+/*
+    Copyright © 2016 Zetok Zalbavar <zexavexxe@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Onion packets, used to send requests through a path of 3 nodes, so that
+//! the destination doesn't learn who has sent the request.
+//!
+//! https://toktok.github.io/spec.html#onion
+//!
+//! Request is encrypted in layers by the sender, with a separate temporary
+//! key for each node on the path – see [`OnionPath`]
+//! (./struct.OnionPath.html). Every node removes one layer and sends the
+//! rest to the next node:
+//!
+//! ```text
+//! sender -> A: OnionRequest0
+//!      A -> B: OnionRequest1
+//!      B -> C: OnionRequest2
+//!      C -> D: data
+//! ```
+//!
+//! Each node adds an [`OnionReturn`](./struct.OnionReturn.html) with the
+//! address it has received the request from, encrypted with a key known
+//! only to that node. Response travels back the same way, with each node
+//! removing its `OnionReturn`, thus nodes don't need to keep any state for
+//! requests – see [`OnionRelay`](./struct.OnionRelay.html):
+//!
+//! ```text
+//!      D -> C: OnionResponse3
+//!      C -> B: OnionResponse2
+//!      B -> A: OnionResponse1
+//!      A -> sender: data
+//! ```
+
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+
+use toxcore::binary_io::*;
+use toxcore::crypto_core::*;
+use toxcore::dht::{IpType, PackedNode, PacketKind};
+use toxcore::network::Transport;
+
+
+/// Maximum size in bytes of an onion packet.
+pub const ONION_MAX_PACKET_SIZE: usize = 1400;
+
+/// Size in bytes of serialized IP address and port used in onion packets.
+pub const ONION_IP_PORT_SIZE: usize = 19;
+
+/// Size in bytes of [`OnionReturn`](./struct.OnionReturn.html) added by the
+/// first node of the path.
+pub const ONION_RETURN_1_SIZE: usize = NONCEBYTES + ONION_IP_PORT_SIZE + MACBYTES;
+/// Size in bytes of [`OnionReturn`](./struct.OnionReturn.html) added by the
+/// second node of the path.
+pub const ONION_RETURN_2_SIZE: usize = NONCEBYTES + ONION_IP_PORT_SIZE
+                                     + ONION_RETURN_1_SIZE + MACBYTES;
+/// Size in bytes of [`OnionReturn`](./struct.OnionReturn.html) added by the
+/// third node of the path.
+pub const ONION_RETURN_3_SIZE: usize = NONCEBYTES + ONION_IP_PORT_SIZE
+                                     + ONION_RETURN_2_SIZE + MACBYTES;
+
+/// Minimal size in bytes of [`OnionRequest0`](./struct.OnionRequest0.html).
+pub const ONION_REQUEST_0_MIN_SIZE: usize = 1 // packet kind
+                                          + NONCEBYTES
+                                          + PUBLICKEYBYTES
+                                          + MACBYTES;
+/// Minimal size in bytes of [`OnionRequest1`](./struct.OnionRequest1.html).
+pub const ONION_REQUEST_1_MIN_SIZE: usize = ONION_REQUEST_0_MIN_SIZE
+                                          + ONION_RETURN_1_SIZE;
+/// Minimal size in bytes of [`OnionRequest2`](./struct.OnionRequest2.html).
+pub const ONION_REQUEST_2_MIN_SIZE: usize = ONION_REQUEST_0_MIN_SIZE
+                                          + ONION_RETURN_2_SIZE;
+
+/// Minimal size in bytes of [`OnionResponse3`](./struct.OnionResponse3.html).
+pub const ONION_RESPONSE_3_MIN_SIZE: usize = 1 + ONION_RETURN_3_SIZE + 1;
+/// Minimal size in bytes of [`OnionResponse2`](./struct.OnionResponse2.html).
+pub const ONION_RESPONSE_2_MIN_SIZE: usize = 1 + ONION_RETURN_2_SIZE + 1;
+/// Minimal size in bytes of [`OnionResponse1`](./struct.OnionResponse1.html).
+pub const ONION_RESPONSE_1_MIN_SIZE: usize = 1 + ONION_RETURN_1_SIZE + 1;
+
+
+/// Serialize IP address and port of `addr`.
+///
+/// Serialized form:
+///
+/// Length | Contents
+/// ------ | --------
+/// `1`    | IP family – `2` for IPv4, `10` for IPv6
+/// `16`   | IP address; IPv4 is padded with `0`s
+/// `2`    | Port, big endian
+pub fn ip_port_to_bytes(addr: &SocketAddr) -> [u8; ONION_IP_PORT_SIZE] {
+    let mut result = [0; ONION_IP_PORT_SIZE];
+    match *addr {
+        SocketAddr::V4(ref a) => {
+            result[0] = IpType::U4 as u8;
+            result[1..5].copy_from_slice(&a.ip().octets());
+        },
+        SocketAddr::V6(ref a) => {
+            result[0] = IpType::U6 as u8;
+            for (n, s) in a.ip().segments().iter().enumerate() {
+                result[1 + 2 * n] = (s >> 8) as u8;
+                result[2 + 2 * n] = *s as u8;
+            }
+        },
+    }
+    result[17] = (addr.port() >> 8) as u8;
+    result[18] = addr.port() as u8;
+    result
+}
+
+/// De-serialize IP address and port serialized with [`ip_port_to_bytes()`]
+/// (./fn.ip_port_to_bytes.html).
+///
+/// Returns `None` if there are less than [`ONION_IP_PORT_SIZE`]
+/// (./constant.ONION_IP_PORT_SIZE.html) bytes, or IP family is unknown.
+pub fn ip_port_from_bytes(bytes: &[u8]) -> Option<SocketAddr> {
+    if bytes.len() < ONION_IP_PORT_SIZE {
+        debug!(target: "Onion", "Not enough bytes for IP and port!");
+        return None
+    }
+    let port = (bytes[17] as u16) << 8 | bytes[18] as u16;
+    if bytes[0] == IpType::U4 as u8 {
+        let ip = Ipv4Addr::new(bytes[1], bytes[2], bytes[3], bytes[4]);
+        Some(SocketAddr::V4(SocketAddrV4::new(ip, port)))
+    } else if bytes[0] == IpType::U6 as u8 {
+        let mut s = [0u16; 8];
+        for (n, seg) in s.iter_mut().enumerate() {
+            *seg = (bytes[1 + 2 * n] as u16) << 8 | bytes[2 + 2 * n] as u16;
+        }
+        let ip = Ipv6Addr::new(s[0], s[1], s[2], s[3], s[4], s[5], s[6], s[7]);
+        Some(SocketAddr::V6(SocketAddrV6::new(ip, port, 0, 0)))
+    } else {
+        debug!(target: "Onion", "Unknown IP family: {}", bytes[0]);
+        None
+    }
+}
+
+
+/// Encrypted return path of an onion request, added by each node on the
+/// path, so that the response can find its way back. Only the node that
+/// added it can decrypt it.
+///
+/// Serialized form:
+///
+/// Length      | Contents
+/// ----------- | --------
+/// `24`        | Nonce
+/// variable    | Encrypted payload
+///
+/// Decrypted payload:
+///
+/// Length      | Contents
+/// ----------- | --------
+/// `19`        | IP and port of the previous node
+/// `0`, `59` or `118` | `OnionReturn` of the previous node, if any
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OnionReturn {
+    /// Nonce used to encrypt the payload.
+    pub nonce: Nonce,
+    /// Encrypted address of the previous node, followed by its
+    /// `OnionReturn`, if there's such.
+    pub payload: Vec<u8>,
+}
+
+impl OnionReturn {
+    /// Create new `OnionReturn` with address of the previous node and its
+    /// `OnionReturn`, encrypted with own `symmetric_key`.
+    pub fn new(symmetric_key: &PrecomputedKey, ip_port: &SocketAddr,
+               inner: Option<&OnionReturn>) -> Self {
+        let mut plain = ip_port_to_bytes(ip_port).to_vec();
+        if let Some(inner) = inner {
+            plain.extend_from_slice(&inner.to_bytes());
+        }
+        let nonce = gen_nonce();
+        OnionReturn {
+            nonce: nonce,
+            payload: encrypt_data_symmetric(symmetric_key, &nonce, &plain),
+        }
+    }
+
+    /// Decrypt `OnionReturn` with own `symmetric_key`, getting address of
+    /// the previous node and its `OnionReturn`, if there's such.
+    ///
+    /// Returns `None` if decryption or parsing fails.
+    pub fn get_payload(&self, symmetric_key: &PrecomputedKey)
+        -> Option<(SocketAddr, Option<OnionReturn>)>
+    {
+        let plain = match decrypt_data_symmetric(symmetric_key, &self.nonce,
+                                                 &self.payload) {
+            Ok(p) => p,
+            Err(_) => {
+                debug!(target: "Onion", "Decrypting OnionReturn failed!");
+                return None
+            },
+        };
+        let ip_port = match ip_port_from_bytes(&plain) {
+            Some(a) => a,
+            None => return None,
+        };
+        if plain.len() == ONION_IP_PORT_SIZE {
+            return Some((ip_port, None))
+        }
+        OnionReturn::from_bytes(&plain[ONION_IP_PORT_SIZE..])
+            .map(|inner| (ip_port, Some(inner)))
+    }
+}
+
+impl ToBytes for OnionReturn {
+    fn to_bytes(&self) -> Vec<u8> {
+        let Nonce(ref nonce) = self.nonce;
+        let mut result = Vec::with_capacity(NONCEBYTES + self.payload.len());
+        result.extend_from_slice(nonce);
+        result.extend_from_slice(&self.payload);
+        result
+    }
+}
+
+/// De-serialization of `OnionReturn`. All bytes are used, thus they should
+/// be of one of the return sizes, e.g. [`ONION_RETURN_1_SIZE`]
+/// (./constant.ONION_RETURN_1_SIZE.html).
+///
+/// Fails if there are not more bytes than a nonce and MAC take.
+impl FromBytes<OnionReturn> for OnionReturn {
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() <= NONCEBYTES + MACBYTES {
+            debug!(target: "Onion", "Not enough bytes for OnionReturn!");
+            return None
+        }
+        Nonce::from_slice(&bytes[..NONCEBYTES]).map(|nonce| OnionReturn {
+            nonce: nonce,
+            payload: bytes[NONCEBYTES..].to_vec(),
+        })
+    }
+}
+
+
+/// Split data received from the third node of the path into the data sent
+/// by the sender of the request, and the appended [`OnionReturn`]
+/// (./struct.OnionReturn.html) of [`ONION_RETURN_3_SIZE`]
+/// (./constant.ONION_RETURN_3_SIZE.html).
+///
+/// Returns `None` if there are not more bytes than `OnionReturn` takes.
+pub fn split_onion_return(bytes: &[u8]) -> Option<(&[u8], OnionReturn)> {
+    if bytes.len() <= ONION_RETURN_3_SIZE {
+        debug!(target: "Onion", "Not enough bytes for data with OnionReturn!");
+        return None
+    }
+    let return_pos = bytes.len() - ONION_RETURN_3_SIZE;
+    OnionReturn::from_bytes(&bytes[return_pos..])
+        .map(|onion_return| (&bytes[..return_pos], onion_return))
+}
+
+
+/// Decrypted payload of [`OnionRequest0`](./struct.OnionRequest0.html) and
+/// [`OnionRequest1`](./struct.OnionRequest1.html) – what should be sent to
+/// the next node of the path.
+///
+/// Serialized form:
+///
+/// Length      | Contents
+/// ----------- | --------
+/// `19`        | IP and port of the next node
+/// `32`        | Temporary PK for the next node
+/// variable    | Payload encrypted for the next node
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OnionRequestPayload {
+    /// Address of the next node.
+    pub ip_port: SocketAddr,
+    /// Temporary PK that the next node should decrypt `inner` with.
+    pub temporary_pk: PublicKey,
+    /// Payload encrypted for the next node.
+    pub inner: Vec<u8>,
+}
+
+impl ToBytes for OnionRequestPayload {
+    fn to_bytes(&self) -> Vec<u8> {
+        let PublicKey(ref pk) = self.temporary_pk;
+        let mut result = Vec::with_capacity(ONION_IP_PORT_SIZE + PUBLICKEYBYTES
+                                            + self.inner.len());
+        result.extend_from_slice(&ip_port_to_bytes(&self.ip_port));
+        result.extend_from_slice(pk);
+        result.extend_from_slice(&self.inner);
+        result
+    }
+}
+
+impl FromBytes<OnionRequestPayload> for OnionRequestPayload {
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        const PK_POS: usize = ONION_IP_PORT_SIZE;
+        const INNER_POS: usize = PK_POS + PUBLICKEYBYTES;
+        if bytes.len() <= INNER_POS + MACBYTES {
+            debug!(target: "Onion", "Not enough bytes for OnionRequestPayload!");
+            return None
+        }
+        let ip_port = ip_port_from_bytes(bytes);
+        let temporary_pk = PublicKey::from_slice(&bytes[PK_POS..INNER_POS]);
+        match (ip_port, temporary_pk) {
+            (Some(ip_port), Some(pk)) => Some(OnionRequestPayload {
+                ip_port: ip_port,
+                temporary_pk: pk,
+                inner: bytes[INNER_POS..].to_vec(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+
+/// Decrypted payload of [`OnionRequest2`](./struct.OnionRequest2.html) –
+/// data for the destination.
+///
+/// Serialized form:
+///
+/// Length      | Contents
+/// ----------- | --------
+/// `19`        | IP and port of the destination
+/// variable    | Data
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OnionRequest2Payload {
+    /// Address of the destination.
+    pub ip_port: SocketAddr,
+    /// Data for the destination, e.g. an announce request.
+    pub data: Vec<u8>,
+}
+
+impl ToBytes for OnionRequest2Payload {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut result = Vec::with_capacity(ONION_IP_PORT_SIZE + self.data.len());
+        result.extend_from_slice(&ip_port_to_bytes(&self.ip_port));
+        result.extend_from_slice(&self.data);
+        result
+    }
+}
+
+impl FromBytes<OnionRequest2Payload> for OnionRequest2Payload {
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() <= ONION_IP_PORT_SIZE {
+            debug!(target: "Onion", "Not enough bytes for OnionRequest2Payload!");
+            return None
+        }
+        ip_port_from_bytes(bytes).map(|ip_port| OnionRequest2Payload {
+            ip_port: ip_port,
+            data: bytes[ONION_IP_PORT_SIZE..].to_vec(),
+        })
+    }
+}
+
+
+/// Decrypt `payload` encrypted by `temporary_pk` for us.
+fn open_layer(payload: &[u8], nonce: &Nonce, temporary_pk: &PublicKey,
+              own_sk: &SecretKey) -> Option<Vec<u8>> {
+    match open(payload, nonce, temporary_pk, own_sk) {
+        Ok(p) => Some(p),
+        Err(_) => {
+            debug!(target: "Onion", "Decrypting onion layer failed!");
+            None
+        },
+    }
+}
+
+/// Check that `bytes` have packet `kind` and size within limits.
+fn check_packet(bytes: &[u8], kind: PacketKind, min_size: usize) -> bool {
+    if bytes.len() < min_size || bytes.len() > ONION_MAX_PACKET_SIZE {
+        debug!(target: "Onion", "Wrong size of {:?}: {}", kind, bytes.len());
+        return false
+    }
+    if PacketKind::from_bytes(bytes) != Some(kind) {
+        debug!(target: "Onion", "Not a {:?}!", kind);
+        return false
+    }
+    true
+}
+
+
+/// First onion request, sent to the first node of the path.
+///
+/// Packet kind [`PacketKind::OnionReq0`](../dht/enum.PacketKind.html).
+///
+/// Serialized form:
+///
+/// Length      | Contents
+/// ----------- | --------
+/// `1`         | `0x80`
+/// `24`        | Nonce
+/// `32`        | Temporary PK
+/// variable    | Encrypted [`OnionRequestPayload`](./struct.OnionRequestPayload.html)
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OnionRequest0 {
+    /// Nonce used for all layers.
+    pub nonce: Nonce,
+    /// Temporary PK that payload was encrypted with.
+    pub temporary_pk: PublicKey,
+    /// Encrypted payload.
+    pub payload: Vec<u8>,
+}
+
+impl OnionRequest0 {
+    /// Decrypt payload with own DHT SK.
+    ///
+    /// Returns `None` if decryption or parsing fails.
+    pub fn get_payload(&self, own_sk: &SecretKey) -> Option<OnionRequestPayload> {
+        debug!(target: "Onion", "Getting payload of OnionRequest0.");
+        open_layer(&self.payload, &self.nonce, &self.temporary_pk, own_sk)
+            .and_then(|p| OnionRequestPayload::from_bytes(&p))
+    }
+}
+
+impl ToBytes for OnionRequest0 {
+    fn to_bytes(&self) -> Vec<u8> {
+        let Nonce(ref nonce) = self.nonce;
+        let PublicKey(ref pk) = self.temporary_pk;
+        let mut result = Vec::with_capacity(ONION_REQUEST_0_MIN_SIZE
+                                            + self.payload.len());
+        result.push(PacketKind::OnionReq0 as u8);
+        result.extend_from_slice(nonce);
+        result.extend_from_slice(pk);
+        result.extend_from_slice(&self.payload);
+        result
+    }
+}
+
+/// De-serialization of `OnionRequest0`. Fails if packet kind is wrong, or
+/// there are less than [`ONION_REQUEST_0_MIN_SIZE`]
+/// (./constant.ONION_REQUEST_0_MIN_SIZE.html) or more than
+/// [`ONION_MAX_PACKET_SIZE`](./constant.ONION_MAX_PACKET_SIZE.html) bytes.
+impl FromBytes<OnionRequest0> for OnionRequest0 {
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        debug!(target: "Onion", "De-serializing OnionRequest0.");
+        if !check_packet(bytes, PacketKind::OnionReq0, ONION_REQUEST_0_MIN_SIZE) {
+            return None
+        }
+        const PK_POS: usize = 1 + NONCEBYTES;
+        const PAYLOAD_POS: usize = PK_POS + PUBLICKEYBYTES;
+        let nonce = Nonce::from_slice(&bytes[1..PK_POS]);
+        let pk = PublicKey::from_slice(&bytes[PK_POS..PAYLOAD_POS]);
+        match (nonce, pk) {
+            (Some(nonce), Some(pk)) => Some(OnionRequest0 {
+                nonce: nonce,
+                temporary_pk: pk,
+                payload: bytes[PAYLOAD_POS..].to_vec(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+
+/// Second onion request, sent by the first node of the path to the second
+/// one.
+///
+/// Packet kind [`PacketKind::OnionReq1`](../dht/enum.PacketKind.html).
+///
+/// Serialized form:
+///
+/// Length      | Contents
+/// ----------- | --------
+/// `1`         | `0x81`
+/// `24`        | Nonce
+/// `32`        | Temporary PK
+/// variable    | Encrypted [`OnionRequestPayload`](./struct.OnionRequestPayload.html)
+/// `59`        | [`OnionReturn`](./struct.OnionReturn.html) of the first node
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OnionRequest1 {
+    /// Nonce used for all layers.
+    pub nonce: Nonce,
+    /// Temporary PK that payload was encrypted with.
+    pub temporary_pk: PublicKey,
+    /// Encrypted payload.
+    pub payload: Vec<u8>,
+    /// Return path added by the first node.
+    pub onion_return: OnionReturn,
+}
+
+impl OnionRequest1 {
+    /// Decrypt payload with own DHT SK.
+    ///
+    /// Returns `None` if decryption or parsing fails.
+    pub fn get_payload(&self, own_sk: &SecretKey) -> Option<OnionRequestPayload> {
+        debug!(target: "Onion", "Getting payload of OnionRequest1.");
+        open_layer(&self.payload, &self.nonce, &self.temporary_pk, own_sk)
+            .and_then(|p| OnionRequestPayload::from_bytes(&p))
+    }
+}
+
+impl ToBytes for OnionRequest1 {
+    fn to_bytes(&self) -> Vec<u8> {
+        let Nonce(ref nonce) = self.nonce;
+        let PublicKey(ref pk) = self.temporary_pk;
+        let mut result = Vec::with_capacity(ONION_REQUEST_1_MIN_SIZE
+                                            + self.payload.len());
+        result.push(PacketKind::OnionReq1 as u8);
+        result.extend_from_slice(nonce);
+        result.extend_from_slice(pk);
+        result.extend_from_slice(&self.payload);
+        result.extend_from_slice(&self.onion_return.to_bytes());
+        result
+    }
+}
+
+/// De-serialization of `OnionRequest1`. Fails if packet kind is wrong, or
+/// there are less than [`ONION_REQUEST_1_MIN_SIZE`]
+/// (./constant.ONION_REQUEST_1_MIN_SIZE.html) or more than
+/// [`ONION_MAX_PACKET_SIZE`](./constant.ONION_MAX_PACKET_SIZE.html) bytes.
+impl FromBytes<OnionRequest1> for OnionRequest1 {
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        debug!(target: "Onion", "De-serializing OnionRequest1.");
+        if !check_packet(bytes, PacketKind::OnionReq1, ONION_REQUEST_1_MIN_SIZE) {
+            return None
+        }
+        const PK_POS: usize = 1 + NONCEBYTES;
+        const PAYLOAD_POS: usize = PK_POS + PUBLICKEYBYTES;
+        let return_pos = bytes.len() - ONION_RETURN_1_SIZE;
+        let nonce = Nonce::from_slice(&bytes[1..PK_POS]);
+        let pk = PublicKey::from_slice(&bytes[PK_POS..PAYLOAD_POS]);
+        let onion_return = OnionReturn::from_bytes(&bytes[return_pos..]);
+        match (nonce, pk, onion_return) {
+            (Some(nonce), Some(pk), Some(onion_return)) => Some(OnionRequest1 {
+                nonce: nonce,
+                temporary_pk: pk,
+                payload: bytes[PAYLOAD_POS..return_pos].to_vec(),
+                onion_return: onion_return,
+            }),
+            _ => None,
+        }
+    }
+}
+
+
+/// Third onion request, sent by the second node of the path to the third
+/// one.
+///
+/// Packet kind [`PacketKind::OnionReq2`](../dht/enum.PacketKind.html).
+///
+/// Serialized form:
+///
+/// Length      | Contents
+/// ----------- | --------
+/// `1`         | `0x82`
+/// `24`        | Nonce
+/// `32`        | Temporary PK
+/// variable    | Encrypted [`OnionRequest2Payload`](./struct.OnionRequest2Payload.html)
+/// `118`       | [`OnionReturn`](./struct.OnionReturn.html) of the second node
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OnionRequest2 {
+    /// Nonce used for all layers.
+    pub nonce: Nonce,
+    /// Temporary PK that payload was encrypted with.
+    pub temporary_pk: PublicKey,
+    /// Encrypted payload.
+    pub payload: Vec<u8>,
+    /// Return path added by the second node.
+    pub onion_return: OnionReturn,
+}
+
+impl OnionRequest2 {
+    /// Decrypt payload with own DHT SK.
+    ///
+    /// Returns `None` if decryption or parsing fails.
+    pub fn get_payload(&self, own_sk: &SecretKey) -> Option<OnionRequest2Payload> {
+        debug!(target: "Onion", "Getting payload of OnionRequest2.");
+        open_layer(&self.payload, &self.nonce, &self.temporary_pk, own_sk)
+            .and_then(|p| OnionRequest2Payload::from_bytes(&p))
+    }
+}
+
+impl ToBytes for OnionRequest2 {
+    fn to_bytes(&self) -> Vec<u8> {
+        let Nonce(ref nonce) = self.nonce;
+        let PublicKey(ref pk) = self.temporary_pk;
+        let mut result = Vec::with_capacity(ONION_REQUEST_2_MIN_SIZE
+                                            + self.payload.len());
+        result.push(PacketKind::OnionReq2 as u8);
+        result.extend_from_slice(nonce);
+        result.extend_from_slice(pk);
+        result.extend_from_slice(&self.payload);
+        result.extend_from_slice(&self.onion_return.to_bytes());
+        result
+    }
+}
+
+/// De-serialization of `OnionRequest2`. Fails if packet kind is wrong, or
+/// there are less than [`ONION_REQUEST_2_MIN_SIZE`]
+/// (./constant.ONION_REQUEST_2_MIN_SIZE.html) or more than
+/// [`ONION_MAX_PACKET_SIZE`](./constant.ONION_MAX_PACKET_SIZE.html) bytes.
+impl FromBytes<OnionRequest2> for OnionRequest2 {
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        debug!(target: "Onion", "De-serializing OnionRequest2.");
+        if !check_packet(bytes, PacketKind::OnionReq2, ONION_REQUEST_2_MIN_SIZE) {
+            return None
+        }
+        const PK_POS: usize = 1 + NONCEBYTES;
+        const PAYLOAD_POS: usize = PK_POS + PUBLICKEYBYTES;
+        let return_pos = bytes.len() - ONION_RETURN_2_SIZE;
+        let nonce = Nonce::from_slice(&bytes[1..PK_POS]);
+        let pk = PublicKey::from_slice(&bytes[PK_POS..PAYLOAD_POS]);
+        let onion_return = OnionReturn::from_bytes(&bytes[return_pos..]);
+        match (nonce, pk, onion_return) {
+            (Some(nonce), Some(pk), Some(onion_return)) => Some(OnionRequest2 {
+                nonce: nonce,
+                temporary_pk: pk,
+                payload: bytes[PAYLOAD_POS..return_pos].to_vec(),
+                onion_return: onion_return,
+            }),
+            _ => None,
+        }
+    }
+}
+
+
+/// Path of 3 nodes that onion requests are sent through.
+///
+/// Each node gets a separate temporary keypair, so that nodes can't tell
+/// whether they are on the same path.
+#[derive(Clone)]
+pub struct OnionPath {
+    /// Nodes of the path, in order in which request goes through them.
+    pub nodes: [PackedNode; 3],
+    /// Temporary PK and key precomputed with the node's DHT PK, for each
+    /// node.
+    keys: Vec<(PublicKey, PrecomputedKey)>,
+}
+
+impl OnionPath {
+    /// Create new path through `nodes`, with new temporary keypairs.
+    pub fn new(nodes: &[PackedNode; 3]) -> Self {
+        debug!(target: "Onion", "Creating new OnionPath.");
+        trace!(target: "Onion", "With nodes: {:?}", nodes);
+        let keys = nodes.iter().map(|node| {
+            let (pk, sk) = gen_keypair();
+            (pk, encrypt_precompute(&node.pk, &sk))
+        }).collect();
+        OnionPath { nodes: *nodes, keys: keys }
+    }
+
+    /// Wrap `data` into an [`OnionRequest0`](./struct.OnionRequest0.html)
+    /// that will be delivered through the path to the `destination`.
+    ///
+    /// Request should be sent to the address of the first node.
+    pub fn create_request(&self, destination: SocketAddr, data: &[u8])
+        -> OnionRequest0
+    {
+        debug!(target: "Onion", "Creating onion request.");
+        let nonce = gen_nonce();
+
+        let layer_3 = OnionRequest2Payload {
+            ip_port: destination,
+            data: data.to_vec(),
+        };
+        let layer_2 = OnionRequestPayload {
+            ip_port: self.nodes[2].saddr,
+            temporary_pk: self.keys[2].0,
+            inner: encrypt_data_symmetric(&self.keys[2].1, &nonce,
+                                          &layer_3.to_bytes()),
+        };
+        let layer_1 = OnionRequestPayload {
+            ip_port: self.nodes[1].saddr,
+            temporary_pk: self.keys[1].0,
+            inner: encrypt_data_symmetric(&self.keys[1].1, &nonce,
+                                          &layer_2.to_bytes()),
+        };
+        OnionRequest0 {
+            nonce: nonce,
+            temporary_pk: self.keys[0].0,
+            payload: encrypt_data_symmetric(&self.keys[0].1, &nonce,
+                                            &layer_1.to_bytes()),
+        }
+    }
+}
+
+
+/// Response sent by the destination to the third node of the path, with the
+/// [`OnionReturn`](./struct.OnionReturn.html) that came with the request.
+///
+/// Packet kind [`PacketKind::OnionResp3`](../dht/enum.PacketKind.html).
+///
+/// Serialized form:
+///
+/// Length      | Contents
+/// ----------- | --------
+/// `1`         | `0x8c`
+/// `177`       | [`OnionReturn`](./struct.OnionReturn.html) of the third node
+/// variable    | Data, e.g. an announce response
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OnionResponse3 {
+    /// Return path, to be decrypted by the receiving node.
+    pub onion_return: OnionReturn,
+    /// Data for the sender of the request.
+    pub payload: Vec<u8>,
+}
+
+impl ToBytes for OnionResponse3 {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut result = Vec::with_capacity(ONION_RESPONSE_3_MIN_SIZE + self.payload.len());
+        result.push(PacketKind::OnionResp3 as u8);
+        result.extend_from_slice(&self.onion_return.to_bytes());
+        result.extend_from_slice(&self.payload);
+        result
+    }
+}
+
+/// De-serialization of `OnionResponse3`. Fails if packet kind is wrong, or there
+/// are less than [`ONION_RESPONSE_3_MIN_SIZE`]
+/// (./constant.ONION_RESPONSE_3_MIN_SIZE.html) or more than
+/// [`ONION_MAX_PACKET_SIZE`](./constant.ONION_MAX_PACKET_SIZE.html) bytes.
+impl FromBytes<OnionResponse3> for OnionResponse3 {
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        debug!(target: "Onion", "De-serializing OnionResponse3.");
+        if !check_packet(bytes, PacketKind::OnionResp3, ONION_RESPONSE_3_MIN_SIZE) {
+            return None
+        }
+        const PAYLOAD_POS: usize = 1 + ONION_RETURN_3_SIZE;
+        OnionReturn::from_bytes(&bytes[1..PAYLOAD_POS]).map(|r| OnionResponse3 {
+            onion_return: r,
+            payload: bytes[PAYLOAD_POS..].to_vec(),
+        })
+    }
+}
+
+
+/// Response sent by the third node of the path to the second one.
+///
+/// Packet kind [`PacketKind::OnionResp2`](../dht/enum.PacketKind.html).
+///
+/// Serialized form:
+///
+/// Length      | Contents
+/// ----------- | --------
+/// `1`         | `0x8d`
+/// `118`       | [`OnionReturn`](./struct.OnionReturn.html) of the second node
+/// variable    | Data, e.g. an announce response
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OnionResponse2 {
+    /// Return path, to be decrypted by the receiving node.
+    pub onion_return: OnionReturn,
+    /// Data for the sender of the request.
+    pub payload: Vec<u8>,
+}
+
+impl ToBytes for OnionResponse2 {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut result = Vec::with_capacity(ONION_RESPONSE_2_MIN_SIZE + self.payload.len());
+        result.push(PacketKind::OnionResp2 as u8);
+        result.extend_from_slice(&self.onion_return.to_bytes());
+        result.extend_from_slice(&self.payload);
+        result
+    }
+}
+
+/// De-serialization of `OnionResponse2`. Fails if packet kind is wrong, or there
+/// are less than [`ONION_RESPONSE_2_MIN_SIZE`]
+/// (./constant.ONION_RESPONSE_2_MIN_SIZE.html) or more than
+/// [`ONION_MAX_PACKET_SIZE`](./constant.ONION_MAX_PACKET_SIZE.html) bytes.
+impl FromBytes<OnionResponse2> for OnionResponse2 {
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        debug!(target: "Onion", "De-serializing OnionResponse2.");
+        if !check_packet(bytes, PacketKind::OnionResp2, ONION_RESPONSE_2_MIN_SIZE) {
+            return None
+        }
+        const PAYLOAD_POS: usize = 1 + ONION_RETURN_2_SIZE;
+        OnionReturn::from_bytes(&bytes[1..PAYLOAD_POS]).map(|r| OnionResponse2 {
+            onion_return: r,
+            payload: bytes[PAYLOAD_POS..].to_vec(),
+        })
+    }
+}
+
+
+/// Response sent by the second node of the path to the first one.
+///
+/// Packet kind [`PacketKind::OnionResp1`](../dht/enum.PacketKind.html).
+///
+/// Serialized form:
+///
+/// Length      | Contents
+/// ----------- | --------
+/// `1`         | `0x8e`
+/// `59`        | [`OnionReturn`](./struct.OnionReturn.html) of the first node
+/// variable    | Data, e.g. an announce response
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OnionResponse1 {
+    /// Return path, to be decrypted by the receiving node.
+    pub onion_return: OnionReturn,
+    /// Data for the sender of the request.
+    pub payload: Vec<u8>,
+}
+
+impl ToBytes for OnionResponse1 {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut result = Vec::with_capacity(ONION_RESPONSE_1_MIN_SIZE + self.payload.len());
+        result.push(PacketKind::OnionResp1 as u8);
+        result.extend_from_slice(&self.onion_return.to_bytes());
+        result.extend_from_slice(&self.payload);
+        result
+    }
+}
+
+/// De-serialization of `OnionResponse1`. Fails if packet kind is wrong, or there
+/// are less than [`ONION_RESPONSE_1_MIN_SIZE`]
+/// (./constant.ONION_RESPONSE_1_MIN_SIZE.html) or more than
+/// [`ONION_MAX_PACKET_SIZE`](./constant.ONION_MAX_PACKET_SIZE.html) bytes.
+impl FromBytes<OnionResponse1> for OnionResponse1 {
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        debug!(target: "Onion", "De-serializing OnionResponse1.");
+        if !check_packet(bytes, PacketKind::OnionResp1, ONION_RESPONSE_1_MIN_SIZE) {
+            return None
+        }
+        const PAYLOAD_POS: usize = 1 + ONION_RETURN_1_SIZE;
+        OnionReturn::from_bytes(&bytes[1..PAYLOAD_POS]).map(|r| OnionResponse1 {
+            onion_return: r,
+            payload: bytes[PAYLOAD_POS..].to_vec(),
+        })
+    }
+}
+
+
+/// Node's part in relaying onion requests and responses of others.
+///
+/// Relay doesn't keep any per-request state – address of the node that
+/// request came from is encrypted into an [`OnionReturn`]
+/// (./struct.OnionReturn.html) with relay's random symmetric key, created
+/// along with the relay.
+pub struct OnionRelay {
+    return_key: PrecomputedKey,
+}
+
+impl OnionRelay {
+    /// Create new `OnionRelay` with a new random key for return paths.
+    pub fn new() -> Self {
+        debug!(target: "Onion", "Creating new OnionRelay.");
+        OnionRelay { return_key: new_symmetric_key() }
+    }
+
+    /// Handle `OnionRequest0` received `from` the sender, as the first node
+    /// of the path. Returns `OnionRequest1` and the address of the second
+    /// node it should be sent to.
+    pub fn handle_request_0(&self, own_sk: &SecretKey, request: &OnionRequest0,
+                            from: SocketAddr) -> Option<(SocketAddr, OnionRequest1)> {
+        request.get_payload(own_sk).map(|payload| (payload.ip_port, OnionRequest1 {
+            nonce: request.nonce,
+            temporary_pk: payload.temporary_pk,
+            payload: payload.inner,
+            onion_return: OnionReturn::new(&self.return_key, &from, None),
+        }))
+    }
+
+    /// Handle `OnionRequest1` received `from` the first node, as the second
+    /// node of the path. Returns `OnionRequest2` and the address of the
+    /// third node it should be sent to.
+    pub fn handle_request_1(&self, own_sk: &SecretKey, request: &OnionRequest1,
+                            from: SocketAddr) -> Option<(SocketAddr, OnionRequest2)> {
+        request.get_payload(own_sk).map(|payload| (payload.ip_port, OnionRequest2 {
+            nonce: request.nonce,
+            temporary_pk: payload.temporary_pk,
+            payload: payload.inner,
+            onion_return: OnionReturn::new(&self.return_key, &from,
+                                           Some(&request.onion_return)),
+        }))
+    }
+
+    /// Handle `OnionRequest2` received `from` the second node, as the third
+    /// node of the path. Returns data with appended [`OnionReturn`]
+    /// (./struct.OnionReturn.html) of [`ONION_RETURN_3_SIZE`]
+    /// (./constant.ONION_RETURN_3_SIZE.html), and the address of the
+    /// destination it should be sent to.
+    pub fn handle_request_2(&self, own_sk: &SecretKey, request: &OnionRequest2,
+                            from: SocketAddr) -> Option<(SocketAddr, Vec<u8>)> {
+        request.get_payload(own_sk).map(|payload| {
+            let onion_return = OnionReturn::new(&self.return_key, &from,
+                                                Some(&request.onion_return));
+            let mut data = payload.data;
+            data.extend_from_slice(&onion_return.to_bytes());
+            (payload.ip_port, data)
+        })
+    }
+
+    /// Handle `OnionResponse3` as the third node of the path. Returns
+    /// `OnionResponse2` and the address of the second node it should be
+    /// sent to.
+    pub fn handle_response_3(&self, response: &OnionResponse3)
+        -> Option<(SocketAddr, OnionResponse2)>
+    {
+        match response.onion_return.get_payload(&self.return_key) {
+            Some((addr, Some(inner))) => Some((addr, OnionResponse2 {
+                onion_return: inner,
+                payload: response.payload.clone(),
+            })),
+            _ => {
+                debug!(target: "Onion", "Invalid OnionReturn in OnionResponse3!");
+                None
+            },
+        }
+    }
+
+    /// Handle `OnionResponse2` as the second node of the path. Returns
+    /// `OnionResponse1` and the address of the first node it should be sent
+    /// to.
+    pub fn handle_response_2(&self, response: &OnionResponse2)
+        -> Option<(SocketAddr, OnionResponse1)>
+    {
+        match response.onion_return.get_payload(&self.return_key) {
+            Some((addr, Some(inner))) => Some((addr, OnionResponse1 {
+                onion_return: inner,
+                payload: response.payload.clone(),
+            })),
+            _ => {
+                debug!(target: "Onion", "Invalid OnionReturn in OnionResponse2!");
+                None
+            },
+        }
+    }
+
+    /// Handle `OnionResponse1` as the first node of the path. Returns data
+    /// and the address of the sender of the request it should be sent to.
+    pub fn handle_response_1(&self, response: &OnionResponse1)
+        -> Option<(SocketAddr, Vec<u8>)>
+    {
+        match response.onion_return.get_payload(&self.return_key) {
+            Some((addr, None)) => Some((addr, response.payload.clone())),
+            _ => {
+                debug!(target: "Onion", "Invalid OnionReturn in OnionResponse1!");
+                None
+            },
+        }
+    }
+
+    /// Handle onion request or response received from `addr`, and send the
+    /// result further with `transport`.
+    ///
+    /// Returns `false` if packet isn't an onion request or response, or it
+    /// couldn't be handled.
+    pub fn handle_packet<T: Transport>(&self, transport: &T, own_sk: &SecretKey,
+                                       bytes: &[u8], addr: SocketAddr) -> bool {
+        trace!(target: "Onion", "Relaying onion packet from {}", addr);
+        let next = match PacketKind::from_bytes(bytes) {
+            Some(PacketKind::OnionReq0) => OnionRequest0::from_bytes(bytes)
+                .and_then(|r| self.handle_request_0(own_sk, &r, addr))
+                .map(|(a, r)| (a, r.to_bytes())),
+            Some(PacketKind::OnionReq1) => OnionRequest1::from_bytes(bytes)
+                .and_then(|r| self.handle_request_1(own_sk, &r, addr))
+                .map(|(a, r)| (a, r.to_bytes())),
+            Some(PacketKind::OnionReq2) => OnionRequest2::from_bytes(bytes)
+                .and_then(|r| self.handle_request_2(own_sk, &r, addr)),
+            Some(PacketKind::OnionResp3) => OnionResponse3::from_bytes(bytes)
+                .and_then(|r| self.handle_response_3(&r))
+                .map(|(a, r)| (a, r.to_bytes())),
+            Some(PacketKind::OnionResp2) => OnionResponse2::from_bytes(bytes)
+                .and_then(|r| self.handle_response_2(&r))
+                .map(|(a, r)| (a, r.to_bytes())),
+            Some(PacketKind::OnionResp1) => OnionResponse1::from_bytes(bytes)
+                .and_then(|r| self.handle_response_1(&r)),
+            _ => None,
+        };
+        match next {
+            Some((next_addr, data)) => match transport.send_to(&data, next_addr) {
+                Ok(_) => true,
+                Err(e) => {
+                    debug!(target: "Onion", "Failed to relay to {}: {}",
+                           next_addr, e);
+                    false
+                },
+            },
+            None => {
+                debug!(target: "Onion", "Failed to handle onion packet from {}",
+                       addr);
+                false
+            },
+        }
+    }
+}
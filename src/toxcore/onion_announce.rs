@@ -0,0 +1,837 @@
+/*
+    Copyright © 2016 Zetok Zalbavar <zexavexxe@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Onion announcements – nodes store announced long-term PKs of clients,
+//! along with the return path to them, so that friends can find them.
+//!
+//! https://toktok.github.io/spec.html#announce-request-and-response
+//!
+//! Announce requests are sent through an onion path, and answered with
+//! [`OnionResponse3`](../onion/struct.OnionResponse3.html).
+//!
+//! To announce itself, client first sends a request with a zero ping ID, and
+//! gets a valid ping ID in the response. Request with a valid ping ID, and
+//! searched PK same as the PK of the request, stores the announcement. Ping
+//! ID is derived from time, thus it has to be renewed periodically.
+
+use sodiumoxide::crypto::hash::sha256;
+use sodiumoxide::randombytes::randombytes_into;
+
+use std::cmp::Ordering;
+use std::net::SocketAddr;
+
+use toxcore::binary_io::*;
+use toxcore::crypto_core::*;
+use toxcore::dht::*;
+use toxcore::onion::*;
+
+
+/// Size in bytes of a ping ID used in announce requests.
+pub const ONION_PING_ID_SIZE: usize = 32;
+
+/// Ping ID used in announce requests.
+pub type PingId = [u8; ONION_PING_ID_SIZE];
+
+/// Ping ID used by clients that don't know a valid one, e.g. when searching.
+pub const ZERO_PING_ID: PingId = [0; ONION_PING_ID_SIZE];
+
+/// Number of seconds after which ping ID changes. Ping ID is valid for this
+/// long, and at most twice as long.
+pub const PING_ID_TIMEOUT: u64 = 20;
+
+/// Number of seconds after which an announcement that wasn't renewed is
+/// removed.
+pub const ONION_ANNOUNCE_TIMEOUT: u64 = 300;
+
+/// Maximum number of announcements stored by a node.
+pub const ONION_ANNOUNCE_MAX_ENTRIES: usize = 160;
+
+/// Maximum number of nodes sent in [`AnnounceResponsePayload`]
+/// (./struct.AnnounceResponsePayload.html).
+pub const ONION_ANNOUNCE_MAX_NODES: usize = 4;
+
+/// Size in bytes of serialized [`AnnounceRequestPayload`]
+/// (./struct.AnnounceRequestPayload.html).
+pub const ANNOUNCE_REQUEST_PAYLOAD_SIZE: usize = ONION_PING_ID_SIZE
+                                               + PUBLICKEYBYTES * 2
+                                               + 8;
+
+/// Size in bytes of serialized [`AnnounceRequest`]
+/// (./struct.AnnounceRequest.html).
+pub const ANNOUNCE_REQUEST_SIZE: usize = 1 // packet kind
+                                       + NONCEBYTES
+                                       + PUBLICKEYBYTES
+                                       + ANNOUNCE_REQUEST_PAYLOAD_SIZE
+                                       + MACBYTES;
+
+/// Minimal size in bytes of serialized [`AnnounceResponse`]
+/// (./struct.AnnounceResponse.html).
+pub const ANNOUNCE_RESPONSE_MIN_SIZE: usize = 1 // packet kind
+                                            + 8 // sendback data
+                                            + NONCEBYTES
+                                            + 1 // status
+                                            + ONION_PING_ID_SIZE
+                                            + MACBYTES;
+
+
+/// Decrypted payload of [`AnnounceRequest`](./struct.AnnounceRequest.html).
+///
+/// Serialized form:
+///
+/// Length | Contents
+/// ------ | --------
+/// `32`   | Ping ID
+/// `32`   | PK being announced or searched for
+/// `32`   | Data PK that others should use to send data to announced client
+/// `8`    | Sendback data, returned unchanged in the response
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct AnnounceRequestPayload {
+    /// Ping ID obtained from an earlier response, or [`ZERO_PING_ID`]
+    /// (./constant.ZERO_PING_ID.html).
+    pub ping_id: PingId,
+    /// Long-term PK being announced, or PK of a friend being searched for.
+    pub search_pk: PublicKey,
+    /// PK that others should use to encrypt data for the announced client.
+    pub data_pk: PublicKey,
+    /// Data returned unchanged in the response, to match it with the
+    /// request.
+    pub sendback_data: u64,
+}
+
+impl ToBytes for AnnounceRequestPayload {
+    fn to_bytes(&self) -> Vec<u8> {
+        let PublicKey(ref search_pk) = self.search_pk;
+        let PublicKey(ref data_pk) = self.data_pk;
+        let mut result = Vec::with_capacity(ANNOUNCE_REQUEST_PAYLOAD_SIZE);
+        result.extend_from_slice(&self.ping_id);
+        result.extend_from_slice(search_pk);
+        result.extend_from_slice(data_pk);
+        result.extend_from_slice(&u64_to_array(self.sendback_data));
+        result
+    }
+}
+
+impl FromBytes<AnnounceRequestPayload> for AnnounceRequestPayload {
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != ANNOUNCE_REQUEST_PAYLOAD_SIZE {
+            debug!(target: "Announce", "Wrong size of AnnounceRequestPayload!");
+            return None
+        }
+        const SEARCH_POS: usize = ONION_PING_ID_SIZE;
+        const DATA_POS: usize = SEARCH_POS + PUBLICKEYBYTES;
+        const SENDBACK_POS: usize = DATA_POS + PUBLICKEYBYTES;
+        let mut ping_id = [0; ONION_PING_ID_SIZE];
+        ping_id.copy_from_slice(&bytes[..SEARCH_POS]);
+        let mut sendback = [0; 8];
+        sendback.copy_from_slice(&bytes[SENDBACK_POS..]);
+        let search_pk = PublicKey::from_slice(&bytes[SEARCH_POS..DATA_POS]);
+        let data_pk = PublicKey::from_slice(&bytes[DATA_POS..SENDBACK_POS]);
+        match (search_pk, data_pk) {
+            (Some(search_pk), Some(data_pk)) => Some(AnnounceRequestPayload {
+                ping_id: ping_id,
+                search_pk: search_pk,
+                data_pk: data_pk,
+                sendback_data: array_to_u64(&sendback),
+            }),
+            _ => None,
+        }
+    }
+}
+
+
+/// Announce request, sent through an onion path to the node that should
+/// store the announcement, or that is asked for the announced friend.
+///
+/// Packet kind [`PacketKind::AnnReq`](../dht/enum.PacketKind.html).
+///
+/// Serialized form:
+///
+/// Length | Contents
+/// ------ | --------
+/// `1`    | `0x83`
+/// `24`   | Nonce
+/// `32`   | Long-term PK when announcing, temporary PK when searching
+/// `120`  | Encrypted [`AnnounceRequestPayload`](./struct.AnnounceRequestPayload.html)
+///
+/// When received by the announce node, it's followed by the
+/// [`OnionReturn`](../onion/struct.OnionReturn.html) of the third node of
+/// the path.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AnnounceRequest {
+    /// Nonce used to encrypt the payload.
+    pub nonce: Nonce,
+    /// PK of the sender, that payload was encrypted with.
+    pub pk: PublicKey,
+    /// Encrypted payload.
+    pub payload: Vec<u8>,
+}
+
+impl AnnounceRequest {
+    /// Create new `AnnounceRequest` for the node with `node_pk`, encrypted
+    /// with own keypair.
+    pub fn new(own_pk: &PublicKey, own_sk: &SecretKey, node_pk: &PublicKey,
+               payload: &AnnounceRequestPayload) -> Self {
+        let nonce = gen_nonce();
+        let precomp = encrypt_precompute(node_pk, own_sk);
+        AnnounceRequest {
+            nonce: nonce,
+            pk: *own_pk,
+            payload: encrypt_data_symmetric(&precomp, &nonce, &payload.to_bytes()),
+        }
+    }
+
+    /// Decrypt payload with own DHT SK.
+    ///
+    /// Returns `None` if decryption or parsing fails.
+    pub fn get_payload(&self, own_sk: &SecretKey) -> Option<AnnounceRequestPayload> {
+        match open(&self.payload, &self.nonce, &self.pk, own_sk) {
+            Ok(p) => AnnounceRequestPayload::from_bytes(&p),
+            Err(_) => {
+                debug!(target: "Announce", "Decrypting AnnounceRequest failed!");
+                None
+            },
+        }
+    }
+}
+
+impl ToBytes for AnnounceRequest {
+    fn to_bytes(&self) -> Vec<u8> {
+        let Nonce(ref nonce) = self.nonce;
+        let PublicKey(ref pk) = self.pk;
+        let mut result = Vec::with_capacity(ANNOUNCE_REQUEST_SIZE);
+        result.push(PacketKind::AnnReq as u8);
+        result.extend_from_slice(nonce);
+        result.extend_from_slice(pk);
+        result.extend_from_slice(&self.payload);
+        result
+    }
+}
+
+/// De-serialization of `AnnounceRequest`, without the `OnionReturn`. Fails
+/// if packet kind is wrong, or size isn't [`ANNOUNCE_REQUEST_SIZE`]
+/// (./constant.ANNOUNCE_REQUEST_SIZE.html).
+impl FromBytes<AnnounceRequest> for AnnounceRequest {
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        debug!(target: "Announce", "De-serializing AnnounceRequest.");
+        if bytes.len() != ANNOUNCE_REQUEST_SIZE
+            || PacketKind::from_bytes(bytes) != Some(PacketKind::AnnReq) {
+            debug!("Not an AnnounceRequest!");
+            return None
+        }
+        const PK_POS: usize = 1 + NONCEBYTES;
+        const PAYLOAD_POS: usize = PK_POS + PUBLICKEYBYTES;
+        let nonce = Nonce::from_slice(&bytes[1..PK_POS]);
+        let pk = PublicKey::from_slice(&bytes[PK_POS..PAYLOAD_POS]);
+        match (nonce, pk) {
+            (Some(nonce), Some(pk)) => Some(AnnounceRequest {
+                nonce: nonce,
+                pk: pk,
+                payload: bytes[PAYLOAD_POS..].to_vec(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+
+/// Status of the announcement or search in [`AnnounceResponsePayload`]
+/// (./struct.AnnounceResponsePayload.html).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AnnounceStatus {
+    /// Announcement wasn't stored, or searched PK wasn't found. Response
+    /// carries ping ID to use.
+    Failed = 0,
+    /// Searched PK was found. Response carries its data PK.
+    Found = 1,
+    /// Announcement is stored. Response carries ping ID to use to renew it.
+    Announced = 2,
+}
+
+/// Decrypted payload of [`AnnounceResponse`](./struct.AnnounceResponse.html).
+///
+/// Serialized form:
+///
+/// Length      | Contents
+/// ----------- | --------
+/// `1`         | [`AnnounceStatus`](./enum.AnnounceStatus.html)
+/// `32`        | Ping ID, or data PK if status is `Found`
+/// variable    | Up to 4 [`PackedNode`](../dht/struct.PackedNode.html)s closest to the searched PK
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AnnounceResponsePayload {
+    /// Status of the announcement or search.
+    pub status: AnnounceStatus,
+    /// Ping ID to use, or data PK of the found client.
+    pub ping_id_or_pk: [u8; ONION_PING_ID_SIZE],
+    /// Nodes closest to the searched PK known to the announce node.
+    pub nodes: Vec<PackedNode>,
+}
+
+impl ToBytes for AnnounceResponsePayload {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut result = vec![self.status as u8];
+        result.extend_from_slice(&self.ping_id_or_pk);
+        for node in &self.nodes {
+            result.extend_from_slice(&node.to_bytes());
+        }
+        result
+    }
+}
+
+/// De-serialization of `AnnounceResponsePayload`. Fails if status is unknown,
+/// there are not enough bytes, or nodes can't be parsed.
+impl FromBytes<AnnounceResponsePayload> for AnnounceResponsePayload {
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        const NODES_POS: usize = 1 + ONION_PING_ID_SIZE;
+        if bytes.len() < NODES_POS {
+            debug!(target: "Announce", "Not enough bytes for AnnounceResponsePayload!");
+            return None
+        }
+        let status = match bytes[0] {
+            0 => AnnounceStatus::Failed,
+            1 => AnnounceStatus::Found,
+            2 => AnnounceStatus::Announced,
+            s => {
+                debug!(target: "Announce", "Unknown AnnounceStatus: {}", s);
+                return None
+            },
+        };
+        let nodes = if bytes.len() == NODES_POS {
+            vec![]
+        } else {
+            match PackedNode::from_bytes_multiple(&bytes[NODES_POS..]) {
+                Some(n) if n.len() <= ONION_ANNOUNCE_MAX_NODES => n,
+                _ => {
+                    debug!(target: "Announce", "Invalid nodes in AnnounceResponse!");
+                    return None
+                },
+            }
+        };
+        let mut ping_id_or_pk = [0; ONION_PING_ID_SIZE];
+        ping_id_or_pk.copy_from_slice(&bytes[1..NODES_POS]);
+        Some(AnnounceResponsePayload {
+            status: status,
+            ping_id_or_pk: ping_id_or_pk,
+            nodes: nodes,
+        })
+    }
+}
+
+
+/// Response to [`AnnounceRequest`](./struct.AnnounceRequest.html), sent
+/// back through the onion path.
+///
+/// Packet kind [`PacketKind::AnnResp`](../dht/enum.PacketKind.html).
+///
+/// Serialized form:
+///
+/// Length      | Contents
+/// ----------- | --------
+/// `1`         | `0x84`
+/// `8`         | Sendback data from the request
+/// `24`        | Nonce
+/// variable    | Encrypted [`AnnounceResponsePayload`](./struct.AnnounceResponsePayload.html)
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AnnounceResponse {
+    /// Sendback data from the request.
+    pub sendback_data: u64,
+    /// Nonce used to encrypt the payload.
+    pub nonce: Nonce,
+    /// Encrypted payload.
+    pub payload: Vec<u8>,
+}
+
+impl AnnounceResponse {
+    /// Create new `AnnounceResponse` encrypted for the `receiver_pk` with own
+    /// DHT SK.
+    pub fn new(own_sk: &SecretKey, receiver_pk: &PublicKey, sendback_data: u64,
+               payload: &AnnounceResponsePayload) -> Self {
+        let nonce = gen_nonce();
+        let precomp = encrypt_precompute(receiver_pk, own_sk);
+        AnnounceResponse {
+            sendback_data: sendback_data,
+            nonce: nonce,
+            payload: encrypt_data_symmetric(&precomp, &nonce, &payload.to_bytes()),
+        }
+    }
+
+    /// Decrypt payload with the SK that request was sent with, and the DHT
+    /// PK of the announce node.
+    ///
+    /// Returns `None` if decryption or parsing fails.
+    pub fn get_payload(&self, node_pk: &PublicKey, own_sk: &SecretKey)
+        -> Option<AnnounceResponsePayload>
+    {
+        match open(&self.payload, &self.nonce, node_pk, own_sk) {
+            Ok(p) => AnnounceResponsePayload::from_bytes(&p),
+            Err(_) => {
+                debug!(target: "Announce", "Decrypting AnnounceResponse failed!");
+                None
+            },
+        }
+    }
+}
+
+impl ToBytes for AnnounceResponse {
+    fn to_bytes(&self) -> Vec<u8> {
+        let Nonce(ref nonce) = self.nonce;
+        let mut result = Vec::with_capacity(ANNOUNCE_RESPONSE_MIN_SIZE
+                                            + self.payload.len());
+        result.push(PacketKind::AnnResp as u8);
+        result.extend_from_slice(&u64_to_array(self.sendback_data));
+        result.extend_from_slice(nonce);
+        result.extend_from_slice(&self.payload);
+        result
+    }
+}
+
+/// De-serialization of `AnnounceResponse`. Fails if packet kind is wrong,
+/// or there are less than [`ANNOUNCE_RESPONSE_MIN_SIZE`]
+/// (./constant.ANNOUNCE_RESPONSE_MIN_SIZE.html) bytes.
+impl FromBytes<AnnounceResponse> for AnnounceResponse {
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        debug!(target: "Announce", "De-serializing AnnounceResponse.");
+        if bytes.len() < ANNOUNCE_RESPONSE_MIN_SIZE
+            || PacketKind::from_bytes(bytes) != Some(PacketKind::AnnResp) {
+            debug!("Not an AnnounceResponse!");
+            return None
+        }
+        const NONCE_POS: usize = 1 + 8;
+        const PAYLOAD_POS: usize = NONCE_POS + NONCEBYTES;
+        let mut sendback = [0; 8];
+        sendback.copy_from_slice(&bytes[1..NONCE_POS]);
+        Nonce::from_slice(&bytes[NONCE_POS..PAYLOAD_POS]).map(|nonce| AnnounceResponse {
+            sendback_data: array_to_u64(&sendback),
+            nonce: nonce,
+            payload: bytes[PAYLOAD_POS..].to_vec(),
+        })
+    }
+}
+
+
+/// Announcement stored by the announce node.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AnnounceEntry {
+    /// Announced long-term PK.
+    pub pk: PublicKey,
+    /// PK that data for the announced client should be encrypted with.
+    pub data_pk: PublicKey,
+    /// Address of the third node of the path to the announced client.
+    pub return_addr: SocketAddr,
+    /// Return path to the announced client.
+    pub onion_return: OnionReturn,
+    /// Time in seconds when the announcement was last renewed.
+    pub time: u64,
+}
+
+/// Announce node – stores announcements closest to own DHT PK, and answers
+/// announce requests.
+pub struct AnnounceServer {
+    pk: PublicKey,
+    secret: [u8; 32],
+    /// Sorted by distance to own PK, closest first.
+    entries: Vec<AnnounceEntry>,
+}
+
+impl AnnounceServer {
+    /// Create new `AnnounceServer` for the node with own DHT PK, with a new
+    /// random secret for ping IDs.
+    pub fn new(own_pk: &PublicKey) -> Self {
+        debug!(target: "Announce", "Creating new AnnounceServer.");
+        let mut secret = [0; 32];
+        randombytes_into(&mut secret);
+        AnnounceServer { pk: *own_pk, secret: secret, entries: Vec::new() }
+    }
+
+    /// Ping ID for the client with `pk` at `addr`, valid at `time`: hash of
+    /// the own secret, the time window, the `pk` and `addr`.
+    pub fn ping_id(&self, time: u64, pk: &PublicKey, addr: &SocketAddr) -> PingId {
+        let PublicKey(ref pk) = *pk;
+        let mut data = self.secret.to_vec();
+        data.extend_from_slice(&u64_to_array(time / PING_ID_TIMEOUT));
+        data.extend_from_slice(pk);
+        data.extend_from_slice(&ip_port_to_bytes(addr));
+        let sha256::Digest(hash) = sha256::hash(&data);
+        hash
+    }
+
+    /// Stored announcements that haven't expired at `now`, closest to own PK
+    /// first.
+    pub fn entries(&self, now: u64) -> Vec<&AnnounceEntry> {
+        self.entries.iter()
+            .filter(|e| e.time + ONION_ANNOUNCE_TIMEOUT > now)
+            .collect()
+    }
+
+    /// Get announcement of the `pk`, if it's stored and hasn't expired at
+    /// `now`.
+    pub fn get_entry(&self, pk: &PublicKey, now: u64) -> Option<&AnnounceEntry> {
+        self.entries.iter()
+            .find(|e| &e.pk == pk && e.time + ONION_ANNOUNCE_TIMEOUT > now)
+    }
+
+    /// Store the announcement, if there's room for it, or it's closer to own
+    /// PK than the furthest stored one. Returns `false` if it wasn't stored.
+    fn add_entry(&mut self, entry: AnnounceEntry, now: u64) -> bool {
+        self.entries.retain(|e| e.time + ONION_ANNOUNCE_TIMEOUT > now);
+        if let Some(e) = self.entries.iter_mut().find(|e| e.pk == entry.pk) {
+            *e = entry;
+            return true
+        }
+        let pos = self.entries.iter()
+            .position(|e| self.pk.distance(&entry.pk, &e.pk) == Ordering::Less)
+            .unwrap_or(self.entries.len());
+        if pos >= ONION_ANNOUNCE_MAX_ENTRIES {
+            debug!(target: "Announce", "Announcement is too far to store.");
+            return false
+        }
+        self.entries.insert(pos, entry);
+        self.entries.truncate(ONION_ANNOUNCE_MAX_ENTRIES);
+        true
+    }
+
+    /// Handle `request` received from `addr` – the third node of the path –
+    /// with the `onion_return` that came with it.
+    ///
+    /// Request with a ping ID valid for the requester, and searched PK same
+    /// as the requester's PK, is stored as an announcement.
+    ///
+    /// Returns response to be sent back in [`OnionResponse3`]
+    /// (../onion/struct.OnionResponse3.html) with the `onion_return`, or
+    /// `None` if request can't be decrypted.
+    pub fn handle_request(&mut self, own_sk: &SecretKey,
+                          request: &AnnounceRequest, onion_return: &OnionReturn,
+                          addr: SocketAddr, kbucket: &Kbucket, now: u64)
+        -> Option<AnnounceResponse>
+    {
+        debug!(target: "Announce", "Handling AnnounceRequest from {}", addr);
+        let payload = match request.get_payload(own_sk) {
+            Some(p) => p,
+            None => return None,
+        };
+
+        let ping_id_1 = self.ping_id(now, &request.pk, &addr);
+        let ping_id_2 = self.ping_id(now + PING_ID_TIMEOUT, &request.pk, &addr);
+        let valid_ping = payload.ping_id == ping_id_1 || payload.ping_id == ping_id_2;
+        if valid_ping && payload.search_pk == request.pk {
+            let entry = AnnounceEntry {
+                pk: request.pk,
+                data_pk: payload.data_pk,
+                return_addr: addr,
+                onion_return: onion_return.clone(),
+                time: now,
+            };
+            self.add_entry(entry, now);
+        }
+
+        let (status, ping_id_or_pk) = match self.get_entry(&payload.search_pk, now) {
+            None => (AnnounceStatus::Failed, ping_id_2),
+            Some(e) if e.pk == request.pk => {
+                if e.data_pk == payload.data_pk {
+                    (AnnounceStatus::Announced, ping_id_2)
+                } else {
+                    (AnnounceStatus::Failed, ping_id_2)
+                }
+            },
+            Some(e) => {
+                let PublicKey(data_pk) = e.data_pk;
+                (AnnounceStatus::Found, data_pk)
+            },
+        };
+        let response = AnnounceResponsePayload {
+            status: status,
+            ping_id_or_pk: ping_id_or_pk,
+            nodes: kbucket.get_closest(&payload.search_pk, ONION_ANNOUNCE_MAX_NODES,
+                                       now),
+        };
+        Some(AnnounceResponse::new(own_sk, &request.pk, payload.sendback_data,
+                                   &response))
+    }
+
+    /// Handle `OnionDataRequest` for an announced client, that came with
+    /// the [`OnionReturn`](../onion/struct.OnionReturn.html) of the sender's
+    /// path.
+    ///
+    /// Returns [`OnionResponse3`](../onion/struct.OnionResponse3.html) with
+    /// [`OnionDataResponse`](./struct.OnionDataResponse.html), to be sent
+    /// through the return path stored with the announcement, and the address
+    /// it should be sent to. Returns `None` if destination isn't announced.
+    pub fn handle_data_request(&self, request: &OnionDataRequest, now: u64)
+        -> Option<(SocketAddr, OnionResponse3)>
+    {
+        debug!(target: "Announce", "Handling OnionDataRequest.");
+        let entry = match self.get_entry(&request.destination_pk, now) {
+            Some(e) => e,
+            None => {
+                debug!(target: "Announce", "Destination of data isn't announced.");
+                return None
+            },
+        };
+        let response = OnionDataResponse {
+            nonce: request.nonce,
+            temporary_pk: request.temporary_pk,
+            payload: request.payload.clone(),
+        };
+        Some((entry.return_addr, OnionResponse3 {
+            onion_return: entry.onion_return.clone(),
+            payload: response.to_bytes(),
+        }))
+    }
+}
+
+
+/// Minimal size in bytes of serialized [`OnionDataRequest`]
+/// (./struct.OnionDataRequest.html).
+pub const ONION_DATA_REQUEST_MIN_SIZE: usize = 1 // packet kind
+                                             + PUBLICKEYBYTES // destination
+                                             + NONCEBYTES
+                                             + PUBLICKEYBYTES // temporary
+                                             + PUBLICKEYBYTES // sender
+                                             + MACBYTES * 2;
+
+/// Minimal size in bytes of serialized [`OnionDataResponse`]
+/// (./struct.OnionDataResponse.html).
+pub const ONION_DATA_RESPONSE_MIN_SIZE: usize = ONION_DATA_REQUEST_MIN_SIZE
+                                              - PUBLICKEYBYTES;
+
+/// Encrypt `data` from the sender to the receiver, with the layer that
+/// only the receiver's data SK can open. Returns temporary PK and the
+/// encrypted payload of [`OnionDataRequest`](./struct.OnionDataRequest.html)
+/// and [`OnionDataResponse`](./struct.OnionDataResponse.html).
+fn seal_onion_data(nonce: &Nonce, receiver_pk: &PublicKey,
+                   receiver_data_pk: &PublicKey, sender_pk: &PublicKey,
+                   sender_sk: &SecretKey, data: &[u8]) -> (PublicKey, Vec<u8>) {
+    let PublicKey(ref sender) = *sender_pk;
+    let mut inner = sender.to_vec();
+    let precomp = encrypt_precompute(receiver_pk, sender_sk);
+    inner.extend_from_slice(&encrypt_data_symmetric(&precomp, nonce, data));
+    let (temporary_pk, temporary_sk) = gen_keypair();
+    let precomp = encrypt_precompute(receiver_data_pk, &temporary_sk);
+    (temporary_pk, encrypt_data_symmetric(&precomp, nonce, &inner))
+}
+
+
+/// Data sent to a client through the announce node that stores its
+/// announcement. Announce node passes it to the client as
+/// [`OnionDataResponse`](./struct.OnionDataResponse.html).
+///
+/// Packet kind [`PacketKind::OnionDataReq`](../dht/enum.PacketKind.html).
+///
+/// Serialized form:
+///
+/// Length      | Contents
+/// ----------- | --------
+/// `1`         | `0x85`
+/// `32`        | Long-term PK of the receiver
+/// `24`        | Nonce
+/// `32`        | Temporary PK
+/// variable    | Payload
+///
+/// Payload is encrypted with the temporary SK and the data PK of the
+/// receiver:
+///
+/// Length      | Contents
+/// ----------- | --------
+/// `32`        | Long-term PK of the sender
+/// variable    | Data, encrypted with the long-term keys of the sender and the receiver
+///
+/// When received by the announce node, it's followed by the
+/// [`OnionReturn`](../onion/struct.OnionReturn.html) of the third node of
+/// the path.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OnionDataRequest {
+    /// Long-term PK of the receiver.
+    pub destination_pk: PublicKey,
+    /// Nonce used for both layers of encryption.
+    pub nonce: Nonce,
+    /// Temporary PK that payload was encrypted with.
+    pub temporary_pk: PublicKey,
+    /// Encrypted payload.
+    pub payload: Vec<u8>,
+}
+
+impl OnionDataRequest {
+    /// Create new `OnionDataRequest` with `data` for the client with
+    /// long-term `destination_pk`, that announced `destination_data_pk`.
+    pub fn new(destination_pk: &PublicKey, destination_data_pk: &PublicKey,
+               own_pk: &PublicKey, own_sk: &SecretKey, data: &[u8]) -> Self {
+        let nonce = gen_nonce();
+        let (temporary_pk, payload) = seal_onion_data(&nonce, destination_pk,
+                                                      destination_data_pk,
+                                                      own_pk, own_sk, data);
+        OnionDataRequest {
+            destination_pk: *destination_pk,
+            nonce: nonce,
+            temporary_pk: temporary_pk,
+            payload: payload,
+        }
+    }
+}
+
+impl ToBytes for OnionDataRequest {
+    fn to_bytes(&self) -> Vec<u8> {
+        let PublicKey(ref destination_pk) = self.destination_pk;
+        let Nonce(ref nonce) = self.nonce;
+        let PublicKey(ref temporary_pk) = self.temporary_pk;
+        let mut result = Vec::with_capacity(ONION_DATA_REQUEST_MIN_SIZE
+                                            + self.payload.len());
+        result.push(PacketKind::OnionDataReq as u8);
+        result.extend_from_slice(destination_pk);
+        result.extend_from_slice(nonce);
+        result.extend_from_slice(temporary_pk);
+        result.extend_from_slice(&self.payload);
+        result
+    }
+}
+
+/// De-serialization of `OnionDataRequest`, without the `OnionReturn`. Fails
+/// if packet kind is wrong, or there are less than
+/// [`ONION_DATA_REQUEST_MIN_SIZE`]
+/// (./constant.ONION_DATA_REQUEST_MIN_SIZE.html) or more than
+/// [`ONION_MAX_PACKET_SIZE`](../onion/constant.ONION_MAX_PACKET_SIZE.html)
+/// bytes.
+impl FromBytes<OnionDataRequest> for OnionDataRequest {
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        debug!(target: "Announce", "De-serializing OnionDataRequest.");
+        if bytes.len() < ONION_DATA_REQUEST_MIN_SIZE
+            || bytes.len() > ONION_MAX_PACKET_SIZE
+            || PacketKind::from_bytes(bytes) != Some(PacketKind::OnionDataReq) {
+            debug!("Not an OnionDataRequest!");
+            return None
+        }
+        const NONCE_POS: usize = 1 + PUBLICKEYBYTES;
+        const TEMPORARY_POS: usize = NONCE_POS + NONCEBYTES;
+        const PAYLOAD_POS: usize = TEMPORARY_POS + PUBLICKEYBYTES;
+        let destination_pk = PublicKey::from_slice(&bytes[1..NONCE_POS]);
+        let nonce = Nonce::from_slice(&bytes[NONCE_POS..TEMPORARY_POS]);
+        let temporary_pk = PublicKey::from_slice(&bytes[TEMPORARY_POS..PAYLOAD_POS]);
+        match (destination_pk, nonce, temporary_pk) {
+            (Some(destination_pk), Some(nonce), Some(temporary_pk)) =>
+                Some(OnionDataRequest {
+                    destination_pk: destination_pk,
+                    nonce: nonce,
+                    temporary_pk: temporary_pk,
+                    payload: bytes[PAYLOAD_POS..].to_vec(),
+                }),
+            _ => None,
+        }
+    }
+}
+
+
+/// [`OnionDataRequest`](./struct.OnionDataRequest.html) passed by the
+/// announce node to the announced client, through the return path stored
+/// with the announcement.
+///
+/// Packet kind [`PacketKind::OnionDataResp`](../dht/enum.PacketKind.html).
+///
+/// Serialized form:
+///
+/// Length      | Contents
+/// ----------- | --------
+/// `1`         | `0x86`
+/// `24`        | Nonce
+/// `32`        | Temporary PK
+/// variable    | Payload, same as in `OnionDataRequest`
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OnionDataResponse {
+    /// Nonce used for both layers of encryption.
+    pub nonce: Nonce,
+    /// Temporary PK that payload was encrypted with.
+    pub temporary_pk: PublicKey,
+    /// Encrypted payload.
+    pub payload: Vec<u8>,
+}
+
+impl OnionDataResponse {
+    /// Decrypt payload with own data SK, and the data with own long-term
+    /// SK.
+    ///
+    /// Returns long-term PK of the sender and the data, or `None` if
+    /// decryption fails.
+    pub fn get_payload(&self, data_sk: &SecretKey, own_sk: &SecretKey)
+        -> Option<(PublicKey, Vec<u8>)>
+    {
+        let inner = match open(&self.payload, &self.nonce, &self.temporary_pk,
+                               data_sk) {
+            Ok(inner) => inner,
+            Err(_) => {
+                debug!(target: "Announce", "Decrypting OnionDataResponse failed!");
+                return None
+            },
+        };
+        if inner.len() < PUBLICKEYBYTES + MACBYTES {
+            debug!(target: "Announce", "Not enough bytes in OnionDataResponse!");
+            return None
+        }
+        let sender_pk = match PublicKey::from_slice(&inner[..PUBLICKEYBYTES]) {
+            Some(pk) => pk,
+            None => return None,
+        };
+        match open(&inner[PUBLICKEYBYTES..], &self.nonce, &sender_pk, own_sk) {
+            Ok(data) => Some((sender_pk, data)),
+            Err(_) => {
+                debug!(target: "Announce", "Decrypting onion data failed!");
+                None
+            },
+        }
+    }
+}
+
+impl ToBytes for OnionDataResponse {
+    fn to_bytes(&self) -> Vec<u8> {
+        let Nonce(ref nonce) = self.nonce;
+        let PublicKey(ref temporary_pk) = self.temporary_pk;
+        let mut result = Vec::with_capacity(ONION_DATA_RESPONSE_MIN_SIZE
+                                            + self.payload.len());
+        result.push(PacketKind::OnionDataResp as u8);
+        result.extend_from_slice(nonce);
+        result.extend_from_slice(temporary_pk);
+        result.extend_from_slice(&self.payload);
+        result
+    }
+}
+
+/// De-serialization of `OnionDataResponse`. Fails if packet kind is wrong,
+/// or there are less than [`ONION_DATA_RESPONSE_MIN_SIZE`]
+/// (./constant.ONION_DATA_RESPONSE_MIN_SIZE.html) or more than
+/// [`ONION_MAX_PACKET_SIZE`](../onion/constant.ONION_MAX_PACKET_SIZE.html)
+/// bytes.
+impl FromBytes<OnionDataResponse> for OnionDataResponse {
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        debug!(target: "Announce", "De-serializing OnionDataResponse.");
+        if bytes.len() < ONION_DATA_RESPONSE_MIN_SIZE
+            || bytes.len() > ONION_MAX_PACKET_SIZE
+            || PacketKind::from_bytes(bytes) != Some(PacketKind::OnionDataResp) {
+            debug!("Not an OnionDataResponse!");
+            return None
+        }
+        const TEMPORARY_POS: usize = 1 + NONCEBYTES;
+        const PAYLOAD_POS: usize = TEMPORARY_POS + PUBLICKEYBYTES;
+        let nonce = Nonce::from_slice(&bytes[1..TEMPORARY_POS]);
+        let temporary_pk = PublicKey::from_slice(&bytes[TEMPORARY_POS..PAYLOAD_POS]);
+        match (nonce, temporary_pk) {
+            (Some(nonce), Some(temporary_pk)) => Some(OnionDataResponse {
+                nonce: nonce,
+                temporary_pk: temporary_pk,
+                payload: bytes[PAYLOAD_POS..].to_vec(),
+            }),
+            _ => None,
+        }
+    }
+}
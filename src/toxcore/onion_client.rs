@@ -0,0 +1,504 @@
+/*
+    Copyright © 2016 Zetok Zalbavar <zexavexxe@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Onion client – announces own long-term PK to the nodes closest to it,
+//! through onion paths, so that friends can find us without learning our
+//! IP address.
+//!
+//! https://toktok.github.io/spec.html#onion-client
+//!
+//! Client doesn't do any networking by itself – it tells which
+//! [`AnnounceRequest`](../onion_announce/struct.AnnounceRequest.html)s
+//! should be sent to which nodes, and has to be fed with received
+//! [`AnnounceResponse`](../onion_announce/struct.AnnounceResponse.html)s.
+//!
+//! Nodes closest to own PK are found iteratively – each response carries
+//! nodes known to the announce node that are closest to own PK, and they
+//! are asked in turn.
+
+use std::cmp::Ordering;
+
+use toxcore::binary_io::*;
+use toxcore::crypto_core::*;
+use toxcore::dht::*;
+use toxcore::network::Transport;
+use toxcore::onion::*;
+use toxcore::onion_announce::*;
+use toxcore::onion_paths::OnionPaths;
+
+
+/// Number of nodes closest to own PK that client announces itself to.
+pub const ONION_CLIENT_MAX_NODES: usize = 8;
+
+/// Number of seconds between announce requests to a node that doesn't
+/// store our announcement (yet).
+pub const ANNOUNCE_INTERVAL_NOT_ANNOUNCED: u64 = 3;
+
+/// Number of seconds between announce requests to a node that stores our
+/// announcement. Has to be lower than [`PING_ID_TIMEOUT`]
+/// (../onion_announce/constant.PING_ID_TIMEOUT.html), so that ping ID is
+/// renewed before it expires.
+pub const ANNOUNCE_INTERVAL_ANNOUNCED: u64 = 15;
+
+/// Number of seconds after which response to an announce request is not
+/// accepted anymore.
+pub const ANNOUNCE_REQUEST_TIMEOUT: u64 = 10;
+
+/// Number of announce requests in a row without response after which node
+/// is removed.
+pub const ANNOUNCE_MAX_TRIES: u8 = 3;
+
+/// Kind of onion data with [`DhtPkAnnounce`](./struct.DhtPkAnnounce.html).
+pub const ONION_DATA_DHT_PK: u8 = 156;
+
+/// Minimal size in bytes of serialized [`DhtPkAnnounce`]
+/// (./struct.DhtPkAnnounce.html).
+pub const DHT_PK_ANNOUNCE_MIN_SIZE: usize = 1 // kind
+                                          + 8 // no_replay
+                                          + PUBLICKEYBYTES;
+
+/// Maximum number of nodes in [`DhtPkAnnounce`](./struct.DhtPkAnnounce.html).
+pub const DHT_PK_ANNOUNCE_MAX_NODES: usize = 4;
+
+
+/// Onion data that tells a friend our current DHT PK, and nodes close to
+/// us, that friend can ask for our address.
+///
+/// Sent in [`OnionDataRequest`]
+/// (../onion_announce/struct.OnionDataRequest.html), so that it's
+/// authenticated with our long-term PK.
+///
+/// Serialized form:
+///
+/// Length      | Contents
+/// ----------- | --------
+/// `1`         | `0x9c`
+/// `8`         | `no_replay` in big endian
+/// `32`        | DHT PK
+/// variable    | Up to 4 [`PackedNode`](../dht/struct.PackedNode.html)s
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DhtPkAnnounce {
+    /// Number that increases with every new announcement, e.g. time. Older
+    /// announcements are ignored.
+    pub no_replay: u64,
+    /// Current DHT PK of the sender.
+    pub dht_pk: PublicKey,
+    /// Nodes close to the sender.
+    pub nodes: Vec<PackedNode>,
+}
+
+impl ToBytes for DhtPkAnnounce {
+    fn to_bytes(&self) -> Vec<u8> {
+        let PublicKey(ref dht_pk) = self.dht_pk;
+        let mut result = Vec::with_capacity(DHT_PK_ANNOUNCE_MIN_SIZE);
+        result.push(ONION_DATA_DHT_PK);
+        result.extend_from_slice(&u64_to_array(self.no_replay.to_be()));
+        result.extend_from_slice(dht_pk);
+        for node in self.nodes.iter().take(DHT_PK_ANNOUNCE_MAX_NODES) {
+            result.extend_from_slice(&node.to_bytes());
+        }
+        result
+    }
+}
+
+/// De-serialization of `DhtPkAnnounce`. Fails if kind is wrong, there are
+/// not enough bytes, or nodes can't be parsed.
+impl FromBytes<DhtPkAnnounce> for DhtPkAnnounce {
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        debug!(target: "AnnounceClient", "De-serializing DhtPkAnnounce.");
+        if bytes.len() < DHT_PK_ANNOUNCE_MIN_SIZE || bytes[0] != ONION_DATA_DHT_PK {
+            debug!("Not a DhtPkAnnounce!");
+            return None
+        }
+        const PK_POS: usize = 1 + 8;
+        let nodes = if bytes.len() == DHT_PK_ANNOUNCE_MIN_SIZE {
+            vec![]
+        } else {
+            match PackedNode::from_bytes_multiple(&bytes[DHT_PK_ANNOUNCE_MIN_SIZE..]) {
+                Some(n) if n.len() <= DHT_PK_ANNOUNCE_MAX_NODES => n,
+                _ => {
+                    debug!(target: "AnnounceClient", "Invalid nodes in DhtPkAnnounce!");
+                    return None
+                },
+            }
+        };
+        let mut no_replay = [0; 8];
+        no_replay.copy_from_slice(&bytes[1..PK_POS]);
+        PublicKey::from_slice(&bytes[PK_POS..DHT_PK_ANNOUNCE_MIN_SIZE])
+            .map(|dht_pk| DhtPkAnnounce {
+                no_replay: u64::from_be(array_to_u64(&no_replay)),
+                dht_pk: dht_pk,
+                nodes: nodes,
+            })
+    }
+}
+
+
+/// Node that client announces itself to.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct AnnounceNode {
+    /// The announce node.
+    pub node: PackedNode,
+    /// Ping ID from the last response, or [`ZERO_PING_ID`]
+    /// (../onion_announce/constant.ZERO_PING_ID.html).
+    pub ping_id: PingId,
+    /// Whether node stores our announcement, according to its last
+    /// response.
+    pub announced: bool,
+    /// Time in seconds when the last request was sent to the node.
+    pub last_sent: Option<u64>,
+    /// Time in seconds when the last response was received from the node.
+    pub last_response: Option<u64>,
+    /// Number of requests sent since the last response.
+    unanswered: u8,
+}
+
+impl AnnounceNode {
+    fn new(node: &PackedNode) -> Self {
+        AnnounceNode {
+            node: *node,
+            ping_id: ZERO_PING_ID,
+            announced: false,
+            last_sent: None,
+            last_response: None,
+            unanswered: 0,
+        }
+    }
+
+    /// Whether it's time to send the next request to the node.
+    fn should_send(&self, now: u64) -> bool {
+        let interval = if self.announced { ANNOUNCE_INTERVAL_ANNOUNCED }
+                       else { ANNOUNCE_INTERVAL_NOT_ANNOUNCED };
+        match self.last_sent {
+            None => true,
+            Some(sent) => sent + interval <= now,
+        }
+    }
+}
+
+/// Request waiting for response.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct PendingRequest {
+    sendback_data: u64,
+    pk: PublicKey,
+    sent: u64,
+    /// ID of the path in [`OnionPaths`](../onion_paths/struct.OnionPaths.html)
+    /// that request was sent through.
+    path_id: Option<u32>,
+}
+
+
+/// Client announcing own long-term PK to the nodes closest to it.
+///
+/// Usage:
+///
+/// * add nodes to start from with [`add_nodes()`](#method.add_nodes), e.g.
+///   the closest nodes from own `Kbucket`;
+/// * periodically call [`send_requests()`](#method.send_requests) with a
+///   pool of onion paths, or [`next_requests()`](#method.next_requests) and
+///   send returned requests through an onion path;
+/// * pass received `AnnounceResponse`s to [`handle_response()`]
+///   (#method.handle_response), along with the pool, so that paths that
+///   delivered them are known to work.
+///
+/// First request to a node carries zero ping ID, and node answers it with a
+/// valid one, that is used for the next request. Announcement is renewed
+/// every [`ANNOUNCE_INTERVAL_ANNOUNCED`]
+/// (./constant.ANNOUNCE_INTERVAL_ANNOUNCED.html) seconds.
+pub struct AnnounceClient {
+    pk: PublicKey,
+    sk: SecretKey,
+    data_pk: PublicKey,
+    data_sk: SecretKey,
+    /// Sorted by distance to own PK, closest first.
+    nodes: Vec<AnnounceNode>,
+    pending: Vec<PendingRequest>,
+}
+
+impl AnnounceClient {
+    /// Create new `AnnounceClient` for own long-term keypair, with a new
+    /// keypair for onion data, and no nodes.
+    pub fn new(pk: &PublicKey, sk: &SecretKey) -> Self {
+        debug!(target: "AnnounceClient", "Creating new AnnounceClient.");
+        let (data_pk, data_sk) = gen_keypair();
+        AnnounceClient {
+            pk: *pk,
+            sk: sk.clone(),
+            data_pk: data_pk,
+            data_sk: data_sk,
+            nodes: Vec::new(),
+            pending: Vec::new(),
+        }
+    }
+
+    /// Own long-term PK.
+    pub fn pk(&self) -> &PublicKey {
+        &self.pk
+    }
+
+    /// PK that friends should use to encrypt onion data for us. It's
+    /// announced along with own long-term PK.
+    pub fn data_pk(&self) -> &PublicKey {
+        &self.data_pk
+    }
+
+    /// SK for data encrypted with [`data_pk()`](#method.data_pk).
+    pub fn data_sk(&self) -> &SecretKey {
+        &self.data_sk
+    }
+
+    /// Nodes that client announces itself to, closest to own PK first.
+    pub fn nodes(&self) -> &[AnnounceNode] {
+        &self.nodes
+    }
+
+    /// Nodes that store our announcement.
+    pub fn announced_nodes(&self) -> Vec<PackedNode> {
+        self.nodes.iter()
+            .filter(|n| n.announced)
+            .map(|n| n.node)
+            .collect()
+    }
+
+    /// Whether at least one node stores our announcement.
+    pub fn is_announced(&self) -> bool {
+        self.nodes.iter().any(|n| n.announced)
+    }
+
+    /// Add node, if it's closer to own PK than the furthest node, or there
+    /// are less than [`ONION_CLIENT_MAX_NODES`]
+    /// (./constant.ONION_CLIENT_MAX_NODES.html).
+    ///
+    /// Returns `false` if node wasn't added, e.g. it's already known.
+    pub fn add_node(&mut self, node: &PackedNode) -> bool {
+        if node.pk == self.pk {
+            return false
+        }
+        let mut pos = self.nodes.len();
+        for (n, an) in self.nodes.iter().enumerate() {
+            match self.pk.distance(&node.pk, &an.node.pk) {
+                Ordering::Less => {
+                    pos = n;
+                    break
+                },
+                Ordering::Equal => return false,
+                Ordering::Greater => {},
+            }
+        }
+        if pos >= ONION_CLIENT_MAX_NODES {
+            return false
+        }
+        trace!(target: "AnnounceClient", "Adding node: {:?}", node);
+        self.nodes.insert(pos, AnnounceNode::new(node));
+        self.nodes.truncate(ONION_CLIENT_MAX_NODES);
+        true
+    }
+
+    /// Add nodes with [`add_node()`](#method.add_node). Returns number of
+    /// added nodes.
+    pub fn add_nodes(&mut self, nodes: &[PackedNode]) -> usize {
+        nodes.iter().filter(|n| self.add_node(n)).count()
+    }
+
+    /// Remove nodes that didn't answer [`ANNOUNCE_MAX_TRIES`]
+    /// (./constant.ANNOUNCE_MAX_TRIES.html) requests in a row, and expired
+    /// pending requests.
+    fn remove_timed_out(&mut self, now: u64) {
+        self.pending.retain(|p| p.sent + ANNOUNCE_REQUEST_TIMEOUT > now);
+        let pending = &self.pending;
+        self.nodes.retain(|n| {
+            let waiting = pending.iter().any(|p| p.pk == n.node.pk);
+            if n.unanswered >= ANNOUNCE_MAX_TRIES && !waiting {
+                debug!(target: "AnnounceClient", "Node timed out: {:?}", n.node);
+                false
+            } else {
+                true
+            }
+        });
+    }
+
+    /// Get announce requests that should be sent now, with the nodes they
+    /// should be sent to. Nodes that didn't answer are removed.
+    ///
+    /// `now` – current time in seconds.
+    pub fn next_requests(&mut self, now: u64) -> Vec<(PackedNode, AnnounceRequest)> {
+        self.requests(now, None)
+    }
+
+    fn requests(&mut self, now: u64, path_id: Option<u32>)
+        -> Vec<(PackedNode, AnnounceRequest)>
+    {
+        trace!(target: "AnnounceClient", "Getting next announce requests.");
+        self.remove_timed_out(now);
+        let mut result = Vec::new();
+        for n in &mut self.nodes {
+            if !n.should_send(now) {
+                continue
+            }
+            let sendback_data = random_u64();
+            let payload = AnnounceRequestPayload {
+                ping_id: n.ping_id,
+                search_pk: self.pk,
+                data_pk: self.data_pk,
+                sendback_data: sendback_data,
+            };
+            let request = AnnounceRequest::new(&self.pk, &self.sk, &n.node.pk,
+                                               &payload);
+            n.last_sent = Some(now);
+            n.unanswered = n.unanswered.saturating_add(1);
+            self.pending.push(PendingRequest {
+                sendback_data: sendback_data,
+                pk: n.node.pk,
+                sent: now,
+                path_id: path_id,
+            });
+            result.push((n.node, request));
+        }
+        result
+    }
+
+    /// Send [`next_requests()`](#method.next_requests) with `transport`
+    /// through a random path from `paths`. Returns number of sent requests.
+    pub fn send_requests<T: Transport>(&mut self, transport: &T,
+                                       paths: &mut OnionPaths, now: u64) -> usize {
+        self.remove_timed_out(now);
+        if !self.nodes.iter().any(|n| n.should_send(now)) {
+            return 0
+        }
+        let (path_id, path) = match paths.random_path(now) {
+            Some((id, path)) => (id, path.clone()),
+            None => return 0,
+        };
+        let mut sent = 0;
+        for (node, request) in self.requests(now, Some(path_id)) {
+            let onion = path.create_request(node.saddr, &request.to_bytes());
+            match transport.send_to(&onion.to_bytes(), path.nodes[0].saddr) {
+                Ok(_) => {
+                    paths.handle_sent(path_id);
+                    sent += 1;
+                },
+                Err(e) => debug!(target: "AnnounceClient",
+                                 "Failed to send announce request to {}: {}",
+                                 path.nodes[0].saddr, e),
+            }
+        }
+        sent
+    }
+
+    /// Handle `AnnounceResponse`. Nodes closest to own PK from the response
+    /// are added to be announced to.
+    ///
+    /// If node answered a request with zero ping ID, request with the ping ID
+    /// it sent is due immediately.
+    ///
+    /// Path from `paths` that request was sent through is marked as
+    /// working.
+    ///
+    /// Returns `false` if response wasn't expected, or it couldn't be
+    /// decrypted.
+    pub fn handle_response(&mut self, response: &AnnounceResponse,
+                           paths: &mut OnionPaths, now: u64) -> bool {
+        debug!(target: "AnnounceClient", "Handling AnnounceResponse.");
+        let pos = match self.pending.iter()
+            .position(|p| p.sendback_data == response.sendback_data
+                          && p.sent + ANNOUNCE_REQUEST_TIMEOUT > now) {
+            Some(pos) => pos,
+            None => {
+                debug!(target: "AnnounceClient", "Unexpected response; ignoring.");
+                return false
+            },
+        };
+        let pending = self.pending[pos];
+        let payload = match response.get_payload(&pending.pk, &self.sk) {
+            Some(p) => p,
+            None => return false,
+        };
+        self.pending.remove(pos);
+        if let Some(id) = pending.path_id {
+            paths.handle_success(id, now);
+        }
+
+        if let Some(n) = self.nodes.iter_mut().find(|n| n.node.pk == pending.pk) {
+            let had_ping_id = n.ping_id != ZERO_PING_ID;
+            n.announced = payload.status == AnnounceStatus::Announced;
+            n.ping_id = payload.ping_id_or_pk;
+            n.last_response = Some(now);
+            n.unanswered = 0;
+            if !n.announced && !had_ping_id {
+                // announce with the first ping ID right away
+                n.last_sent = None;
+            }
+        }
+        for node in &payload.nodes {
+            self.add_node(node);
+        }
+        true
+    }
+
+    /// Create `OnionDataRequest` with `data` for the friend with long-term
+    /// `friend_pk`, that announced `friend_data_pk`.
+    pub fn data_request(&self, friend_pk: &PublicKey, friend_data_pk: &PublicKey,
+                        data: &[u8]) -> OnionDataRequest {
+        OnionDataRequest::new(friend_pk, friend_data_pk, &self.pk, &self.sk, data)
+    }
+
+    /// Send `data` to the friend through the onion `path`, and the
+    /// `announce_node` that stores friend's announcement.
+    ///
+    /// Returns `false` if sending failed.
+    pub fn send_data<T: Transport>(&self, transport: &T, path: &OnionPath,
+                                   announce_node: &PackedNode,
+                                   friend_pk: &PublicKey,
+                                   friend_data_pk: &PublicKey, data: &[u8])
+        -> bool
+    {
+        let request = self.data_request(friend_pk, friend_data_pk, data);
+        let onion = path.create_request(announce_node.saddr, &request.to_bytes());
+        match transport.send_to(&onion.to_bytes(), path.nodes[0].saddr) {
+            Ok(_) => true,
+            Err(e) => {
+                debug!(target: "AnnounceClient",
+                       "Failed to send onion data to {}: {}",
+                       path.nodes[0].saddr, e);
+                false
+            },
+        }
+    }
+
+    /// Decrypt `OnionDataResponse` sent to us by a friend.
+    ///
+    /// Returns long-term PK of the friend and the data, or `None` if
+    /// decryption fails.
+    pub fn handle_data_response(&self, response: &OnionDataResponse)
+        -> Option<(PublicKey, Vec<u8>)>
+    {
+        response.get_payload(&self.data_sk, &self.sk)
+    }
+
+    /// Handle packet received from the first node of the onion path.
+    ///
+    /// Returns `false` if packet isn't an expected `AnnounceResponse`.
+    pub fn handle_packet(&mut self, bytes: &[u8], paths: &mut OnionPaths,
+                         now: u64) -> bool {
+        match AnnounceResponse::from_bytes(bytes) {
+            Some(response) => self.handle_response(&response, paths, now),
+            None => false,
+        }
+    }
+}
@@ -0,0 +1,538 @@
+/*
+    Copyright © 2016 Zetok Zalbavar <zexavexxe@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Friend discovery through onion search.
+//!
+//! Announce nodes closest to friend's long-term PK are asked whether friend
+//! announced itself to them, with [`AnnounceRequest`]
+//! (../onion_announce/struct.AnnounceRequest.html)s sent with a temporary
+//! PK. Once friend is found, we know its data PK, and send it
+//! [`DhtPkAnnounce`](../onion_client/struct.DhtPkAnnounce.html) with our
+//! DHT PK through the announce nodes that store friend's announcement.
+//! Friend does the same, which tells us its current DHT PK.
+//!
+//! Friends that can't be found are searched for less and less often.
+
+use std::cmp::{self, Ordering};
+
+use toxcore::binary_io::*;
+use toxcore::crypto_core::*;
+use toxcore::dht::*;
+use toxcore::network::Transport;
+use toxcore::onion_announce::*;
+use toxcore::onion_client::*;
+use toxcore::onion_paths::OnionPaths;
+
+
+/// Number of announce nodes closest to friend's PK that are asked for the
+/// friend.
+pub const FRIEND_SEARCH_MAX_NODES: usize = 8;
+
+/// Initial number of seconds between searches for a friend that wasn't
+/// found.
+pub const FRIEND_SEARCH_INTERVAL: u64 = 3;
+
+/// Maximum number of seconds between searches for a friend that wasn't
+/// found. Interval is doubled after every search that didn't find friend.
+pub const FRIEND_SEARCH_MAX_INTERVAL: u64 = 120;
+
+/// Number of seconds between searches for a friend that was found, to
+/// notice when it goes offline, or changes its data PK.
+pub const FRIEND_FOUND_INTERVAL: u64 = ANNOUNCE_INTERVAL_ANNOUNCED;
+
+/// Number of seconds between sending [`DhtPkAnnounce`]
+/// (../onion_client/struct.DhtPkAnnounce.html) to a found friend.
+pub const ONION_DHTPK_SEND_INTERVAL: u64 = 30;
+
+
+/// Announce node that is asked for the friend.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SearchNode {
+    /// The announce node.
+    pub node: PackedNode,
+    /// Whether node stores friend's announcement, according to its last
+    /// response.
+    pub found: bool,
+    /// Time in seconds when the last request was sent to the node, during
+    /// the current search.
+    last_sent: Option<u64>,
+    /// Number of requests sent since the last response.
+    unanswered: u8,
+}
+
+/// Request waiting for response.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct PendingRequest {
+    sendback_data: u64,
+    friend_pk: PublicKey,
+    node_pk: PublicKey,
+    sent: u64,
+    path_id: u32,
+}
+
+
+/// Friend that is searched for through the onion.
+pub struct OnionFriend {
+    /// Long-term PK of the friend.
+    pub real_pk: PublicKey,
+    /// Data PK of the friend, from the last announce node that had friend's
+    /// announcement.
+    pub data_pk: Option<PublicKey>,
+    /// DHT PK of the friend, from the last [`DhtPkAnnounce`]
+    /// (../onion_client/struct.DhtPkAnnounce.html) received from it.
+    pub dht_pk: Option<PublicKey>,
+    /// Temporary keypair that search requests are sent with, so that
+    /// announce nodes don't learn who searches for the friend.
+    temporary_pk: PublicKey,
+    temporary_sk: SecretKey,
+    /// Sorted by distance to friend's PK, closest first.
+    nodes: Vec<SearchNode>,
+    /// Number of seconds between searches.
+    interval: u64,
+    last_search: Option<u64>,
+    /// Whether friend was found since the last search started.
+    found_in_search: bool,
+    last_no_replay: u64,
+    last_dht_pk_sent: Option<u64>,
+}
+
+impl OnionFriend {
+    /// Create new `OnionFriend` with long-term `real_pk`, with a new
+    /// temporary keypair, and no nodes.
+    pub fn new(real_pk: &PublicKey) -> Self {
+        let (temporary_pk, temporary_sk) = gen_keypair();
+        OnionFriend {
+            real_pk: *real_pk,
+            data_pk: None,
+            dht_pk: None,
+            temporary_pk: temporary_pk,
+            temporary_sk: temporary_sk,
+            nodes: Vec::new(),
+            interval: FRIEND_SEARCH_INTERVAL,
+            last_search: None,
+            found_in_search: false,
+            last_no_replay: 0,
+            last_dht_pk_sent: None,
+        }
+    }
+
+    /// Announce nodes that are asked for the friend, closest to friend's PK
+    /// first.
+    pub fn nodes(&self) -> &[SearchNode] {
+        &self.nodes
+    }
+
+    /// Whether at least one node stores friend's announcement.
+    pub fn is_found(&self) -> bool {
+        self.nodes.iter().any(|n| n.found)
+    }
+
+    /// Number of seconds between searches for the friend.
+    pub fn search_interval(&self) -> u64 {
+        self.interval
+    }
+
+    /// Add node to be asked for the friend, if it's closer to friend's PK
+    /// than the furthest node, or there are less than
+    /// [`FRIEND_SEARCH_MAX_NODES`](./constant.FRIEND_SEARCH_MAX_NODES.html).
+    ///
+    /// Returns `false` if node wasn't added.
+    pub fn add_node(&mut self, node: &PackedNode) -> bool {
+        let mut pos = self.nodes.len();
+        for (n, sn) in self.nodes.iter().enumerate() {
+            match self.real_pk.distance(&node.pk, &sn.node.pk) {
+                Ordering::Less => {
+                    pos = n;
+                    break
+                },
+                Ordering::Equal => return false,
+                Ordering::Greater => {},
+            }
+        }
+        if pos >= FRIEND_SEARCH_MAX_NODES {
+            return false
+        }
+        self.nodes.insert(pos, SearchNode {
+            node: *node,
+            found: false,
+            last_sent: None,
+            unanswered: 0,
+        });
+        self.nodes.truncate(FRIEND_SEARCH_MAX_NODES);
+        true
+    }
+
+    /// Start a new search if it's time for it, adjusting the interval.
+    fn maybe_start_search(&mut self, now: u64) {
+        let due = match self.last_search {
+            None => true,
+            Some(last) => last + self.interval <= now,
+        };
+        if !due {
+            return
+        }
+        if self.last_search.is_some() {
+            self.interval = if self.found_in_search {
+                FRIEND_FOUND_INTERVAL
+            } else {
+                cmp::min(self.interval * 2, FRIEND_SEARCH_MAX_INTERVAL)
+            };
+            trace!(target: "OnionFriend", "Search interval is now {}.",
+                   self.interval);
+        }
+        self.last_search = Some(now);
+        self.found_in_search = false;
+        let mut removed = false;
+        self.nodes.retain(|n| if n.unanswered >= ANNOUNCE_MAX_TRIES {
+            removed = true;
+            false
+        } else {
+            true
+        });
+        if removed {
+            debug!(target: "OnionFriend", "Removed nodes that didn't answer.");
+        }
+        for n in &mut self.nodes {
+            n.last_sent = None;
+        }
+    }
+
+    /// Nodes that should be asked now. Nodes that weren't asked during the
+    /// current search are asked right away.
+    ///
+    /// If there are less than [`FRIEND_SEARCH_MAX_NODES`]
+    /// (./constant.FRIEND_SEARCH_MAX_NODES.html) nodes, nodes closest to
+    /// friend's PK are added from the `kbucket` first.
+    fn due_nodes(&mut self, kbucket: &Kbucket, now: u64) -> Vec<PackedNode> {
+        self.maybe_start_search(now);
+        if self.nodes.len() < FRIEND_SEARCH_MAX_NODES {
+            let closest = kbucket.get_closest(&self.real_pk,
+                                              FRIEND_SEARCH_MAX_NODES, now);
+            for node in closest {
+                self.add_node(&node);
+            }
+        }
+        self.nodes.iter()
+            .filter(|n| n.last_sent.is_none())
+            .map(|n| n.node)
+            .collect()
+    }
+
+    /// Create search request to the `node`, marking it as asked.
+    ///
+    /// Returns `None` if there is no such node.
+    fn request(&mut self, node: &PackedNode, now: u64)
+        -> Option<(u64, AnnounceRequest)>
+    {
+        let n = match self.nodes.iter_mut().find(|n| n.node.pk == node.pk) {
+            Some(n) => n,
+            None => return None,
+        };
+        let sendback_data = random_u64();
+        let payload = AnnounceRequestPayload {
+            ping_id: ZERO_PING_ID,
+            search_pk: self.real_pk,
+            data_pk: PublicKey([0; PUBLICKEYBYTES]),
+            sendback_data: sendback_data,
+        };
+        let request = AnnounceRequest::new(&self.temporary_pk,
+                                           &self.temporary_sk,
+                                           &n.node.pk, &payload);
+        n.last_sent = Some(now);
+        n.unanswered = n.unanswered.saturating_add(1);
+        Some((sendback_data, request))
+    }
+}
+
+
+/// Friends that are searched for through the onion.
+///
+/// Usage:
+///
+/// * add friends with [`add_friend()`](#method.add_friend);
+/// * periodically call [`send_requests()`](#method.send_requests) and
+///   [`send_dht_pks()`](#method.send_dht_pks);
+/// * pass received `AnnounceResponse`s to [`handle_response()`]
+///   (#method.handle_response), and data received with
+///   [`AnnounceClient::handle_data_response()`]
+///   (../onion_client/struct.AnnounceClient.html#method.handle_data_response)
+///   to [`handle_data()`](#method.handle_data).
+///
+/// When friend's DHT PK changes, callback set with [`on_dht_pk()`]
+/// (#method.on_dht_pk) is called.
+pub struct OnionFriends {
+    friends: Vec<OnionFriend>,
+    pending: Vec<PendingRequest>,
+    on_dht_pk: Option<Box<FnMut(&PublicKey, &PublicKey, &[PackedNode])>>,
+}
+
+impl OnionFriends {
+    /// Create an empty list of friends.
+    pub fn new() -> Self {
+        OnionFriends { friends: Vec::new(), pending: Vec::new(), on_dht_pk: None }
+    }
+
+    /// Set callback that will be called with friend's long-term PK, its new
+    /// DHT PK, and nodes close to it, whenever friend's DHT PK changes.
+    pub fn on_dht_pk(&mut self,
+                     callback: Box<FnMut(&PublicKey, &PublicKey, &[PackedNode])>) {
+        self.on_dht_pk = Some(callback);
+    }
+
+    /// Add friend with long-term `pk`. Nodes to ask for the friend are taken
+    /// from the `kbucket`, and refilled from it later by
+    /// [`send_requests()`](#method.send_requests).
+    ///
+    /// Returns `false` if friend was already added.
+    pub fn add_friend(&mut self, pk: &PublicKey, kbucket: &Kbucket, now: u64)
+        -> bool
+    {
+        debug!(target: "OnionFriends", "Adding friend: {:?}", pk);
+        if self.get(pk).is_some() {
+            return false
+        }
+        let mut friend = OnionFriend::new(pk);
+        for node in kbucket.get_closest(pk, FRIEND_SEARCH_MAX_NODES, now) {
+            friend.add_node(&node);
+        }
+        self.friends.push(friend);
+        true
+    }
+
+    /// Remove friend with long-term `pk`. Returns `false` if there was no
+    /// such friend.
+    pub fn remove_friend(&mut self, pk: &PublicKey) -> bool {
+        debug!(target: "OnionFriends", "Removing friend: {:?}", pk);
+        let len = self.friends.len();
+        self.friends.retain(|f| &f.real_pk != pk);
+        self.pending.retain(|p| &p.friend_pk != pk);
+        len != self.friends.len()
+    }
+
+    /// Get friend with long-term `pk`.
+    pub fn get(&self, pk: &PublicKey) -> Option<&OnionFriend> {
+        self.friends.iter().find(|f| &f.real_pk == pk)
+    }
+
+    /// Get mutable friend with long-term `pk`.
+    pub fn get_mut(&mut self, pk: &PublicKey) -> Option<&mut OnionFriend> {
+        self.friends.iter_mut().find(|f| &f.real_pk == pk)
+    }
+
+    /// Friends that are searched for.
+    pub fn friends(&self) -> &[OnionFriend] {
+        &self.friends
+    }
+
+    /// Send search requests that are due with `transport`, each through a
+    /// random path from `paths`. Friends with less than
+    /// [`FRIEND_SEARCH_MAX_NODES`](./constant.FRIEND_SEARCH_MAX_NODES.html)
+    /// nodes to ask get nodes closest to them from the `kbucket`.
+    ///
+    /// Node is marked as asked only if there is a path for the request.
+    ///
+    /// Returns number of sent requests.
+    pub fn send_requests<T: Transport>(&mut self, transport: &T,
+                                       paths: &mut OnionPaths,
+                                       kbucket: &Kbucket, now: u64) -> usize {
+        self.pending.retain(|p| p.sent + ANNOUNCE_REQUEST_TIMEOUT > now);
+        if paths.is_empty() {
+            debug!(target: "OnionFriends", "No paths to search for friends.");
+            return 0
+        }
+        let mut sent = 0;
+        for friend in &mut self.friends {
+            for node in friend.due_nodes(kbucket, now) {
+                let (path_id, path) = match paths.random_path(now) {
+                    Some((id, path)) => (id, path.clone()),
+                    None => return sent,
+                };
+                let (sendback_data, request) = match friend.request(&node, now) {
+                    Some(r) => r,
+                    None => continue,
+                };
+                let onion = path.create_request(node.saddr, &request.to_bytes());
+                match transport.send_to(&onion.to_bytes(), path.nodes[0].saddr) {
+                    Ok(_) => {
+                        paths.handle_sent(path_id);
+                        sent += 1;
+                    },
+                    Err(e) => debug!(target: "OnionFriends",
+                                     "Failed to send search request to {}: {}",
+                                     path.nodes[0].saddr, e),
+                }
+                self.pending.push(PendingRequest {
+                    sendback_data: sendback_data,
+                    friend_pk: friend.real_pk,
+                    node_pk: node.pk,
+                    sent: now,
+                    path_id: path_id,
+                });
+            }
+        }
+        sent
+    }
+
+    /// Handle `AnnounceResponse` to a search request. Nodes closest to
+    /// friend's PK from the response are added to be asked for the friend.
+    ///
+    /// Path from `paths` that request was sent through is marked as
+    /// working.
+    ///
+    /// Returns `false` if response wasn't expected, or it couldn't be
+    /// decrypted.
+    pub fn handle_response(&mut self, response: &AnnounceResponse,
+                           paths: &mut OnionPaths, now: u64) -> bool {
+        debug!(target: "OnionFriends", "Handling AnnounceResponse.");
+        let pos = match self.pending.iter()
+            .position(|p| p.sendback_data == response.sendback_data
+                          && p.sent + ANNOUNCE_REQUEST_TIMEOUT > now) {
+            Some(pos) => pos,
+            None => {
+                debug!(target: "OnionFriends", "Unexpected response; ignoring.");
+                return false
+            },
+        };
+        let pending = self.pending[pos];
+        let friend = match self.friends.iter_mut()
+            .find(|f| f.real_pk == pending.friend_pk) {
+            Some(f) => f,
+            None => return false,
+        };
+        let payload = match response.get_payload(&pending.node_pk,
+                                                 &friend.temporary_sk) {
+            Some(p) => p,
+            None => return false,
+        };
+        self.pending.remove(pos);
+        paths.handle_success(pending.path_id, now);
+
+        let found = payload.status == AnnounceStatus::Found;
+        if let Some(n) = friend.nodes.iter_mut().find(|n| n.node.pk == pending.node_pk) {
+            n.found = found;
+            n.unanswered = 0;
+        }
+        if found {
+            let data_pk = PublicKey(payload.ping_id_or_pk);
+            if friend.data_pk != Some(data_pk) {
+                debug!(target: "OnionFriends", "Found friend with new data PK.");
+                friend.data_pk = Some(data_pk);
+                // tell the friend our DHT PK right away
+                friend.last_dht_pk_sent = None;
+            }
+            friend.found_in_search = true;
+        }
+        for node in &payload.nodes {
+            friend.add_node(node);
+        }
+        true
+    }
+
+    /// Send [`DhtPkAnnounce`](../onion_client/struct.DhtPkAnnounce.html)
+    /// with own `dht_pk` and `nodes` close to us, to friends that were found
+    /// and weren't told our DHT PK in the last [`ONION_DHTPK_SEND_INTERVAL`]
+    /// (./constant.ONION_DHTPK_SEND_INTERVAL.html) seconds.
+    ///
+    /// Data is sent with `client`'s long-term keys, through the announce
+    /// nodes that store friend's announcement, and random paths from
+    /// `paths`. `now` is used as `no_replay`.
+    ///
+    /// Returns number of friends that data was sent to.
+    pub fn send_dht_pks<T: Transport>(&mut self, transport: &T,
+                                      paths: &mut OnionPaths,
+                                      client: &AnnounceClient,
+                                      dht_pk: &PublicKey, nodes: &[PackedNode],
+                                      now: u64) -> usize {
+        let announce = DhtPkAnnounce {
+            no_replay: now,
+            dht_pk: *dht_pk,
+            nodes: nodes.iter().take(DHT_PK_ANNOUNCE_MAX_NODES).cloned().collect(),
+        };
+        let data = announce.to_bytes();
+        let mut sent = 0;
+        for friend in &mut self.friends {
+            let data_pk = match friend.data_pk {
+                Some(pk) => pk,
+                None => continue,
+            };
+            let due = match friend.last_dht_pk_sent {
+                None => true,
+                Some(last) => last + ONION_DHTPK_SEND_INTERVAL <= now,
+            };
+            if !due || !friend.is_found() {
+                continue
+            }
+            let mut sent_to_friend = false;
+            for n in friend.nodes.iter().filter(|n| n.found) {
+                let (path_id, path) = match paths.random_path(now) {
+                    Some((id, path)) => (id, path.clone()),
+                    None => return sent,
+                };
+                if client.send_data(transport, &path, &n.node, &friend.real_pk,
+                                    &data_pk, &data) {
+                    paths.handle_sent(path_id);
+                    sent_to_friend = true;
+                }
+            }
+            if sent_to_friend {
+                friend.last_dht_pk_sent = Some(now);
+                sent += 1;
+            }
+        }
+        sent
+    }
+
+    /// Handle data from the friend with long-term PK `from`, decrypted with
+    /// [`AnnounceClient::handle_data_response()`]
+    /// (../onion_client/struct.AnnounceClient.html#method.handle_data_response).
+    ///
+    /// If it's [`DhtPkAnnounce`](../onion_client/struct.DhtPkAnnounce.html)
+    /// with new DHT PK, callback set with [`on_dht_pk()`](#method.on_dht_pk)
+    /// is called.
+    ///
+    /// Returns `false` if data isn't a `DhtPkAnnounce` from a friend, or it's
+    /// older than the last one.
+    pub fn handle_data(&mut self, from: &PublicKey, data: &[u8]) -> bool {
+        let announce = match DhtPkAnnounce::from_bytes(data) {
+            Some(a) => a,
+            None => return false,
+        };
+        let friend = match self.friends.iter_mut().find(|f| &f.real_pk == from) {
+            Some(f) => f,
+            None => {
+                debug!(target: "OnionFriends", "DhtPkAnnounce from a stranger.");
+                return false
+            },
+        };
+        if announce.no_replay <= friend.last_no_replay {
+            debug!(target: "OnionFriends", "Replayed DhtPkAnnounce; ignoring.");
+            return false
+        }
+        friend.last_no_replay = announce.no_replay;
+        if friend.dht_pk != Some(announce.dht_pk) {
+            debug!(target: "OnionFriends", "Friend's DHT PK is now {:?}",
+                   announce.dht_pk);
+            friend.dht_pk = Some(announce.dht_pk);
+            if let Some(ref mut callback) = self.on_dht_pk {
+                callback(from, &announce.dht_pk, &announce.nodes);
+            }
+        }
+        true
+    }
+}
@@ -0,0 +1,260 @@
+/*
+    Copyright © 2016 Zetok Zalbavar <zexavexxe@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Pool of onion paths, built from good nodes of the `Kbucket`.
+//!
+//! Nodes of a path are chosen randomly, so that no two of them are in the
+//! same subnet, as defined by [`same_subnet()`]
+//! (../dht/fn.same_subnet.html). Paths that stopped delivering responses
+//! are dropped, and all paths are rotated after
+//! [`ONION_PATH_MAX_LIFETIME`](./constant.ONION_PATH_MAX_LIFETIME.html).
+
+use toxcore::crypto_core::*;
+use toxcore::dht::*;
+use toxcore::onion::OnionPath;
+
+
+/// Number of paths that pool keeps.
+pub const NUMBER_ONION_PATHS: usize = 6;
+
+/// Number of seconds after which path that was used, but didn't deliver
+/// any response since, is considered to be dead.
+pub const ONION_PATH_TIMEOUT: u64 = 10;
+
+/// Number of seconds after which path is replaced with a new one, even if
+/// it works.
+pub const ONION_PATH_MAX_LIFETIME: u64 = 1200;
+
+
+/// Statistics of a path in [`OnionPaths`](./struct.OnionPaths.html).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct OnionPathStats {
+    /// ID of the path in the pool.
+    pub id: u32,
+    /// Nodes of the path.
+    pub nodes: [PackedNode; 3],
+    /// Time in seconds when path was created.
+    pub created: u64,
+    /// Time in seconds when path delivered the last response.
+    pub last_success: Option<u64>,
+    /// Number of requests sent through the path.
+    pub sent: u64,
+    /// Number of responses received through the path.
+    pub received: u64,
+}
+
+struct PoolPath {
+    path: OnionPath,
+    stats: OnionPathStats,
+    /// Time in seconds when path was first used since the last response.
+    first_unanswered: Option<u64>,
+}
+
+impl PoolPath {
+    fn is_dead(&self, now: u64) -> bool {
+        match self.first_unanswered {
+            Some(used) => used + ONION_PATH_TIMEOUT <= now,
+            None => false,
+        }
+    }
+
+    fn is_expired(&self, now: u64) -> bool {
+        self.stats.created + ONION_PATH_MAX_LIFETIME <= now
+    }
+}
+
+
+/// Choose 3 random nodes from `nodes` for a path, no two of them in the
+/// same subnet.
+///
+/// Returns `None` if there are no such 3 nodes.
+pub fn select_path_nodes(nodes: &[PackedNode]) -> Option<[PackedNode; 3]> {
+    let mut nodes = nodes.to_vec();
+    // Fisher–Yates shuffle
+    for i in (1..nodes.len()).rev() {
+        let j = random_u32() as usize % (i + 1);
+        nodes.swap(i, j);
+    }
+    let mut chosen: Vec<PackedNode> = Vec::with_capacity(3);
+    for node in nodes {
+        if chosen.iter().all(|c| c.pk != node.pk
+                                 && !same_subnet(&c.saddr, &node.saddr)) {
+            chosen.push(node);
+            if chosen.len() == 3 {
+                return Some([chosen[0], chosen[1], chosen[2]])
+            }
+        }
+    }
+    debug!(target: "OnionPaths", "Not enough diverse nodes for a path.");
+    None
+}
+
+
+/// Pool of onion paths.
+///
+/// Usage:
+///
+/// * periodically call [`update()`](#method.update) with own `Kbucket`, to
+///   replace dead and old paths;
+/// * get a path to send request through with [`random_path()`]
+///   (#method.random_path), and report every request sent through it with
+///   [`handle_sent()`](#method.handle_sent);
+/// * report responses that came through the path with
+///   [`handle_success()`](#method.handle_success).
+pub struct OnionPaths {
+    paths: Vec<PoolPath>,
+    next_id: u32,
+    timed_out: u64,
+    rotated: u64,
+}
+
+impl OnionPaths {
+    /// Create new empty `OnionPaths`.
+    pub fn new() -> Self {
+        debug!(target: "OnionPaths", "Creating new OnionPaths.");
+        OnionPaths {
+            paths: Vec::new(),
+            next_id: 0,
+            timed_out: 0,
+            rotated: 0,
+        }
+    }
+
+    /// Number of paths in the pool.
+    pub fn len(&self) -> usize {
+        self.paths.len()
+    }
+
+    /// Whether pool has no paths.
+    pub fn is_empty(&self) -> bool {
+        self.paths.is_empty()
+    }
+
+    /// Number of paths dropped because they stopped delivering responses.
+    pub fn timed_out(&self) -> u64 {
+        self.timed_out
+    }
+
+    /// Number of paths replaced because of their age.
+    pub fn rotated(&self) -> u64 {
+        self.rotated
+    }
+
+    /// Add `path` to the pool, created at `now`. Returns ID of the path.
+    pub fn add_path(&mut self, path: OnionPath, now: u64) -> u32 {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        debug!(target: "OnionPaths", "Adding path {}.", id);
+        self.paths.push(PoolPath {
+            stats: OnionPathStats {
+                id: id,
+                nodes: path.nodes,
+                created: now,
+                last_success: None,
+                sent: 0,
+                received: 0,
+            },
+            path: path,
+            first_unanswered: None,
+        });
+        id
+    }
+
+    /// Drop paths that are dead or expired at `now`, and create new ones
+    /// from good nodes of the `kbucket`, up to [`NUMBER_ONION_PATHS`]
+    /// (./constant.NUMBER_ONION_PATHS.html).
+    ///
+    /// Returns number of created paths.
+    pub fn update(&mut self, kbucket: &Kbucket, now: u64) -> usize {
+        trace!(target: "OnionPaths", "Updating onion paths.");
+        let before = self.paths.len();
+        self.paths.retain(|p| !p.is_dead(now));
+        self.timed_out += (before - self.paths.len()) as u64;
+
+        let before = self.paths.len();
+        self.paths.retain(|p| !p.is_expired(now));
+        self.rotated += (before - self.paths.len()) as u64;
+
+        let nodes = kbucket.good_packed_nodes(now);
+        let mut created = 0;
+        while self.paths.len() < NUMBER_ONION_PATHS {
+            match select_path_nodes(&nodes) {
+                Some(path_nodes) => {
+                    self.add_path(OnionPath::new(&path_nodes), now);
+                    created += 1;
+                },
+                None => break,
+            }
+        }
+        created
+    }
+
+    /// Random path that isn't dead at `now`, and its ID. Path is marked as
+    /// used, and it's considered to be dead if it doesn't deliver any
+    /// response in [`ONION_PATH_TIMEOUT`](./constant.ONION_PATH_TIMEOUT.html).
+    ///
+    /// Returns `None` if there are no such paths.
+    pub fn random_path(&mut self, now: u64) -> Option<(u32, &OnionPath)> {
+        let alive: Vec<usize> = (0..self.paths.len())
+            .filter(|&i| !self.paths[i].is_dead(now))
+            .collect();
+        if alive.is_empty() {
+            debug!(target: "OnionPaths", "No onion path available.");
+            return None
+        }
+        let path = &mut self.paths[alive[random_u32() as usize % alive.len()]];
+        if path.first_unanswered.is_none() {
+            path.first_unanswered = Some(now);
+        }
+        Some((path.stats.id, &path.path))
+    }
+
+    /// Report that request was sent through the path with `id`.
+    ///
+    /// Returns `false` if there's no such path.
+    pub fn handle_sent(&mut self, id: u32) -> bool {
+        match self.paths.iter_mut().find(|p| p.stats.id == id) {
+            Some(path) => {
+                path.stats.sent += 1;
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Report that response came through the path with `id`.
+    ///
+    /// Returns `false` if there's no such path.
+    pub fn handle_success(&mut self, id: u32, now: u64) -> bool {
+        match self.paths.iter_mut().find(|p| p.stats.id == id) {
+            Some(path) => {
+                path.stats.last_success = Some(now);
+                path.stats.received += 1;
+                path.first_unanswered = None;
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Statistics of paths in the pool.
+    pub fn stats(&self) -> Vec<OnionPathStats> {
+        self.paths.iter().map(|p| p.stats).collect()
+    }
+}
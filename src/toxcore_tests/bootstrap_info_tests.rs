@@ -0,0 +1,129 @@
+/*
+    Copyright © 2016 Zetok Zalbavar <zexavexxe@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Tests for the `bootstrap_info` module.
+
+use std::io;
+use std::net::UdpSocket;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use toxcore::binary_io::*;
+use toxcore::bootstrap_info::*;
+use toxcore::dht::*;
+use toxcore::network::Transport;
+
+use super::sim_network::*;
+
+
+// send_bootstrap_info_request()
+
+#[test]
+fn send_bootstrap_info_request_test() {
+    let net = SimNetwork::new();
+    let socket = net.bind("1.0.0.1:33445");
+    let node_socket = net.bind("1.0.0.2:33445");
+    send_bootstrap_info_request(&socket, node_socket.addr).unwrap();
+
+    let mut buf = [0; 2048];
+    let (len, addr) = node_socket.recv_from(&mut buf).unwrap();
+    assert_eq!(socket.addr, addr);
+    assert_eq!(Some(BootstrapInfoRequest),
+               BootstrapInfoRequest::from_bytes(&buf[..len]));
+}
+
+
+// recv_bootstrap_info()
+
+#[test]
+fn recv_bootstrap_info_test() {
+    let net = SimNetwork::new();
+    let socket = net.bind("1.0.0.1:33445");
+    let node_socket = net.bind("1.0.0.2:33445");
+    let other_socket = net.bind("1.0.0.3:33445");
+    let info = BootstrapInfo::new(1, "motd");
+
+    // packets from other addresses and garbage are ignored
+    other_socket.send_to(&info.to_bytes(), socket.addr).unwrap();
+    node_socket.send_to(&[0; 3], socket.addr).unwrap();
+    node_socket.send_to(&info.to_bytes(), socket.addr).unwrap();
+    assert_eq!(None, recv_bootstrap_info(&socket, node_socket.addr).unwrap());
+    assert_eq!(None, recv_bootstrap_info(&socket, node_socket.addr).unwrap());
+    assert_eq!(Some(info),
+               recv_bootstrap_info(&socket, node_socket.addr).unwrap());
+
+    // error when there is nothing more to receive
+    assert!(recv_bootstrap_info(&socket, node_socket.addr).is_err());
+}
+
+
+// query_bootstrap_info()
+
+#[test]
+fn query_bootstrap_info_test() {
+    let node = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = node.local_addr().unwrap();
+    thread::spawn(move || {
+        let mut buf = [0; 2048];
+        let (len, src) = node.recv_from(&mut buf).unwrap();
+        assert!(BootstrapInfoRequest::from_bytes(&buf[..len]).is_some());
+        node.send_to(&BootstrapInfo::new(42, "Hello!").to_bytes(), src).unwrap();
+    });
+
+    let rx = query_bootstrap_info(addr, Duration::from_secs(5));
+    assert_eq!(BootstrapInfo::new(42, "Hello!"), rx.recv().unwrap().unwrap());
+}
+
+#[test]
+fn query_bootstrap_info_test_timeout() {
+    // node that never answers
+    let node = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = node.local_addr().unwrap();
+
+    let rx = query_bootstrap_info(addr, Duration::from_millis(100));
+    match rx.recv().unwrap() {
+        Err(ref e) if e.kind() == io::ErrorKind::TimedOut => {},
+        r => panic!("Expected timeout, got {:?}", r),
+    }
+}
+
+#[test]
+fn query_bootstrap_info_test_timeout_garbage() {
+    // node that keeps sending garbage instead of an answer
+    let node = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = node.local_addr().unwrap();
+    thread::spawn(move || {
+        let mut buf = [0; 2048];
+        let (_, src) = node.recv_from(&mut buf).unwrap();
+        for _ in 0..100 {
+            if node.send_to(&[0; 3], src).is_err() {
+                break
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    });
+
+    let start = Instant::now();
+    let rx = query_bootstrap_info(addr, Duration::from_millis(100));
+    match rx.recv().unwrap() {
+        Err(ref e) if e.kind() == io::ErrorKind::TimedOut => {},
+        r => panic!("Expected timeout, got {:?}", r),
+    }
+    assert!(start.elapsed() < Duration::from_millis(500));
+}
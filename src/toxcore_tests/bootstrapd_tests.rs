@@ -0,0 +1,130 @@
+/*
+    Copyright © 2016 Zetok Zalbavar <zexavexxe@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Tests for the `bootstrapd` module.
+
+use std::env;
+use std::fs::{self, File};
+use std::io::Write;
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+
+use toxcore::bootstrapd::*;
+use toxcore::crypto_core::*;
+use toxcore::network::PORT_MIN;
+
+
+/// Path to a file in the temporary directory that doesn't exist yet.
+fn temp_path() -> PathBuf {
+    let mut path = env::temp_dir();
+    path.push(format!("tox-bootstrapd-test-{:x}", random_u64()));
+    path
+}
+
+
+// parse_config()
+
+#[test]
+fn parse_config_test() {
+    let json = r#"{
+        "keys_file": "/tmp/keys",
+        "motd": "Hello!",
+        "addresses": ["0.0.0.0", "::"],
+        "ports": [33445, 3389],
+        "enable_lan_discovery": false,
+        "nodes": [{
+            "ipv4": "1.2.3.4",
+            "ipv6": "-",
+            "port": 33445,
+            "public_key": "0000000000000000000000000000000000000000000000000000000000000000"
+        }]
+    }"#;
+    let config = parse_config(json).unwrap();
+    assert_eq!("/tmp/keys", config.keys_file);
+    assert_eq!("Hello!", config.motd);
+    let bind: Vec<SocketAddr> = ["0.0.0.0:33445", "0.0.0.0:3389",
+                                 "[::]:33445", "[::]:3389"].iter()
+        .map(|a| a.parse().unwrap())
+        .collect();
+    assert_eq!(bind, config.bind);
+    assert_eq!(1, config.nodes.len());
+    assert_eq!("1.2.3.4:33445".parse::<SocketAddr>().unwrap(),
+               config.nodes[0].saddr);
+    assert!(!config.lan_discovery);
+}
+
+#[test]
+fn parse_config_test_defaults() {
+    let config = parse_config("{}").unwrap();
+    assert_eq!(DEFAULT_KEYS_FILE, config.keys_file);
+    assert_eq!(DEFAULT_MOTD, config.motd);
+    assert_eq!(vec![format!("[::]:{}", PORT_MIN).parse::<SocketAddr>().unwrap()],
+               config.bind);
+    assert!(config.nodes.is_empty());
+    assert!(config.lan_discovery);
+}
+
+#[test]
+fn parse_config_test_invalid() {
+    for json in &["", "[]", "not json", r#"{"motd": 1}"#,
+                  r#"{"keys_file": []}"#, r#"{"addresses": ["1.2.3"]}"#,
+                  r#"{"addresses": "::"}"#, r#"{"ports": [0]}"#,
+                  r#"{"ports": [65536]}"#, r#"{"nodes": 1}"#,
+                  r#"{"enable_lan_discovery": 1}"#] {
+        assert!(parse_config(json).is_none(), "{}", json);
+    }
+}
+
+
+// load_or_create_keys()
+
+#[test]
+fn load_or_create_keys_test() {
+    let path = temp_path();
+    let (pk, sk) = load_or_create_keys(&path).unwrap();
+    assert_eq!(KEYS_FILE_SIZE as u64, fs::metadata(&path).unwrap().len());
+
+    // same keys are loaded next time
+    let (pk2, sk2) = load_or_create_keys(&path).unwrap();
+    assert_eq!(pk, pk2);
+    let SecretKey(ref sk_bytes) = sk;
+    let SecretKey(ref sk2_bytes) = sk2;
+    assert_eq!(&sk_bytes[..], &sk2_bytes[..]);
+    fs::remove_file(&path).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn load_or_create_keys_permissions_test() {
+    let path = temp_path();
+    load_or_create_keys(&path).unwrap();
+    let mode = fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(0o600, mode & 0o777);
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn load_or_create_keys_test_invalid() {
+    let path = temp_path();
+    File::create(&path).unwrap().write_all(&[0; KEYS_FILE_SIZE - 1]).unwrap();
+    assert!(load_or_create_keys(&path).is_err());
+    fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,188 @@
+/*
+    Copyright © 2016 Zetok Zalbavar <zexavexxe@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+
+//! Tests for the `congestion` module.
+
+use toxcore::congestion::*;
+
+
+/// Every interval `count` packets leave the buffer with `queue` packets,
+/// until the whole window of intervals is filled.
+fn leave(congestion: &mut ToxcoreCongestion, count: usize, queue: usize) {
+    congestion.can_send(queue, 0);
+    for i in 1..CONGESTION_QUEUE_ARRAY_SIZE as u64 + 1 {
+        congestion.on_acked(count, None, i * PACKET_COUNTER_AVERAGE_INTERVAL - 1);
+        congestion.can_send(queue, i * PACKET_COUNTER_AVERAGE_INTERVAL);
+    }
+}
+
+// ToxcoreCongestion::can_send()
+
+#[test]
+fn toxcore_congestion_can_send_test() {
+    let mut congestion = ToxcoreCongestion::new();
+    assert_eq!(CRYPTO_PACKET_MIN_RATE, congestion.send_rate());
+    // burst is allowed right away
+    for i in 0..CRYPTO_MIN_QUEUE_LENGTH {
+        assert!(congestion.can_send(i, 0));
+        congestion.on_sent(false, 0);
+    }
+    assert!(!congestion.can_send(CRYPTO_MIN_QUEUE_LENGTH, 0));
+    // and then packets are allowed at the send rate
+    assert!(!congestion.can_send(CRYPTO_MIN_QUEUE_LENGTH, 200));
+    assert!(congestion.can_send(CRYPTO_MIN_QUEUE_LENGTH, 300));
+}
+
+#[test]
+fn toxcore_congestion_can_send_resent_test() {
+    let mut congestion = ToxcoreCongestion::new();
+    // resent packets use allowance as well
+    for _ in 0..CRYPTO_MIN_QUEUE_LENGTH {
+        congestion.on_sent(true, 0);
+    }
+    assert!(!congestion.can_send(0, 0));
+}
+
+// ToxcoreCongestion::can_resend()
+
+#[test]
+fn toxcore_congestion_can_resend_test() {
+    let mut congestion = ToxcoreCongestion::new();
+    // new and resent packets use the same allowance
+    for _ in 0..CRYPTO_MIN_QUEUE_LENGTH - 1 {
+        congestion.on_sent(false, 0);
+    }
+    assert!(congestion.can_resend(CRYPTO_MIN_QUEUE_LENGTH, 0));
+    congestion.on_sent(true, 0);
+    assert!(!congestion.can_resend(CRYPTO_MIN_QUEUE_LENGTH, 0));
+    assert!(!congestion.can_send(CRYPTO_MIN_QUEUE_LENGTH, 0));
+}
+
+// ToxcoreCongestion::send_rate()
+
+#[test]
+fn toxcore_congestion_send_rate_grows_test() {
+    let mut congestion = ToxcoreCongestion::new();
+    // 10 packets every 50 ms are 200 packets per second
+    leave(&mut congestion, 10, 0);
+    assert!((congestion.send_rate() - 240.0).abs() < 1e-6);
+}
+
+#[test]
+fn toxcore_congestion_send_rate_congested_test() {
+    let mut congestion = ToxcoreCongestion::new();
+    congestion.on_sent(true, 0);
+    leave(&mut congestion, 10, 0);
+    assert!((congestion.send_rate() - 180.0).abs() < 1e-6);
+}
+
+#[test]
+fn toxcore_congestion_send_rate_queue_test() {
+    let mut congestion = ToxcoreCongestion::new();
+    // it would take 5 seconds to empty the queue
+    leave(&mut congestion, 10, 1000);
+    assert!((congestion.send_rate() - 80.0).abs() < 1e-6);
+}
+
+#[test]
+fn toxcore_congestion_send_rate_min_test() {
+    let mut congestion = ToxcoreCongestion::new();
+    congestion.on_sent(true, 0);
+    leave(&mut congestion, 0, 1000);
+    assert_eq!(CRYPTO_PACKET_MIN_RATE, congestion.send_rate());
+}
+
+// Ledbat::can_send()
+
+#[test]
+fn ledbat_can_send_test() {
+    let mut ledbat = Ledbat::new();
+    assert_eq!(LEDBAT_INITIAL_WINDOW, ledbat.window());
+    assert!(ledbat.can_send(3, 0));
+    assert!(!ledbat.can_send(4, 0));
+}
+
+// Ledbat::can_resend()
+
+#[test]
+fn ledbat_can_resend_test() {
+    let mut ledbat = Ledbat::new();
+    // initial window of packets can be resent at once
+    for _ in 0..LEDBAT_INITIAL_WINDOW as usize {
+        assert!(ledbat.can_resend(100, 0));
+        ledbat.on_sent(true, 0);
+    }
+    assert!(!ledbat.can_resend(100, 0));
+    assert_eq!(LEDBAT_MIN_WINDOW, ledbat.window());
+    // and then they are paced at the window per round-trip time, which is
+    // a second before it's measured
+    assert!(!ledbat.can_resend(100, 400));
+    assert!(ledbat.can_resend(100, 600));
+}
+
+// Ledbat::on_acked()
+
+#[test]
+fn ledbat_on_acked_test() {
+    let mut ledbat = Ledbat::new();
+    // without queuing delay window grows
+    ledbat.on_acked(4, Some(50), 0);
+    assert_eq!(Some(50), ledbat.base_delay());
+    assert_eq!(5.0, ledbat.window());
+    assert_eq!(100.0, ledbat.send_rate());
+    // and with queuing delay above the target it shrinks
+    ledbat.on_acked(5, Some(250), 100);
+    assert_eq!(Some(50), ledbat.base_delay());
+    assert_eq!(4.0, ledbat.window());
+    // at the target it stays the same
+    ledbat.on_acked(4, Some(150), 200);
+    assert_eq!(4.0, ledbat.window());
+    // packets without round-trip time don't change anything
+    ledbat.on_acked(4, None, 300);
+    assert_eq!(4.0, ledbat.window());
+}
+
+#[test]
+fn ledbat_on_acked_base_delay_test() {
+    let mut ledbat = Ledbat::new();
+    ledbat.on_acked(1, Some(50), 0);
+    ledbat.on_acked(1, Some(80), 60000);
+    assert_eq!(Some(50), ledbat.base_delay());
+    // old base delay is forgotten
+    ledbat.on_acked(1, Some(90), 60000 * LEDBAT_BASE_HISTORY as u64);
+    assert_eq!(Some(80), ledbat.base_delay());
+}
+
+// Ledbat::on_sent()
+
+#[test]
+fn ledbat_on_sent_test() {
+    let mut ledbat = Ledbat::new();
+    ledbat.on_acked(4, Some(50), 0);
+    ledbat.on_sent(false, 10);
+    assert_eq!(5.0, ledbat.window());
+    // window is halved once per round-trip time
+    ledbat.on_sent(true, 1000);
+    assert_eq!(2.5, ledbat.window());
+    ledbat.on_sent(true, 1049);
+    assert_eq!(2.5, ledbat.window());
+    ledbat.on_sent(true, 1050);
+    assert_eq!(LEDBAT_MIN_WINDOW, ledbat.window());
+}
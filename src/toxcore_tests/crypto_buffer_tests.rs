@@ -0,0 +1,192 @@
+/*
+    Copyright © 2016 Zetok Zalbavar <zexavexxe@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+
+//! Tests for the `crypto_buffer` module.
+
+use toxcore::crypto_buffer::*;
+
+use super::quickcheck::quickcheck;
+
+
+// SendBuffer::push()
+
+#[test]
+fn send_buffer_push_test() {
+    let mut buffer = SendBuffer::new();
+    assert!(buffer.is_empty());
+    assert_eq!(Some(0), buffer.push(vec![16], 1));
+    assert_eq!(Some(1), buffer.push(vec![17], 2));
+    assert_eq!(2, buffer.len());
+    assert_eq!(0, buffer.start());
+    assert_eq!(2, buffer.end());
+    assert_eq!(Some(&SentPacket { data: vec![17], sent: 2, resent: false, requested: false }),
+               buffer.get(1));
+    assert_eq!(None, buffer.get(2));
+    assert_eq!(vec![0, 1], buffer.numbers());
+}
+
+#[test]
+fn send_buffer_push_full_test() {
+    let mut buffer = SendBuffer::new();
+    for _ in 0..CRYPTO_PACKET_BUFFER_SIZE {
+        assert!(buffer.push(vec![16], 0).is_some());
+    }
+    assert!(buffer.is_full());
+    assert_eq!(None, buffer.push(vec![16], 0));
+}
+
+// SendBuffer::ack()
+
+#[test]
+fn send_buffer_ack_test() {
+    fn with_count(count: u8, acked: u8) {
+        let mut buffer = SendBuffer::new();
+        for n in 0..count {
+            buffer.push(vec![n], 0);
+        }
+        if acked <= count {
+            let removed = buffer.ack(acked as u32);
+            assert_eq!((0..acked).map(|n| vec![n]).collect::<Vec<_>>(),
+                       removed.into_iter().map(|p| p.data).collect::<Vec<_>>());
+            assert_eq!(acked as u32, buffer.start());
+            assert_eq!((count - acked) as usize, buffer.len());
+            if acked > 0 {
+                assert_eq!(None, buffer.get(acked as u32 - 1));
+            }
+        } else {
+            // out of buffer
+            assert!(buffer.ack(acked as u32).is_empty());
+            assert_eq!(count as usize, buffer.len());
+        }
+    }
+    quickcheck(with_count as fn(u8, u8));
+}
+
+#[test]
+fn send_buffer_ack_before_start_test() {
+    let mut buffer = SendBuffer::new();
+    buffer.push(vec![16], 0);
+    buffer.push(vec![16], 0);
+    assert_eq!(1, buffer.ack(1).len());
+    // old acknowledgement is ignored
+    assert!(buffer.ack(0).is_empty());
+    assert_eq!(1, buffer.start());
+    assert_eq!(1, buffer.len());
+}
+
+// SendBuffer::handle_request()
+
+#[test]
+fn send_buffer_handle_request_test() {
+    let mut buffer = SendBuffer::new();
+    for _ in 0..400 {
+        buffer.push(vec![16], 0);
+    }
+    buffer.ack(1);
+    // packets 1, 3 and 300 are missing
+    assert_eq!(vec![1, 3, 300], buffer.handle_request(&[1, 2, 0, 42]));
+    // packets before the last requested one were received
+    assert!(buffer.get(2).is_none());
+    assert!(buffer.get(299).is_none());
+    assert!(buffer.get(301).is_some());
+    assert_eq!(1, buffer.start());
+
+    // empty request doesn't remove anything
+    assert!(buffer.handle_request(&[]).is_empty());
+    assert_eq!(102, buffer.numbers().len());
+    assert_eq!(102, buffer.count());
+}
+
+#[test]
+fn send_buffer_handle_request_roundtrip_test() {
+    fn with_received(received: Vec<bool>) {
+        let mut recv_buffer = RecvBuffer::new();
+        let mut send_buffer = SendBuffer::new();
+        for (n, &r) in received.iter().enumerate() {
+            if r {
+                recv_buffer.insert(n as u32, vec![16]);
+            }
+            send_buffer.push(vec![16], 0);
+        }
+        let missing = (0..recv_buffer.end())
+            .filter(|&n| !recv_buffer.contains(n))
+            .collect::<Vec<_>>();
+        let request = recv_buffer.packet_request(usize::max_value());
+        assert_eq!(missing.clone(), send_buffer.handle_request(&request));
+        // packets after the last missing one are kept
+        let kept_from = missing.last().map_or(0, |&n| n + 1);
+        let mut expected = missing;
+        expected.extend(kept_from..received.len() as u32);
+        assert_eq!(expected, send_buffer.numbers());
+    }
+    quickcheck(with_received as fn(Vec<bool>));
+}
+
+// RecvBuffer::insert()
+
+#[test]
+fn recv_buffer_insert_test() {
+    let mut buffer = RecvBuffer::new();
+    assert!(buffer.insert(2, vec![2]));
+    assert!(buffer.contains(2));
+    assert_eq!(false, buffer.contains(1));
+    assert_eq!(3, buffer.end());
+    // duplicate
+    assert_eq!(false, buffer.insert(2, vec![2]));
+    // too far ahead
+    assert_eq!(false, buffer.insert(CRYPTO_PACKET_BUFFER_SIZE, vec![0]));
+}
+
+// RecvBuffer::pop()
+
+#[test]
+fn recv_buffer_pop_test() {
+    let mut buffer = RecvBuffer::new();
+    buffer.insert(1, vec![1]);
+    buffer.insert(2, vec![2]);
+    // first packet is missing
+    assert_eq!(None, buffer.pop());
+    assert!(buffer.insert(0, vec![0]));
+    assert_eq!(Some(vec![0]), buffer.pop());
+    assert_eq!(Some(vec![1]), buffer.pop());
+    assert_eq!(Some(vec![2]), buffer.pop());
+    assert_eq!(None, buffer.pop());
+    assert_eq!(3, buffer.start());
+    // delivered packet is ignored
+    assert_eq!(false, buffer.insert(1, vec![1]));
+}
+
+// RecvBuffer::packet_request()
+
+#[test]
+fn recv_buffer_packet_request_test() {
+    let mut buffer = RecvBuffer::new();
+    assert!(buffer.packet_request(100).is_empty());
+    buffer.insert(0, vec![0]);
+    buffer.pop();
+    for n in 2..400 {
+        if n != 3 && n != 300 {
+            buffer.insert(n, vec![16]);
+        }
+    }
+    assert!(buffer.has_missing());
+    assert_eq!(vec![1, 2, 0, 42], buffer.packet_request(100));
+    assert_eq!(vec![1, 2], buffer.packet_request(2));
+}
@@ -0,0 +1,812 @@
+/*
+    Copyright © 2016 Zetok Zalbavar <zexavexxe@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+
+//! Tests for the `crypto_connection` module.
+
+use std::cell::RefCell;
+use std::net::SocketAddr;
+use std::rc::Rc;
+
+use toxcore::binary_io::*;
+use toxcore::congestion::*;
+use toxcore::crypto_connection::*;
+use toxcore::crypto_core::*;
+use toxcore::net_crypto::*;
+use toxcore::network::Transport;
+
+use super::sim_network::*;
+
+
+fn new_keys() -> ConnectionKeys {
+    let (real_pk, real_sk) = gen_keypair();
+    let (dht_pk, dht_sk) = gen_keypair();
+    ConnectionKeys {
+        real_pk: real_pk,
+        real_sk: real_sk,
+        dht_pk: dht_pk,
+        dht_sk: dht_sk,
+    }
+}
+
+/// Create `NetCrypto` that accepts all connections initiated by peers.
+fn accepting() -> NetCrypto {
+    let mut net_crypto = NetCrypto::new(&new_keys());
+    net_crypto.on_new_connection(Box::new(|_: &PublicKey, _: &PublicKey| true));
+    net_crypto
+}
+
+/// Deliver all packets between both sides until there are none left.
+fn pump(a: &mut NetCrypto, a_sock: &SimSocket, b: &mut NetCrypto,
+        b_sock: &SimSocket, now: u64) {
+    let mut buf = [0; 2048];
+    loop {
+        let mut received = false;
+        while let Ok((size, addr)) = a_sock.recv_from(&mut buf) {
+            a.handle_packet(a_sock, &buf[..size], addr, now);
+            received = true;
+        }
+        while let Ok((size, addr)) = b_sock.recv_from(&mut buf) {
+            b.handle_packet(b_sock, &buf[..size], addr, now);
+            received = true;
+        }
+        if !received {
+            break
+        }
+    }
+}
+
+/// Check that both sides agreed on session PKs.
+fn assert_connected(a: &NetCrypto, b: &NetCrypto) {
+    let a_conn = a.connection(&b.keys().real_pk).unwrap();
+    let b_conn = b.connection(&a.keys().real_pk).unwrap();
+    assert_eq!(ConnectionStatus::NotConfirmed, a_conn.status());
+    assert_eq!(ConnectionStatus::NotConfirmed, b_conn.status());
+    assert_eq!(Some(b_conn.session_pk()), a_conn.peer_session_pk());
+    assert_eq!(Some(a_conn.session_pk()), b_conn.peer_session_pk());
+    assert!(a_conn.session_key().is_some());
+    assert!(b_conn.session_key().is_some());
+}
+
+
+// CryptoConnection::new()
+
+#[test]
+fn crypto_connection_new_test() {
+    let keys = new_keys();
+    let (peer_pk, peer_sk) = gen_keypair();
+    let mut connection = CryptoConnection::new(&keys, &gen_keypair().0, &peer_pk,
+                                               "1.2.3.4:33445".parse().unwrap());
+    assert_eq!(ConnectionStatus::CookieRequesting, connection.status());
+    assert_eq!(None, connection.peer_session_pk());
+    assert!(connection.session_key().is_none());
+
+    let packet = connection.next_packet(0).unwrap();
+    let request = CookieRequest::from_bytes(&packet).unwrap();
+    assert_eq!(keys.real_pk, request.get_payload(&peer_sk).unwrap().real_pk);
+}
+
+// CryptoConnection::next_packet()
+
+#[test]
+fn crypto_connection_next_packet_timeout_test() {
+    let mut connection = CryptoConnection::new(&new_keys(), &gen_keypair().0,
+                                               &gen_keypair().0,
+                                               "1.2.3.4:33445".parse().unwrap());
+    let mut now = 0;
+    for _ in 0..MAX_NUM_SENDPACKET_TRIES {
+        assert!(connection.next_packet(now).is_some());
+        // not resent before interval passes
+        assert_eq!(None, connection.next_packet(now + CRYPTO_SEND_PACKET_INTERVAL - 1));
+        now += CRYPTO_SEND_PACKET_INTERVAL;
+    }
+    assert_eq!(ConnectionStatus::CookieRequesting, connection.status());
+    assert_eq!(None, connection.next_packet(now));
+    assert_eq!(ConnectionStatus::TimedOut, connection.status());
+}
+
+// CryptoConnection::handle_cookie_response()
+
+#[test]
+fn crypto_connection_handle_cookie_response_test() {
+    let keys = new_keys();
+    let peer_keys = new_keys();
+    let issuer = CookieIssuer::new();
+    let peer_issuer = CookieIssuer::new();
+    let mut connection = CryptoConnection::new(&keys, &peer_keys.real_pk,
+                                               &peer_keys.dht_pk,
+                                               "1.2.3.4:33445".parse().unwrap());
+    let request = CookieRequest::from_bytes(&connection.next_packet(0).unwrap())
+        .unwrap();
+    let response = peer_issuer.handle_request(&peer_keys.dht_sk, &request, 0)
+        .unwrap();
+
+    // wrong echo ID
+    let wrong = CookieResponse::new(&peer_keys.dht_sk, &keys.dht_pk,
+                                    &CookieResponsePayload {
+        cookie: peer_issuer.issue(&keys.real_pk, &keys.dht_pk, 0),
+        echo_id: response.get_payload(&keys.dht_sk, &peer_keys.dht_pk)
+            .unwrap().echo_id.wrapping_add(1),
+    });
+    assert_eq!(false, connection.handle_cookie_response(&wrong, &issuer, 0));
+
+    assert!(connection.handle_cookie_response(&response, &issuer, 0));
+    assert_eq!(ConnectionStatus::HandshakeSending, connection.status());
+    // handshake is sent immediately
+    let handshake = CryptoHandshake::from_bytes(&connection.next_packet(0).unwrap())
+        .unwrap();
+    let cookie = peer_issuer.check(&handshake.cookie, 0).unwrap();
+    assert_eq!(keys.real_pk, cookie.real_pk);
+    let payload = handshake.get_payload(&peer_keys.real_sk, &cookie.real_pk).unwrap();
+    assert_eq!(*connection.session_pk(), payload.session_pk);
+    assert!(issuer.check(&payload.other_cookie, 0).is_some());
+
+    // response is accepted only once
+    assert_eq!(false, connection.handle_cookie_response(&response, &issuer, 0));
+}
+
+// NetCrypto::handle_packet()
+
+#[test]
+fn net_crypto_connect_test() {
+    let net = SimNetwork::new();
+    let a_sock = net.bind("1.0.0.1:33445");
+    let b_sock = net.bind("1.0.0.2:33445");
+    let mut a = accepting();
+    let mut b = accepting();
+
+    let (b_real_pk, b_dht_pk) = (b.keys().real_pk, b.keys().dht_pk);
+    assert!(a.add_connection(&b_real_pk, &b_dht_pk, b_sock.addr));
+    assert_eq!(false, a.add_connection(&b_real_pk, &b_dht_pk, b_sock.addr));
+    assert_eq!(1, a.send_packets(&a_sock, 0));
+    pump(&mut a, &a_sock, &mut b, &b_sock, 0);
+
+    // B accepted connection from A
+    assert_connected(&a, &b);
+    assert_eq!(a_sock.addr, b.connection(&a.keys().real_pk).unwrap().addr);
+}
+
+#[test]
+fn net_crypto_on_new_connection_test() {
+    let net = SimNetwork::new();
+    let a_sock = net.bind("1.0.0.1:33445");
+    let b_sock = net.bind("1.0.0.2:33445");
+    let mut a = accepting();
+    let mut b = NetCrypto::new(&new_keys());
+    let (a_real_pk, a_dht_pk) = (a.keys().real_pk, a.keys().dht_pk);
+    let (b_real_pk, b_dht_pk) = (b.keys().real_pk, b.keys().dht_pk);
+
+    // without a callback connection is rejected
+    a.add_connection(&b_real_pk, &b_dht_pk, b_sock.addr);
+    a.send_packets(&a_sock, 0);
+    pump(&mut a, &a_sock, &mut b, &b_sock, 0);
+    assert!(b.connections().is_empty());
+
+    // callback is called with both PKs of the peer
+    let peers = Rc::new(RefCell::new(Vec::new()));
+    let peers_c = peers.clone();
+    b.on_new_connection(Box::new(move |real_pk: &PublicKey, dht_pk: &PublicKey| {
+        peers_c.borrow_mut().push((*real_pk, *dht_pk));
+        false
+    }));
+    a.send_packets(&a_sock, CRYPTO_SEND_PACKET_INTERVAL);
+    pump(&mut a, &a_sock, &mut b, &b_sock, CRYPTO_SEND_PACKET_INTERVAL);
+    assert_eq!(vec![(a_real_pk, a_dht_pk)], *peers.borrow());
+    assert!(b.connections().is_empty());
+}
+
+#[test]
+fn net_crypto_max_pending_connections_test() {
+    let net = SimNetwork::new();
+    let a_sock = net.bind("1.0.0.1:33445");
+    let mut a = accepting();
+    let (a_real_pk, a_dht_pk) = (a.keys().real_pk, a.keys().dht_pk);
+
+    for n in 0..MAX_PENDING_CONNECTIONS + 1 {
+        let sock = net.bind(&format!("1.0.1.{}:33445", n + 1));
+        let mut peer = accepting();
+        peer.add_connection(&a_real_pk, &a_dht_pk, a_sock.addr);
+        peer.send_packets(&sock, 0);
+        pump(&mut a, &a_sock, &mut peer, &sock, 0);
+    }
+    // connections that didn't receive any data aren't established
+    assert_eq!(MAX_PENDING_CONNECTIONS, a.connections().len());
+}
+
+#[test]
+fn net_crypto_simultaneous_open_test() {
+    let net = SimNetwork::new();
+    let a_sock = net.bind("1.0.0.1:33445");
+    let b_sock = net.bind("1.0.0.2:33445");
+    let mut a = accepting();
+    let mut b = accepting();
+
+    let (a_real_pk, a_dht_pk) = (a.keys().real_pk, a.keys().dht_pk);
+    let (b_real_pk, b_dht_pk) = (b.keys().real_pk, b.keys().dht_pk);
+    assert!(a.add_connection(&b_real_pk, &b_dht_pk, b_sock.addr));
+    assert!(b.add_connection(&a_real_pk, &a_dht_pk, a_sock.addr));
+    assert_eq!(1, a.send_packets(&a_sock, 0));
+    assert_eq!(1, b.send_packets(&b_sock, 0));
+    pump(&mut a, &a_sock, &mut b, &b_sock, 0);
+
+    assert_connected(&a, &b);
+    assert_eq!(1, a.connections().len());
+    assert_eq!(1, b.connections().len());
+}
+
+#[test]
+fn net_crypto_peer_restart_test() {
+    let net = SimNetwork::new();
+    let a_sock = net.bind("1.0.0.1:33445");
+    let b_sock = net.bind("1.0.0.2:33445");
+    let mut a = accepting();
+    let mut b = accepting();
+
+    let (a_real_pk, a_dht_pk) = (a.keys().real_pk, a.keys().dht_pk);
+    let (b_real_pk, b_dht_pk) = (b.keys().real_pk, b.keys().dht_pk);
+    a.add_connection(&b_real_pk, &b_dht_pk, b_sock.addr);
+    a.send_packets(&a_sock, 0);
+    pump(&mut a, &a_sock, &mut b, &b_sock, 0);
+    let old_session_pk = *a.connection(&b_real_pk).unwrap().session_pk();
+
+    // B forgets connection and connects again with a new session
+    assert!(b.remove_connection(&a_real_pk));
+    b.add_connection(&a_real_pk, &a_dht_pk, a_sock.addr);
+    b.send_packets(&b_sock, 1000);
+    pump(&mut a, &a_sock, &mut b, &b_sock, 1000);
+
+    assert_connected(&a, &b);
+    assert!(old_session_pk != *a.connection(&b_real_pk).unwrap().session_pk());
+}
+
+#[test]
+fn net_crypto_handle_packet_unexpected_test() {
+    let net = SimNetwork::new();
+    let a_sock = net.bind("1.0.0.1:33445");
+    let b_sock = net.bind("1.0.0.2:33445");
+    let mut a = accepting();
+    let mut b = accepting();
+
+    // cookie response without a connection
+    let (a_real_pk, a_dht_pk) = (a.keys().real_pk, a.keys().dht_pk);
+    b.add_connection(&a_real_pk, &a_dht_pk, a_sock.addr);
+    b.send_packets(&b_sock, 0);
+    let mut buf = [0; 2048];
+    let (size, addr) = a_sock.recv_from(&mut buf).unwrap();
+    assert!(a.handle_packet(&a_sock, &buf[..size], addr, 0));
+    let (size, addr) = b_sock.recv_from(&mut buf).unwrap();
+    let response = buf[..size].to_vec();
+    assert_eq!(false, a.handle_packet(&a_sock, &response, addr, 0));
+
+    // handshake with expired cookie
+    assert!(b.handle_packet(&b_sock, &response, a_sock.addr, 0));
+    let (size, addr) = a_sock.recv_from(&mut buf).unwrap();
+    let now = (COOKIE_TIMEOUT + 1) * 1000;
+    assert_eq!(false, a.handle_packet(&a_sock, &buf[..size], addr, now));
+    assert!(a.connections().is_empty());
+
+    assert_eq!(false, a.handle_packet(&a_sock, &[0; 10], addr, 0));
+}
+
+/// Create two `NetCrypto`s with a connection between them, where A
+/// initiated the connection.
+fn connected() -> (SimSocket, NetCrypto, SimSocket, NetCrypto) {
+    let net = SimNetwork::new();
+    let a_sock = net.bind("1.0.0.1:33445");
+    let b_sock = net.bind("1.0.0.2:33445");
+    let mut a = accepting();
+    let mut b = accepting();
+    let (b_real_pk, b_dht_pk) = (b.keys().real_pk, b.keys().dht_pk);
+    a.add_connection(&b_real_pk, &b_dht_pk, b_sock.addr);
+    a.send_packets(&a_sock, 0);
+    pump(&mut a, &a_sock, &mut b, &b_sock, 0);
+    (a_sock, a, b_sock, b)
+}
+
+/// Receive all packets waiting in the socket.
+fn recv_all(sock: &SimSocket) -> Vec<(Vec<u8>, SocketAddr)> {
+    let mut buf = [0; 2048];
+    let mut result = Vec::new();
+    while let Ok((size, addr)) = sock.recv_from(&mut buf) {
+        result.push((buf[..size].to_vec(), addr));
+    }
+    result
+}
+
+/// All messages received by `net_crypto` from the peer with `pk`.
+fn recv_messages(net_crypto: &mut NetCrypto, pk: &PublicKey) -> Vec<CryptoMessage> {
+    let connection = net_crypto.connection_mut(pk).unwrap();
+    let mut result = Vec::new();
+    while let Some(message) = connection.recv_message() {
+        result.push(message);
+    }
+    result
+}
+
+
+// CryptoMessage::from_bytes()
+
+#[test]
+fn crypto_message_from_bytes_test() {
+    let message = CryptoMessage::new(PACKET_ID_LOSSY_START, &[1, 2, 3]);
+    let bytes = message.to_bytes();
+    assert_eq!(vec![PACKET_ID_LOSSY_START, 1, 2, 3], bytes);
+    assert_eq!(Some(message), CryptoMessage::from_bytes(&bytes));
+    assert_eq!(None, CryptoMessage::from_bytes(&[]));
+}
+
+// CryptoMessage::is_lossless()
+
+#[test]
+fn crypto_message_is_lossless_test() {
+    for id in 0..256u16 {
+        let message = CryptoMessage::new(id as u8, &[]);
+        let id = id as u8;
+        assert_eq!(id >= 16 && id <= 191, message.is_lossless());
+        assert_eq!(id >= 192 && id <= 254, message.is_lossy());
+    }
+}
+
+// CryptoConnection::send_message()
+
+#[test]
+fn crypto_connection_send_message_not_connected_test() {
+    let mut connection = CryptoConnection::new(&new_keys(), &gen_keypair().0,
+                                               &gen_keypair().0,
+                                               "1.2.3.4:33445".parse().unwrap());
+    let message = CryptoMessage::new(PACKET_ID_LOSSLESS_START, &[1]);
+    assert_eq!(None, connection.send_message(&message, 0));
+    assert_eq!(None, connection.kill());
+    assert_eq!(ConnectionStatus::Killed, connection.status());
+}
+
+#[test]
+fn crypto_connection_send_message_test() {
+    let (_a_sock, mut a, _b_sock, b) = connected();
+    let b_real_pk = b.keys().real_pk;
+    let connection = a.connection_mut(&b_real_pk).unwrap();
+
+    let lossless = CryptoMessage::new(PACKET_ID_LOSSLESS_START, &[1]);
+    assert!(connection.send_message(&lossless, 0).is_some());
+    assert_eq!(1, connection.send_buffer().len());
+    // lossy messages aren't kept
+    let lossy = CryptoMessage::new(PACKET_ID_LOSSY_START, &[1]);
+    assert!(connection.send_message(&lossy, 0).is_some());
+    assert_eq!(1, connection.send_buffer().len());
+    // reserved IDs
+    assert_eq!(None, connection.send_message(&CryptoMessage::new(PACKET_ID_KILL, &[]), 0));
+    assert_eq!(None, connection.send_message(&CryptoMessage::new(255, &[]), 0));
+    // too big
+    let big = CryptoMessage::new(PACKET_ID_LOSSY_START, &[0; MAX_CRYPTO_DATA_SIZE]);
+    assert_eq!(None, connection.send_message(&big, 0));
+}
+
+// NetCrypto::send_message()
+
+#[test]
+fn net_crypto_send_message_test() {
+    let (a_sock, mut a, b_sock, mut b) = connected();
+    let a_real_pk = a.keys().real_pk;
+    let b_real_pk = b.keys().real_pk;
+
+    let lossless = CryptoMessage::new(PACKET_ID_LOSSLESS_START, &[1, 2, 3]);
+    let lossy = CryptoMessage::new(PACKET_ID_LOSSY_START, &[4, 5]);
+    assert!(a.send_message(&a_sock, &b_real_pk, &lossless, 10));
+    assert!(a.send_message(&a_sock, &b_real_pk, &lossy, 10));
+    assert_eq!(false, a.send_message(&a_sock, &gen_keypair().0, &lossy, 10));
+    pump(&mut a, &a_sock, &mut b, &b_sock, 10);
+
+    // receiving data establishes connection
+    assert_eq!(ConnectionStatus::Established,
+               b.connection(&a_real_pk).unwrap().status());
+    assert_eq!(vec![lossless.clone(), lossy], recv_messages(&mut b, &a_real_pk));
+
+    // B acknowledges the lossless message
+    assert_eq!(1, a.connection(&b_real_pk).unwrap().send_buffer().len());
+    assert_eq!(1, b.send_packets(&b_sock, 20));
+    pump(&mut a, &a_sock, &mut b, &b_sock, 20);
+    let a_conn = a.connection(&b_real_pk).unwrap();
+    assert_eq!(ConnectionStatus::Established, a_conn.status());
+    assert!(a_conn.send_buffer().is_empty());
+    // nothing more to send
+    assert_eq!(0, a.send_packets(&a_sock, 20 + CRYPTO_INITIAL_RTO));
+    assert_eq!(0, b.send_packets(&b_sock, 20 + CRYPTO_INITIAL_RTO));
+
+    // and the other way around
+    assert!(b.send_message(&b_sock, &a_real_pk, &lossless, 30));
+    pump(&mut a, &a_sock, &mut b, &b_sock, 30);
+    assert_eq!(vec![lossless], recv_messages(&mut a, &b_real_pk));
+}
+
+#[test]
+fn net_crypto_lossless_order_test() {
+    let (a_sock, mut a, b_sock, mut b) = connected();
+    let a_real_pk = a.keys().real_pk;
+    let b_real_pk = b.keys().real_pk;
+
+    let messages = (0..3).map(|n| CryptoMessage::new(PACKET_ID_LOSSLESS_START, &[n]))
+        .collect::<Vec<_>>();
+    for message in &messages {
+        assert!(a.send_message(&a_sock, &b_real_pk, message, 0));
+    }
+    // second message is lost
+    let packets = recv_all(&b_sock);
+    assert_eq!(3, packets.len());
+    for &i in &[2, 0] {
+        let (ref packet, addr) = packets[i];
+        assert!(b.handle_packet(&b_sock, packet, addr, 0));
+    }
+    assert_eq!(vec![messages[0].clone()], recv_messages(&mut b, &a_real_pk));
+    assert_eq!(1, b.connection(&a_real_pk).unwrap().recv_buffer().start());
+
+    // all unacknowledged messages are resent, and the handshake as well,
+    // since A hasn't received any data yet
+    assert_eq!(0, a.send_packets(&a_sock, CRYPTO_INITIAL_RTO - 1));
+    assert_eq!(4, a.send_packets(&a_sock, CRYPTO_INITIAL_RTO));
+    pump(&mut a, &a_sock, &mut b, &b_sock, CRYPTO_INITIAL_RTO);
+    assert_eq!(messages[1..].to_vec(), recv_messages(&mut b, &a_real_pk));
+}
+
+#[test]
+fn net_crypto_kill_connection_test() {
+    let (a_sock, mut a, b_sock, mut b) = connected();
+    let a_real_pk = a.keys().real_pk;
+    let b_real_pk = b.keys().real_pk;
+
+    assert!(a.kill_connection(&a_sock, &b_real_pk));
+    assert!(a.connection(&b_real_pk).is_none());
+    assert_eq!(false, a.kill_connection(&a_sock, &b_real_pk));
+    pump(&mut a, &a_sock, &mut b, &b_sock, 0);
+    assert_eq!(ConnectionStatus::Killed, b.connection(&a_real_pk).unwrap().status());
+
+    // killed connection can be started again
+    let a_dht_pk = a.keys().dht_pk;
+    assert!(b.add_connection(&a_real_pk, &a_dht_pk, a_sock.addr));
+    b.send_packets(&b_sock, 1000);
+    pump(&mut a, &a_sock, &mut b, &b_sock, 1000);
+    assert_connected(&a, &b);
+}
+
+#[test]
+fn net_crypto_handle_data_wrong_addr_test() {
+    let (a_sock, mut a, b_sock, mut b) = connected();
+    let b_real_pk = b.keys().real_pk;
+    let message = CryptoMessage::new(PACKET_ID_LOSSY_START, &[1]);
+    assert!(a.send_message(&a_sock, &b_real_pk, &message, 0));
+    let (packet, _) = recv_all(&b_sock).remove(0);
+    assert_eq!(false, b.handle_packet(&b_sock, &packet, "1.0.0.3:33445".parse().unwrap(), 0));
+    assert!(b.handle_packet(&b_sock, &packet, a_sock.addr, 0));
+    assert_eq!(vec![message], recv_messages(&mut b, &a.keys().real_pk));
+}
+
+#[test]
+fn net_crypto_handle_data_reordered_test() {
+    let (a_sock, mut a, b_sock, mut b) = connected();
+    let a_real_pk = a.keys().real_pk;
+    let b_real_pk = b.keys().real_pk;
+
+    // peer's nonce is moved forward twice, while packets arrive reordered
+    let count = DATA_NUM_THRESHOLD as usize * 3 + 8;
+    let message = CryptoMessage::new(PACKET_ID_LOSSY_START, &[1]);
+    for _ in 0..count / 4 {
+        for _ in 0..4 {
+            assert!(a.send_message(&a_sock, &b_real_pk, &message, 0));
+        }
+        for (packet, addr) in recv_all(&b_sock).into_iter().rev() {
+            assert!(b.handle_packet(&b_sock, &packet, addr, 0));
+        }
+        assert_eq!(4, recv_messages(&mut b, &a_real_pk).len());
+    }
+}
+
+// RttEstimator::add_sample()
+
+#[test]
+fn rtt_estimator_add_sample_test() {
+    let mut rtt = RttEstimator::new();
+    assert_eq!(None, rtt.srtt());
+    assert_eq!(CRYPTO_INITIAL_RTO, rtt.rto());
+
+    rtt.add_sample(100);
+    assert_eq!(Some(100), rtt.srtt());
+    assert_eq!(300, rtt.rto());
+    rtt.add_sample(100);
+    assert_eq!(Some(100), rtt.srtt());
+    assert_eq!(248, rtt.rto());
+    rtt.add_sample(180);
+    assert_eq!(Some(110), rtt.srtt());
+
+    // timeout is never too short
+    let mut rtt = RttEstimator::new();
+    rtt.add_sample(1);
+    assert_eq!(CRYPTO_MIN_RTO, rtt.rto());
+}
+
+// RttEstimator::backoff()
+
+#[test]
+fn rtt_estimator_backoff_test() {
+    let mut rtt = RttEstimator::new();
+    rtt.backoff();
+    assert_eq!(2 * CRYPTO_INITIAL_RTO, rtt.rto());
+    for _ in 0..10 {
+        rtt.backoff();
+    }
+    assert_eq!(CRYPTO_MAX_RTO, rtt.rto());
+    // new sample resets the timeout
+    rtt.add_sample(100);
+    assert_eq!(300, rtt.rto());
+}
+
+// CryptoConnection::data_packets()
+
+#[test]
+fn net_crypto_resend_requested_test() {
+    let (a_sock, mut a, b_sock, mut b) = connected();
+    let a_real_pk = a.keys().real_pk;
+    let b_real_pk = b.keys().real_pk;
+
+    let messages = (0..5).map(|n| CryptoMessage::new(PACKET_ID_LOSSLESS_START, &[n]))
+        .collect::<Vec<_>>();
+    for message in &messages {
+        assert!(a.send_message(&a_sock, &b_real_pk, message, 0));
+    }
+    // packets 1 and 3 are lost
+    let packets = recv_all(&b_sock);
+    for &i in &[0, 2, 4] {
+        let (ref packet, addr) = packets[i];
+        assert!(b.handle_packet(&b_sock, packet, addr, 0));
+    }
+    assert_eq!(vec![messages[0].clone()], recv_messages(&mut b, &a_real_pk));
+
+    // B requests the missing packets
+    assert_eq!(1, b.send_packets(&b_sock, 10));
+    pump(&mut a, &a_sock, &mut b, &b_sock, 10);
+    {
+        let a_conn = a.connection(&b_real_pk).unwrap();
+        assert_eq!(Some(10), a_conn.rtt().srtt());
+        // packet 0 was acknowledged, and packet 2 was received
+        assert_eq!(vec![1, 3, 4], a_conn.send_buffer().numbers());
+    }
+
+    // and only they are resent
+    assert_eq!(2, a.send_packets(&a_sock, 10));
+    for (packet, addr) in recv_all(&b_sock) {
+        assert!(b.handle_packet(&b_sock, &packet, addr, 10));
+    }
+    assert_eq!(messages[1..].to_vec(), recv_messages(&mut b, &a_real_pk));
+
+    // request isn't repeated, since nothing is missing
+    assert_eq!(1, b.send_packets(&b_sock, 20));
+    pump(&mut a, &a_sock, &mut b, &b_sock, 20);
+    assert_eq!(0, b.send_packets(&b_sock, 20 + PACKET_REQUEST_INTERVAL));
+    assert!(a.connection(&b_real_pk).unwrap().send_buffer().is_empty());
+}
+
+#[test]
+fn net_crypto_request_repeated_test() {
+    let (a_sock, mut a, b_sock, mut b) = connected();
+    let b_real_pk = b.keys().real_pk;
+
+    for n in 0..2 {
+        let message = CryptoMessage::new(PACKET_ID_LOSSLESS_START, &[n]);
+        assert!(a.send_message(&a_sock, &b_real_pk, &message, 0));
+    }
+    // first packet is lost
+    let (packet, addr) = recv_all(&b_sock).remove(1);
+    assert!(b.handle_packet(&b_sock, &packet, addr, 0));
+
+    // request is lost as well, so it's repeated
+    assert_eq!(1, b.send_packets(&b_sock, 0));
+    recv_all(&a_sock);
+    assert_eq!(0, b.send_packets(&b_sock, PACKET_REQUEST_INTERVAL - 1));
+    assert_eq!(1, b.send_packets(&b_sock, PACKET_REQUEST_INTERVAL));
+}
+
+#[test]
+fn net_crypto_lossy_network_test() {
+    let net = SimNetwork::new();
+    net.set_impairment(20, 20, 42);
+    let a_sock = net.bind("1.0.0.1:33445");
+    let b_sock = net.bind("1.0.0.2:33445");
+    let mut a = accepting();
+    let mut b = accepting();
+    let (a_real_pk, b_real_pk) = (a.keys().real_pk, b.keys().real_pk);
+    let b_dht_pk = b.keys().dht_pk;
+    a.add_connection(&b_real_pk, &b_dht_pk, b_sock.addr);
+
+    const COUNT: u16 = 300;
+    let message = |n: u16| CryptoMessage::new(PACKET_ID_LOSSLESS_START,
+                                              &[(n >> 8) as u8, n as u8]);
+    let (mut a_sent, mut b_sent) = (0, 0);
+    let (mut a_received, mut b_received) = (Vec::new(), Vec::new());
+    let mut now = 0;
+    while (a_received.len() < COUNT as usize || b_received.len() < COUNT as usize)
+          && now < 600000 {
+        // both sides send a few messages every step, as far as congestion
+        // control allows
+        for _ in 0..5 {
+            if a_sent < COUNT && a.connection(&b_real_pk).map_or(false, |c| c.is_connected())
+               && a.send_message(&a_sock, &b_real_pk, &message(a_sent), now) {
+                a_sent += 1;
+            }
+            if b_sent < COUNT && b.connection(&a_real_pk).map_or(false, |c| c.is_connected())
+               && b.send_message(&b_sock, &a_real_pk, &message(b_sent), now) {
+                b_sent += 1;
+            }
+        }
+        a.send_packets(&a_sock, now);
+        b.send_packets(&b_sock, now);
+        for (packet, addr) in recv_all(&a_sock) {
+            a.handle_packet(&a_sock, &packet, addr, now);
+        }
+        for (packet, addr) in recv_all(&b_sock) {
+            b.handle_packet(&b_sock, &packet, addr, now);
+        }
+        if let Some(c) = a.connection_mut(&b_real_pk) {
+            while let Some(m) = c.recv_message() {
+                a_received.push(m);
+            }
+        }
+        if let Some(c) = b.connection_mut(&a_real_pk) {
+            while let Some(m) = c.recv_message() {
+                b_received.push(m);
+            }
+        }
+        now += 10;
+    }
+
+    // all messages arrived exactly once, in order
+    let expected = (0..COUNT).map(&message).collect::<Vec<_>>();
+    assert_eq!(expected, a_received);
+    assert_eq!(expected, b_received);
+    let a_conn = a.connection(&b_real_pk).unwrap();
+    assert_eq!(ConnectionStatus::Established, a_conn.status());
+    assert!(a_conn.rtt().srtt().is_some());
+}
+
+#[test]
+fn crypto_connection_data_packets_congested_test() {
+    let (a_sock, mut a, b_sock, b) = connected();
+    let b_real_pk = b.keys().real_pk;
+    const COUNT: u32 = CRYPTO_MIN_QUEUE_LENGTH as u32;
+    for n in 0..COUNT {
+        let message = CryptoMessage::new(PACKET_ID_LOSSLESS_START, &[n as u8]);
+        assert!(a.send_message(&a_sock, &b_real_pk, &message, 0));
+    }
+    // all packets are lost
+    recv_all(&b_sock);
+
+    // timed out packets aren't resent all at once
+    let connection = a.connection_mut(&b_real_pk).unwrap();
+    let first = connection.data_packets(CRYPTO_INITIAL_RTO).len();
+    assert!(first > 0);
+    assert!(first < COUNT as usize);
+    let mut now = CRYPTO_INITIAL_RTO;
+    while now < 60000 {
+        now += 100;
+        assert!(connection.data_packets(now).len() < COUNT as usize / 2);
+    }
+    // but eventually all of them are
+    assert!((0..COUNT).all(|n| connection.send_buffer().get(n).unwrap().resent));
+}
+
+// CryptoConnection::metrics()
+
+#[test]
+fn crypto_connection_metrics_test() {
+    let (a_sock, mut a, b_sock, mut b) = connected();
+    let b_real_pk = b.keys().real_pk;
+    assert_eq!(CongestionMetrics {
+        send_rate: CRYPTO_PACKET_MIN_RATE,
+        queue_depth: 0,
+        rtt: None,
+    }, a.connection(&b_real_pk).unwrap().metrics());
+
+    for n in 0..3 {
+        let message = CryptoMessage::new(PACKET_ID_LOSSLESS_START, &[n]);
+        assert!(a.send_message(&a_sock, &b_real_pk, &message, 10));
+    }
+    // lossy messages aren't queued
+    let lossy = CryptoMessage::new(PACKET_ID_LOSSY_START, &[]);
+    assert!(a.send_message(&a_sock, &b_real_pk, &lossy, 10));
+    assert_eq!(3, a.connection(&b_real_pk).unwrap().metrics().queue_depth);
+
+    pump(&mut a, &a_sock, &mut b, &b_sock, 10);
+    assert_eq!(1, b.send_packets(&b_sock, 30));
+    pump(&mut a, &a_sock, &mut b, &b_sock, 30);
+    let metrics = a.connection(&b_real_pk).unwrap().metrics();
+    assert_eq!(0, metrics.queue_depth);
+    assert_eq!(Some(20), metrics.rtt);
+}
+
+// CryptoConnection::set_congestion_control()
+
+#[test]
+fn crypto_connection_set_congestion_control_test() {
+    let (a_sock, mut a, b_sock, mut b) = connected();
+    let b_real_pk = b.keys().real_pk;
+    a.connection_mut(&b_real_pk).unwrap()
+        .set_congestion_control(Box::new(Ledbat::new()));
+
+    let message = CryptoMessage::new(PACKET_ID_LOSSLESS_START, &[]);
+    for _ in 0..LEDBAT_INITIAL_WINDOW as usize {
+        assert!(a.send_message(&a_sock, &b_real_pk, &message, 0));
+    }
+    // window is full, but lossy messages can be still sent
+    assert!(!a.send_message(&a_sock, &b_real_pk, &message, 0));
+    let lossy = CryptoMessage::new(PACKET_ID_LOSSY_START, &[]);
+    assert!(a.send_message(&a_sock, &b_real_pk, &lossy, 0));
+
+    // acknowledgement makes room
+    pump(&mut a, &a_sock, &mut b, &b_sock, 0);
+    b.send_packets(&b_sock, 10);
+    pump(&mut a, &a_sock, &mut b, &b_sock, 10);
+    assert!(a.send_message(&a_sock, &b_real_pk, &message, 10));
+    assert_eq!(1, a.connection(&b_real_pk).unwrap().metrics().queue_depth);
+}
+
+// NetCrypto::send_packets()
+
+#[test]
+fn net_crypto_established_timeout_test() {
+    let (a_sock, mut a, b_sock, mut b) = connected();
+    let a_real_pk = a.keys().real_pk;
+    let b_real_pk = b.keys().real_pk;
+    let message = CryptoMessage::new(PACKET_ID_LOSSLESS_START, &[]);
+    assert!(a.send_message(&a_sock, &b_real_pk, &message, 0));
+    pump(&mut a, &a_sock, &mut b, &b_sock, 0);
+    b.send_packets(&b_sock, 0);
+    pump(&mut a, &a_sock, &mut b, &b_sock, 0);
+    assert_eq!(ConnectionStatus::Established,
+               a.connection(&b_real_pk).unwrap().status());
+
+    // keepalives keep connection established while both sides are alive
+    let mut now = 0;
+    while now < 2 * CRYPTO_CONNECTION_TIMEOUT {
+        now += CRYPTO_SEND_PACKET_INTERVAL;
+        a.send_packets(&a_sock, now);
+        b.send_packets(&b_sock, now);
+        pump(&mut a, &a_sock, &mut b, &b_sock, now);
+    }
+    assert_eq!(ConnectionStatus::Established,
+               a.connection(&b_real_pk).unwrap().status());
+    assert_eq!(ConnectionStatus::Established,
+               b.connection(&a_real_pk).unwrap().status());
+
+    // B disappears after its last message, with a message from A that
+    // wasn't acknowledged
+    let lossy = CryptoMessage::new(PACKET_ID_LOSSY_START, &[]);
+    assert!(b.send_message(&b_sock, &a_real_pk, &lossy, now));
+    pump(&mut a, &a_sock, &mut b, &b_sock, now);
+    assert!(a.send_message(&a_sock, &b_real_pk, &message, now));
+    recv_all(&b_sock);
+    let last_received = now;
+    while now + CRYPTO_SEND_PACKET_INTERVAL < last_received + CRYPTO_CONNECTION_TIMEOUT {
+        now += CRYPTO_SEND_PACKET_INTERVAL;
+        a.send_packets(&a_sock, now);
+        recv_all(&b_sock);
+    }
+    assert_eq!(ConnectionStatus::Established,
+               a.connection(&b_real_pk).unwrap().status());
+    assert_eq!(0, a.send_packets(&a_sock, last_received + CRYPTO_CONNECTION_TIMEOUT));
+    let a_conn = a.connection(&b_real_pk).unwrap();
+    assert_eq!(ConnectionStatus::TimedOut, a_conn.status());
+    assert!(a_conn.is_closed());
+}
@@ -75,6 +75,20 @@ fn random_u64_test() {
     assert!(a != b);
 }
 
+#[test]
+fn new_symmetric_key_test() {
+    let PrecomputedKey(a) = new_symmetric_key();
+    let PrecomputedKey(b) = new_symmetric_key();
+    assert!(a != [0; PRECOMPUTEDKEYBYTES]);
+    assert!(a != b);
+
+    // key works for encryption and decryption
+    let key = new_symmetric_key();
+    let nonce = gen_nonce();
+    let encrypted = encrypt_data_symmetric(&key, &nonce, &[1, 2, 3]);
+    assert_eq!(Ok(vec![1, 2, 3]), decrypt_data_symmetric(&key, &nonce, &encrypted));
+}
+
 
 #[test]
 fn public_key_valid_test() {
@@ -0,0 +1,289 @@
+/*
+    Copyright © 2016 Zetok Zalbavar <zexavexxe@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Tests for the `dht_bootstrap` module.
+
+use std::net::SocketAddr;
+
+use toxcore::binary_io::*;
+use toxcore::crypto_core::*;
+use toxcore::dht::*;
+use toxcore::dht_bootstrap::*;
+use toxcore::dht_friend::DhtFriends;
+
+use super::sim_network::RecordingTransport;
+
+
+fn bootstrap_nodes(num: u16) -> Vec<(SocketAddr, PublicKey)> {
+    (0..num).map(|n| {
+        (format!("127.0.0.1:{}", 33445 + n).parse().unwrap(), gen_keypair().0)
+    }).collect()
+}
+
+
+// Bootstrap::next_requests()
+
+#[test]
+fn bootstrap_next_requests_test() {
+    let (pk, _) = gen_keypair();
+    let kbucket = Kbucket::new(KBUCKET_MAX_ENTRIES as u8, &pk);
+    let nodes = bootstrap_nodes(3);
+    let mut bootstrap = Bootstrap::new(&nodes);
+
+    let requests = bootstrap.next_requests(&pk, &kbucket, 0);
+    assert_eq!(3, requests.len());
+    for (&(node, request), &(saddr, ref node_pk)) in requests.iter().zip(&nodes) {
+        assert_eq!(saddr, node.saddr);
+        assert_eq!(node_pk, &node.pk);
+        assert_eq!(pk, request.pk);
+    }
+
+    // backoff
+    assert!(bootstrap.next_requests(&pk, &kbucket, 1).is_empty());
+    assert_eq!(3, bootstrap.next_requests(&pk, &kbucket, BOOTSTRAP_RETRY_BASE).len());
+    let second = BOOTSTRAP_RETRY_BASE + BOOTSTRAP_RETRY_BASE * 2;
+    assert!(bootstrap.next_requests(&pk, &kbucket, second - 1).is_empty());
+    assert_eq!(3, bootstrap.next_requests(&pk, &kbucket, second).len());
+
+    // delay doesn't grow beyond maximum
+    let mut now = second;
+    for _ in 0..20 {
+        now += BOOTSTRAP_RETRY_MAX;
+        assert_eq!(3, bootstrap.next_requests(&pk, &kbucket, now).len());
+    }
+}
+
+#[test]
+fn bootstrap_next_requests_test_done() {
+    let (pk, _) = gen_keypair();
+    let mut kbucket = Kbucket::new(KBUCKET_MAX_ENTRIES as u8, &pk);
+    let nodes = bootstrap_nodes(1);
+    let mut bootstrap = Bootstrap::new(&nodes);
+    bootstrap.enough_nodes = 1;
+    assert_eq!(false, bootstrap.is_done(&kbucket, 0));
+
+    let node = PackedNode::new(true, nodes[0].0, &nodes[0].1);
+    assert!(kbucket.try_add(&Node::new(&node, 10)));
+    assert!(bootstrap.is_done(&kbucket, 0));
+    assert!(bootstrap.next_requests(&pk, &kbucket, 0).is_empty());
+
+    // node timed out
+    assert_eq!(false, bootstrap.is_done(&kbucket, 10));
+    assert_eq!(1, bootstrap.next_requests(&pk, &kbucket, 10).len());
+}
+
+// Bootstrap::add_nodes()
+
+#[test]
+fn bootstrap_add_nodes_test() {
+    let (pk, _) = gen_keypair();
+    let kbucket = Kbucket::new(KBUCKET_MAX_ENTRIES as u8, &pk);
+    let nodes: Vec<PackedNode> = bootstrap_nodes(3).iter()
+        .map(|&(saddr, ref pk)| PackedNode::new(true, saddr, pk))
+        .collect();
+    let mut bootstrap = Bootstrap::from_packed_nodes(&nodes[..2]);
+    // already known node is skipped
+    bootstrap.add_nodes(&nodes[1..]);
+
+    let requests = bootstrap.next_requests(&pk, &kbucket, 0);
+    assert_eq!(3, requests.len());
+    for (n, &(node, _)) in requests.iter().enumerate() {
+        assert_eq!(nodes[n], node);
+    }
+}
+
+// Bootstrap::send_requests()
+
+#[test]
+fn bootstrap_send_requests_test() {
+    let (pk, sk) = gen_keypair();
+    let kbucket = Kbucket::new(KBUCKET_MAX_ENTRIES as u8, &pk);
+    let (node_pk, node_sk) = gen_keypair();
+    let saddr: SocketAddr = "127.0.0.1:33445".parse().unwrap();
+    let mut bootstrap = Bootstrap::new(&[(saddr, node_pk)]);
+    let transport = RecordingTransport::new();
+
+    assert_eq!(1, bootstrap.send_requests(&transport, &pk, &sk, &kbucket, 0));
+    let sent = transport.sent.borrow();
+    assert_eq!(saddr, sent[0].1);
+
+    let packet = DhtPacket::from_bytes(&sent[0].0).unwrap();
+    assert_eq!(pk, packet.sender_pk);
+    match packet.get_packet(&node_sk) {
+        Some(DPacketT::GetNodes(gn)) => assert_eq!(pk, gn.pk),
+        p => panic!("Expected GetNodes, got {:?}", p),
+    }
+}
+
+
+// nodes_to_save()
+
+#[test]
+fn nodes_to_save_test() {
+    let (pk, _) = gen_keypair();
+    let mut kbucket = Kbucket::new(KBUCKET_MAX_ENTRIES as u8, &pk);
+    let mut friends = DhtFriends::new();
+    assert!(friends.add_friend(&gen_keypair().0, &kbucket, 0));
+
+    let nodes: Vec<PackedNode> = bootstrap_nodes(4).iter()
+        .map(|&(saddr, ref pk)| PackedNode::new(true, saddr, pk))
+        .collect();
+    // node known both to Kbucket and friend
+    assert!(kbucket.try_add(&Node::new(&nodes[0], 10)));
+    assert!(friends.try_add_node(&Node::new(&nodes[0], 10)));
+    assert!(kbucket.try_add(&Node::new(&nodes[1], 10)));
+    assert!(friends.try_add_node(&Node::new(&nodes[2], 10)));
+    // timed out node
+    assert!(friends.try_add_node(&Node::new(&nodes[3], 1)));
+
+    let saved = nodes_to_save(&kbucket, &friends, 5);
+    assert_eq!(3, saved.len());
+    for node in &nodes[..3] {
+        assert!(saved.contains(node));
+    }
+    assert!(nodes_to_save(&kbucket, &friends, 10).is_empty());
+}
+
+// save_nodes()
+
+#[test]
+fn save_nodes_test() {
+    let (pk1, _) = gen_keypair();
+    let (pk2, _) = gen_keypair();
+    let nodes = vec![
+        PackedNode::new(true, "1.2.3.4:33445".parse().unwrap(), &pk1),
+        PackedNode::new(true, "[2001:db8::1]:33445".parse().unwrap(), &pk2),
+    ];
+    let bytes = save_nodes(&nodes);
+    assert_eq!(PACKED_NODE_IPV4_SIZE + PACKED_NODE_IPV6_SIZE, bytes.len());
+    assert_eq!(&nodes[0].to_bytes()[..], &bytes[..PACKED_NODE_IPV4_SIZE]);
+    assert_eq!(&nodes[1].to_bytes()[..], &bytes[PACKED_NODE_IPV4_SIZE..]);
+    assert!(save_nodes(&[]).is_empty());
+}
+
+// load_nodes()
+
+#[test]
+fn load_nodes_test() {
+    let nodes: Vec<PackedNode> = bootstrap_nodes(5).iter()
+        .map(|&(saddr, ref pk)| PackedNode::new(true, saddr, pk))
+        .collect();
+    let mut bytes = save_nodes(&nodes);
+    assert_eq!(nodes, load_nodes(&bytes));
+
+    // parsing stops at invalid node
+    bytes.extend_from_slice(&[0xff; 10]);
+    assert_eq!(nodes, load_nodes(&bytes));
+
+    assert!(load_nodes(&[]).is_empty());
+    assert!(load_nodes(&[0xff; 100]).is_empty());
+}
+
+#[test]
+fn load_nodes_test_bootstrap() {
+    // nodes saved in the previous run are used to bootstrap
+    let (pk, sk) = gen_keypair();
+    let mut old_kbucket = Kbucket::new(KBUCKET_MAX_ENTRIES as u8, &pk);
+    let nodes = bootstrap_nodes(3);
+    for &(saddr, ref node_pk) in &nodes {
+        let node = PackedNode::new(true, saddr, node_pk);
+        assert!(old_kbucket.try_add(&Node::new(&node, 10)));
+    }
+    let saved = save_nodes(&nodes_to_save(&old_kbucket, &DhtFriends::new(), 0));
+
+    let kbucket = Kbucket::new(KBUCKET_MAX_ENTRIES as u8, &pk);
+    let mut bootstrap = Bootstrap::from_packed_nodes(&load_nodes(&saved));
+    let transport = RecordingTransport::new();
+    assert_eq!(3, bootstrap.send_requests(&transport, &pk, &sk, &kbucket, 0));
+    let sent = transport.sent.borrow();
+    for &(saddr, _) in &nodes {
+        assert!(sent.iter().any(|&(_, a)| a == saddr));
+    }
+}
+
+
+// parse_nodes_json()
+
+#[test]
+fn parse_nodes_json_test() {
+    let json = r#"{
+        "last_scan": 1468254440,
+        "nodes": [
+            {
+                "ipv4": "144.76.60.215",
+                "ipv6": "2a01:4f8:191:64d6::1",
+                "port": 33445,
+                "public_key": "04119E835DF3E78BACF0F84235B300546AF8B936F035185E2A8E9E0A67C8924F",
+                "status_udp": true,
+                "status_tcp": true
+            },
+            {
+                "ipv4": "23.226.230.47",
+                "ipv6": "-",
+                "port": 33445,
+                "public_key": "A09162D68618E742FFBCA1C2C70385E6679604B2D80EA6E84AD0996A1AC8A074",
+                "status_udp": true
+            },
+            {
+                "ipv4": "node.tox.example.org",
+                "ipv6": "-",
+                "port": 33445,
+                "public_key": "7A6098B590BDC73F9723FC59F82B3F9085A64D1B213AAF8E610FD351930D052D"
+            },
+            {
+                "ipv4": "178.21.112.187",
+                "ipv6": "-",
+                "port": 33445,
+                "public_key": "4B2C19E924972CB9B57732FB172F8A8604DE13EEDA2A6234E348983344B23057",
+                "status_udp": false
+            },
+            {
+                "ipv4": "195.154.119.113",
+                "ipv6": "-",
+                "port": 33445,
+                "public_key": "E398A69646B8CEACA9F0B84F553726C1C49270558C57DF5F3C368F05A7D71354F"
+            }
+        ]
+    }"#;
+    let nodes = parse_nodes_json(json).unwrap();
+    // first node has IPv4 and IPv6; hostname, UDP down and invalid PK are
+    // skipped
+    assert_eq!(3, nodes.len());
+
+    assert_eq!(IpType::U4, nodes[0].ip_type);
+    assert_eq!("144.76.60.215:33445".parse::<SocketAddr>().unwrap(),
+               nodes[0].saddr);
+    assert_eq!(IpType::U6, nodes[1].ip_type);
+    assert_eq!("[2a01:4f8:191:64d6::1]:33445".parse::<SocketAddr>().unwrap(),
+               nodes[1].saddr);
+    assert_eq!(nodes[0].pk, nodes[1].pk);
+    let PublicKey(ref pk) = nodes[0].pk;
+    assert_eq!(&[0x04, 0x11, 0x9E], &pk[..3]);
+
+    assert_eq!("23.226.230.47:33445".parse::<SocketAddr>().unwrap(),
+               nodes[2].saddr);
+}
+
+#[test]
+fn parse_nodes_json_test_invalid() {
+    assert_eq!(None, parse_nodes_json(""));
+    assert_eq!(None, parse_nodes_json("{}"));
+    assert_eq!(None, parse_nodes_json(r#"{"nodes": 5}"#));
+    assert_eq!(Some(vec![]), parse_nodes_json(r#"{"nodes": []}"#));
+}
@@ -0,0 +1,227 @@
+/*
+    Copyright © 2016 Zetok Zalbavar <zexavexxe@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Tests for the `dht_crawler` module.
+
+use rustc_serialize::json::Json;
+
+use toxcore::crypto_core::*;
+use toxcore::dht::*;
+use toxcore::dht_crawler::*;
+use toxcore::dht_node::DhtNode;
+use toxcore::network::Transport;
+
+use super::sim_network::*;
+
+
+fn random_node(n: u8) -> PackedNode {
+    PackedNode::new(true, format!("1.0.0.{}:33445", n).parse().unwrap(),
+                    &gen_keypair().0)
+}
+
+
+// DhtCrawler::add_node()
+
+#[test]
+fn dht_crawler_add_node_test() {
+    let (pk, sk) = gen_keypair();
+    let node = random_node(1);
+    let mut crawler = DhtCrawler::new(&pk, &sk, &[node], 0);
+    assert_eq!(false, crawler.add_node(&node));
+    assert_eq!(false, crawler.add_node(&PackedNode::new(true, node.saddr, &pk)));
+    // same PK with a different address is crawled separately
+    let mut other = node;
+    other.saddr = "1.0.0.2:33445".parse().unwrap();
+    assert!(crawler.add_node(&other));
+    assert_eq!(2, crawler.report().len());
+    assert_eq!(1, crawler.unique_pks());
+}
+
+
+// DhtCrawler::next_requests()
+
+#[test]
+fn dht_crawler_next_requests_test() {
+    let (pk, sk) = gen_keypair();
+    let nodes: Vec<PackedNode> = (1..4).map(random_node).collect();
+    let mut crawler = DhtCrawler::new(&pk, &sk, &nodes, 2);
+    assert_eq!(false, crawler.is_finished());
+
+    // parallelism is respected
+    let requests = crawler.next_requests(0);
+    assert_eq!(2, requests.len());
+    assert!(crawler.next_requests(0).is_empty());
+
+    // targets are random
+    assert!(requests[0].1.pk != requests[1].1.pk);
+    assert!(requests[0].1.pk != pk);
+}
+
+#[test]
+fn dht_crawler_next_requests_test_timeout() {
+    let (pk, sk) = gen_keypair();
+    let node = random_node(1);
+    let mut crawler = DhtCrawler::new(&pk, &sk, &[node], 0);
+
+    let mut now = 0;
+    for _ in 0..CRAWLER_MAX_TRIES {
+        assert_eq!(1, crawler.next_requests(now).len());
+        assert!(crawler.next_requests(now + CRAWLER_REQUEST_TIMEOUT - 1).is_empty());
+        now += CRAWLER_REQUEST_TIMEOUT;
+    }
+    assert!(crawler.next_requests(now).is_empty());
+    assert!(crawler.is_finished());
+    assert_eq!(Reachability::Unreachable, crawler.report()[0].reachability);
+}
+
+
+// DhtCrawler::handle_response()
+
+#[test]
+fn dht_crawler_handle_response_test() {
+    let (pk, sk) = gen_keypair();
+    let node = random_node(1);
+    let mut crawler = DhtCrawler::new(&pk, &sk, &[node], 0);
+    let (_, request) = crawler.next_requests(0)[0];
+
+    let new = random_node(2);
+    let response = SendNodes::from_request(&request, vec![new]).unwrap();
+    // wrong ID
+    let wrong = SendNodes::from_request(&GetNodes::new(&pk), vec![new]).unwrap();
+    assert_eq!(false, crawler.handle_response(&node, &wrong));
+    // wrong address
+    let mut moved = node;
+    moved.saddr = "1.0.0.9:33445".parse().unwrap();
+    assert_eq!(false, crawler.handle_response(&moved, &response));
+
+    assert!(crawler.handle_response(&node, &response));
+    // only once
+    assert_eq!(false, crawler.handle_response(&node, &response));
+
+    let report = crawler.report();
+    assert_eq!(2, report.len());
+    assert_eq!(CrawledNode {
+        node: node,
+        reachability: Reachability::Reachable,
+        nodes_returned: 1,
+    }, report[0]);
+    assert_eq!(new, report[1].node);
+    assert_eq!(Reachability::Unknown, report[1].reachability);
+}
+
+
+// DhtCrawler::send_requests(), DhtCrawler::handle_packet()
+
+#[test]
+fn dht_crawler_sim_test() {
+    let net = SimNetwork::new();
+    let keys: Vec<(PublicKey, SecretKey)> = (0..6).map(|_| gen_keypair()).collect();
+    let sockets: Vec<SimSocket> = (0..6)
+        .map(|n| net.bind(&format!("1.0.{}.1:33445", n)))
+        .collect();
+    let mut nodes: Vec<DhtNode> = keys.iter()
+        .map(|&(ref pk, ref sk)| DhtNode::new(pk, sk))
+        .collect();
+    // every node knows only the next one
+    for n in 0..nodes.len() {
+        let next = (n + 1) % nodes.len();
+        let node = PackedNode::new(true, sockets[next].addr, &keys[next].0);
+        nodes[n].kbucket.try_add(&Node::new(&node, 1000));
+    }
+
+    let crawler_socket = net.bind("2.0.0.1:33445");
+    let (pk, sk) = gen_keypair();
+    let first = PackedNode::new(true, sockets[0].addr, &keys[0].0);
+    // node that doesn't exist
+    let dead = PackedNode::new(true, "3.0.0.1:33445".parse().unwrap(),
+                               &gen_keypair().0);
+    let mut crawler = DhtCrawler::new(&pk, &sk, &[first, dead], 0);
+
+    let mut now = 0;
+    let mut buf = [0; 2048];
+    while !crawler.is_finished() {
+        assert!(now < 100, "crawler didn't finish");
+        crawler.send_requests(&crawler_socket, now);
+        for (node, socket) in nodes.iter_mut().zip(&sockets) {
+            while let Ok((len, addr)) = socket.recv_from(&mut buf) {
+                node.handle_packet(socket, &buf[..len], addr, now);
+            }
+        }
+        while let Ok((len, addr)) = crawler_socket.recv_from(&mut buf) {
+            crawler.handle_packet(&buf[..len], addr);
+        }
+        now += 1;
+    }
+
+    let report = crawler.report();
+    assert_eq!(7, report.len());
+    assert_eq!(7, crawler.unique_pks());
+    for (pk, socket) in keys.iter().map(|k| k.0).zip(&sockets) {
+        let found = report.iter().find(|n| n.node.pk == pk).unwrap();
+        assert_eq!(socket.addr, found.node.saddr);
+        assert_eq!(Reachability::Reachable, found.reachability);
+    }
+    let found = report.iter().find(|n| n.node.pk == dead.pk).unwrap();
+    assert_eq!(Reachability::Unreachable, found.reachability);
+}
+
+
+// report_to_json()
+
+#[test]
+fn report_to_json_test() {
+    let node = random_node(1);
+    let report = vec![
+        CrawledNode { node: node, reachability: Reachability::Reachable,
+                      nodes_returned: 4 },
+        CrawledNode { node: random_node(2), reachability: Reachability::Unreachable,
+                      nodes_returned: 0 },
+    ];
+    let json = Json::from_str(&report_to_json(&report)).unwrap();
+    assert_eq!(Some(2), json.find("total").and_then(|j| j.as_u64()));
+    assert_eq!(Some(2), json.find("unique_pks").and_then(|j| j.as_u64()));
+    assert_eq!(Some(1), json.find("reachable").and_then(|j| j.as_u64()));
+
+    let nodes = json.find("nodes").and_then(|n| n.as_array()).unwrap();
+    assert_eq!(2, nodes.len());
+    let PublicKey(ref pk) = node.pk;
+    let pk_hex: String = pk.iter().map(|b| format!("{:02X}", b)).collect();
+    assert_eq!(Some(&pk_hex[..]),
+               nodes[0].find("public_key").and_then(|j| j.as_string()));
+    assert_eq!(Some("1.0.0.1"), nodes[0].find("ip").and_then(|j| j.as_string()));
+    assert_eq!(Some(33445), nodes[0].find("port").and_then(|j| j.as_u64()));
+    assert_eq!(Some("reachable"), nodes[0].find("status").and_then(|j| j.as_string()));
+    assert_eq!(Some(4), nodes[0].find("nodes_returned").and_then(|j| j.as_u64()));
+    assert_eq!(Some("unreachable"), nodes[1].find("status").and_then(|j| j.as_string()));
+}
+
+
+// report_to_csv()
+
+#[test]
+fn report_to_csv_test() {
+    let node = random_node(1);
+    let report = vec![CrawledNode { node: node, reachability: Reachability::Unknown,
+                                    nodes_returned: 0 }];
+    let PublicKey(ref pk) = node.pk;
+    let pk_hex: String = pk.iter().map(|b| format!("{:02X}", b)).collect();
+    assert_eq!(format!("public_key,ip,port,status,nodes_returned\n\
+                        {},1.0.0.1,33445,unknown,0\n", pk_hex),
+               report_to_csv(&report));
+}
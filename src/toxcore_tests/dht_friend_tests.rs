@@ -0,0 +1,292 @@
+/*
+    Copyright © 2016 Zetok Zalbavar <zexavexxe@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Tests for the `dht_friend` module.
+
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::net::SocketAddr;
+use std::rc::Rc;
+
+use toxcore::binary_io::*;
+use toxcore::crypto_core::*;
+use toxcore::dht::*;
+use toxcore::dht_friend::*;
+
+use super::quickcheck::quickcheck;
+use super::sim_network::RecordingTransport;
+
+
+fn random_node(port: u16, timeout: u64) -> Node {
+    let saddr = format!("127.0.0.1:{}", port).parse().unwrap();
+    Node::new(&PackedNode::new(true, saddr, &gen_keypair().0), timeout)
+}
+
+
+// DhtFriend::new()
+
+#[test]
+fn dht_friend_new_test() {
+    let (pk, _) = gen_keypair();
+    let friend = DhtFriend::new(&pk);
+    assert_eq!(pk, friend.pk);
+    assert_eq!(None, friend.addr);
+    assert!(friend.close_nodes().is_empty());
+}
+
+// DhtFriend::try_add_node()
+
+#[test]
+fn dht_friend_try_add_node_test() {
+    fn with_nodes(nodes: Vec<Node>) {
+        let (pk, _) = gen_keypair();
+        let mut friend = DhtFriend::new(&pk);
+        for node in &nodes {
+            friend.try_add_node(node);
+        }
+        assert!(friend.close_nodes().len() <= BUCKET_SIZE);
+        for pair in friend.close_nodes().windows(2) {
+            assert_eq!(Ordering::Less,
+                       pk.distance(pair[0].pk(), pair[1].pk()));
+        }
+        // furthest kept node is closer than any node that was dropped
+        if let Some(furthest) = friend.close_nodes().last() {
+            for node in &nodes {
+                if !friend.close_nodes().iter().any(|n| n.pk() == node.pk()) {
+                    assert_eq!(Ordering::Less,
+                               pk.distance(furthest.pk(), node.pk()));
+                }
+            }
+        }
+    }
+    quickcheck(with_nodes as fn(Vec<Node>));
+}
+
+#[test]
+fn dht_friend_try_add_node_test_friend() {
+    let (pk, _) = gen_keypair();
+    let mut friend = DhtFriend::new(&pk);
+    let saddr = "127.0.0.1:33445".parse().unwrap();
+    let node = Node::new(&PackedNode::new(true, saddr, &pk), 10);
+    assert_eq!(false, friend.try_add_node(&node));
+}
+
+// DhtFriend::next_requests()
+
+#[test]
+fn dht_friend_next_requests_test() {
+    let (pk, _) = gen_keypair();
+    let mut friend = DhtFriend::new(&pk);
+    assert!(friend.next_requests(0).is_empty());
+
+    assert!(friend.try_add_node(&random_node(1, 100)));
+    assert!(friend.try_add_node(&random_node(2, 10)));
+    let requests = friend.next_requests(0);
+    assert_eq!(2, requests.len());
+    for &(_, request) in &requests {
+        assert_eq!(pk, request.pk);
+    }
+
+    assert!(friend.next_requests(FRIEND_GET_NODES_INTERVAL - 1).is_empty());
+    // one of nodes has timed out
+    assert_eq!(1, friend.next_requests(FRIEND_GET_NODES_INTERVAL).len());
+    assert_eq!(1, friend.close_nodes().len());
+}
+
+// DhtFriend::handle_send_nodes()
+
+#[test]
+fn dht_friend_handle_send_nodes_test() {
+    let (pk, _) = gen_keypair();
+    let mut friend = DhtFriend::new(&pk);
+    let close = random_node(1, 100);
+    assert!(friend.try_add_node(&close));
+    let (node, request) = friend.next_requests(0)[0];
+
+    let other = random_node(2, 0).node;
+    let response = SendNodes::from_request(&request, vec![other]).unwrap();
+    // unexpected sender
+    assert_eq!(None, friend.handle_send_nodes(&gen_keypair().0, &response, 0));
+    assert_eq!(None, friend.handle_send_nodes(&node.pk, &response, 0));
+    assert_eq!(2, friend.close_nodes().len());
+    // response is accepted only once
+    assert_eq!(None, friend.handle_send_nodes(&node.pk, &response, 0));
+
+    let friend_saddr: SocketAddr = "127.0.0.2:33445".parse().unwrap();
+    let friend_node = PackedNode::new(true, friend_saddr, &pk);
+    let (node, request) = friend.next_requests(FRIEND_GET_NODES_INTERVAL)[0];
+    let response = SendNodes::from_request(&request, vec![friend_node]).unwrap();
+    assert_eq!(Some(friend_saddr),
+               friend.handle_send_nodes(&node.pk, &response,
+                                        FRIEND_GET_NODES_INTERVAL));
+    assert_eq!(Some(friend_saddr), friend.addr);
+}
+
+
+// DhtFriends::add_friend()
+
+#[test]
+fn dht_friends_add_friend_test() {
+    let (own_pk, _) = gen_keypair();
+    let mut kbucket = Kbucket::new(KBUCKET_MAX_ENTRIES as u8, &own_pk);
+    for port in 0..20 {
+        kbucket.try_add(&random_node(port, 10));
+    }
+    let (pk, _) = gen_keypair();
+    let mut friends = DhtFriends::new();
+    assert!(friends.add_friend(&pk, &kbucket, 0));
+    assert_eq!(false, friends.add_friend(&pk, &kbucket, 0));
+
+    let friend = friends.get(&pk).unwrap();
+    let expected = kbucket.get_closest(&pk, BUCKET_SIZE, 0);
+    let close: Vec<PackedNode> = friend.close_nodes().iter()
+        .map(|n| n.node).collect();
+    assert_eq!(expected, close);
+
+    assert!(friends.remove_friend(&pk));
+    assert_eq!(false, friends.remove_friend(&pk));
+    assert!(friends.friends().is_empty());
+}
+
+// DhtFriends::handle_send_nodes()
+
+#[test]
+fn dht_friends_handle_send_nodes_test() {
+    let (own_pk, _) = gen_keypair();
+    let mut kbucket = Kbucket::new(KBUCKET_MAX_ENTRIES as u8, &own_pk);
+    kbucket.try_add(&random_node(1, 10));
+
+    let (pk, _) = gen_keypair();
+    let mut friends = DhtFriends::new();
+    let found = Rc::new(RefCell::new(vec![]));
+    let found_c = found.clone();
+    friends.on_friend_addr(Box::new(move |pk: &PublicKey, addr: SocketAddr| {
+        found_c.borrow_mut().push((*pk, addr));
+    }));
+    assert!(friends.add_friend(&pk, &kbucket, 0));
+
+    let (node, request) = friends.next_requests(0)[0];
+    let friend_saddr: SocketAddr = "127.0.0.2:33445".parse().unwrap();
+    let friend_node = PackedNode::new(true, friend_saddr, &pk);
+    let response = SendNodes::from_request(&request, vec![friend_node]).unwrap();
+
+    assert!(friends.handle_send_nodes(&node.pk, &response, 0));
+    assert_eq!(vec![(pk, friend_saddr)], *found.borrow());
+    assert_eq!(false, friends.handle_send_nodes(&node.pk, &response, 0));
+}
+
+// DhtFriend::nat_ping_request()
+
+#[test]
+fn dht_friend_nat_ping_request_test() {
+    let (pk, _) = gen_keypair();
+    let mut friend = DhtFriend::new(&pk);
+    let ping = friend.nat_ping_request(0).unwrap();
+    assert!(ping.is_request());
+    assert_eq!(None, friend.nat_ping_request(NAT_PING_INTERVAL - 1));
+    assert!(friend.nat_ping_request(NAT_PING_INTERVAL).is_some());
+}
+
+// DhtFriend::handle_nat_ping_response()
+
+#[test]
+fn dht_friend_handle_nat_ping_response_test() {
+    let (pk, _) = gen_keypair();
+    let mut friend = DhtFriend::new(&pk);
+    let unexpected = Ping::new().response().unwrap();
+    assert_eq!(false, friend.handle_nat_ping_response(&unexpected, 0));
+
+    let ping = friend.nat_ping_request(0).unwrap();
+    assert_eq!(false, friend.handle_nat_ping_response(&ping, 0));
+    assert_eq!(false, friend.handle_nat_ping_response(&unexpected, 0));
+    assert_eq!(false, friend.is_nat_ping_confirmed(0));
+
+    assert!(friend.handle_nat_ping_response(&ping.response().unwrap(), 1));
+    assert!(friend.is_nat_ping_confirmed(1 + NAT_PING_INTERVAL * 2));
+    assert_eq!(false, friend.is_nat_ping_confirmed(2 + NAT_PING_INTERVAL * 2));
+}
+
+// DhtFriends::send_nat_pings()
+
+#[test]
+fn dht_friends_send_nat_pings_test() {
+    let (own_pk, own_sk) = gen_keypair();
+    let mut kbucket = Kbucket::new(KBUCKET_MAX_ENTRIES as u8, &own_pk);
+    let close = random_node(1, 10);
+    kbucket.try_add(&close);
+
+    let (friend_pk, friend_sk) = gen_keypair();
+    let mut friends = DhtFriends::new();
+    assert!(friends.add_friend(&friend_pk, &kbucket, 0));
+    let transport = RecordingTransport::new();
+
+    assert_eq!(1, friends.send_nat_pings(&transport, &own_pk, &own_sk, 0));
+    assert_eq!(0, friends.send_nat_pings(&transport, &own_pk, &own_sk, 1));
+
+    let sent = transport.sent.borrow();
+    assert_eq!(1, sent.len());
+    // sent to the close node, which should forward it to the friend
+    assert_eq!(close.node.saddr, sent[0].1);
+    let request = DhtRequest::from_bytes(&sent[0].0).unwrap();
+    assert_eq!(friend_pk, request.receiver_pk);
+    match request.get_payload(&friend_sk) {
+        Some(DhtRequestT::NatPing(p)) => assert!(p.is_request()),
+        p => panic!("Expected NAT ping, got {:?}", p),
+    }
+}
+
+// DhtFriends::handle_nat_ping()
+
+#[test]
+fn dht_friends_handle_nat_ping_test() {
+    let (own_pk, own_sk) = gen_keypair();
+    let mut kbucket = Kbucket::new(KBUCKET_MAX_ENTRIES as u8, &own_pk);
+    kbucket.try_add(&random_node(1, 10));
+
+    let (friend_pk, friend_sk) = gen_keypair();
+    let mut friends = DhtFriends::new();
+    assert!(friends.add_friend(&friend_pk, &kbucket, 0));
+    let transport = RecordingTransport::new();
+
+    // not a friend
+    assert_eq!(false, friends.handle_nat_ping(&transport, &own_pk, &own_sk,
+                                              &gen_keypair().0, &Ping::new(), 0));
+
+    // request from friend is answered
+    let request = Ping::new();
+    assert!(friends.handle_nat_ping(&transport, &own_pk, &own_sk,
+                                    &friend_pk, &request, 0));
+    let answer = DhtRequest::from_bytes(&transport.sent.borrow()[0].0).unwrap();
+    assert_eq!(Some(DhtRequestT::NatPing(request.response().unwrap())),
+               answer.get_payload(&friend_sk));
+
+    // response from friend confirms NAT ping
+    assert_eq!(1, friends.send_nat_pings(&transport, &own_pk, &own_sk, 0));
+    let ping = {
+        let sent = transport.sent.borrow();
+        match DhtRequest::from_bytes(&sent[1].0).unwrap().get_payload(&friend_sk) {
+            Some(DhtRequestT::NatPing(p)) => p,
+            p => panic!("Expected NAT ping, got {:?}", p),
+        }
+    };
+    let response = ping.response().unwrap();
+    assert!(friends.handle_nat_ping(&transport, &own_pk, &own_sk,
+                                    &friend_pk, &response, 1));
+    assert!(friends.get(&friend_pk).unwrap().is_nat_ping_confirmed(1));
+}
@@ -0,0 +1,175 @@
+/*
+    Copyright © 2016 Zetok Zalbavar <zexavexxe@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Tests for the `dht_lookup` module.
+
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::rc::Rc;
+
+use toxcore::crypto_core::*;
+use toxcore::dht::*;
+use toxcore::dht_lookup::*;
+
+use super::quickcheck::quickcheck;
+
+
+/// Create `num` nodes with random PKs.
+fn random_nodes(num: u16) -> Vec<PackedNode> {
+    (0..num).map(|n| {
+        let saddr = SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), n);
+        PackedNode::new(true, SocketAddr::V4(saddr), &gen_keypair().0)
+    }).collect()
+}
+
+/// Get up to `num` nodes from `nodes` that are closest to the `pk`.
+fn closest_to(pk: &PublicKey, nodes: &[PackedNode], num: usize)
+    -> Vec<PackedNode>
+{
+    let mut nodes = nodes.to_vec();
+    nodes.sort_by(|a, b| pk.distance(&a.pk, &b.pk));
+    nodes.truncate(num);
+    nodes
+}
+
+/// Run lookup in a network where every node knows every other node, and
+/// answers with 4 nodes closest to requested PK.
+fn run_lookup(lookup: &mut NodeLookup, network: &[PackedNode]) {
+    let mut now = 0;
+    while !lookup.is_finished() {
+        for (node, request) in lookup.next_requests(now) {
+            let nodes = closest_to(&request.pk, network, 4);
+            let response = SendNodes::from_request(&request, nodes).unwrap();
+            assert!(lookup.handle_response(&node.pk, &response));
+        }
+        now += 1;
+    }
+}
+
+
+// NodeLookup::new()
+
+#[test]
+fn node_lookup_new_test() {
+    let (target, _) = gen_keypair();
+    let lookup = NodeLookup::new(&target, &[], 0);
+    assert_eq!(&target, lookup.target());
+    assert_eq!(false, lookup.is_finished());
+    assert!(lookup.closest().is_empty());
+}
+
+// NodeLookup::next_requests()
+
+#[test]
+fn node_lookup_next_requests_test() {
+    fn with_parallelism(parallelism: u8) {
+        let parallelism = parallelism as usize % 8 + 1;
+        let (target, _) = gen_keypair();
+        let nodes = random_nodes(16);
+        let mut lookup = NodeLookup::new(&target, &nodes, parallelism);
+
+        let requests = lookup.next_requests(0);
+        assert_eq!(parallelism, requests.len());
+        // closest nodes are queried first
+        let closest = closest_to(&target, &nodes, parallelism);
+        for (&(node, request), expected) in requests.iter().zip(closest) {
+            assert_eq!(expected, node);
+            assert_eq!(target, request.pk);
+        }
+        // no more requests until responses arrive
+        assert!(lookup.next_requests(1).is_empty());
+    }
+    quickcheck(with_parallelism as fn(u8));
+}
+
+#[test]
+fn node_lookup_next_requests_test_timeout() {
+    let (target, _) = gen_keypair();
+    let nodes = random_nodes(2);
+    let mut lookup = NodeLookup::new(&target, &nodes, 1);
+
+    assert_eq!(1, lookup.next_requests(0).len());
+    // first request timed out, second node is queried
+    assert_eq!(1, lookup.next_requests(LOOKUP_REQUEST_TIMEOUT).len());
+    assert!(lookup.next_requests(LOOKUP_REQUEST_TIMEOUT * 2).is_empty());
+    // no node has responded
+    assert!(lookup.is_finished());
+    assert!(lookup.closest().is_empty());
+}
+
+// NodeLookup::handle_response()
+
+#[test]
+fn node_lookup_handle_response_test_unexpected() {
+    let (target, _) = gen_keypair();
+    let nodes = random_nodes(1);
+    let mut lookup = NodeLookup::new(&target, &nodes, 0);
+    let (node, request) = lookup.next_requests(0)[0];
+
+    let mut wrong_id = SendNodes::from_request(&request, nodes.clone()).unwrap();
+    wrong_id.id = request.id.wrapping_add(1);
+    assert_eq!(false, lookup.handle_response(&node.pk, &wrong_id));
+
+    let response = SendNodes::from_request(&request, nodes.clone()).unwrap();
+    assert_eq!(false, lookup.handle_response(&gen_keypair().0, &response));
+
+    assert!(lookup.handle_response(&node.pk, &response));
+    // only one response is accepted
+    assert_eq!(false, lookup.handle_response(&node.pk, &response));
+}
+
+// NodeLookup::closest()
+
+#[test]
+fn node_lookup_closest_test() {
+    let network = random_nodes(100);
+    let (target, _) = gen_keypair();
+    let mut lookup = NodeLookup::new(&target, &network[..4], 0);
+    run_lookup(&mut lookup, &network);
+
+    // nodes answer with only 4 closest nodes, thus only they are guaranteed
+    // to be found
+    let closest = lookup.closest();
+    assert!(closest.len() <= LOOKUP_SHORTLIST_SIZE);
+    assert_eq!(closest_to(&target, &network, 4), closest[..4].to_vec());
+    for pair in closest.windows(2) {
+        assert_eq!(Ordering::Less, target.distance(&pair[0].pk, &pair[1].pk));
+    }
+}
+
+// NodeLookup::on_done()
+
+#[test]
+fn node_lookup_on_done_test() {
+    let network = random_nodes(30);
+    let (target, _) = gen_keypair();
+    let mut lookup = NodeLookup::new(&target, &network[..1], 0);
+
+    let found = Rc::new(RefCell::new(None));
+    let found_c = found.clone();
+    lookup.on_done(Box::new(move |pk: &PublicKey, nodes: &[PackedNode]| {
+        *found_c.borrow_mut() = Some((*pk, nodes.to_vec()));
+    }));
+    run_lookup(&mut lookup, &network);
+
+    let (pk, nodes) = found.borrow_mut().take().unwrap();
+    assert_eq!(target, pk);
+    assert_eq!(lookup.closest(), nodes);
+}
@@ -0,0 +1,468 @@
+/*
+    Copyright © 2016 Zetok Zalbavar <zexavexxe@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Tests for the `dht_node` module.
+
+use std::net::SocketAddr;
+
+use toxcore::binary_io::*;
+use toxcore::crypto_core::*;
+use toxcore::dht::*;
+use toxcore::dht_node::*;
+use toxcore::lan_discovery::*;
+use toxcore::network::Transport;
+use toxcore::onion::*;
+use toxcore::onion_announce::*;
+
+use super::sim_network::*;
+
+
+/// Pass all packets received by `socket` to the `node`.
+fn receive(node: &mut DhtNode, socket: &SimSocket, now: u64) -> usize {
+    let mut buf = [0; 2048];
+    let mut handled = 0;
+    while let Ok((len, addr)) = socket.recv_from(&mut buf) {
+        if node.handle_packet(socket, &buf[..len], addr, now) {
+            handled += 1;
+        }
+    }
+    handled
+}
+
+/// Receive a single DHT packet from `socket` and decrypt it.
+fn recv_packet(socket: &SimSocket, sk: &SecretKey) -> Option<(DhtPacket, DPacketT)> {
+    let mut buf = [0; 2048];
+    socket.recv_from(&mut buf).ok()
+        .and_then(|(len, _)| DhtPacket::from_bytes(&buf[..len]))
+        .and_then(|p| p.get_packet(sk).map(|d| (p, d)))
+}
+
+/// Receive a ping from the node at `node_addr` with `socket`, and answer
+/// it.
+fn answer_ping(socket: &SimSocket, pk: &PublicKey, sk: &SecretKey,
+               node_addr: SocketAddr) {
+    let (packet, ping) = match recv_packet(socket, sk) {
+        Some((packet, DPacketT::Ping(ping))) => (packet, ping),
+        p => panic!("Expected ping, got {:?}", p),
+    };
+    let precomp = encrypt_precompute(&packet.sender_pk, sk);
+    let response = DhtPacket::new(&precomp, pk, &gen_nonce(),
+                                  ping.response().unwrap().as_packet());
+    socket.send_to(&response.to_bytes(), node_addr).unwrap();
+}
+
+
+// DhtNode::handle_packet()
+
+#[test]
+fn dht_node_handle_packet_test_ping() {
+    let net = SimNetwork::new();
+    let node_socket = net.bind("1.0.0.1:33445");
+    let socket = net.bind("1.0.0.2:33445");
+    let (node_pk, node_sk) = gen_keypair();
+    let (pk, sk) = gen_keypair();
+    let mut node = DhtNode::new(&node_pk, &node_sk);
+
+    let ping = Ping::new();
+    let precomp = encrypt_precompute(&node_pk, &sk);
+    let packet = DhtPacket::new(&precomp, &pk, &gen_nonce(), ping.as_packet());
+    socket.send_to(&packet.to_bytes(), node_socket.addr).unwrap();
+    assert_eq!(1, receive(&mut node, &node_socket, 0));
+
+    let (packet, payload) = recv_packet(&socket, &sk).unwrap();
+    assert_eq!(node_pk, packet.sender_pk);
+    assert_eq!(ping.response().unwrap().as_packet(), payload);
+    // requesting node is added only after it answers a ping
+    assert!(node.kbucket.get_node(&pk).is_none());
+    assert_eq!(1, node.send_requests(&node_socket, 0));
+    answer_ping(&socket, &pk, &sk, node_socket.addr);
+    assert_eq!(1, receive(&mut node, &node_socket, 0));
+    assert!(node.kbucket.get_node(&pk).is_some());
+
+    // unexpected ping response is ignored
+    let response = DhtPacket::new(&precomp, &pk, &gen_nonce(),
+                                  ping.response().unwrap().as_packet());
+    assert_eq!(false, node.handle_packet(&node_socket, &response.to_bytes(),
+                                         socket.addr, 0));
+    // garbage is ignored
+    assert_eq!(false, node.handle_packet(&node_socket, &[0; 100],
+                                         socket.addr, 0));
+}
+
+#[test]
+fn dht_node_handle_packet_test_get_nodes() {
+    let net = SimNetwork::new();
+    let node_socket = net.bind("1.0.0.1:33445");
+    let socket = net.bind("1.0.0.2:33445");
+    let (node_pk, node_sk) = gen_keypair();
+    let (pk, sk) = gen_keypair();
+    let mut node = DhtNode::new(&node_pk, &node_sk);
+
+    let known: Vec<PackedNode> = (0..6).map(|n| {
+        PackedNode::new(true, format!("2.0.{}.1:33445", n).parse().unwrap(),
+                        &gen_keypair().0)
+    }).collect();
+    for n in &known {
+        node.kbucket.try_add(&Node::new(n, 100));
+    }
+
+    let expected = node.kbucket.get_closest(&pk, 4, 0);
+
+    let request = GetNodes::new(&pk);
+    let precomp = encrypt_precompute(&node_pk, &sk);
+    let packet = DhtPacket::new(&precomp, &pk, &gen_nonce(), request.as_packet());
+    socket.send_to(&packet.to_bytes(), node_socket.addr).unwrap();
+    assert_eq!(1, receive(&mut node, &node_socket, 0));
+
+    match recv_packet(&socket, &sk) {
+        Some((_, DPacketT::SendNodes(response))) => {
+            assert_eq!(request.id, response.id);
+            assert_eq!(expected, response.nodes);
+        },
+        p => panic!("Expected SendNodes, got {:?}", p),
+    }
+    // requesting node is added only after it answers a ping
+    assert!(node.kbucket.get_node(&pk).is_none());
+    node.send_requests(&node_socket, 0);
+    answer_ping(&socket, &pk, &sk, node_socket.addr);
+    assert_eq!(1, receive(&mut node, &node_socket, 0));
+    assert!(node.kbucket.get_node(&pk).is_some());
+}
+
+#[test]
+fn dht_node_handle_packet_test_spoofed_requests() {
+    let net = SimNetwork::new();
+    let node_socket = net.bind("1.0.0.1:33445");
+    let socket = net.bind("1.0.0.2:33445");
+    let (node_pk, node_sk) = gen_keypair();
+    let mut node = DhtNode::new(&node_pk, &node_sk);
+
+    // requests from addresses that never answer pings don't fill `Kbucket`
+    for n in 0..10 {
+        let (pk, sk) = gen_keypair();
+        let precomp = encrypt_precompute(&node_pk, &sk);
+        let ping = DhtPacket::new(&precomp, &pk, &gen_nonce(),
+                                  Ping::new().as_packet());
+        let addr = format!("3.0.{}.1:33445", n).parse().unwrap();
+        assert!(node.handle_packet(&node_socket, &ping.to_bytes(), addr, 0));
+    }
+    assert_eq!(10, node.send_requests(&socket, 0));
+    assert_eq!(0, node.send_requests(&socket, REQUEST_TIMEOUT));
+    assert!(node.kbucket.is_empty());
+}
+
+#[test]
+fn dht_node_handle_packet_test_unexpected_send_nodes() {
+    let (node_pk, node_sk) = gen_keypair();
+    let (pk, sk) = gen_keypair();
+    let net = SimNetwork::new();
+    let node_socket = net.bind("1.0.0.1:33445");
+    let mut node = DhtNode::new(&node_pk, &node_sk);
+
+    let other = PackedNode::new(true, "2.0.0.1:33445".parse().unwrap(),
+                                &gen_keypair().0);
+    let response = SendNodes::from_request(&GetNodes::new(&node_pk),
+                                           vec![other]).unwrap();
+    let precomp = encrypt_precompute(&node_pk, &sk);
+    let packet = DhtPacket::new(&precomp, &pk, &gen_nonce(),
+                                response.as_packet());
+    assert_eq!(false, node.handle_packet(&node_socket, &packet.to_bytes(),
+                                         "1.0.0.2:33445".parse().unwrap(), 0));
+    assert!(node.kbucket.is_empty());
+}
+
+
+// DhtNode::send_requests()
+
+#[test]
+fn dht_node_send_requests_test() {
+    let net = SimNetwork::new();
+    let socket = net.bind("1.0.0.1:33445");
+    let peer_socket = net.bind("1.0.0.2:33445");
+    let (pk, sk) = gen_keypair();
+    let (peer_pk, peer_sk) = gen_keypair();
+    let mut node = DhtNode::new(&pk, &sk);
+    assert_eq!(0, node.send_requests(&socket, 0));
+
+    let peer = PackedNode::new(true, peer_socket.addr, &peer_pk);
+    node.add_bootstrap_nodes(&[peer]);
+    assert_eq!(1, node.send_requests(&socket, 0));
+    match recv_packet(&peer_socket, &peer_sk) {
+        Some((_, DPacketT::GetNodes(request))) => assert_eq!(pk, request.pk),
+        p => panic!("Expected GetNodes, got {:?}", p),
+    }
+
+    // good nodes in Kbucket are pinged and asked for nodes
+    node.kbucket.try_add(&Node::new(&peer, 1000));
+    assert!(node.send_requests(&socket, PING_INTERVAL) >= 2);
+    let mut kinds = vec![];
+    while let Some((_, payload)) = recv_packet(&peer_socket, &peer_sk) {
+        kinds.push(payload.kind());
+    }
+    assert!(kinds.contains(&PacketKind::PingReq));
+    assert!(kinds.contains(&PacketKind::GetN));
+}
+
+#[test]
+fn dht_node_handle_packet_test_ping_response_rtt() {
+    let net = SimNetwork::new();
+    let socket = net.bind("1.0.0.1:33445");
+    let peer_socket = net.bind("1.0.0.2:33445");
+    let (pk, sk) = gen_keypair();
+    let (peer_pk, peer_sk) = gen_keypair();
+    let mut node = DhtNode::new(&pk, &sk);
+
+    let peer = PackedNode::new(true, peer_socket.addr, &peer_pk);
+    node.kbucket.try_add(&Node::new(&peer, 1000));
+    assert_eq!(None, node.kbucket.get_node(&peer_pk).unwrap().rtt);
+    node.send_requests(&socket, 0);
+
+    // answer the ping
+    while let Some((packet, payload)) = recv_packet(&peer_socket, &peer_sk) {
+        if let DPacketT::Ping(ping) = payload {
+            let precomp = encrypt_precompute(&packet.sender_pk, &peer_sk);
+            let response = DhtPacket::new(&precomp, &peer_pk, &gen_nonce(),
+                                          ping.response().unwrap().as_packet());
+            peer_socket.send_to(&response.to_bytes(), socket.addr).unwrap();
+        }
+    }
+    assert_eq!(1, receive(&mut node, &socket, 1));
+    assert!(node.kbucket.get_node(&peer_pk).unwrap().rtt.is_some());
+    assert_eq!(Some(1), node.kbucket.get_node(&peer_pk).unwrap().last_seen);
+}
+
+#[test]
+fn dht_node_bootstrap_sim_test() {
+    let net = SimNetwork::new();
+    let keys: Vec<(PublicKey, SecretKey)> = (0..3).map(|_| gen_keypair()).collect();
+    let sockets: Vec<SimSocket> = (0..3)
+        .map(|n| net.bind(&format!("1.0.{}.1:33445", n)))
+        .collect();
+    let mut nodes: Vec<DhtNode> = keys.iter()
+        .map(|&(ref pk, ref sk)| DhtNode::new(pk, sk))
+        .collect();
+
+    // 2 nodes know only the first one
+    let first = PackedNode::new(true, sockets[0].addr, &keys[0].0);
+    nodes[1].add_bootstrap_nodes(&[first]);
+    nodes[2].add_bootstrap_nodes(&[first]);
+
+    let mut now = 0;
+    for _ in 0..10 {
+        for (node, socket) in nodes.iter_mut().zip(&sockets) {
+            node.send_requests(socket, now);
+        }
+        for _ in 0..3 {
+            for (node, socket) in nodes.iter_mut().zip(&sockets) {
+                receive(node, socket, now);
+            }
+        }
+        now += 1;
+    }
+
+    // all nodes know each other
+    for (n, node) in nodes.iter().enumerate() {
+        for (m, &(ref pk, _)) in keys.iter().enumerate() {
+            if n != m {
+                assert!(node.kbucket.get_node(pk).is_some(),
+                        "node {} doesn't know node {}", n, m);
+            }
+        }
+    }
+}
+
+#[test]
+fn dht_node_handle_packet_test_bootstrap_info() {
+    let net = SimNetwork::new();
+    let node_socket = net.bind("1.0.0.1:33445");
+    let socket = net.bind("1.0.0.2:33445");
+    let (node_pk, node_sk) = gen_keypair();
+    let mut node = DhtNode::new(&node_pk, &node_sk);
+    node.version = 42;
+    node.motd = "Hello!".to_owned();
+
+    socket.send_to(&BootstrapInfoRequest.to_bytes(), node_socket.addr).unwrap();
+    assert_eq!(1, receive(&mut node, &node_socket, 0));
+
+    let mut buf = [0; 2048];
+    let (len, addr) = socket.recv_from(&mut buf).unwrap();
+    assert_eq!(node_socket.addr, addr);
+    assert_eq!(Some(BootstrapInfo::new(42, "Hello!")),
+               BootstrapInfo::from_bytes(&buf[..len]));
+    // requester is not added to the Kbucket
+    assert!(node.kbucket.is_empty());
+}
+
+#[test]
+fn dht_node_handle_packet_test_onion() {
+    let net = SimNetwork::new();
+    let node_socket = net.bind("1.0.0.1:33445");
+    let socket = net.bind("1.0.0.2:33445");
+    let (node_pk, node_sk) = gen_keypair();
+    let mut node = DhtNode::new(&node_pk, &node_sk);
+
+    let next = PackedNode::new(true, "1.0.0.3:33445".parse().unwrap(),
+                               &gen_keypair().0);
+    let last = PackedNode::new(true, "1.0.0.4:33445".parse().unwrap(),
+                               &gen_keypair().0);
+    let next_socket = net.bind("1.0.0.3:33445");
+    let path = OnionPath::new(&[PackedNode::new(true, node_socket.addr, &node_pk),
+                                next, last]);
+    let request = path.create_request("1.0.0.5:33445".parse().unwrap(), &[1]);
+    socket.send_to(&request.to_bytes(), node_socket.addr).unwrap();
+    assert_eq!(1, receive(&mut node, &node_socket, 0));
+
+    // request is relayed to the next node
+    let mut buf = [0; 2048];
+    let (len, addr) = next_socket.recv_from(&mut buf).unwrap();
+    assert_eq!(node_socket.addr, addr);
+    assert!(OnionRequest1::from_bytes(&buf[..len]).is_some());
+    // sender of onion request is not added to the Kbucket
+    assert!(node.kbucket.is_empty());
+}
+
+#[test]
+fn dht_node_handle_packet_test_announce_request() {
+    let net = SimNetwork::new();
+    let node_socket = net.bind("1.0.0.1:33445");
+    let socket = net.bind("1.0.0.2:33445");
+    let (node_pk, node_sk) = gen_keypair();
+    let mut node = DhtNode::new(&node_pk, &node_sk);
+
+    let (pk, sk) = gen_keypair();
+    let payload = AnnounceRequestPayload {
+        ping_id: ZERO_PING_ID,
+        search_pk: pk,
+        data_pk: gen_keypair().0,
+        sendback_data: 7,
+    };
+    let request = AnnounceRequest::new(&pk, &sk, &node_pk, &payload);
+    let onion_return = OnionReturn::from_bytes(&[1; ONION_RETURN_3_SIZE]).unwrap();
+    let mut bytes = request.to_bytes();
+    bytes.extend_from_slice(&onion_return.to_bytes());
+    socket.send_to(&bytes, node_socket.addr).unwrap();
+    assert_eq!(1, receive(&mut node, &node_socket, 0));
+
+    // response is sent back with the same return path
+    let mut buf = [0; 2048];
+    let (len, addr) = socket.recv_from(&mut buf).unwrap();
+    assert_eq!(node_socket.addr, addr);
+    let response = OnionResponse3::from_bytes(&buf[..len]).unwrap();
+    assert_eq!(onion_return, response.onion_return);
+    let response = AnnounceResponse::from_bytes(&response.payload).unwrap();
+    assert_eq!(7, response.sendback_data);
+    let response = response.get_payload(&node_pk, &sk).unwrap();
+    assert_eq!(AnnounceStatus::Failed, response.status);
+    assert_eq!(node.announce.ping_id(PING_ID_TIMEOUT, &pk, &socket.addr),
+               response.ping_id_or_pk);
+
+    // request without the return path is ignored
+    socket.send_to(&request.to_bytes(), node_socket.addr).unwrap();
+    assert_eq!(0, receive(&mut node, &node_socket, 0));
+}
+
+#[test]
+fn dht_node_handle_packet_test_onion_data_request() {
+    let net = SimNetwork::new();
+    let node_socket = net.bind("1.0.0.1:33445");
+    let socket = net.bind("1.0.0.2:33445");
+    let (node_pk, node_sk) = gen_keypair();
+    let mut node = DhtNode::new(&node_pk, &node_sk);
+
+    // announce client through the socket
+    let (pk, sk) = gen_keypair();
+    let (data_pk, data_sk) = gen_keypair();
+    let payload = AnnounceRequestPayload {
+        ping_id: node.announce.ping_id(0, &pk, &socket.addr),
+        search_pk: pk,
+        data_pk: data_pk,
+        sendback_data: 0,
+    };
+    let onion_return = OnionReturn::from_bytes(&[1; ONION_RETURN_3_SIZE]).unwrap();
+    let mut bytes = AnnounceRequest::new(&pk, &sk, &node_pk, &payload).to_bytes();
+    bytes.extend_from_slice(&onion_return.to_bytes());
+    socket.send_to(&bytes, node_socket.addr).unwrap();
+    assert_eq!(1, receive(&mut node, &node_socket, 0));
+    let mut buf = [0; 2048];
+    socket.recv_from(&mut buf).unwrap();
+
+    // data for the client is sent through its return path
+    let (friend_pk, friend_sk) = gen_keypair();
+    let request = OnionDataRequest::new(&pk, &data_pk, &friend_pk, &friend_sk, &[42]);
+    let mut bytes = request.to_bytes();
+    bytes.extend_from_slice(&[2; ONION_RETURN_3_SIZE]);
+    socket.send_to(&bytes, node_socket.addr).unwrap();
+    assert_eq!(1, receive(&mut node, &node_socket, 1));
+    let (len, _) = socket.recv_from(&mut buf).unwrap();
+    let response = OnionResponse3::from_bytes(&buf[..len]).unwrap();
+    assert_eq!(onion_return, response.onion_return);
+    let data = OnionDataResponse::from_bytes(&response.payload).unwrap();
+    assert_eq!(Some((friend_pk, vec![42])), data.get_payload(&data_sk, &sk));
+
+    // data for someone who isn't announced is dropped
+    let request = OnionDataRequest::new(&friend_pk, &data_pk, &pk, &sk, &[42]);
+    let mut bytes = request.to_bytes();
+    bytes.extend_from_slice(&[2; ONION_RETURN_3_SIZE]);
+    socket.send_to(&bytes, node_socket.addr).unwrap();
+    assert_eq!(0, receive(&mut node, &node_socket, 1));
+}
+
+#[test]
+fn dht_node_handle_packet_test_lan_discovery_disabled() {
+    let net = SimNetwork::new();
+    let node_socket = net.bind("192.168.1.1:33445");
+    let (node_pk, node_sk) = gen_keypair();
+    let mut node = DhtNode::new(&node_pk, &node_sk);
+
+    let packet = LanDiscovery::new(&gen_keypair().0).to_bytes();
+    assert_eq!(false, node.handle_packet(&node_socket, &packet,
+                                         "192.168.1.2:33445".parse().unwrap(), 0));
+}
+
+#[test]
+fn dht_node_lan_discovery_sim_test() {
+    // two nodes without any bootstrap nodes find each other in the LAN
+    let net = SimNetwork::new();
+    let a_socket = net.bind("192.168.1.1:33445");
+    let b_socket = net.bind("192.168.1.2:33445");
+    let (a_pk, a_sk) = gen_keypair();
+    let (b_pk, b_sk) = gen_keypair();
+    let mut a = DhtNode::new(&a_pk, &a_sk);
+    let mut b = DhtNode::new(&b_pk, &b_sk);
+    a.lan_discovery = Some(LanDiscoverySender::new(33445));
+    b.lan_discovery = Some(LanDiscoverySender::new(33445));
+
+    // node that sends `GetNodes` in response to `LanDiscovery` is pinged,
+    // and added to the `Kbucket` when it answers, since empty `Kbucket`
+    // can't answer with `SendNodes`
+    assert!(a.send_requests(&a_socket, 0) > 0);
+    assert!(b.send_requests(&b_socket, 0) > 0);
+    for _ in 0..2 {
+        receive(&mut b, &b_socket, 0);
+        receive(&mut a, &a_socket, 0);
+    }
+    assert!(a.send_requests(&a_socket, 1) > 0);
+    assert!(b.send_requests(&b_socket, 1) > 0);
+    for _ in 0..2 {
+        receive(&mut b, &b_socket, 1);
+        receive(&mut a, &a_socket, 1);
+    }
+    assert!(a.kbucket.get_node(&b_pk).is_some());
+    assert!(b.kbucket.get_node(&a_pk).is_some());
+}
@@ -798,6 +798,7 @@ fn packet_kind_from_bytes_test() {
             0x8c => assert_eq!(PacketKind::OnionResp3, PacketKind::from_bytes(&bytes).unwrap()),
             0x8d => assert_eq!(PacketKind::OnionResp2, PacketKind::from_bytes(&bytes).unwrap()),
             0x8e => assert_eq!(PacketKind::OnionResp2, PacketKind::from_bytes(&bytes).unwrap()),
+            0xf0 => assert_eq!(PacketKind::BootstrapInfo, PacketKind::from_bytes(&bytes).unwrap()),
             _ => assert_eq!(None, PacketKind::from_bytes(&bytes)),
         }
     }
@@ -940,6 +941,118 @@ fn dht_packet_from_bytes_test() {
     quickcheck(with_packet as fn(DhtPacket, Vec<u8>));
 }
 
+// XorDistance::new()
+
+#[test]
+fn xor_distance_new_test() {
+    fn with_pks(a: u64, b: u64, c: u64, d: u64, e: u64, f: u64) {
+        let pk1 = nums_to_pk(a, b, c, d);
+        let pk2 = nums_to_pk(c, d, e, f);
+        let XorDistance(distance) = XorDistance::new(&pk1, &pk2);
+        let PublicKey(ref bytes1) = pk1;
+        let PublicKey(ref bytes2) = pk2;
+        for i in 0..PUBLICKEYBYTES {
+            assert_eq!(bytes1[i] ^ bytes2[i], distance[i]);
+        }
+        assert_eq!(XorDistance::new(&pk1, &pk2), XorDistance::new(&pk2, &pk1));
+        assert!(XorDistance::new(&pk1, &pk1).is_zero());
+        assert_eq!(pk1 == pk2, XorDistance::new(&pk1, &pk2).is_zero());
+    }
+    quickcheck(with_pks as fn(u64, u64, u64, u64, u64, u64));
+}
+
+// XorDistance::leading_zeros()
+
+#[test]
+fn xor_distance_leading_zeros_test() {
+    let mut bytes = [0; PUBLICKEYBYTES];
+    assert_eq!(256, XorDistance(bytes).leading_zeros());
+    bytes[PUBLICKEYBYTES - 1] = 1;
+    assert_eq!(255, XorDistance(bytes).leading_zeros());
+    bytes[1] = 0b0010_0000;
+    assert_eq!(10, XorDistance(bytes).leading_zeros());
+    bytes[0] = 0xff;
+    assert_eq!(0, XorDistance(bytes).leading_zeros());
+}
+
+// XorDistance::bucket_index()
+
+#[test]
+fn xor_distance_bucket_index_test() {
+    fn with_pks(a: u64, b: u64, c: u64, d: u64, e: u64) {
+        let pk1 = nums_to_pk(a, b, c, d);
+        let pk2 = nums_to_pk(a, b, c, e);
+        let distance = XorDistance::new(&pk1, &pk2);
+        assert_eq!(kbucket_index(&pk1, &pk2), distance.bucket_index());
+        if d == e {
+            assert_eq!(None, distance.bucket_index());
+        } else {
+            assert_eq!(Some(distance.leading_zeros() as u8),
+                       distance.bucket_index());
+            assert!(distance.leading_zeros() >= 192);
+        }
+    }
+    quickcheck(with_pks as fn(u64, u64, u64, u64, u64));
+}
+
+// XorDistance::cmp()
+
+#[test]
+fn xor_distance_cmp_test() {
+    fn with_pks(own: PublicKey, pk1: PublicKey, pk2: PublicKey) {
+        let d1 = XorDistance::new(&own, &pk1);
+        let d2 = XorDistance::new(&own, &pk2);
+        assert_eq!(own.distance(&pk1, &pk2), d1.cmp(&d2));
+        // closer node shares more leading bits
+        if d1.leading_zeros() > d2.leading_zeros() {
+            assert!(d1 < d2);
+        }
+    }
+    fn with_nums(a: u64, b: u64, c: u64, d: u64, e: u64, f: u64) {
+        with_pks(nums_to_pk(a, b, c, d), nums_to_pk(b, c, d, e),
+                 nums_to_pk(c, d, e, f));
+        with_pks(nums_to_pk(a, b, c, d), nums_to_pk(a, b, c, e),
+                 nums_to_pk(a, b, d, f));
+    }
+    quickcheck(with_nums as fn(u64, u64, u64, u64, u64, u64));
+}
+
+// XorDistance::fmt()
+
+#[test]
+fn xor_distance_fmt_test() {
+    let mut bytes = [0; PUBLICKEYBYTES];
+    bytes[0] = 0xab;
+    bytes[PUBLICKEYBYTES - 1] = 0x01;
+    let hex = format!("{}", XorDistance(bytes));
+    assert_eq!(PUBLICKEYBYTES * 2, hex.len());
+    assert!(hex.starts_with("ab00"));
+    assert!(hex.ends_with("0001"));
+}
+
+// closest_nodes()
+
+#[test]
+fn closest_nodes_test() {
+    fn with_nodes(nodes: Vec<PackedNode>, target: GetNodes, count: usize) {
+        let count = count % 10;
+        let closest = closest_nodes(&target.pk, &nodes, count);
+        assert_eq!(::std::cmp::min(count, nodes.len()), closest.len());
+        for pair in closest.windows(2) {
+            assert!(target.pk.distance(&pair[0].pk, &pair[1].pk)
+                    != Ordering::Greater);
+        }
+        // no node that wasn't picked is closer than the furthest picked one
+        if let Some(furthest) = closest.last() {
+            for node in nodes.iter().filter(|n| !closest.contains(n)) {
+                assert!(target.pk.distance(&node.pk, &furthest.pk)
+                        != Ordering::Less);
+            }
+        }
+    }
+    quickcheck(with_nodes as fn(Vec<PackedNode>, GetNodes, usize));
+}
+
 // PublicKey::distance()
 
 #[test]
@@ -978,6 +1091,8 @@ fn node_new_test() {
         assert_eq!(timeout, node.timeout);
         assert_eq!(0, node.id);
         assert_eq!(pn, node.node);
+        assert_eq!(None, node.rtt);
+        assert_eq!(None, node.last_seen);
     }
     quickcheck(with_pn as fn(PackedNode, u64));
 }
@@ -1054,3 +1169,657 @@ fn bucket_try_add_test() {
     }
     quickcheck(with_nodes as fn(Node, Node, Node, Node, Node, Node, Node, Node));
 }
+
+// Bucket::refresh()
+
+#[test]
+fn bucket_refresh_test() {
+    fn with_nodes(n1: Node, n2: Node, timeout: u64) {
+        let pk = PublicKey::from_slice(&[0; PUBLICKEYBYTES]).unwrap();
+        let mut bucket = Bucket::new(&pk, 0);
+        assert_eq!(false, bucket.refresh(&n1));
+
+        assert!(bucket.try_add(&n1));
+        let mut updated = n1;
+        updated.timeout = timeout;
+        assert!(bucket.refresh(&updated));
+        assert_eq!(1, bucket.len());
+        assert_eq!(timeout, bucket.nodes()[0].timeout);
+
+        if n1.pk() != n2.pk() {
+            assert_eq!(false, bucket.refresh(&n2));
+        }
+    }
+    quickcheck(with_nodes as fn(Node, Node, u64));
+}
+
+// Bucket::remove()
+
+#[test]
+fn bucket_remove_test() {
+    fn with_nodes(n1: Node, n2: Node) {
+        let pk = PublicKey::from_slice(&[0; PUBLICKEYBYTES]).unwrap();
+        if n1.pk() == n2.pk() || n1.pk() == &pk || n2.pk() == &pk {
+            return
+        }
+        let mut bucket = Bucket::new(&pk, 0);
+        assert_eq!(None, bucket.remove(n1.pk()));
+        assert!(bucket.try_add(&n1));
+        assert!(bucket.try_add(&n2));
+        assert_eq!(Some(n1), bucket.remove(n1.pk()));
+        assert_eq!(None, bucket.remove(n1.pk()));
+        assert_eq!(&[n2], bucket.nodes());
+    }
+    quickcheck(with_nodes as fn(Node, Node));
+}
+
+
+// Kbucket::new()
+
+#[test]
+fn kbucket_new_test() {
+    fn with_pk(a: u64, b: u64, c: u64, d: u64, k: u8) {
+        let pk = nums_to_pk(a, b, c, d);
+        let kbucket = Kbucket::new(k, &pk);
+        assert_eq!(k, kbucket.k);
+        assert!(kbucket.is_empty());
+        assert_eq!(0, kbucket.len());
+    }
+    quickcheck(with_pk as fn(u64, u64, u64, u64, u8));
+}
+
+// Kbucket::try_add()
+
+#[test]
+fn kbucket_try_add_test() {
+    fn with_node(node: Node) {
+        let pk = PublicKey::from_slice(&[0; PUBLICKEYBYTES]).unwrap();
+        let mut kbucket = Kbucket::new(KBUCKET_MAX_ENTRIES as u8, &pk);
+        if node.pk() == &pk {
+            assert_eq!(false, kbucket.try_add(&node));
+            return
+        }
+        assert!(kbucket.try_add(&node));
+        assert_eq!(1, kbucket.len());
+        // adding same node again refreshes it
+        assert!(kbucket.try_add(&node));
+        assert_eq!(1, kbucket.len());
+    }
+    quickcheck(with_node as fn(Node));
+
+    // index out of range
+    let pk = PublicKey::from_slice(&[0; PUBLICKEYBYTES]).unwrap();
+    let mut kbucket = Kbucket::new(1, &pk);
+    let far = PackedNode::new(true, "127.0.0.1:33445".parse().unwrap(),
+                              &PublicKey::from_slice(&[1; PUBLICKEYBYTES]).unwrap());
+    assert_eq!(false, kbucket.try_add(&Node::new(&far, 1)));
+}
+
+// Kbucket::get_closest()
+
+#[test]
+fn kbucket_get_closest_test() {
+    fn with_nodes(nodes: Vec<PackedNode>, target: GetNodes) {
+        let pk = PublicKey::from_slice(&[0; PUBLICKEYBYTES]).unwrap();
+        let mut kbucket = Kbucket::new(KBUCKET_MAX_ENTRIES as u8, &pk);
+        for node in &nodes {
+            kbucket.try_add(&Node::new(node, 10));
+        }
+
+        let closest = kbucket.get_closest(&target.pk, 4, 0);
+        assert!(closest.len() <= 4);
+        assert!(closest.len() <= kbucket.len());
+        for pair in closest.windows(2) {
+            assert_eq!(Ordering::Less,
+                       target.pk.distance(&pair[0].pk, &pair[1].pk));
+        }
+        // timed out nodes are not returned
+        assert!(kbucket.get_closest(&target.pk, 4, 10).is_empty());
+    }
+    quickcheck(with_nodes as fn(Vec<PackedNode>, GetNodes));
+}
+
+// Kbucket::good_nodes()
+
+#[test]
+fn kbucket_good_nodes_test() {
+    fn with_nodes(n1: PackedNode, n2: PackedNode) {
+        let pk = PublicKey::from_slice(&[0; PUBLICKEYBYTES]).unwrap();
+        if n1.pk == n2.pk || n1.pk == pk || n2.pk == pk {
+            return
+        }
+        let mut kbucket = Kbucket::new(KBUCKET_MAX_ENTRIES as u8, &pk);
+        assert!(kbucket.try_add(&Node::new(&n1, 5)));
+        assert!(kbucket.try_add(&Node::new(&n2, 10)));
+        assert_eq!(2, kbucket.good_nodes(0));
+        assert_eq!(1, kbucket.good_nodes(5));
+        assert_eq!(0, kbucket.good_nodes(10));
+    }
+    quickcheck(with_nodes as fn(PackedNode, PackedNode));
+}
+
+// Kbucket::good_packed_nodes()
+
+#[test]
+fn kbucket_good_packed_nodes_test() {
+    fn with_nodes(n1: PackedNode, n2: PackedNode) {
+        let pk = PublicKey::from_slice(&[0; PUBLICKEYBYTES]).unwrap();
+        if n1.pk == n2.pk || n1.pk == pk || n2.pk == pk {
+            return
+        }
+        let mut kbucket = Kbucket::new(KBUCKET_MAX_ENTRIES as u8, &pk);
+        assert!(kbucket.try_add(&Node::new(&n1, 5)));
+        assert!(kbucket.try_add(&Node::new(&n2, 10)));
+        let nodes = kbucket.good_packed_nodes(0);
+        assert_eq!(2, nodes.len());
+        assert!(nodes.contains(&n1) && nodes.contains(&n2));
+        assert_eq!(vec![n2], kbucket.good_packed_nodes(5));
+        assert!(kbucket.good_packed_nodes(10).is_empty());
+    }
+    quickcheck(with_nodes as fn(PackedNode, PackedNode));
+}
+
+// Kbucket::get_node()
+
+#[test]
+fn kbucket_get_node_test() {
+    fn with_node(node: Node, other: PackedNode) {
+        let pk = PublicKey::from_slice(&[0; PUBLICKEYBYTES]).unwrap();
+        let mut kbucket = Kbucket::new(KBUCKET_MAX_ENTRIES as u8, &pk);
+        if node.pk() == &pk || node.pk() == &other.pk {
+            return
+        }
+        assert_eq!(None, kbucket.get_node(node.pk()));
+        assert!(kbucket.try_add(&node));
+        assert_eq!(Some(&node), kbucket.get_node(node.pk()));
+        assert_eq!(None, kbucket.get_node(&other.pk));
+    }
+    quickcheck(with_node as fn(Node, PackedNode));
+}
+
+
+// same_subnet()
+
+#[test]
+fn same_subnet_test() {
+    fn saddr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+    assert!(same_subnet(&saddr("1.2.3.4:1"), &saddr("1.2.3.5:2")));
+    assert!(!same_subnet(&saddr("1.2.3.4:1"), &saddr("1.2.4.4:1")));
+    assert!(same_subnet(&saddr("[2001:db8:1::1]:1"),
+                        &saddr("[2001:db8:1:ffff::2]:1")));
+    assert!(!same_subnet(&saddr("[2001:db8:1::1]:1"),
+                         &saddr("[2001:db8:2::1]:1")));
+    // IPv4-mapped IPv6 is the same as IPv4
+    assert!(same_subnet(&saddr("1.2.3.4:1"), &saddr("[::ffff:1.2.3.9]:1")));
+    assert!(!same_subnet(&saddr("0.0.0.1:1"), &saddr("[::1]:1")));
+}
+
+
+// Kbucket diversity limits
+
+/// Node with PK that starts with `prefix`, followed by zeros and `n`, at
+/// `ip` address.
+fn diverse_node(prefix: &[u8], n: u8, ip: &str) -> Node {
+    let mut pk = [0; PUBLICKEYBYTES];
+    pk[..prefix.len()].copy_from_slice(prefix);
+    pk[PUBLICKEYBYTES - 1] = n;
+    let pk = PublicKey::from_slice(&pk).unwrap();
+    Node::new(&PackedNode::new(true, format!("{}:33445", ip).parse().unwrap(),
+                               &pk), 10)
+}
+
+#[test]
+fn kbucket_diversity_test_subnet_per_bucket() {
+    let pk = PublicKey::from_slice(&[0; PUBLICKEYBYTES]).unwrap();
+    let mut kbucket = Kbucket::new(KBUCKET_MAX_ENTRIES as u8, &pk);
+    assert_eq!(DiversityLimits::default(), kbucket.limits);
+
+    // all nodes go to bucket 0; they differ in PK prefix
+    let same = [diverse_node(&[0xc0], 0, "1.2.3.1"),
+                diverse_node(&[0xd0], 0, "1.2.3.2"),
+                diverse_node(&[0xe0], 0, "1.2.3.3")];
+    for node in &same {
+        // over the limit, but there is room
+        assert!(kbucket.try_add(node));
+    }
+    for n in 0..5 {
+        assert!(kbucket.try_add(&diverse_node(&[0x81 + n], 0,
+                                              &format!("1.1.{}.1", n))));
+    }
+    assert_eq!(BUCKET_SIZE, kbucket.len());
+
+    // full bucket – node from the same subnet is rejected, even if closer
+    let closer = diverse_node(&[0x80], 0, "1.2.3.4");
+    assert_eq!(false, kbucket.try_add(&closer));
+
+    // diverse node replaces the furthest node exceeding the limit, even if
+    // it's further than all nodes
+    let further = diverse_node(&[0xf0], 0, "9.9.9.9");
+    assert!(kbucket.try_add(&further));
+    assert_eq!(BUCKET_SIZE, kbucket.len());
+    assert_eq!(None, kbucket.get_node(same[2].pk()));
+    assert!(kbucket.get_node(same[0].pk()).is_some());
+    assert!(kbucket.get_node(same[1].pk()).is_some());
+
+    // no more nodes exceeding limits – usual rules apply
+    assert_eq!(false, kbucket.try_add(&diverse_node(&[0xf8], 0, "8.8.8.8")));
+
+    // without limits nodes are compared only by distance
+    let mut kbucket = Kbucket::new(KBUCKET_MAX_ENTRIES as u8, &pk);
+    kbucket.limits = DiversityLimits::none();
+    for n in 0..(BUCKET_SIZE as u8) {
+        assert!(kbucket.try_add(&diverse_node(&[0x81 + n], 0, "1.2.3.1")));
+    }
+    assert!(kbucket.try_add(&closer));
+}
+
+#[test]
+fn kbucket_diversity_test_subnet_per_table() {
+    let pk = PublicKey::from_slice(&[0; PUBLICKEYBYTES]).unwrap();
+    let mut kbucket = Kbucket::new(KBUCKET_MAX_ENTRIES as u8, &pk);
+    kbucket.limits.subnet_per_table = 2;
+
+    // nodes in buckets 1, 2 and 3
+    let same = [diverse_node(&[0x40], 0, "1.2.3.1"),
+                diverse_node(&[0x20], 0, "1.2.3.2"),
+                diverse_node(&[0x10], 0, "1.2.3.3")];
+    for node in &same {
+        assert!(kbucket.try_add(node));
+    }
+    // fill bucket 3 with diverse nodes
+    for n in 1..(BUCKET_SIZE as u8) {
+        assert!(kbucket.try_add(&diverse_node(&[0x10 + n], 0,
+                                              &format!("1.1.{}.1", n))));
+    }
+    // node from bucket 3 exceeds the table limit and is replaced
+    assert!(kbucket.try_add(&diverse_node(&[0x1f], 0, "9.9.9.9")));
+    assert_eq!(None, kbucket.get_node(same[2].pk()));
+    assert!(kbucket.get_node(same[0].pk()).is_some());
+}
+
+#[test]
+fn kbucket_diversity_test_pk_prefix() {
+    let pk = PublicKey::from_slice(&[0; PUBLICKEYBYTES]).unwrap();
+    let mut kbucket = Kbucket::new(KBUCKET_MAX_ENTRIES as u8, &pk);
+    kbucket.limits.subnet_per_bucket = BUCKET_SIZE;
+
+    // bucket 0, same bits 1..9
+    let same: Vec<Node> = (1..4)
+        .map(|n| diverse_node(&[0xaa, 0x00], n, &format!("{}.0.0.1", n)))
+        .collect();
+    for node in &same {
+        assert!(kbucket.try_add(node));
+    }
+    for n in 0..5 {
+        assert!(kbucket.try_add(&diverse_node(&[0x81 + n], 0,
+                                              &format!("2.{}.0.1", n))));
+    }
+    assert_eq!(BUCKET_SIZE, kbucket.len());
+    // next bit differs – not the same prefix
+    let different = diverse_node(&[0xaa, 0x80], 0, "3.0.0.1");
+    assert!(kbucket.try_add(&different));
+    assert_eq!(1, same.iter()
+        .filter(|n| kbucket.get_node(n.pk()).is_none()).count());
+    // same prefix is rejected
+    assert_eq!(false, kbucket.try_add(&diverse_node(&[0xaa], 9, "4.0.0.1")));
+}
+
+
+// DhtRequestT::
+
+impl Arbitrary for DhtRequestT {
+    fn arbitrary<G: Gen>(g: &mut G) -> Self {
+        DhtRequestT::NatPing(Arbitrary::arbitrary(g))
+    }
+}
+
+// DhtRequestT::to_bytes()
+
+#[test]
+fn dht_request_t_to_bytes_test() {
+    fn with_payload(payload: DhtRequestT) {
+        let bytes = payload.to_bytes();
+        match payload {
+            DhtRequestT::NatPing(p) => {
+                assert_eq!(NAT_PING_PACKET_ID, bytes[0]);
+                assert_eq!(p.to_bytes(), &bytes[1..]);
+            },
+        }
+    }
+    quickcheck(with_payload as fn(DhtRequestT));
+}
+
+// DhtRequestT::from_bytes()
+
+#[test]
+fn dht_request_t_from_bytes_test() {
+    fn with_payload(payload: DhtRequestT) {
+        assert_eq!(payload, DhtRequestT::from_bytes(&payload.to_bytes()).unwrap());
+    }
+    quickcheck(with_payload as fn(DhtRequestT));
+
+    fn with_bytes(bytes: Vec<u8>) {
+        if bytes.is_empty() || bytes[0] != NAT_PING_PACKET_ID {
+            assert_eq!(None, DhtRequestT::from_bytes(&bytes));
+        } else {
+            assert_eq!(Ping::from_bytes(&bytes[1..]).map(DhtRequestT::NatPing),
+                       DhtRequestT::from_bytes(&bytes));
+        }
+    }
+    quickcheck(with_bytes as fn(Vec<u8>));
+}
+
+
+// DhtRequest::
+
+impl Arbitrary for DhtRequest {
+    fn arbitrary<G: Gen>(g: &mut G) -> Self {
+        let (pk, sk) = gen_keypair();  // "sender" keypair
+        let (r_pk, _) = gen_keypair();  // receiver PK
+        let precomputed = precompute(&r_pk, &sk);
+        DhtRequest::new(&precomputed, &pk, &r_pk, &gen_nonce(),
+                        Arbitrary::arbitrary(g))
+    }
+}
+
+// DhtRequest::new()
+
+#[test]
+fn dht_request_new_test() {
+    fn with_payload(payload: DhtRequestT) {
+        let (alice_pk, alice_sk) = gen_keypair();
+        let (bob_pk, _) = gen_keypair();
+        let precomputed = precompute(&bob_pk, &alice_sk);
+        let request = DhtRequest::new(&precomputed, &alice_pk, &bob_pk,
+                                      &gen_nonce(), payload);
+        assert_eq!(alice_pk, request.sender_pk);
+        assert_eq!(bob_pk, request.receiver_pk);
+    }
+    quickcheck(with_payload as fn(DhtRequestT));
+}
+
+// DhtRequest::get_payload()
+
+#[test]
+fn dht_request_get_payload_test() {
+    fn with_payload(payload: DhtRequestT) {
+        let (alice_pk, alice_sk) = gen_keypair();
+        let (bob_pk, bob_sk) = gen_keypair();
+        let precomputed = precompute(&bob_pk, &alice_sk);
+        let request = DhtRequest::new(&precomputed, &alice_pk, &bob_pk,
+                                      &gen_nonce(), payload);
+        assert_eq!(Some(payload), request.get_payload(&bob_sk));
+        assert_eq!(None, request.get_payload(&alice_sk));
+    }
+    quickcheck(with_payload as fn(DhtRequestT));
+}
+
+// DhtRequest::route()
+
+#[test]
+fn dht_request_route_test() {
+    fn with_node(pn: PackedNode, payload: DhtRequestT) {
+        let (own_pk, _) = gen_keypair();
+        let mut kbucket = Kbucket::new(KBUCKET_MAX_ENTRIES as u8, &own_pk);
+        let (alice_pk, alice_sk) = gen_keypair();
+
+        let to_self = DhtRequest::new(&precompute(&own_pk, &alice_sk),
+                                      &alice_pk, &own_pk, &gen_nonce(), payload);
+        assert_eq!(DhtRequestRoute::ToSelf, to_self.route(&own_pk, &kbucket));
+
+        let to_node = DhtRequest::new(&precompute(&pn.pk, &alice_sk),
+                                      &alice_pk, &pn.pk, &gen_nonce(), payload);
+        assert_eq!(DhtRequestRoute::Drop, to_node.route(&own_pk, &kbucket));
+        assert!(kbucket.try_add(&Node::new(&pn, 1)));
+        assert_eq!(DhtRequestRoute::Forward(pn.saddr),
+                   to_node.route(&own_pk, &kbucket));
+    }
+    quickcheck(with_node as fn(PackedNode, DhtRequestT));
+}
+
+// DhtRequest::to_bytes()
+
+#[test]
+fn dht_request_to_bytes_test() {
+    fn with_request(request: DhtRequest) {
+        let bytes = request.to_bytes();
+        assert_eq!(PacketKind::DhtReq as u8, bytes[0]);
+        let PublicKey(receiver_pk) = request.receiver_pk;
+        assert_eq!(receiver_pk, bytes[1..(1 + PUBLICKEYBYTES)]);
+        let PublicKey(sender_pk) = request.sender_pk;
+        assert_eq!(sender_pk,
+                   bytes[(1 + PUBLICKEYBYTES)..(1 + 2 * PUBLICKEYBYTES)]);
+        assert!(bytes.len() >= DHT_REQUEST_MIN_SIZE);
+    }
+    quickcheck(with_request as fn(DhtRequest));
+}
+
+// DhtRequest::from_bytes()
+
+#[test]
+fn dht_request_from_bytes_test() {
+    fn with_request(request: DhtRequest, invalid: Vec<u8>) {
+        assert_eq!(request, DhtRequest::from_bytes(&request.to_bytes()).unwrap());
+
+        if invalid.is_empty() || invalid[0] != PacketKind::DhtReq as u8 {
+            assert_eq!(None, DhtRequest::from_bytes(&invalid));
+        }
+        // DhtPacket can't be parsed as DhtRequest and vice versa
+        assert_eq!(None, DhtPacket::from_bytes(&request.to_bytes()));
+    }
+    quickcheck(with_request as fn(DhtRequest, Vec<u8>));
+}
+
+
+// BootstrapInfoRequest::to_bytes()
+
+#[test]
+fn bootstrap_info_request_to_bytes_test() {
+    let bytes = BootstrapInfoRequest.to_bytes();
+    assert_eq!(BOOTSTRAP_INFO_REQUEST_SIZE, bytes.len());
+    assert_eq!(PacketKind::BootstrapInfo as u8, bytes[0]);
+    assert!(bytes[1..].iter().all(|&b| b == 0));
+}
+
+// BootstrapInfoRequest::from_bytes()
+
+#[test]
+fn bootstrap_info_request_from_bytes_test() {
+    assert_eq!(Some(BootstrapInfoRequest),
+               BootstrapInfoRequest::from_bytes(&BootstrapInfoRequest.to_bytes()));
+    // padding is ignored
+    let mut bytes = vec![1; BOOTSTRAP_INFO_REQUEST_SIZE];
+    bytes[0] = PacketKind::BootstrapInfo as u8;
+    assert_eq!(Some(BootstrapInfoRequest), BootstrapInfoRequest::from_bytes(&bytes));
+    // wrong length or kind
+    assert_eq!(None, BootstrapInfoRequest::from_bytes(&bytes[1..]));
+    assert_eq!(None, BootstrapInfoRequest::from_bytes(&bytes[..10]));
+    bytes[0] = PacketKind::LanDisc as u8;
+    assert_eq!(None, BootstrapInfoRequest::from_bytes(&bytes));
+}
+
+
+// BootstrapInfo::new()
+
+#[test]
+fn bootstrap_info_new_test() {
+    let info = BootstrapInfo::new(1, "motd");
+    assert_eq!(1, info.version);
+    assert_eq!("motd", info.motd);
+
+    // too long MOTD is truncated
+    let motd: String = ::std::iter::repeat('a').take(300).collect();
+    let info = BootstrapInfo::new(1, &motd);
+    assert_eq!(BOOTSTRAP_INFO_MAX_MOTD_LENGTH - 1, info.motd.len());
+    // on a char boundary
+    let motd: String = ::std::iter::repeat('ž').take(200).collect();
+    let info = BootstrapInfo::new(1, &motd);
+    assert_eq!(BOOTSTRAP_INFO_MAX_MOTD_LENGTH - 2, info.motd.len());
+}
+
+// BootstrapInfo::to_bytes()
+
+#[test]
+fn bootstrap_info_to_bytes_test() {
+    let bytes = BootstrapInfo::new(0x01020304, "abc").to_bytes();
+    assert_eq!(vec![0xf0, 1, 2, 3, 4, b'a', b'b', b'c', 0], bytes);
+}
+
+// BootstrapInfo::from_bytes()
+
+#[test]
+fn bootstrap_info_from_bytes_test() {
+    fn with_info(version: u32, motd: String) {
+        let info = BootstrapInfo::new(version, &motd.replace("\0", ""));
+        assert_eq!(info, BootstrapInfo::from_bytes(&info.to_bytes()).unwrap());
+    }
+    quickcheck(with_info as fn(u32, String));
+
+    // MOTD padded with zeros, as toxcore does
+    let mut bytes = vec![0xf0, 0, 0, 0, 1, b'a', b'b', 0, 0, 0];
+    let info = BootstrapInfo::from_bytes(&bytes).unwrap();
+    assert_eq!(BootstrapInfo::new(1, "ab"), info);
+    // no terminating zero
+    bytes.truncate(7);
+    assert_eq!(Some(info), BootstrapInfo::from_bytes(&bytes));
+    // empty MOTD
+    assert_eq!(Some(BootstrapInfo::new(1, "")),
+               BootstrapInfo::from_bytes(&bytes[..BOOTSTRAP_INFO_MIN_SIZE]));
+}
+
+#[test]
+fn bootstrap_info_from_bytes_test_invalid() {
+    // too short
+    assert_eq!(None, BootstrapInfo::from_bytes(&[0xf0, 0, 0, 0]));
+    // too long
+    assert_eq!(None, BootstrapInfo::from_bytes(
+        &[0xf0; BOOTSTRAP_INFO_MIN_SIZE + BOOTSTRAP_INFO_MAX_MOTD_LENGTH + 1]));
+    // wrong kind
+    assert_eq!(None, BootstrapInfo::from_bytes(&[0x21, 0, 0, 0, 1, 0]));
+}
+
+
+// Bucket::refresh()
+
+#[test]
+fn bucket_refresh_test_keeps_rtt() {
+    let (pk, _) = gen_keypair();
+    let mut bucket = Bucket::new(&pk, 0);
+    let pn = PackedNode::new(true, "1.2.3.4:33445".parse().unwrap(),
+                             &gen_keypair().0);
+    let mut node = Node::new(&pn, 10);
+    node.rtt = Some(50);
+    node.last_seen = Some(5);
+    assert!(bucket.try_add(&node));
+
+    // unknown RTT and last seen time don't replace known ones
+    assert!(bucket.refresh(&Node::new(&pn, 20)));
+    assert_eq!(20, bucket.nodes()[0].timeout);
+    assert_eq!(Some(50), bucket.nodes()[0].rtt);
+    assert_eq!(Some(5), bucket.nodes()[0].last_seen);
+
+    node.rtt = Some(30);
+    node.last_seen = Some(8);
+    assert!(bucket.refresh(&node));
+    assert_eq!(Some(30), bucket.nodes()[0].rtt);
+    assert_eq!(Some(8), bucket.nodes()[0].last_seen);
+}
+
+
+// Kbucket::snapshot()
+
+#[test]
+fn kbucket_snapshot_test() {
+    let (pk, _) = gen_keypair();
+    let mut kbucket = Kbucket::new(KBUCKET_MAX_ENTRIES as u8, &pk);
+    let snapshot = kbucket.snapshot(0);
+    assert_eq!(pk, snapshot.pk);
+    assert_eq!(KBUCKET_MAX_ENTRIES, snapshot.buckets.len());
+    assert!(snapshot.buckets.iter().all(|b| b.nodes.is_empty()));
+
+    let v4 = PackedNode::new(true, "1.2.3.4:33445".parse().unwrap(),
+                             &gen_keypair().0);
+    let v6 = PackedNode::new(true, "[2001::1]:33445".parse().unwrap(),
+                             &gen_keypair().0);
+    let timed_out = PackedNode::new(true, "5.6.7.8:33445".parse().unwrap(),
+                                    &gen_keypair().0);
+    let mut node = Node::new(&v4, 100 + NODE_TIMEOUT);
+    node.rtt = Some(42);
+    node.last_seen = Some(100);
+    assert!(kbucket.try_add(&node));
+    assert!(kbucket.try_add(&Node::new(&v6, 10)));
+    let mut node = Node::new(&timed_out, 10);
+    node.last_seen = Some(5);
+    assert!(kbucket.try_add(&node));
+
+    let snapshot = kbucket.snapshot(50);
+    assert_eq!(50, snapshot.time);
+    for (i, b) in snapshot.buckets.iter().enumerate() {
+        assert_eq!(i, b.index as usize);
+        for n in &b.nodes {
+            assert_eq!(kbucket_index(&pk, &n.node.pk), Some(b.index));
+            assert_eq!(XorDistance::new(&pk, &n.node.pk), n.distance);
+        }
+    }
+    let nodes: Vec<&NodeSnapshot> = snapshot.buckets.iter()
+        .flat_map(|b| b.nodes.iter())
+        .collect();
+    assert_eq!(3, nodes.len());
+
+    let n4 = nodes.iter().find(|n| n.node == v4).unwrap();
+    assert_eq!(IpType::U4, n4.node.ip_type);
+    assert_eq!(Some(100), n4.last_seen);
+    assert_eq!(Some(true), n4.good);
+    assert_eq!(Some(42), n4.rtt);
+
+    // node that was never seen
+    let n6 = nodes.iter().find(|n| n.node == v6).unwrap();
+    assert_eq!(IpType::U6, n6.node.ip_type);
+    assert_eq!(None, n6.last_seen);
+    assert_eq!(None, n6.good);
+    assert_eq!(None, n6.rtt);
+
+    let n = nodes.iter().find(|n| n.node == timed_out).unwrap();
+    assert_eq!(Some(5), n.last_seen);
+    assert_eq!(Some(false), n.good);
+}
+
+// KbucketSnapshot::to_json_string()
+
+#[test]
+fn kbucket_snapshot_to_json_string_test() {
+    use rustc_serialize::json::Json;
+
+    let (pk, _) = gen_keypair();
+    let mut kbucket = Kbucket::new(KBUCKET_MAX_ENTRIES as u8, &pk);
+    let pn = PackedNode::new(true, "[2001::1]:33445".parse().unwrap(),
+                             &gen_keypair().0);
+    let index = kbucket_index(&pk, &pn.pk).unwrap();
+    let mut node = Node::new(&pn, 100 + NODE_TIMEOUT);
+    node.last_seen = Some(100);
+    assert!(kbucket.try_add(&node));
+
+    let json = Json::from_str(&kbucket.snapshot(50).to_json_string()).unwrap();
+    let PublicKey(ref pk_bytes) = pk;
+    let pk_hex: String = pk_bytes.iter().map(|b| format!("{:02X}", b)).collect();
+    assert_eq!(Some(&pk_hex[..]), json.find("public_key").and_then(|j| j.as_string()));
+    assert_eq!(Some(50), json.find("time").and_then(|j| j.as_u64()));
+
+    let buckets = json.find("buckets").and_then(|b| b.as_array()).unwrap();
+    assert_eq!(KBUCKET_MAX_ENTRIES, buckets.len());
+    let bucket = &buckets[index as usize];
+    assert_eq!(Some(index as u64), bucket.find("index").and_then(|j| j.as_u64()));
+    let nodes = bucket.find("nodes").and_then(|n| n.as_array()).unwrap();
+    assert_eq!(1, nodes.len());
+    let node = &nodes[0];
+    assert_eq!(Some("2001::1"), node.find("ip").and_then(|j| j.as_string()));
+    assert_eq!(Some(33445), node.find("port").and_then(|j| j.as_u64()));
+    assert_eq!(Some("ipv6"), node.find("family").and_then(|j| j.as_string()));
+    let distance = XorDistance::new(&pk, &pn.pk).to_string();
+    assert_eq!(Some(&distance[..]), node.find("distance").and_then(|j| j.as_string()));
+    assert_eq!(Some(100), node.find("last_seen").and_then(|j| j.as_u64()));
+    assert_eq!(Some(true), node.find("good").and_then(|j| j.as_boolean()));
+    assert!(node.find("rtt").unwrap().is_null());
+}
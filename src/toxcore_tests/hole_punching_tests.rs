@@ -0,0 +1,268 @@
+/*
+    Copyright © 2016 Zetok Zalbavar <zexavexxe@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Tests for the `hole_punching` module.
+
+use std::net::SocketAddr;
+
+use toxcore::binary_io::*;
+use toxcore::crypto_core::*;
+use toxcore::dht::*;
+use toxcore::dht_friend::*;
+use toxcore::hole_punching::*;
+use toxcore::network::Transport;
+
+use super::quickcheck::quickcheck;
+use super::sim_network::*;
+
+
+fn addr(s: &str) -> SocketAddr {
+    s.parse().unwrap()
+}
+
+
+// most_common_ip()
+
+#[test]
+fn most_common_ip_test() {
+    assert_eq!(None, most_common_ip(&[]));
+    assert_eq!(Some(addr("1.2.3.4:0")), most_common_ip(&[addr("1.2.3.4:5")]));
+    let addrs = [addr("1.1.1.1:1"), addr("2.2.2.2:1"), addr("2.2.2.2:2"),
+                 addr("3.3.3.3:1")];
+    assert_eq!(Some(addr("2.2.2.2:0")), most_common_ip(&addrs));
+}
+
+// HolePunch::next_addrs()
+
+#[test]
+fn hole_punch_next_addrs_test_cone() {
+    let mut punch = HolePunch::new();
+    assert!(punch.next_addrs(&[], 0).is_empty());
+
+    let reported = [addr("1.2.3.4:33445"), addr("1.2.3.4:33445"),
+                    addr("5.6.7.8:1")];
+    assert_eq!(vec![addr("1.2.3.4:33445")], punch.next_addrs(&reported, 0));
+    assert_eq!(1, punch.tries());
+    assert!(punch.next_addrs(&reported, PUNCH_INTERVAL - 1).is_empty());
+    assert_eq!(1, punch.next_addrs(&reported, PUNCH_INTERVAL).len());
+
+    punch.done();
+    assert!(punch.is_done());
+    assert!(punch.next_addrs(&reported, PUNCH_INTERVAL * 2).is_empty());
+    punch.reset();
+    assert_eq!(0, punch.tries());
+    assert_eq!(1, punch.next_addrs(&reported, 0).len());
+}
+
+#[test]
+fn hole_punch_next_addrs_test_symmetric() {
+    let mut punch = HolePunch::new();
+    let reported = [addr("1.2.3.4:2000"), addr("1.2.3.4:1000")];
+
+    let first = punch.next_addrs(&reported, 0);
+    assert_eq!(MAX_PUNCHING_PORTS as usize, first.len());
+    for a in &first {
+        assert_eq!(addr("1.2.3.4:0"), { let mut a = *a; a.set_port(0); a });
+    }
+    // reported ports and their neighbours are tried first
+    assert_eq!(&[addr("1.2.3.4:1000"), addr("1.2.3.4:2000")], &first[..2]);
+    for port in &[999, 1001, 1999, 2001] {
+        assert!(first.contains(&addr(&format!("1.2.3.4:{}", port))));
+    }
+    // every port is tried only once
+    let mut ports: Vec<u16> = first.iter().map(|a| a.port()).collect();
+    ports.sort();
+    ports.dedup();
+    assert_eq!(first.len(), ports.len());
+
+    // next attempt continues the sweep further away
+    let second = punch.next_addrs(&reported, PUNCH_INTERVAL);
+    assert!(!second.contains(&addr("1.2.3.4:1000")));
+    assert!(!second.contains(&addr("1.2.3.4:988")));
+    assert!(second.contains(&addr("1.2.3.4:987")));
+    assert!(second.iter().all(|a| !first.contains(a)));
+}
+
+#[test]
+fn hole_punch_next_addrs_test_random() {
+    fn with_ports(p1: u16, p2: u16) {
+        if p1 == p2 { return }
+        let mut punch = HolePunch::new();
+        let reported = [addr(&format!("1.2.3.4:{}", p1)),
+                        addr(&format!("1.2.3.4:{}", p2))];
+        let mut now = 0;
+        for _ in 0..(MAX_NORMAL_PUNCHING_TRIES + 1) {
+            assert!(punch.next_addrs(&reported, now).len() <=
+                    MAX_PUNCHING_PORTS as usize);
+            now += PUNCH_INTERVAL;
+        }
+        // random sweep is added
+        let addrs = punch.next_addrs(&reported, now);
+        assert!(addrs.len() > MAX_PUNCHING_PORTS as usize);
+        for a in &addrs[(addrs.len() - MAX_PUNCHING_PORTS as usize)..] {
+            assert!(a.port() >= RANDOM_PORT_MIN);
+        }
+    }
+    quickcheck(with_ports as fn(u16, u16));
+}
+
+
+// punching in the simulated network
+
+/// Friend with own socket, keys and friend list.
+struct Peer {
+    socket: SimSocket,
+    pk: PublicKey,
+    sk: SecretKey,
+    friends: DhtFriends,
+}
+
+impl Peer {
+    /// Handle all received pings, answering ping requests.
+    fn receive(&mut self) {
+        let mut buf = [0; 2048];
+        while let Ok((len, src)) = self.socket.recv_from(&mut buf) {
+            let packet = match DhtPacket::from_bytes(&buf[..len]) {
+                Some(p) => p,
+                None => continue,
+            };
+            if let Some(DPacketT::Ping(_)) = packet.get_packet(&self.sk) {
+                let precomp = precompute(&packet.sender_pk, &self.sk);
+                if let Some(resp) = packet.ping_resp(&self.sk, &precomp, &self.pk) {
+                    self.socket.send_to(&resp.to_bytes(), src).unwrap();
+                }
+                self.friends.handle_ping(&packet.sender_pk, src);
+            }
+        }
+    }
+
+    fn is_punched(&self, friend_pk: &PublicKey) -> bool {
+        self.friends.get(friend_pk).unwrap().is_hole_punched()
+    }
+}
+
+/// Create peer with private address `private`, behind NAT of `nat_type`
+/// with `external` address.
+fn peer(net: &SimNetwork, nat_type: NatType, external: &str, private: &str,
+        first_port: u16) -> Peer {
+    net.add_nat(nat_type, external, private, first_port);
+    let (pk, sk) = gen_keypair();
+    Peer {
+        socket: net.bind(private),
+        pk: pk,
+        sk: sk,
+        friends: DhtFriends::new(),
+    }
+}
+
+/// Make `peer` know `friend` through DHT nodes, which report address from
+/// which they see the friend.
+fn add_friend(peer: &mut Peer, friend: &Peer, nodes: &[(SimSocket, PublicKey)]) {
+    let mut kbucket = Kbucket::new(KBUCKET_MAX_ENTRIES as u8, &peer.pk);
+    for &(ref socket, ref pk) in nodes {
+        kbucket.try_add(&Node::new(&PackedNode::new(true, socket.addr, pk), 100));
+    }
+    assert!(peer.friends.add_friend(&friend.pk, &kbucket, 0));
+
+    for (node, request) in peer.friends.next_requests(0) {
+        let &(ref socket, _) = nodes.iter()
+            .find(|&&(_, ref pk)| pk == &node.pk).unwrap();
+        // friend contacts the node, so that node knows friend's address
+        friend.socket.send_to(&[0], socket.addr).unwrap();
+        let mut buf = [0; 16];
+        let (_, seen) = socket.recv_from(&mut buf).unwrap();
+
+        let friend_node = PackedNode::new(true, seen, &friend.pk);
+        let response = SendNodes::from_request(&request, vec![friend_node]).unwrap();
+        assert!(peer.friends.handle_send_nodes(&node.pk, &response, 0));
+    }
+}
+
+/// Pretend that NAT ping was answered.
+fn confirm_nat_ping(peer: &mut Peer, friend_pk: &PublicKey, now: u64) {
+    let friend = peer.friends.get_mut(friend_pk).unwrap();
+    if let Some(ping) = friend.nat_ping_request(now) {
+        assert!(friend.handle_nat_ping_response(&ping.response().unwrap(), now));
+    }
+}
+
+/// Try to punch holes between peers behind NATs of given types, returning
+/// whether both have succeeded.
+fn punch(alice_nat: NatType, bob_nat: NatType) -> bool {
+    let net = SimNetwork::new();
+    let nodes: Vec<(SimSocket, PublicKey)> = (1..5)
+        .map(|n| (net.bind(&format!("1.0.0.{}:33445", n)), gen_keypair().0))
+        .collect();
+    let mut alice = peer(&net, alice_nat, "2.0.0.1:0", "10.0.0.1:33445", 40000);
+    let mut bob = peer(&net, bob_nat, "3.0.0.1:0", "10.0.0.2:33445", 50000);
+    add_friend(&mut alice, &bob, &nodes);
+    add_friend(&mut bob, &alice, &nodes);
+
+    let mut now = 0;
+    for _ in 0..20 {
+        confirm_nat_ping(&mut alice, &bob.pk, now);
+        confirm_nat_ping(&mut bob, &alice.pk, now);
+        alice.friends.send_punches(&alice.socket, &alice.pk, &alice.sk, now);
+        bob.friends.send_punches(&bob.socket, &bob.pk, &bob.sk, now);
+        alice.receive();
+        bob.receive();
+        alice.receive();
+
+        if alice.is_punched(&bob.pk) && bob.is_punched(&alice.pk) {
+            return true
+        }
+        now += PUNCH_INTERVAL;
+    }
+    false
+}
+
+#[test]
+fn hole_punching_sim_test_no_punching_without_nat_ping() {
+    let net = SimNetwork::new();
+    let nodes: Vec<(SimSocket, PublicKey)> = (1..5)
+        .map(|n| (net.bind(&format!("1.0.0.{}:33445", n)), gen_keypair().0))
+        .collect();
+    let mut alice = peer(&net, NatType::FullCone, "2.0.0.1:0", "10.0.0.1:1", 1);
+    let bob = peer(&net, NatType::FullCone, "3.0.0.1:0", "10.0.0.2:1", 1);
+    add_friend(&mut alice, &bob, &nodes);
+    assert_eq!(0, alice.friends.send_punches(&alice.socket, &alice.pk,
+                                             &alice.sk, 0));
+}
+
+#[test]
+fn hole_punching_sim_test_full_cone() {
+    assert!(punch(NatType::FullCone, NatType::FullCone));
+}
+
+#[test]
+fn hole_punching_sim_test_restricted_cone() {
+    assert!(punch(NatType::RestrictedCone, NatType::PortRestrictedCone));
+}
+
+#[test]
+fn hole_punching_sim_test_port_restricted_cone() {
+    assert!(punch(NatType::PortRestrictedCone, NatType::PortRestrictedCone));
+}
+
+#[test]
+fn hole_punching_sim_test_symmetric() {
+    // symmetric NAT allocates ports sequentially, thus they can be guessed
+    assert!(punch(NatType::PortRestrictedCone, NatType::Symmetric));
+    assert!(punch(NatType::Symmetric, NatType::FullCone));
+}
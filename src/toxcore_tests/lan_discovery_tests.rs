@@ -0,0 +1,209 @@
+/*
+    Copyright © 2016 Zetok Zalbavar <zexavexxe@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Tests for the `lan_discovery` module.
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+use toxcore::binary_io::*;
+use toxcore::crypto_core::*;
+use toxcore::dht::*;
+use toxcore::lan_discovery::*;
+use toxcore::network::Transport;
+
+use super::quickcheck::{Arbitrary, Gen, quickcheck};
+use super::sim_network::*;
+
+
+impl Arbitrary for LanDiscovery {
+    fn arbitrary<G: Gen>(g: &mut G) -> Self {
+        let mut a: [u8; PUBLICKEYBYTES] = [0; PUBLICKEYBYTES];
+        g.fill_bytes(&mut a);
+        LanDiscovery { pk: PublicKey::from_slice(&a).unwrap() }
+    }
+}
+
+fn ip(s: &str) -> IpAddr {
+    s.parse().unwrap()
+}
+
+
+// LanDiscovery::to_bytes()
+
+#[test]
+fn lan_discovery_to_bytes_test() {
+    fn with_ld(ld: LanDiscovery) {
+        let bytes = ld.to_bytes();
+        assert_eq!(LAN_DISCOVERY_SIZE, bytes.len());
+        assert_eq!(PacketKind::LanDisc as u8, bytes[0]);
+        let PublicKey(ref pk) = ld.pk;
+        assert_eq!(pk, &bytes[1..]);
+        assert_eq!(Some(ld), LanDiscovery::from_bytes(&bytes));
+    }
+    quickcheck(with_ld as fn(LanDiscovery));
+}
+
+// LanDiscovery::from_bytes()
+
+#[test]
+fn lan_discovery_from_bytes_test() {
+    fn with_bytes(bytes: Vec<u8>) {
+        let ld = LanDiscovery::from_bytes(&bytes);
+        if bytes.len() < LAN_DISCOVERY_SIZE
+            || bytes[0] != PacketKind::LanDisc as u8 {
+            assert_eq!(None, ld);
+        } else {
+            let PublicKey(ref pk) = ld.unwrap().pk;
+            assert_eq!(pk, &bytes[1..LAN_DISCOVERY_SIZE]);
+        }
+    }
+    quickcheck(with_bytes as fn(Vec<u8>));
+
+    // wrong packet kind
+    let mut bytes = LanDiscovery::new(&gen_keypair().0).to_bytes();
+    bytes[0] = PacketKind::DhtReq as u8;
+    assert_eq!(None, LanDiscovery::from_bytes(&bytes));
+}
+
+// LanDiscovery::request()
+
+#[test]
+fn lan_discovery_request_test() {
+    let (own_pk, _) = gen_keypair();
+    let (pk, _) = gen_keypair();
+    let addr: SocketAddr = "192.168.1.2:33445".parse().unwrap();
+
+    let (node, request) = LanDiscovery::new(&pk).request(&own_pk, addr).unwrap();
+    assert_eq!(PackedNode::new(true, addr, &pk), node);
+    assert_eq!(own_pk, request.pk);
+
+    // own packet
+    assert_eq!(None, LanDiscovery::new(&own_pk).request(&own_pk, addr));
+    // not from LAN
+    let addr = "8.8.8.8:33445".parse().unwrap();
+    assert_eq!(None, LanDiscovery::new(&pk).request(&own_pk, addr));
+}
+
+
+// is_lan()
+
+#[test]
+fn is_lan_test() {
+    for lan in &["127.0.0.1", "10.1.2.3", "172.16.0.1", "172.31.255.255",
+                 "192.168.0.1", "169.254.1.1", "100.64.0.1", "::1", "fe80::1",
+                 "fd00::1", "::ffff:192.168.0.1"] {
+        assert!(is_lan(&ip(lan)), "{} should be LAN", lan);
+    }
+    for wan in &["8.8.8.8", "172.32.0.1", "192.169.0.1", "100.128.0.1",
+                 "2001:db8::1", "::ffff:8.8.8.8"] {
+        assert!(!is_lan(&ip(wan)), "{} shouldn't be LAN", wan);
+    }
+}
+
+// ipv4_broadcast()
+
+#[test]
+fn ipv4_broadcast_test() {
+    assert_eq!(Ipv4Addr::new(192, 168, 1, 255),
+               ipv4_broadcast(Ipv4Addr::new(192, 168, 1, 42),
+                              Ipv4Addr::new(255, 255, 255, 0)));
+    assert_eq!(Ipv4Addr::new(10, 255, 255, 255),
+               ipv4_broadcast(Ipv4Addr::new(10, 1, 2, 3),
+                              Ipv4Addr::new(255, 0, 0, 0)));
+}
+
+// parse_route_table()
+
+#[test]
+#[cfg(target_endian = "little")]
+fn parse_route_table_test() {
+    let table = "\
+Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT
+eth0\t00000000\t0101A8C0\t0003\t0\t0\t100\t00000000\t0\t0\t0
+eth0\t0001A8C0\t00000000\t0001\t0\t0\t100\t00FFFFFF\t0\t0\t0
+wlan0\t0000000A\t00000000\t0001\t0\t0\t600\t000000FF\t0\t0\t0
+wlan0\t0000000A\t00000000\t0001\t0\t0\t600\t000000FF\t0\t0\t0
+broken line
+";
+    assert_eq!(vec![Ipv4Addr::new(192, 168, 1, 255),
+                    Ipv4Addr::new(10, 255, 255, 255)],
+               parse_route_table(table));
+    assert!(parse_route_table("").is_empty());
+}
+
+
+// LanDiscoverySender::
+
+#[test]
+fn lan_discovery_sender_broadcast_addrs_test() {
+    let mut sender = LanDiscoverySender::new(0);
+    assert_eq!(vec!["255.255.255.255:33445".parse().unwrap(),
+                    "[ff02::1]:33445".parse().unwrap()],
+               sender.broadcast_addrs());
+
+    sender.add_interface(Ipv4Addr::new(192, 168, 1, 42),
+                         Ipv4Addr::new(255, 255, 255, 0));
+    // duplicates are not added
+    sender.add_interface(Ipv4Addr::new(192, 168, 1, 1),
+                         Ipv4Addr::new(255, 255, 255, 0));
+    let addrs = sender.broadcast_addrs();
+    assert_eq!(3, addrs.len());
+    assert_eq!("192.168.1.255:33445".parse::<SocketAddr>().unwrap(), addrs[2]);
+
+    let sender = LanDiscoverySender::new(12345);
+    assert!(sender.broadcast_addrs().iter().all(|a| a.port() == 12345));
+}
+
+#[test]
+fn lan_discovery_sim_test() {
+    let net = SimNetwork::new();
+    let alice_socket = net.bind("192.168.1.2:33445");
+    let bob_socket = net.bind("192.168.1.3:33445");
+    let other_port = net.bind("192.168.1.4:33446");
+    let (alice_pk, alice_sk) = gen_keypair();
+    let (bob_pk, bob_sk) = gen_keypair();
+
+    let mut sender = LanDiscoverySender::new(33445);
+    assert_eq!(2, sender.send(&alice_socket, &alice_pk, 0));
+    // interval has to pass
+    assert_eq!(0, sender.send(&alice_socket, &alice_pk,
+                              LAN_DISCOVERY_INTERVAL - 1));
+    assert_eq!(2, sender.send(&alice_socket, &alice_pk,
+                              LAN_DISCOVERY_INTERVAL));
+
+    let mut buf = [0; 2048];
+    assert!(other_port.recv_from(&mut buf).is_err());
+    assert!(alice_socket.recv_from(&mut buf).is_err());
+
+    // bob gets LanDiscovery and asks alice for nodes
+    let (len, src) = bob_socket.recv_from(&mut buf).unwrap();
+    assert_eq!(alice_socket.addr, src);
+    let packet = LanDiscovery::from_bytes(&buf[..len]).unwrap();
+    assert_eq!(alice_pk, packet.pk);
+    let (node, request) = handle_lan_discovery(&bob_socket, &bob_pk, &bob_sk,
+                                               &packet, src).unwrap();
+    assert_eq!(alice_pk, node.pk);
+
+    // alice gets GetNodes from bob
+    let (len, src) = alice_socket.recv_from(&mut buf).unwrap();
+    assert_eq!(bob_socket.addr, src);
+    let packet = DhtPacket::from_bytes(&buf[..len]).unwrap();
+    assert_eq!(bob_pk, packet.sender_pk);
+    assert_eq!(Some(DPacketT::GetNodes(request)), packet.get_packet(&alice_sk));
+}
@@ -0,0 +1,327 @@
+/*
+    Copyright © 2016 Zetok Zalbavar <zexavexxe@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Tests for the `net_crypto` module.
+
+use toxcore::binary_io::*;
+use toxcore::crypto_core::*;
+use toxcore::dht::PacketKind;
+use toxcore::net_crypto::*;
+
+use super::quickcheck::quickcheck;
+
+
+// Cookie::from_bytes()
+
+#[test]
+fn cookie_from_bytes_test() {
+    fn with_time(time: u64) {
+        let cookie = Cookie {
+            time: time,
+            real_pk: gen_keypair().0,
+            dht_pk: gen_keypair().0,
+        };
+        let bytes = cookie.to_bytes();
+        assert_eq!(COOKIE_SIZE, bytes.len());
+        assert_eq!(Some(cookie), Cookie::from_bytes(&bytes));
+        assert_eq!(None, Cookie::from_bytes(&bytes[1..]));
+    }
+    quickcheck(with_time as fn(u64));
+}
+
+// Cookie::is_valid()
+
+#[test]
+fn cookie_is_valid_test() {
+    let cookie = Cookie { time: 10, real_pk: gen_keypair().0, dht_pk: gen_keypair().0 };
+    assert_eq!(false, cookie.is_valid(9));
+    assert!(cookie.is_valid(10));
+    assert!(cookie.is_valid(10 + COOKIE_TIMEOUT - 1));
+    // cookie is still valid in the last second
+    assert!(cookie.is_valid(10 + COOKIE_TIMEOUT));
+    assert_eq!(false, cookie.is_valid(10 + COOKIE_TIMEOUT + 1));
+}
+
+// EncryptedCookie::get_payload()
+
+#[test]
+fn encrypted_cookie_get_payload_test() {
+    let key = new_symmetric_key();
+    let cookie = Cookie { time: 1, real_pk: gen_keypair().0, dht_pk: gen_keypair().0 };
+    let encrypted = cookie.encrypt(&key);
+    let bytes = encrypted.to_bytes();
+    assert_eq!(ENCRYPTED_COOKIE_SIZE, bytes.len());
+    let parsed = EncryptedCookie::from_bytes(&bytes).unwrap();
+    assert_eq!(encrypted, parsed);
+    assert_eq!(Some(cookie), parsed.get_payload(&key));
+    assert_eq!(None, parsed.get_payload(&new_symmetric_key()));
+    assert_eq!(None, EncryptedCookie::from_bytes(&bytes[1..]));
+}
+
+// CookieRequestPayload::from_bytes()
+
+#[test]
+fn cookie_request_payload_from_bytes_test() {
+    fn with_echo_id(echo_id: u64) {
+        let payload = CookieRequestPayload { real_pk: gen_keypair().0, echo_id: echo_id };
+        let bytes = payload.to_bytes();
+        assert_eq!(COOKIE_REQUEST_PAYLOAD_SIZE, bytes.len());
+        // padding
+        assert_eq!(&[0; 32], &bytes[PUBLICKEYBYTES..PUBLICKEYBYTES + 32]);
+        assert_eq!(Some(payload), CookieRequestPayload::from_bytes(&bytes));
+        assert_eq!(None, CookieRequestPayload::from_bytes(&bytes[1..]));
+    }
+    quickcheck(with_echo_id as fn(u64));
+}
+
+// CookieRequest::from_bytes()
+
+#[test]
+fn cookie_request_from_bytes_test() {
+    let (pk, sk) = gen_keypair();
+    let (node_pk, node_sk) = gen_keypair();
+    let payload = CookieRequestPayload { real_pk: gen_keypair().0, echo_id: 42 };
+    let request = CookieRequest::new(&pk, &sk, &node_pk, &payload);
+    let bytes = request.to_bytes();
+    assert_eq!(COOKIE_REQUEST_SIZE, bytes.len());
+    assert_eq!(PacketKind::CookieReq as u8, bytes[0]);
+    let parsed = CookieRequest::from_bytes(&bytes).unwrap();
+    assert_eq!(request, parsed);
+    assert_eq!(Some(payload), parsed.get_payload(&node_sk));
+    assert_eq!(None, parsed.get_payload(&sk));
+
+    assert_eq!(None, CookieRequest::from_bytes(&bytes[..bytes.len() - 1]));
+    let mut wrong_kind = bytes.clone();
+    wrong_kind[0] = PacketKind::CookieResp as u8;
+    assert_eq!(None, CookieRequest::from_bytes(&wrong_kind));
+}
+
+// CookieResponse::from_bytes()
+
+#[test]
+fn cookie_response_from_bytes_test() {
+    fn with_echo_id(echo_id: u64) {
+        let (pk, sk) = gen_keypair();
+        let (node_pk, node_sk) = gen_keypair();
+        let cookie = Cookie { time: 0, real_pk: pk, dht_pk: pk }
+            .encrypt(&new_symmetric_key());
+        let payload = CookieResponsePayload { cookie: cookie, echo_id: echo_id };
+        let response = CookieResponse::new(&node_sk, &pk, &payload);
+        let bytes = response.to_bytes();
+        assert_eq!(COOKIE_RESPONSE_SIZE, bytes.len());
+        let parsed = CookieResponse::from_bytes(&bytes).unwrap();
+        assert_eq!(response, parsed);
+        assert_eq!(Some(payload), parsed.get_payload(&sk, &node_pk));
+        assert_eq!(None, parsed.get_payload(&sk, &pk));
+        assert_eq!(None, CookieResponse::from_bytes(&bytes[1..]));
+    }
+    quickcheck(with_echo_id as fn(u64));
+}
+
+// HandshakePayload::from_bytes()
+
+#[test]
+fn handshake_payload_from_bytes_test() {
+    let cookie = Cookie { time: 0, real_pk: gen_keypair().0, dht_pk: gen_keypair().0 }
+        .encrypt(&new_symmetric_key());
+    let payload = HandshakePayload {
+        base_nonce: gen_nonce(),
+        session_pk: gen_keypair().0,
+        cookie_hash: cookie_hash(&cookie),
+        other_cookie: cookie,
+    };
+    let bytes = payload.to_bytes();
+    assert_eq!(HANDSHAKE_PAYLOAD_SIZE, bytes.len());
+    assert_eq!(Some(payload), HandshakePayload::from_bytes(&bytes));
+    assert_eq!(None, HandshakePayload::from_bytes(&bytes[1..]));
+}
+
+// CryptoHandshake::from_bytes()
+
+#[test]
+fn crypto_handshake_from_bytes_test() {
+    let (pk, sk) = gen_keypair();
+    let (peer_pk, peer_sk) = gen_keypair();
+    let key = new_symmetric_key();
+    let cookie = Cookie { time: 0, real_pk: pk, dht_pk: pk }.encrypt(&key);
+    let payload = HandshakePayload {
+        base_nonce: gen_nonce(),
+        session_pk: gen_keypair().0,
+        cookie_hash: cookie_hash(&cookie),
+        other_cookie: Cookie { time: 0, real_pk: peer_pk, dht_pk: peer_pk }
+            .encrypt(&key),
+    };
+    let handshake = CryptoHandshake::new(&sk, &peer_pk, &cookie, &payload);
+    let bytes = handshake.to_bytes();
+    assert_eq!(CRYPTO_HANDSHAKE_SIZE, bytes.len());
+    assert_eq!(PacketKind::CryptoHs as u8, bytes[0]);
+    let parsed = CryptoHandshake::from_bytes(&bytes).unwrap();
+    assert_eq!(handshake, parsed);
+    assert_eq!(Some(payload.clone()), parsed.get_payload(&peer_sk, &pk));
+    assert_eq!(None, parsed.get_payload(&peer_sk, &peer_pk));
+
+    assert_eq!(None, CryptoHandshake::from_bytes(&bytes[..bytes.len() - 1]));
+    let mut wrong_kind = bytes.clone();
+    wrong_kind[0] = PacketKind::CryptoData as u8;
+    assert_eq!(None, CryptoHandshake::from_bytes(&wrong_kind));
+}
+
+// CryptoHandshake::get_payload()
+
+#[test]
+fn crypto_handshake_get_payload_wrong_hash_test() {
+    let (pk, sk) = gen_keypair();
+    let (peer_pk, peer_sk) = gen_keypair();
+    let key = new_symmetric_key();
+    let cookie = Cookie { time: 0, real_pk: pk, dht_pk: pk }.encrypt(&key);
+    let other_cookie = Cookie { time: 0, real_pk: peer_pk, dht_pk: peer_pk }
+        .encrypt(&key);
+    let payload = HandshakePayload {
+        base_nonce: gen_nonce(),
+        session_pk: gen_keypair().0,
+        cookie_hash: cookie_hash(&other_cookie),
+        other_cookie: other_cookie,
+    };
+    let handshake = CryptoHandshake::new(&sk, &peer_pk, &cookie, &payload);
+    assert_eq!(None, handshake.get_payload(&peer_sk, &pk));
+}
+
+// nonce_last_bytes()
+
+#[test]
+fn nonce_last_bytes_test() {
+    let mut nonce = Nonce([0; NONCEBYTES]);
+    assert_eq!(0, nonce_last_bytes(&nonce));
+    nonce.0[NONCEBYTES - 1] = 1;
+    nonce.0[NONCEBYTES - 2] = 2;
+    assert_eq!(0x0201, nonce_last_bytes(&nonce));
+}
+
+// increment_nonce_be()
+
+#[test]
+fn increment_nonce_be_test() {
+    let mut nonce = Nonce([0; NONCEBYTES]);
+    increment_nonce_be(&mut nonce, 1);
+    assert_eq!(1, nonce.0[NONCEBYTES - 1]);
+
+    let mut nonce = Nonce([0; NONCEBYTES]);
+    nonce.0[NONCEBYTES - 1] = 0xff;
+    nonce.0[NONCEBYTES - 2] = 0xff;
+    increment_nonce_be(&mut nonce, 0x0102);
+    assert_eq!([0, 1, 1, 1], &nonce.0[NONCEBYTES - 4..]);
+
+    fn with_num(num: u16) {
+        let mut nonce = gen_nonce();
+        let last = nonce_last_bytes(&nonce);
+        increment_nonce_be(&mut nonce, num);
+        assert_eq!(last.wrapping_add(num), nonce_last_bytes(&nonce));
+    }
+    quickcheck(with_num as fn(u16));
+}
+
+// CryptoDataPayload::from_bytes()
+
+#[test]
+fn crypto_data_payload_from_bytes_test() {
+    fn with_data(buffer_start: u32, packet_number: u32, data: Vec<u8>) {
+        let mut data = data;
+        data.truncate(MAX_CRYPTO_DATA_SIZE);
+        let payload = CryptoDataPayload {
+            buffer_start: buffer_start,
+            packet_number: packet_number,
+            data: data.clone(),
+        };
+        let bytes = payload.to_bytes();
+        assert_eq!([(buffer_start >> 24) as u8, (buffer_start >> 16) as u8,
+                    (buffer_start >> 8) as u8, buffer_start as u8],
+                   &bytes[..4]);
+        // padded to a multiple of CRYPTO_MAX_PADDING counting from maximum
+        assert_eq!(0, (MAX_CRYPTO_DATA_SIZE + 8 - bytes.len()) % CRYPTO_MAX_PADDING);
+        match data.first() {
+            Some(&id) if id != PACKET_ID_PADDING =>
+                assert_eq!(Some(payload), CryptoDataPayload::from_bytes(&bytes)),
+            // data can't start with padding
+            _ => assert!(CryptoDataPayload::from_bytes(&bytes)
+                             .map_or(true, |p| p.data != data)),
+        }
+    }
+    quickcheck(with_data as fn(u32, u32, Vec<u8>));
+
+    assert_eq!(None, CryptoDataPayload::from_bytes(&[0; 7]));
+    assert_eq!(None, CryptoDataPayload::from_bytes(&[0; 20]));
+}
+
+// CryptoData::from_bytes()
+
+#[test]
+fn crypto_data_from_bytes_test() {
+    let key = new_symmetric_key();
+    let nonce = gen_nonce();
+    let payload = CryptoDataPayload {
+        buffer_start: 1,
+        packet_number: 2,
+        data: vec![PACKET_ID_LOSSLESS_START, 1, 2, 3],
+    };
+    let packet = CryptoData::new(&key, &nonce, &payload);
+    assert_eq!(nonce_last_bytes(&nonce), packet.nonce_last_bytes);
+    let bytes = packet.to_bytes();
+    assert_eq!(PacketKind::CryptoData as u8, bytes[0]);
+    assert!(bytes.len() >= CRYPTO_DATA_MIN_SIZE);
+    let parsed = CryptoData::from_bytes(&bytes).unwrap();
+    assert_eq!(packet, parsed);
+    assert_eq!(Some(payload), parsed.get_payload(&key, &nonce));
+    assert_eq!(None, parsed.get_payload(&new_symmetric_key(), &nonce));
+    assert_eq!(None, parsed.get_payload(&key, &gen_nonce()));
+
+    assert_eq!(None, CryptoData::from_bytes(&bytes[..CRYPTO_DATA_MIN_SIZE - 1]));
+    assert_eq!(None, CryptoData::from_bytes(&[27; MAX_CRYPTO_PACKET_SIZE + 1]));
+    let mut wrong_kind = bytes.clone();
+    wrong_kind[0] = PacketKind::CryptoHs as u8;
+    assert_eq!(None, CryptoData::from_bytes(&wrong_kind));
+}
+
+// CookieIssuer::handle_request()
+
+#[test]
+fn cookie_issuer_handle_request_test() {
+    let issuer = CookieIssuer::new();
+    let (node_pk, node_sk) = gen_keypair();
+    let (dht_pk, dht_sk) = gen_keypair();
+    let (real_pk, _) = gen_keypair();
+    let payload = CookieRequestPayload { real_pk: real_pk, echo_id: 7 };
+    let request = CookieRequest::new(&dht_pk, &dht_sk, &node_pk, &payload);
+
+    let response = issuer.handle_request(&node_sk, &request, 100).unwrap();
+    let payload = response.get_payload(&dht_sk, &node_pk).unwrap();
+    assert_eq!(7, payload.echo_id);
+    // cookie can be checked only by the issuer
+    let cookie = issuer.check(&payload.cookie, 100).unwrap();
+    assert_eq!(Cookie { time: 100, real_pk: real_pk, dht_pk: dht_pk }, cookie);
+    assert_eq!(None, CookieIssuer::new().check(&payload.cookie, 100));
+    // cookie expires
+    assert!(issuer.check(&payload.cookie, 100 + COOKIE_TIMEOUT).is_some());
+    assert_eq!(None, issuer.check(&payload.cookie, 100 + COOKIE_TIMEOUT + 1));
+
+    // request for someone else
+    let request = CookieRequest::new(&dht_pk, &dht_sk, &gen_keypair().0,
+                                     &CookieRequestPayload { real_pk: real_pk,
+                                                             echo_id: 7 });
+    assert_eq!(None, issuer.handle_request(&node_sk, &request, 100));
+}
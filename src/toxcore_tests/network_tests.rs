@@ -20,6 +20,7 @@
 //! Tests for network module.
 
 
+use std::net::UdpSocket;
 use std::thread;
 use std::time::Duration;
 
@@ -45,3 +46,30 @@ fn bind_udp_test() {
         });
     }
 }
+
+
+// Sockets::
+
+#[test]
+fn sockets_test() {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.set_nonblocking(true).unwrap();
+    let addr = socket.local_addr().unwrap();
+    let sockets = Sockets(vec![socket]);
+
+    let mut buf = [0; 16];
+    match sockets.recv_from(&mut buf) {
+        Err(ref e) if e.kind() == ::std::io::ErrorKind::WouldBlock => {},
+        r => panic!("Expected WouldBlock, got {:?}", r),
+    }
+
+    assert_eq!(3, sockets.send_to(&[1, 2, 3], addr).unwrap());
+    // packet may need a moment to arrive
+    thread::sleep(Duration::from_millis(10));
+    assert_eq!((3, addr), sockets.recv_from(&mut buf).unwrap());
+    assert_eq!([1, 2, 3], buf[..3]);
+
+    // no IPv6 socket, nor IPv6 socket to send to IPv4 with
+    assert!(sockets.send_to(&[1], "[::1]:33445".parse().unwrap()).is_err());
+    assert!(Sockets(vec![]).send_to(&[1], addr).is_err());
+}
@@ -0,0 +1,409 @@
+/*
+    Copyright © 2016 Zetok Zalbavar <zexavexxe@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Tests for the `onion_announce` module.
+
+use std::net::SocketAddr;
+
+use toxcore::binary_io::*;
+use toxcore::crypto_core::*;
+use toxcore::dht::*;
+use toxcore::onion::*;
+use toxcore::onion_announce::*;
+
+use super::quickcheck::quickcheck;
+
+
+/// `OnionReturn` with random content.
+fn random_return() -> OnionReturn {
+    let mut bytes = vec![0; ONION_RETURN_3_SIZE];
+    for b in &mut bytes {
+        *b = random_u32() as u8;
+    }
+    OnionReturn::from_bytes(&bytes).unwrap()
+}
+
+/// Send announce request from client to the server, and decrypt response.
+fn announce(server: &mut AnnounceServer, server_pk: &PublicKey,
+            server_sk: &SecretKey, kbucket: &Kbucket, client_pk: &PublicKey,
+            client_sk: &SecretKey, payload: &AnnounceRequestPayload,
+            addr: SocketAddr, now: u64) -> AnnounceResponsePayload {
+    let request = AnnounceRequest::new(client_pk, client_sk, server_pk, payload);
+    let response = server.handle_request(server_sk, &request, &random_return(),
+                                         addr, kbucket, now).unwrap();
+    assert_eq!(payload.sendback_data, response.sendback_data);
+    response.get_payload(server_pk, client_sk).unwrap()
+}
+
+
+// AnnounceRequestPayload::from_bytes()
+
+#[test]
+fn announce_request_payload_from_bytes_test() {
+    fn with_data(ping: u64, sendback_data: u64) {
+        let mut ping_id = ZERO_PING_ID;
+        ping_id[..8].copy_from_slice(&u64_to_array(ping));
+        let payload = AnnounceRequestPayload {
+            ping_id: ping_id,
+            search_pk: gen_keypair().0,
+            data_pk: gen_keypair().0,
+            sendback_data: sendback_data,
+        };
+        let bytes = payload.to_bytes();
+        assert_eq!(ANNOUNCE_REQUEST_PAYLOAD_SIZE, bytes.len());
+        assert_eq!(Some(payload), AnnounceRequestPayload::from_bytes(&bytes));
+        assert_eq!(None, AnnounceRequestPayload::from_bytes(&bytes[1..]));
+    }
+    quickcheck(with_data as fn(u64, u64));
+}
+
+// AnnounceRequest::from_bytes()
+
+#[test]
+fn announce_request_from_bytes_test() {
+    let (pk, sk) = gen_keypair();
+    let payload = AnnounceRequestPayload {
+        ping_id: ZERO_PING_ID,
+        search_pk: pk,
+        data_pk: gen_keypair().0,
+        sendback_data: 42,
+    };
+    let request = AnnounceRequest::new(&pk, &sk, &gen_keypair().0, &payload);
+    let bytes = request.to_bytes();
+    assert_eq!(ANNOUNCE_REQUEST_SIZE, bytes.len());
+    assert_eq!(Some(request), AnnounceRequest::from_bytes(&bytes));
+    assert_eq!(None, AnnounceRequest::from_bytes(&bytes[..bytes.len() - 1]));
+
+    let mut wrong_kind = bytes.clone();
+    wrong_kind[0] = PacketKind::AnnResp as u8;
+    assert_eq!(None, AnnounceRequest::from_bytes(&wrong_kind));
+}
+
+// AnnounceRequest::get_payload()
+
+#[test]
+fn announce_request_get_payload_test() {
+    let (pk, sk) = gen_keypair();
+    let (node_pk, node_sk) = gen_keypair();
+    let payload = AnnounceRequestPayload {
+        ping_id: [7; ONION_PING_ID_SIZE],
+        search_pk: gen_keypair().0,
+        data_pk: gen_keypair().0,
+        sendback_data: 1,
+    };
+    let request = AnnounceRequest::new(&pk, &sk, &node_pk, &payload);
+    assert_eq!(Some(payload), request.get_payload(&node_sk));
+    assert_eq!(None, request.get_payload(&sk));
+}
+
+// AnnounceResponsePayload::from_bytes()
+
+#[test]
+fn announce_response_payload_from_bytes_test() {
+    let nodes = vec![
+        PackedNode::new(true, "1.2.3.4:33445".parse().unwrap(), &gen_keypair().0),
+        PackedNode::new(false, "[2001::1]:1".parse().unwrap(), &gen_keypair().0),
+    ];
+    for status in &[AnnounceStatus::Failed, AnnounceStatus::Found,
+                    AnnounceStatus::Announced] {
+        let payload = AnnounceResponsePayload {
+            status: *status,
+            ping_id_or_pk: [3; ONION_PING_ID_SIZE],
+            nodes: nodes.clone(),
+        };
+        let bytes = payload.to_bytes();
+        assert_eq!(Some(payload), AnnounceResponsePayload::from_bytes(&bytes));
+    }
+
+    let payload = AnnounceResponsePayload {
+        status: AnnounceStatus::Failed,
+        ping_id_or_pk: ZERO_PING_ID,
+        nodes: vec![],
+    };
+    let mut bytes = payload.to_bytes();
+    assert_eq!(Some(payload), AnnounceResponsePayload::from_bytes(&bytes));
+    assert_eq!(None, AnnounceResponsePayload::from_bytes(&bytes[1..]));
+    // unknown status
+    bytes[0] = 3;
+    assert_eq!(None, AnnounceResponsePayload::from_bytes(&bytes));
+}
+
+// AnnounceResponse::from_bytes()
+
+#[test]
+fn announce_response_from_bytes_test() {
+    fn with_sendback(sendback_data: u64) {
+        let payload = AnnounceResponsePayload {
+            status: AnnounceStatus::Announced,
+            ping_id_or_pk: [1; ONION_PING_ID_SIZE],
+            nodes: vec![],
+        };
+        let (pk, sk) = gen_keypair();
+        let (node_pk, node_sk) = gen_keypair();
+        let response = AnnounceResponse::new(&node_sk, &pk, sendback_data, &payload);
+        let bytes = response.to_bytes();
+        assert_eq!(ANNOUNCE_RESPONSE_MIN_SIZE, bytes.len());
+        assert_eq!(Some(response.clone()), AnnounceResponse::from_bytes(&bytes));
+        assert_eq!(None, AnnounceResponse::from_bytes(&bytes[..bytes.len() - 1]));
+        assert_eq!(Some(payload), response.get_payload(&node_pk, &sk));
+        assert_eq!(None, response.get_payload(&pk, &sk));
+    }
+    quickcheck(with_sendback as fn(u64));
+}
+
+// AnnounceServer::ping_id()
+
+#[test]
+fn announce_server_ping_id_test() {
+    let server = AnnounceServer::new(&gen_keypair().0);
+    let pk = gen_keypair().0;
+    let addr = "1.2.3.4:33445".parse().unwrap();
+    let ping_id = server.ping_id(0, &pk, &addr);
+    assert_eq!(ping_id, server.ping_id(PING_ID_TIMEOUT - 1, &pk, &addr));
+    assert!(ping_id != server.ping_id(PING_ID_TIMEOUT, &pk, &addr));
+    assert!(ping_id != server.ping_id(0, &gen_keypair().0, &addr));
+    assert!(ping_id != server.ping_id(0, &pk, &"1.2.3.4:33446".parse().unwrap()));
+    // different secret
+    let other = AnnounceServer::new(&gen_keypair().0);
+    assert!(ping_id != other.ping_id(0, &pk, &addr));
+}
+
+// AnnounceServer::handle_request()
+
+#[test]
+fn announce_server_handle_request_test_announce() {
+    let (server_pk, server_sk) = gen_keypair();
+    let mut server = AnnounceServer::new(&server_pk);
+    let kbucket = Kbucket::new(KBUCKET_MAX_ENTRIES as u8, &server_pk);
+    let (pk, sk) = gen_keypair();
+    let data_pk = gen_keypair().0;
+    let addr = "1.2.3.4:33445".parse().unwrap();
+    let mut payload = AnnounceRequestPayload {
+        ping_id: ZERO_PING_ID,
+        search_pk: pk,
+        data_pk: data_pk,
+        sendback_data: 5,
+    };
+
+    // zero ping ID gets a valid one, announcement isn't stored
+    let response = announce(&mut server, &server_pk, &server_sk, &kbucket, &pk,
+                            &sk, &payload, addr, 0);
+    assert_eq!(AnnounceStatus::Failed, response.status);
+    assert_eq!(server.ping_id(PING_ID_TIMEOUT, &pk, &addr), response.ping_id_or_pk);
+    assert_eq!(None, server.get_entry(&pk, 0));
+
+    // valid ping ID stores the announcement
+    payload.ping_id = response.ping_id_or_pk;
+    let response = announce(&mut server, &server_pk, &server_sk, &kbucket, &pk,
+                            &sk, &payload, addr, 1);
+    assert_eq!(AnnounceStatus::Announced, response.status);
+    {
+        let entry = server.get_entry(&pk, 1).unwrap();
+        assert_eq!(data_pk, entry.data_pk);
+        assert_eq!(addr, entry.return_addr);
+    }
+    assert_eq!(1, server.entries(1).len());
+
+    // announcement expires
+    assert_eq!(None, server.get_entry(&pk, 1 + ONION_ANNOUNCE_TIMEOUT));
+    assert!(server.entries(1 + ONION_ANNOUNCE_TIMEOUT).is_empty());
+}
+
+#[test]
+fn announce_server_handle_request_test_search() {
+    let (server_pk, server_sk) = gen_keypair();
+    let mut server = AnnounceServer::new(&server_pk);
+    let mut kbucket = Kbucket::new(KBUCKET_MAX_ENTRIES as u8, &server_pk);
+    let node = PackedNode::new(true, "1.0.0.1:33445".parse().unwrap(),
+                               &gen_keypair().0);
+    kbucket.try_add(&Node::new(&node, NODE_TIMEOUT));
+
+    let (pk, sk) = gen_keypair();
+    let data_pk = gen_keypair().0;
+    let addr = "1.2.3.4:33445".parse().unwrap();
+    let payload = AnnounceRequestPayload {
+        ping_id: server.ping_id(0, &pk, &addr),
+        search_pk: pk,
+        data_pk: data_pk,
+        sendback_data: 0,
+    };
+    announce(&mut server, &server_pk, &server_sk, &kbucket, &pk, &sk, &payload,
+             addr, 0);
+
+    // searching with a temporary PK finds data PK of the announced client
+    let (tmp_pk, tmp_sk) = gen_keypair();
+    let search = AnnounceRequestPayload {
+        ping_id: ZERO_PING_ID,
+        search_pk: pk,
+        data_pk: gen_keypair().0,
+        sendback_data: 1,
+    };
+    let response = announce(&mut server, &server_pk, &server_sk, &kbucket,
+                            &tmp_pk, &tmp_sk, &search,
+                            "5.6.7.8:33445".parse().unwrap(), 1);
+    let PublicKey(data_pk_bytes) = data_pk;
+    assert_eq!(AnnounceStatus::Found, response.status);
+    assert_eq!(data_pk_bytes, response.ping_id_or_pk);
+    assert_eq!(vec![node], response.nodes);
+
+    // unknown PK isn't found
+    let search = AnnounceRequestPayload { search_pk: gen_keypair().0, ..search };
+    let response = announce(&mut server, &server_pk, &server_sk, &kbucket,
+                            &tmp_pk, &tmp_sk, &search,
+                            "5.6.7.8:33445".parse().unwrap(), 1);
+    assert_eq!(AnnounceStatus::Failed, response.status);
+    assert_eq!(1, server.entries(1).len());
+}
+
+#[test]
+fn announce_server_handle_request_test_full() {
+    let (server_pk, server_sk) = gen_keypair();
+    let mut server = AnnounceServer::new(&server_pk);
+    let kbucket = Kbucket::new(KBUCKET_MAX_ENTRIES as u8, &server_pk);
+    let addr = "1.2.3.4:33445".parse().unwrap();
+
+    let mut announced = Vec::new();
+    for _ in 0..ONION_ANNOUNCE_MAX_ENTRIES + 10 {
+        let (pk, sk) = gen_keypair();
+        let payload = AnnounceRequestPayload {
+            ping_id: server.ping_id(0, &pk, &addr),
+            search_pk: pk,
+            data_pk: gen_keypair().0,
+            sendback_data: 0,
+        };
+        announce(&mut server, &server_pk, &server_sk, &kbucket, &pk, &sk,
+                 &payload, addr, 0);
+        announced.push(pk);
+    }
+    let entries = server.entries(0);
+    assert_eq!(ONION_ANNOUNCE_MAX_ENTRIES, entries.len());
+
+    // stored are the closest to the server PK, sorted
+    announced.sort_by(|a, b| server_pk.distance(a, b));
+    for (entry, pk) in entries.iter().zip(&announced) {
+        assert_eq!(pk, &entry.pk);
+    }
+}
+
+#[test]
+fn announce_server_handle_request_test_invalid() {
+    let (server_pk, server_sk) = gen_keypair();
+    let mut server = AnnounceServer::new(&server_pk);
+    let kbucket = Kbucket::new(KBUCKET_MAX_ENTRIES as u8, &server_pk);
+    let (pk, sk) = gen_keypair();
+    let payload = AnnounceRequestPayload {
+        ping_id: ZERO_PING_ID,
+        search_pk: pk,
+        data_pk: pk,
+        sendback_data: 0,
+    };
+    // encrypted for someone else
+    let request = AnnounceRequest::new(&pk, &sk, &gen_keypair().0, &payload);
+    assert_eq!(None, server.handle_request(&server_sk, &request, &random_return(),
+                                           "1.2.3.4:33445".parse().unwrap(),
+                                           &kbucket, 0));
+}
+
+// AnnounceServer::handle_data_request()
+
+#[test]
+fn announce_server_handle_data_request_test() {
+    let (server_pk, server_sk) = gen_keypair();
+    let mut server = AnnounceServer::new(&server_pk);
+    let kbucket = Kbucket::new(KBUCKET_MAX_ENTRIES as u8, &server_pk);
+    let (pk, sk) = gen_keypair();
+    let (data_pk, data_sk) = gen_keypair();
+    let addr = "1.2.3.4:33445".parse().unwrap();
+    let onion_return = random_return();
+    let payload = AnnounceRequestPayload {
+        ping_id: server.ping_id(0, &pk, &addr),
+        search_pk: pk,
+        data_pk: data_pk,
+        sendback_data: 0,
+    };
+    let request = AnnounceRequest::new(&pk, &sk, &server_pk, &payload);
+    server.handle_request(&server_sk, &request, &onion_return, addr, &kbucket, 0)
+        .unwrap();
+
+    let (friend_pk, friend_sk) = gen_keypair();
+    let request = OnionDataRequest::new(&pk, &data_pk, &friend_pk, &friend_sk,
+                                        &[1, 2, 3]);
+    let (to, response) = server.handle_data_request(&request, 1).unwrap();
+    assert_eq!(addr, to);
+    assert_eq!(onion_return, response.onion_return);
+    let data = OnionDataResponse::from_bytes(&response.payload).unwrap();
+    assert_eq!(Some((friend_pk, vec![1, 2, 3])), data.get_payload(&data_sk, &sk));
+    // both layers have to be decrypted with the right keys
+    assert_eq!(None, data.get_payload(&sk, &sk));
+    assert_eq!(None, data.get_payload(&data_sk, &data_sk));
+
+    // announcement expired
+    assert_eq!(None, server.handle_data_request(&request, ONION_ANNOUNCE_TIMEOUT));
+    // not announced
+    let request = OnionDataRequest::new(&friend_pk, &data_pk, &pk, &sk, &[1]);
+    assert_eq!(None, server.handle_data_request(&request, 1));
+}
+
+// OnionDataRequest::from_bytes()
+
+#[test]
+fn onion_data_request_from_bytes_test() {
+    fn with_data(data: Vec<u8>) {
+        let (pk, sk) = gen_keypair();
+        let request = OnionDataRequest::new(&gen_keypair().0, &gen_keypair().0,
+                                            &pk, &sk, &data);
+        let bytes = request.to_bytes();
+        assert_eq!(ONION_DATA_REQUEST_MIN_SIZE + data.len(), bytes.len());
+        if bytes.len() <= ONION_MAX_PACKET_SIZE {
+            assert_eq!(Some(request), OnionDataRequest::from_bytes(&bytes));
+        } else {
+            assert_eq!(None, OnionDataRequest::from_bytes(&bytes));
+        }
+        assert_eq!(None, OnionDataRequest::from_bytes(
+            &bytes[..ONION_DATA_REQUEST_MIN_SIZE - 1]));
+    }
+    quickcheck(with_data as fn(Vec<u8>));
+}
+
+// OnionDataResponse::from_bytes()
+
+#[test]
+fn onion_data_response_from_bytes_test() {
+    fn with_data(data: Vec<u8>) {
+        let (pk, sk) = gen_keypair();
+        let request = OnionDataRequest::new(&gen_keypair().0, &gen_keypair().0,
+                                            &pk, &sk, &data);
+        let response = OnionDataResponse {
+            nonce: request.nonce,
+            temporary_pk: request.temporary_pk,
+            payload: request.payload,
+        };
+        let bytes = response.to_bytes();
+        assert_eq!(ONION_DATA_RESPONSE_MIN_SIZE + data.len(), bytes.len());
+        if bytes.len() <= ONION_MAX_PACKET_SIZE {
+            assert_eq!(Some(response), OnionDataResponse::from_bytes(&bytes));
+        } else {
+            assert_eq!(None, OnionDataResponse::from_bytes(&bytes));
+        }
+        let mut wrong_kind = bytes.clone();
+        wrong_kind[0] = PacketKind::OnionDataReq as u8;
+        assert_eq!(None, OnionDataResponse::from_bytes(&wrong_kind));
+    }
+    quickcheck(with_data as fn(Vec<u8>));
+}
@@ -0,0 +1,384 @@
+/*
+    Copyright © 2016 Zetok Zalbavar <zexavexxe@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Tests for the `onion_client` module.
+
+use toxcore::binary_io::*;
+use toxcore::crypto_core::*;
+use toxcore::dht::*;
+use toxcore::dht_node::*;
+use toxcore::network::Transport;
+use toxcore::onion::*;
+use toxcore::onion_announce::*;
+use toxcore::onion_client::*;
+use toxcore::onion_paths::*;
+
+use super::quickcheck::quickcheck;
+use super::sim_network::*;
+
+
+fn random_node() -> PackedNode {
+    let addr = format!("1.0.0.{}:33445", random_u32() as u8).parse().unwrap();
+    PackedNode::new(true, addr, &gen_keypair().0)
+}
+
+/// Response from the node with `node_sk` to the `request`.
+fn response(node_sk: &SecretKey, request: &AnnounceRequest,
+            status: AnnounceStatus, ping_id: PingId, nodes: Vec<PackedNode>)
+    -> AnnounceResponse
+{
+    let payload = request.get_payload(node_sk).unwrap();
+    AnnounceResponse::new(node_sk, &request.pk, payload.sendback_data,
+                          &AnnounceResponsePayload {
+                              status: status,
+                              ping_id_or_pk: ping_id,
+                              nodes: nodes,
+                          })
+}
+
+
+// DhtPkAnnounce::from_bytes()
+
+#[test]
+fn dht_pk_announce_from_bytes_test() {
+    fn with_no_replay(no_replay: u64, nodes: u8) {
+        let announce = DhtPkAnnounce {
+            no_replay: no_replay,
+            dht_pk: gen_keypair().0,
+            nodes: (0..nodes % (DHT_PK_ANNOUNCE_MAX_NODES as u8 + 1))
+                .map(|_| random_node())
+                .collect(),
+        };
+        let bytes = announce.to_bytes();
+        assert_eq!(ONION_DATA_DHT_PK, bytes[0]);
+        assert_eq!(u64_to_array(no_replay.to_be()), &bytes[1..9]);
+        assert_eq!(Some(announce), DhtPkAnnounce::from_bytes(&bytes));
+        assert_eq!(None, DhtPkAnnounce::from_bytes(&bytes[..bytes.len() - 1]));
+    }
+    quickcheck(with_no_replay as fn(u64, u8));
+}
+
+#[test]
+fn dht_pk_announce_from_bytes_test_too_many_nodes() {
+    let mut bytes = DhtPkAnnounce {
+        no_replay: 1,
+        dht_pk: gen_keypair().0,
+        nodes: vec![],
+    }.to_bytes();
+    for _ in 0..DHT_PK_ANNOUNCE_MAX_NODES + 1 {
+        bytes.extend_from_slice(&random_node().to_bytes());
+    }
+    assert_eq!(None, DhtPkAnnounce::from_bytes(&bytes));
+    bytes[0] = 0;
+    assert_eq!(None, DhtPkAnnounce::from_bytes(&bytes[..DHT_PK_ANNOUNCE_MIN_SIZE]));
+}
+
+// AnnounceClient::add_node()
+
+#[test]
+fn announce_client_add_node_test() {
+    let (pk, sk) = gen_keypair();
+    let mut client = AnnounceClient::new(&pk, &sk);
+    let nodes: Vec<PackedNode> = (0..ONION_CLIENT_MAX_NODES + 5)
+        .map(|_| random_node())
+        .collect();
+    assert_eq!(false, client.add_node(&PackedNode::new(true, nodes[0].saddr, &pk)));
+    assert!(client.add_node(&nodes[0]));
+    assert_eq!(false, client.add_node(&nodes[0]));
+    client.add_nodes(&nodes[1..]);
+    assert_eq!(ONION_CLIENT_MAX_NODES, client.nodes().len());
+
+    // closest nodes are kept, sorted
+    let mut sorted = nodes.clone();
+    sorted.sort_by(|a, b| pk.distance(&a.pk, &b.pk));
+    for (an, node) in client.nodes().iter().zip(&sorted) {
+        assert_eq!(node, &an.node);
+    }
+    let furthest = sorted[ONION_CLIENT_MAX_NODES];
+    assert_eq!(false, client.add_node(&furthest));
+}
+
+// AnnounceClient::next_requests()
+
+#[test]
+fn announce_client_next_requests_test() {
+    let (pk, sk) = gen_keypair();
+    let (node_pk, node_sk) = gen_keypair();
+    let node = PackedNode::new(true, "1.2.3.4:33445".parse().unwrap(), &node_pk);
+    let mut client = AnnounceClient::new(&pk, &sk);
+    client.add_node(&node);
+
+    let requests = client.next_requests(0);
+    assert_eq!(1, requests.len());
+    assert_eq!(node, requests[0].0);
+    let payload = requests[0].1.get_payload(&node_sk).unwrap();
+    assert_eq!(ZERO_PING_ID, payload.ping_id);
+    assert_eq!(pk, payload.search_pk);
+    assert_eq!(client.data_pk(), &payload.data_pk);
+
+    // no new requests until the interval passes
+    assert!(client.next_requests(ANNOUNCE_INTERVAL_NOT_ANNOUNCED - 1).is_empty());
+    assert_eq!(1, client.next_requests(ANNOUNCE_INTERVAL_NOT_ANNOUNCED).len());
+}
+
+#[test]
+fn announce_client_next_requests_test_timeout() {
+    let (pk, sk) = gen_keypair();
+    let mut client = AnnounceClient::new(&pk, &sk);
+    client.add_node(&random_node());
+
+    let mut now = 0;
+    for _ in 0..ANNOUNCE_MAX_TRIES {
+        assert_eq!(1, client.next_requests(now).len());
+        now += ANNOUNCE_INTERVAL_NOT_ANNOUNCED;
+    }
+    // node is removed only once its last request times out
+    assert_eq!(1, client.nodes().len());
+    assert!(client.next_requests(now + ANNOUNCE_REQUEST_TIMEOUT).is_empty());
+    assert!(client.nodes().is_empty());
+}
+
+// AnnounceClient::handle_response()
+
+#[test]
+fn announce_client_handle_response_test() {
+    let (pk, sk) = gen_keypair();
+    let (node_pk, node_sk) = gen_keypair();
+    let node = PackedNode::new(true, "1.2.3.4:33445".parse().unwrap(), &node_pk);
+    let mut client = AnnounceClient::new(&pk, &sk);
+    let mut paths = OnionPaths::new();
+    client.add_node(&node);
+
+    // zero ping ID is answered with a valid one, that is used right away
+    let (_, request) = client.next_requests(0).remove(0);
+    let other = random_node();
+    let resp = response(&node_sk, &request, AnnounceStatus::Failed, [1; 32],
+                        vec![other]);
+    assert!(client.handle_response(&resp, &mut paths, 0));
+    assert_eq!(false, client.is_announced());
+    assert_eq!(2, client.nodes().len());
+    // response is accepted only once
+    assert_eq!(false, client.handle_response(&resp, &mut paths, 0));
+
+    let requests = client.next_requests(1);
+    assert_eq!(2, requests.len());
+    let (_, request) = requests.into_iter().find(|&(n, _)| n == node).unwrap();
+    assert_eq!([1; 32], request.get_payload(&node_sk).unwrap().ping_id);
+
+    let resp = response(&node_sk, &request, AnnounceStatus::Announced, [2; 32],
+                        vec![]);
+    assert!(client.handle_response(&resp, &mut paths, 1));
+    assert!(client.is_announced());
+    assert_eq!(vec![node], client.announced_nodes());
+
+    // announcement is renewed with the new ping ID
+    assert!(client.next_requests(2).iter().all(|&(n, _)| n != node));
+    let requests = client.next_requests(1 + ANNOUNCE_INTERVAL_ANNOUNCED);
+    let (_, request) = requests.into_iter().find(|&(n, _)| n == node).unwrap();
+    assert_eq!([2; 32], request.get_payload(&node_sk).unwrap().ping_id);
+}
+
+#[test]
+fn announce_client_handle_response_test_invalid() {
+    let (pk, sk) = gen_keypair();
+    let (node_pk, node_sk) = gen_keypair();
+    let node = PackedNode::new(true, "1.2.3.4:33445".parse().unwrap(), &node_pk);
+    let mut client = AnnounceClient::new(&pk, &sk);
+    let mut paths = OnionPaths::new();
+    client.add_node(&node);
+    let (_, request) = client.next_requests(0).remove(0);
+
+    // encrypted by someone else
+    let resp = response(&node_sk, &request, AnnounceStatus::Announced, [1; 32],
+                        vec![]);
+    let forged = AnnounceResponse::new(&gen_keypair().1, &pk, resp.sendback_data,
+        &resp.get_payload(&node_pk, &sk).unwrap());
+    assert_eq!(false, client.handle_response(&forged, &mut paths, 0));
+    assert_eq!(false, client.is_announced());
+
+    // too late
+    let (_, request) = client.next_requests(ANNOUNCE_INTERVAL_NOT_ANNOUNCED)
+        .remove(0);
+    let resp = response(&node_sk, &request, AnnounceStatus::Announced, [1; 32],
+                        vec![]);
+    assert_eq!(false, client.handle_response(&resp, &mut paths,
+                                             ANNOUNCE_INTERVAL_NOT_ANNOUNCED
+                                             + ANNOUNCE_REQUEST_TIMEOUT));
+    assert_eq!(false, client.is_announced());
+}
+
+// AnnounceClient::send_requests()
+
+#[test]
+fn announce_client_send_requests_test() {
+    let (pk, sk) = gen_keypair();
+    let mut client = AnnounceClient::new(&pk, &sk);
+    client.add_nodes(&[random_node(), random_node(), random_node()]);
+    let transport = RecordingTransport::new();
+    let mut paths = OnionPaths::new();
+    assert_eq!(0, client.send_requests(&transport, &mut paths, 0));
+
+    // all requests are sent through the same path, and counted
+    let path = [random_node(), random_node(), random_node()];
+    let id = paths.add_path(OnionPath::new(&path), 0);
+    assert_eq!(3, client.send_requests(&transport, &mut paths, 0));
+    assert_eq!(3, transport.sent.borrow().len());
+    assert!(transport.sent.borrow().iter()
+        .all(|&(_, addr)| addr == path[0].saddr));
+    let stats = paths.stats();
+    assert_eq!(id, stats[0].id);
+    assert_eq!(3, stats[0].sent);
+    assert_eq!(0, stats[0].received);
+}
+
+/// Pass all packets received by the sockets to the nodes. Returns number of
+/// handled packets.
+fn relay(nodes: &mut [DhtNode], sockets: &[SimSocket], now: u64) -> usize {
+    let mut buf = [0; 2048];
+    let mut handled = 0;
+    for (node, socket) in nodes.iter_mut().zip(sockets) {
+        while let Ok((len, addr)) = socket.recv_from(&mut buf) {
+            node.handle_packet(socket, &buf[..len], addr, now);
+            handled += 1;
+        }
+    }
+    handled
+}
+
+/// Pass all packets received by the sockets to the nodes and the client,
+/// until there are no more packets.
+fn pump(nodes: &mut [DhtNode], sockets: &[SimSocket], client: &mut AnnounceClient,
+        client_socket: &SimSocket, paths: &mut OnionPaths, now: u64) {
+    let mut buf = [0; 2048];
+    loop {
+        let mut handled = relay(nodes, sockets, now);
+        while let Ok((len, _)) = client_socket.recv_from(&mut buf) {
+            client.handle_packet(&buf[..len], paths, now);
+            handled += 1;
+        }
+        if handled == 0 {
+            break
+        }
+    }
+}
+
+#[test]
+fn announce_client_sim_test() {
+    let net = SimNetwork::new();
+    let keys: Vec<(PublicKey, SecretKey)> = (0..12).map(|_| gen_keypair()).collect();
+    let sockets: Vec<SimSocket> = (0..keys.len())
+        .map(|i| net.bind(&format!("1.0.0.{}:33445", i + 1)))
+        .collect();
+    let packed: Vec<PackedNode> = keys.iter().zip(&sockets)
+        .map(|(&(ref pk, _), s)| PackedNode::new(true, s.addr, pk))
+        .collect();
+    let mut nodes: Vec<DhtNode> = keys.iter()
+        .map(|&(ref pk, ref sk)| DhtNode::new(pk, sk))
+        .collect();
+    for node in &mut nodes {
+        for p in &packed {
+            node.kbucket.try_add(&Node::new(p, NODE_TIMEOUT));
+        }
+    }
+
+    let (pk, sk) = gen_keypair();
+    let client_socket = net.bind("2.0.0.1:33445");
+    let mut client = AnnounceClient::new(&pk, &sk);
+    // start from a single node, others are found through responses
+    client.add_node(&packed[5]);
+    let mut paths = OnionPaths::new();
+    let path_id = paths.add_path(OnionPath::new(&[packed[0], packed[1], packed[2]]), 0);
+
+    for now in 0..10 {
+        client.send_requests(&client_socket, &mut paths, now);
+        pump(&mut nodes, &sockets, &mut client, &client_socket, &mut paths, now);
+    }
+    let stats = paths.stats();
+    assert_eq!(path_id, stats[0].id);
+    assert!(stats[0].received > 0);
+    assert!(stats[0].sent >= stats[0].received);
+
+    // each response brings up to 4 nodes, so the closest ones are found
+    let announced = client.announced_nodes();
+    let mut closest = packed.clone();
+    closest.sort_by(|a, b| pk.distance(&a.pk, &b.pk));
+    for node in &closest[..ONION_ANNOUNCE_MAX_NODES] {
+        assert!(announced.contains(node));
+    }
+    for (node, p) in nodes.iter().zip(&packed) {
+        let entry = node.announce.get_entry(&pk, 9);
+        assert_eq!(announced.contains(p), entry.is_some());
+        if let Some(entry) = entry {
+            assert_eq!(client.data_pk(), &entry.data_pk);
+        }
+    }
+}
+
+// AnnounceClient::send_data()
+
+#[test]
+fn announce_client_send_data_sim_test() {
+    let net = SimNetwork::new();
+    let keys: Vec<(PublicKey, SecretKey)> = (0..4).map(|_| gen_keypair()).collect();
+    let sockets: Vec<SimSocket> = (0..keys.len())
+        .map(|i| net.bind(&format!("1.0.0.{}:33445", i + 1)))
+        .collect();
+    let packed: Vec<PackedNode> = keys.iter().zip(&sockets)
+        .map(|(&(ref pk, _), s)| PackedNode::new(true, s.addr, pk))
+        .collect();
+    let mut nodes: Vec<DhtNode> = keys.iter()
+        .map(|&(ref pk, ref sk)| DhtNode::new(pk, sk))
+        .collect();
+
+    let (pk, sk) = gen_keypair();
+    let client_socket = net.bind("2.0.0.1:33445");
+    let mut client = AnnounceClient::new(&pk, &sk);
+    client.add_node(&packed[3]);
+    let mut paths = OnionPaths::new();
+    paths.add_path(OnionPath::new(&[packed[0], packed[1], packed[2]]), 0);
+    for now in 0..3 {
+        client.send_requests(&client_socket, &mut paths, now);
+        pump(&mut nodes, &sockets, &mut client, &client_socket, &mut paths, now);
+    }
+    assert_eq!(vec![packed[3]], client.announced_nodes());
+
+    // friend sends its DHT PK through its own path
+    let (friend_pk, friend_sk) = gen_keypair();
+    let friend_socket = net.bind("2.0.0.2:33445");
+    let friend = AnnounceClient::new(&friend_pk, &friend_sk);
+    let friend_path = OnionPath::new(&[packed[2], packed[0], packed[1]]);
+    let announce = DhtPkAnnounce {
+        no_replay: 1,
+        dht_pk: gen_keypair().0,
+        nodes: vec![packed[0]],
+    };
+    assert!(friend.send_data(&friend_socket, &friend_path, &packed[3], &pk,
+                             client.data_pk(), &announce.to_bytes()));
+    while relay(&mut nodes, &sockets, 3) > 0 {}
+
+    let mut buf = [0; 2048];
+    let (len, addr) = client_socket.recv_from(&mut buf).unwrap();
+    assert_eq!(packed[0].saddr, addr);
+    let response = OnionDataResponse::from_bytes(&buf[..len]).unwrap();
+    let (from, data) = client.handle_data_response(&response).unwrap();
+    assert_eq!(friend_pk, from);
+    assert_eq!(Some(announce), DhtPkAnnounce::from_bytes(&data));
+    // nothing is sent to the friend
+    assert!(friend_socket.recv_from(&mut buf).is_err());
+}
@@ -0,0 +1,374 @@
+/*
+    Copyright © 2016 Zetok Zalbavar <zexavexxe@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Tests for the `onion_friend` module.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use toxcore::binary_io::*;
+use toxcore::crypto_core::*;
+use toxcore::dht::*;
+use toxcore::dht_node::*;
+use toxcore::network::Transport;
+use toxcore::onion::*;
+use toxcore::onion_announce::*;
+use toxcore::onion_client::*;
+use toxcore::onion_friend::*;
+use toxcore::onion_paths::*;
+
+use super::sim_network::*;
+
+
+/// Node in its own subnet `1.0.n.1`.
+fn node_in_subnet(n: u8, pk: &PublicKey) -> PackedNode {
+    let addr = format!("1.0.{}.1:33445", n).parse().unwrap();
+    PackedNode::new(true, addr, pk)
+}
+
+/// Paths pool with a single path.
+fn single_path() -> OnionPaths {
+    let mut paths = OnionPaths::new();
+    let nodes = [node_in_subnet(1, &gen_keypair().0),
+                 node_in_subnet(2, &gen_keypair().0),
+                 node_in_subnet(3, &gen_keypair().0)];
+    paths.add_path(OnionPath::new(&nodes), 0);
+    paths
+}
+
+
+// OnionFriends::add_friend()
+
+#[test]
+fn onion_friends_add_friend_test() {
+    let (own_pk, _) = gen_keypair();
+    let mut kbucket = Kbucket::new(KBUCKET_MAX_ENTRIES as u8, &own_pk);
+    for n in 0..20 {
+        kbucket.try_add(&Node::new(&node_in_subnet(n, &gen_keypair().0),
+                                   NODE_TIMEOUT));
+    }
+    let (pk, _) = gen_keypair();
+    let mut friends = OnionFriends::new();
+    assert!(friends.add_friend(&pk, &kbucket, 0));
+    assert_eq!(false, friends.add_friend(&pk, &kbucket, 0));
+    {
+        let friend = friends.get(&pk).unwrap();
+        assert_eq!(FRIEND_SEARCH_MAX_NODES, friend.nodes().len());
+        let closest = kbucket.get_closest(&pk, FRIEND_SEARCH_MAX_NODES, 0);
+        for (n, node) in friend.nodes().iter().zip(&closest) {
+            assert_eq!(node, &n.node);
+        }
+        assert_eq!(false, friend.is_found());
+        assert_eq!(None, friend.data_pk);
+        assert_eq!(None, friend.dht_pk);
+    }
+
+    assert!(friends.remove_friend(&pk));
+    assert_eq!(false, friends.remove_friend(&pk));
+    assert!(friends.friends().is_empty());
+}
+
+// OnionFriends::send_requests()
+
+#[test]
+fn onion_friends_send_requests_backoff_test() {
+    let (node_pk, _) = gen_keypair();
+    let (pk, _) = gen_keypair();
+    let kbucket = Kbucket::new(KBUCKET_MAX_ENTRIES as u8, &pk);
+    let mut friends = OnionFriends::new();
+    friends.add_friend(&pk, &kbucket, 0);
+    friends.get_mut(&pk).unwrap().add_node(&node_in_subnet(4, &node_pk));
+    let transport = RecordingTransport::new();
+
+    // no paths
+    assert_eq!(0, friends.send_requests(&transport, &mut OnionPaths::new(),
+                                        &kbucket, 0));
+
+    let mut paths = single_path();
+    assert_eq!(1, friends.send_requests(&transport, &mut paths, &kbucket, 0));
+    assert_eq!(paths.stats()[0].nodes[0].saddr, transport.sent.borrow()[0].1);
+    assert_eq!(0, friends.send_requests(&transport, &mut paths, &kbucket, 1));
+
+    // friend isn't found, interval is doubled after every search
+    let mut now = 0;
+    let mut interval = FRIEND_SEARCH_INTERVAL;
+    for _ in 1..ANNOUNCE_MAX_TRIES {
+        now += interval;
+        assert_eq!(0, friends.send_requests(&transport, &mut paths, &kbucket,
+                                            now - 1));
+        assert_eq!(1, friends.send_requests(&transport, &mut paths, &kbucket, now));
+        interval *= 2;
+        assert_eq!(interval, friends.get(&pk).unwrap().search_interval());
+    }
+
+    // node that never answered is removed
+    now += interval;
+    assert_eq!(0, friends.send_requests(&transport, &mut paths, &kbucket, now));
+    assert!(friends.get(&pk).unwrap().nodes().is_empty());
+    assert_eq!(ANNOUNCE_MAX_TRIES as usize, transport.sent.borrow().len());
+
+    // interval doesn't grow over the maximum
+    for _ in 0..10 {
+        now += FRIEND_SEARCH_MAX_INTERVAL;
+        friends.send_requests(&transport, &mut paths, &kbucket, now);
+    }
+    assert_eq!(FRIEND_SEARCH_MAX_INTERVAL,
+               friends.get(&pk).unwrap().search_interval());
+}
+
+#[test]
+fn onion_friends_send_requests_refill_test() {
+    let (own_pk, _) = gen_keypair();
+    let mut kbucket = Kbucket::new(KBUCKET_MAX_ENTRIES as u8, &own_pk);
+    let (pk, _) = gen_keypair();
+    let mut friends = OnionFriends::new();
+    friends.add_friend(&pk, &kbucket, 0);
+    let transport = RecordingTransport::new();
+    let mut paths = single_path();
+    assert_eq!(0, friends.send_requests(&transport, &mut paths, &kbucket, 0));
+    assert!(friends.get(&pk).unwrap().nodes().is_empty());
+
+    // nodes are taken from the kbucket once it has some
+    for n in 0..20 {
+        kbucket.try_add(&Node::new(&node_in_subnet(n, &gen_keypair().0),
+                                   NODE_TIMEOUT));
+    }
+    assert_eq!(FRIEND_SEARCH_MAX_NODES,
+               friends.send_requests(&transport, &mut paths, &kbucket, 1));
+    assert_eq!(FRIEND_SEARCH_MAX_NODES as u64, paths.stats()[0].sent);
+    let friend = friends.get(&pk).unwrap();
+    let closest = kbucket.get_closest(&pk, FRIEND_SEARCH_MAX_NODES, 1);
+    assert_eq!(FRIEND_SEARCH_MAX_NODES, friend.nodes().len());
+    for (n, node) in friend.nodes().iter().zip(&closest) {
+        assert_eq!(node, &n.node);
+    }
+}
+
+#[test]
+fn onion_friends_send_requests_no_path_test() {
+    let (own_pk, _) = gen_keypair();
+    let mut kbucket = Kbucket::new(KBUCKET_MAX_ENTRIES as u8, &own_pk);
+    for n in 0..20 {
+        kbucket.try_add(&Node::new(&node_in_subnet(n, &gen_keypair().0),
+                                   NODE_TIMEOUT));
+    }
+    let (pk, _) = gen_keypair();
+    let mut friends = OnionFriends::new();
+    friends.add_friend(&pk, &kbucket, 0);
+    let transport = RecordingTransport::new();
+
+    // the only path is dead, so nodes aren't asked
+    let mut paths = single_path();
+    assert!(paths.random_path(0).is_some());
+    let now = ONION_PATH_TIMEOUT;
+    assert_eq!(0, friends.send_requests(&transport, &mut paths, &kbucket, now));
+    assert!(transport.sent.borrow().is_empty());
+
+    // and they are asked once there is a path
+    paths.add_path(OnionPath::new(&[node_in_subnet(30, &gen_keypair().0),
+                                    node_in_subnet(31, &gen_keypair().0),
+                                    node_in_subnet(32, &gen_keypair().0)]),
+                   now);
+    assert_eq!(FRIEND_SEARCH_MAX_NODES,
+               friends.send_requests(&transport, &mut paths, &kbucket, now));
+}
+
+// OnionFriends::handle_data()
+
+#[test]
+fn onion_friends_handle_data_test() {
+    let (pk, _) = gen_keypair();
+    let (own_pk, _) = gen_keypair();
+    let kbucket = Kbucket::new(KBUCKET_MAX_ENTRIES as u8, &own_pk);
+    let mut friends = OnionFriends::new();
+    let changed = Rc::new(RefCell::new(vec![]));
+    let changed_c = changed.clone();
+    friends.on_dht_pk(Box::new(move |pk: &PublicKey, dht_pk: &PublicKey,
+                                     nodes: &[PackedNode]| {
+        changed_c.borrow_mut().push((*pk, *dht_pk, nodes.to_vec()));
+    }));
+    friends.add_friend(&pk, &kbucket, 0);
+
+    let node = node_in_subnet(1, &gen_keypair().0);
+    let announce = DhtPkAnnounce {
+        no_replay: 5,
+        dht_pk: gen_keypair().0,
+        nodes: vec![node],
+    };
+    assert!(friends.handle_data(&pk, &announce.to_bytes()));
+    assert_eq!(Some(announce.dht_pk), friends.get(&pk).unwrap().dht_pk);
+    assert_eq!(vec![(pk, announce.dht_pk, vec![node])], *changed.borrow());
+
+    // replayed
+    assert_eq!(false, friends.handle_data(&pk, &announce.to_bytes()));
+    // same DHT PK doesn't call callback again
+    let same = DhtPkAnnounce { no_replay: 6, ..announce.clone() };
+    assert!(friends.handle_data(&pk, &same.to_bytes()));
+    assert_eq!(1, changed.borrow().len());
+    // from a stranger
+    let new = DhtPkAnnounce { no_replay: 7, dht_pk: gen_keypair().0, nodes: vec![] };
+    assert_eq!(false, friends.handle_data(&gen_keypair().0, &new.to_bytes()));
+    // not a DhtPkAnnounce
+    assert_eq!(false, friends.handle_data(&pk, &[1, 2, 3]));
+
+    assert!(friends.handle_data(&pk, &new.to_bytes()));
+    assert_eq!(Some(new.dht_pk), friends.get(&pk).unwrap().dht_pk);
+    assert_eq!(2, changed.borrow().len());
+}
+
+// OnionFriends::handle_response()
+
+/// Client with its own socket, path, and friends.
+struct SimClient {
+    socket: SimSocket,
+    client: AnnounceClient,
+    friends: OnionFriends,
+    paths: OnionPaths,
+    dht_pk: PublicKey,
+}
+
+impl SimClient {
+    fn new(net: &SimNetwork, addr: &str, path: [PackedNode; 3]) -> Self {
+        let (pk, sk) = gen_keypair();
+        let mut paths = OnionPaths::new();
+        paths.add_path(OnionPath::new(&path), 0);
+        SimClient {
+            socket: net.bind(addr),
+            client: AnnounceClient::new(&pk, &sk),
+            friends: OnionFriends::new(),
+            paths: paths,
+            dht_pk: gen_keypair().0,
+        }
+    }
+
+    fn send(&mut self, kbucket: &Kbucket, now: u64) {
+        self.client.send_requests(&self.socket, &mut self.paths, now);
+        self.friends.send_requests(&self.socket, &mut self.paths, kbucket, now);
+        self.friends.send_dht_pks(&self.socket, &mut self.paths, &self.client,
+                                  &self.dht_pk, &[], now);
+    }
+
+    fn receive(&mut self, now: u64) -> usize {
+        let mut buf = [0; 2048];
+        let mut handled = 0;
+        while let Ok((len, _)) = self.socket.recv_from(&mut buf) {
+            let bytes = &buf[..len];
+            handled += 1;
+            if let Some(response) = AnnounceResponse::from_bytes(bytes) {
+                // response is either to own announcement, or to a search
+                if !self.client.handle_response(&response, &mut self.paths, now) {
+                    assert!(self.friends.handle_response(&response,
+                                                         &mut self.paths, now));
+                }
+            } else if let Some(data) = OnionDataResponse::from_bytes(bytes) {
+                let (from, data) = self.client.handle_data_response(&data).unwrap();
+                // the same DhtPkAnnounce comes through every announce node
+                self.friends.handle_data(&from, &data);
+            }
+        }
+        handled
+    }
+}
+
+/// Alice and Bob search for each other through a network of DHT nodes.
+/// With `empty_kbucket`, friends are added before any nodes are known.
+fn onion_friends_sim(empty_kbucket: bool) {
+    let net = SimNetwork::new();
+    let keys: Vec<(PublicKey, SecretKey)> = (0..8).map(|_| gen_keypair()).collect();
+    let packed: Vec<PackedNode> = keys.iter().enumerate()
+        .map(|(i, &(ref pk, _))| node_in_subnet(i as u8, pk))
+        .collect();
+    let sockets: Vec<SimSocket> = packed.iter()
+        .map(|p| net.bind(&p.saddr.to_string()))
+        .collect();
+    let mut nodes: Vec<DhtNode> = keys.iter()
+        .map(|&(ref pk, ref sk)| DhtNode::new(pk, sk))
+        .collect();
+    for node in &mut nodes {
+        for p in &packed {
+            node.kbucket.try_add(&Node::new(p, NODE_TIMEOUT));
+        }
+    }
+
+    let mut alice = SimClient::new(&net, "2.0.0.1:33445",
+                                   [packed[0], packed[1], packed[2]]);
+    let mut bob = SimClient::new(&net, "2.0.0.2:33445",
+                                 [packed[3], packed[4], packed[5]]);
+    let alice_pk = *alice.client.pk();
+    let bob_pk = *bob.client.pk();
+    alice.client.add_nodes(&packed);
+    bob.client.add_nodes(&packed);
+    {
+        let own_pk = gen_keypair().0;
+        let empty = Kbucket::new(KBUCKET_MAX_ENTRIES as u8, &own_pk);
+        let kbucket = if empty_kbucket { &empty } else { &nodes[0].kbucket };
+        alice.friends.add_friend(&bob_pk, kbucket, 0);
+        bob.friends.add_friend(&alice_pk, kbucket, 0);
+    }
+
+    let changed = Rc::new(RefCell::new(vec![]));
+    let changed_c = changed.clone();
+    bob.friends.on_dht_pk(Box::new(move |pk: &PublicKey, dht_pk: &PublicKey,
+                                         _: &[PackedNode]| {
+        changed_c.borrow_mut().push((*pk, *dht_pk));
+    }));
+
+    for now in 0..20 {
+        alice.send(&nodes[0].kbucket, now);
+        bob.send(&nodes[0].kbucket, now);
+        loop {
+            let mut handled = 0;
+            for (node, socket) in nodes.iter_mut().zip(&sockets) {
+                let mut buf = [0; 2048];
+                while let Ok((len, addr)) = socket.recv_from(&mut buf) {
+                    node.handle_packet(socket, &buf[..len], addr, now);
+                    handled += 1;
+                }
+            }
+            handled += alice.receive(now) + bob.receive(now);
+            if handled == 0 {
+                break
+            }
+        }
+    }
+
+    {
+        let friend = bob.friends.get(&alice_pk).unwrap();
+        assert!(friend.is_found());
+        assert_eq!(Some(*alice.client.data_pk()), friend.data_pk);
+        assert_eq!(Some(alice.dht_pk), friend.dht_pk);
+        assert_eq!(FRIEND_FOUND_INTERVAL, friend.search_interval());
+    }
+    {
+        let friend = alice.friends.get(&bob_pk).unwrap();
+        assert!(friend.is_found());
+        assert_eq!(Some(bob.dht_pk), friend.dht_pk);
+    }
+    assert_eq!(vec![(alice_pk, alice.dht_pk)], *changed.borrow());
+}
+
+#[test]
+fn onion_friends_sim_test() {
+    onion_friends_sim(false);
+}
+
+#[test]
+fn onion_friends_sim_empty_kbucket_test() {
+    onion_friends_sim(true);
+}
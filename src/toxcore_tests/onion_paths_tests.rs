@@ -0,0 +1,164 @@
+/*
+    Copyright © 2016 Zetok Zalbavar <zexavexxe@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Tests for the `onion_paths` module.
+
+use toxcore::crypto_core::*;
+use toxcore::dht::*;
+use toxcore::onion::*;
+use toxcore::onion_paths::*;
+
+
+/// Node in its own subnet `n.0.0.1`.
+fn node_in_subnet(n: u8) -> PackedNode {
+    let addr = format!("{}.0.0.1:33445", n).parse().unwrap();
+    PackedNode::new(true, addr, &gen_keypair().0)
+}
+
+
+// select_path_nodes()
+
+#[test]
+fn select_path_nodes_test() {
+    let nodes: Vec<PackedNode> = (1..10).map(node_in_subnet).collect();
+    for _ in 0..20 {
+        let path = select_path_nodes(&nodes).unwrap();
+        for (i, a) in path.iter().enumerate() {
+            assert!(nodes.contains(a));
+            for b in &path[i + 1..] {
+                assert!(!same_subnet(&a.saddr, &b.saddr));
+            }
+        }
+    }
+}
+
+#[test]
+fn select_path_nodes_test_same_subnet() {
+    // only 2 subnets
+    let nodes: Vec<PackedNode> = (0..10)
+        .map(|i| {
+            let addr = format!("1.2.{}.{}:33445", i % 2, i).parse().unwrap();
+            PackedNode::new(true, addr, &gen_keypair().0)
+        })
+        .collect();
+    assert_eq!(None, select_path_nodes(&nodes));
+    assert_eq!(None, select_path_nodes(&nodes[..0]));
+
+    let node = node_in_subnet(1);
+    assert_eq!(None, select_path_nodes(&[node, node, node]));
+}
+
+// OnionPaths::update()
+
+#[test]
+fn onion_paths_update_test() {
+    let (pk, _) = gen_keypair();
+    let mut kbucket = Kbucket::new(KBUCKET_MAX_ENTRIES as u8, &pk);
+    let mut paths = OnionPaths::new();
+    assert_eq!(0, paths.update(&kbucket, 0));
+    assert!(paths.is_empty());
+
+    for n in 1..20 {
+        kbucket.try_add(&Node::new(&node_in_subnet(n), NODE_TIMEOUT));
+    }
+    assert_eq!(NUMBER_ONION_PATHS, paths.update(&kbucket, 0));
+    assert_eq!(NUMBER_ONION_PATHS, paths.len());
+    // pool is full
+    assert_eq!(0, paths.update(&kbucket, 1));
+
+    // IDs are unique
+    let stats = paths.stats();
+    for (i, a) in stats.iter().enumerate() {
+        assert_eq!(0, a.created);
+        for b in &stats[i + 1..] {
+            assert!(a.id != b.id);
+        }
+    }
+
+    // old paths are rotated, nodes that timed out aren't used
+    assert_eq!(0, paths.update(&kbucket, ONION_PATH_MAX_LIFETIME));
+    assert!(paths.is_empty());
+    assert_eq!(NUMBER_ONION_PATHS as u64, paths.rotated());
+    assert_eq!(0, paths.timed_out());
+}
+
+#[test]
+fn onion_paths_update_test_timeout() {
+    let nodes = [node_in_subnet(1), node_in_subnet(2), node_in_subnet(3)];
+    let (pk, _) = gen_keypair();
+    let kbucket = Kbucket::new(KBUCKET_MAX_ENTRIES as u8, &pk);
+    let mut paths = OnionPaths::new();
+    let id = paths.add_path(OnionPath::new(&nodes), 0);
+
+    // unused path doesn't time out
+    paths.update(&kbucket, ONION_PATH_TIMEOUT * 2);
+    assert_eq!(1, paths.len());
+
+    // path that delivers responses doesn't time out
+    let now = ONION_PATH_TIMEOUT * 2;
+    assert_eq!(id, paths.random_path(now).unwrap().0);
+    assert!(paths.handle_success(id, now + ONION_PATH_TIMEOUT - 1));
+    paths.update(&kbucket, now + ONION_PATH_TIMEOUT);
+    assert_eq!(1, paths.len());
+
+    // path that doesn't deliver responses times out
+    let now = now + ONION_PATH_TIMEOUT;
+    assert_eq!(id, paths.random_path(now).unwrap().0);
+    assert_eq!(id, paths.random_path(now + 1).unwrap().0);
+    paths.update(&kbucket, now + ONION_PATH_TIMEOUT - 1);
+    assert_eq!(1, paths.len());
+    assert!(paths.random_path(now + ONION_PATH_TIMEOUT).is_none());
+    paths.update(&kbucket, now + ONION_PATH_TIMEOUT);
+    assert!(paths.is_empty());
+    assert_eq!(1, paths.timed_out());
+    assert_eq!(0, paths.rotated());
+    assert_eq!(false, paths.handle_success(id, now + ONION_PATH_TIMEOUT));
+}
+
+// OnionPaths::random_path()
+
+#[test]
+fn onion_paths_random_path_test() {
+    let mut paths = OnionPaths::new();
+    assert!(paths.random_path(0).is_none());
+
+    let nodes = [node_in_subnet(1), node_in_subnet(2), node_in_subnet(3)];
+    let id = paths.add_path(OnionPath::new(&nodes), 0);
+    {
+        let (path_id, path) = paths.random_path(0).unwrap();
+        assert_eq!(id, path_id);
+        assert_eq!(nodes, path.nodes);
+    }
+    paths.random_path(1);
+    // only requests that were sent are counted
+    assert!(paths.handle_sent(id));
+    assert!(paths.handle_sent(id));
+    assert_eq!(false, paths.handle_sent(id + 1));
+    paths.handle_success(id, 2);
+
+    let stats = paths.stats();
+    assert_eq!(vec![OnionPathStats {
+        id: id,
+        nodes: nodes,
+        created: 0,
+        last_success: Some(2),
+        sent: 2,
+        received: 1,
+    }], stats);
+}
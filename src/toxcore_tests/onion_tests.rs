@@ -0,0 +1,410 @@
+/*
+    Copyright © 2016 Zetok Zalbavar <zexavexxe@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Tests for the `onion` module.
+
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+
+use toxcore::binary_io::*;
+use toxcore::crypto_core::*;
+use toxcore::dht::*;
+use toxcore::network::Transport;
+use toxcore::onion::*;
+
+use super::quickcheck::quickcheck;
+use super::sim_network::*;
+
+
+/// `OnionReturn` of given size with random content.
+fn random_return(size: usize) -> OnionReturn {
+    let mut bytes = vec![0; size];
+    for b in &mut bytes {
+        *b = random_u32() as u8;
+    }
+    OnionReturn::from_bytes(&bytes).unwrap()
+}
+
+/// 3 nodes with their DHT SKs.
+fn random_path_nodes() -> ([PackedNode; 3], Vec<SecretKey>) {
+    let keys: Vec<(PublicKey, SecretKey)> = (0..3).map(|_| gen_keypair()).collect();
+    let nodes = [
+        PackedNode::new(true, "1.0.0.1:33445".parse().unwrap(), &keys[0].0),
+        PackedNode::new(true, "[2001::2]:33445".parse().unwrap(), &keys[1].0),
+        PackedNode::new(true, "1.0.0.3:33445".parse().unwrap(), &keys[2].0),
+    ];
+    (nodes, keys.into_iter().map(|(_, sk)| sk).collect())
+}
+
+
+// ip_port_to_bytes()
+
+#[test]
+fn ip_port_to_bytes_test() {
+    let bytes = ip_port_to_bytes(&"1.2.3.4:33445".parse().unwrap());
+    assert_eq!([2, 1, 2, 3, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x82, 0xa5],
+               bytes);
+    let bytes = ip_port_to_bytes(&"[102:304::]:1".parse().unwrap());
+    assert_eq!([10, 1, 2, 3, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1],
+               bytes);
+}
+
+// ip_port_from_bytes()
+
+#[test]
+fn ip_port_from_bytes_test() {
+    fn with_addrs(a: u8, b: u8, c: u8, d: u8, segment: u16, port: u16) {
+        let v4 = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(a, b, c, d), port));
+        assert_eq!(Some(v4), ip_port_from_bytes(&ip_port_to_bytes(&v4)));
+        let ip = Ipv6Addr::new(segment, 0, a as u16, 0, b as u16, c as u16, 0, d as u16);
+        let v6 = SocketAddr::V6(SocketAddrV6::new(ip, port, 0, 0));
+        assert_eq!(Some(v6), ip_port_from_bytes(&ip_port_to_bytes(&v6)));
+    }
+    quickcheck(with_addrs as fn(u8, u8, u8, u8, u16, u16));
+}
+
+#[test]
+fn ip_port_from_bytes_test_invalid() {
+    let mut bytes = ip_port_to_bytes(&"1.2.3.4:33445".parse().unwrap());
+    assert_eq!(None, ip_port_from_bytes(&bytes[..ONION_IP_PORT_SIZE - 1]));
+    bytes[0] = 130; // TCP is not used in onion
+    assert_eq!(None, ip_port_from_bytes(&bytes));
+}
+
+
+// OnionReturn::from_bytes()
+
+#[test]
+fn onion_return_from_bytes_test() {
+    let onion_return = random_return(ONION_RETURN_1_SIZE);
+    let bytes = onion_return.to_bytes();
+    assert_eq!(ONION_RETURN_1_SIZE, bytes.len());
+    assert_eq!(Some(onion_return), OnionReturn::from_bytes(&bytes));
+    assert_eq!(None, OnionReturn::from_bytes(&bytes[..NONCEBYTES + MACBYTES]));
+}
+
+
+// OnionReturn::new()
+
+#[test]
+fn onion_return_new_test() {
+    let key = new_symmetric_key();
+    let addr: SocketAddr = "1.2.3.4:33445".parse().unwrap();
+    let return_1 = OnionReturn::new(&key, &addr, None);
+    assert_eq!(ONION_RETURN_1_SIZE, return_1.to_bytes().len());
+    let return_2 = OnionReturn::new(&key, &addr, Some(&return_1));
+    assert_eq!(ONION_RETURN_2_SIZE, return_2.to_bytes().len());
+    let return_3 = OnionReturn::new(&key, &addr, Some(&return_2));
+    assert_eq!(ONION_RETURN_3_SIZE, return_3.to_bytes().len());
+}
+
+// OnionReturn::get_payload()
+
+#[test]
+fn onion_return_get_payload_test() {
+    let key = new_symmetric_key();
+    let addr: SocketAddr = "[2001::1]:33445".parse().unwrap();
+    let return_1 = OnionReturn::new(&key, &addr, None);
+    assert_eq!(Some((addr, None)), return_1.get_payload(&key));
+
+    let return_2 = OnionReturn::new(&key, &addr, Some(&return_1));
+    assert_eq!(Some((addr, Some(return_1))), return_2.get_payload(&key));
+
+    // only the node that created it can decrypt it
+    assert_eq!(None, return_2.get_payload(&new_symmetric_key()));
+}
+
+
+// OnionRequestPayload::from_bytes()
+
+#[test]
+fn onion_request_payload_from_bytes_test() {
+    let payload = OnionRequestPayload {
+        ip_port: "1.2.3.4:33445".parse().unwrap(),
+        temporary_pk: gen_keypair().0,
+        inner: vec![42; MACBYTES + 1],
+    };
+    let bytes = payload.to_bytes();
+    assert_eq!(Some(payload), OnionRequestPayload::from_bytes(&bytes));
+    // inner payload has to be at least MAC + 1 byte
+    assert_eq!(None, OnionRequestPayload::from_bytes(&bytes[..bytes.len() - 1]));
+}
+
+// OnionRequest2Payload::from_bytes()
+
+#[test]
+fn onion_request_2_payload_from_bytes_test() {
+    let payload = OnionRequest2Payload {
+        ip_port: "[2001::1]:33445".parse().unwrap(),
+        data: vec![1, 2, 3],
+    };
+    let bytes = payload.to_bytes();
+    assert_eq!(Some(payload), OnionRequest2Payload::from_bytes(&bytes));
+    assert_eq!(None, OnionRequest2Payload::from_bytes(&bytes[..ONION_IP_PORT_SIZE]));
+}
+
+
+// OnionRequest0::from_bytes()
+
+#[test]
+fn onion_request_0_from_bytes_test() {
+    fn with_payload(payload: Vec<u8>) {
+        let request = OnionRequest0 {
+            nonce: gen_nonce(),
+            temporary_pk: gen_keypair().0,
+            payload: payload,
+        };
+        let bytes = request.to_bytes();
+        if bytes.len() >= ONION_REQUEST_0_MIN_SIZE
+            && bytes.len() <= ONION_MAX_PACKET_SIZE {
+            assert_eq!(PacketKind::OnionReq0 as u8, bytes[0]);
+            assert_eq!(Some(request), OnionRequest0::from_bytes(&bytes));
+        } else {
+            assert_eq!(None, OnionRequest0::from_bytes(&bytes));
+        }
+    }
+    quickcheck(with_payload as fn(Vec<u8>));
+
+    let too_big = OnionRequest0 {
+        nonce: gen_nonce(),
+        temporary_pk: gen_keypair().0,
+        payload: vec![0; ONION_MAX_PACKET_SIZE],
+    };
+    assert_eq!(None, OnionRequest0::from_bytes(&too_big.to_bytes()));
+}
+
+// OnionRequest1::from_bytes()
+
+#[test]
+fn onion_request_1_from_bytes_test() {
+    let request = OnionRequest1 {
+        nonce: gen_nonce(),
+        temporary_pk: gen_keypair().0,
+        payload: vec![42; 100],
+        onion_return: random_return(ONION_RETURN_1_SIZE),
+    };
+    let mut bytes = request.to_bytes();
+    assert_eq!(Some(request), OnionRequest1::from_bytes(&bytes));
+    assert_eq!(None, OnionRequest0::from_bytes(&bytes));
+    assert_eq!(None, OnionRequest1::from_bytes(&bytes[..ONION_REQUEST_1_MIN_SIZE - 1]));
+    bytes[0] = PacketKind::OnionReq2 as u8;
+    assert_eq!(None, OnionRequest1::from_bytes(&bytes));
+}
+
+// OnionRequest2::from_bytes()
+
+#[test]
+fn onion_request_2_from_bytes_test() {
+    let request = OnionRequest2 {
+        nonce: gen_nonce(),
+        temporary_pk: gen_keypair().0,
+        payload: vec![42; 100],
+        onion_return: random_return(ONION_RETURN_2_SIZE),
+    };
+    let bytes = request.to_bytes();
+    assert_eq!(Some(request), OnionRequest2::from_bytes(&bytes));
+    assert_eq!(None, OnionRequest1::from_bytes(&bytes));
+    assert_eq!(None, OnionRequest2::from_bytes(&bytes[..ONION_REQUEST_2_MIN_SIZE - 1]));
+}
+
+
+// OnionPath::create_request()
+
+#[test]
+fn onion_path_create_request_test() {
+    let (nodes, sks) = random_path_nodes();
+    let path = OnionPath::new(&nodes);
+    let destination: SocketAddr = "4.0.0.4:33445".parse().unwrap();
+    let data = vec![1, 2, 3, 4];
+    let request = path.create_request(destination, &data);
+    // same as it would be received
+    let request = OnionRequest0::from_bytes(&request.to_bytes()).unwrap();
+
+    // only the first node can decrypt it
+    assert_eq!(None, request.get_payload(&sks[1]));
+    let payload_0 = request.get_payload(&sks[0]).unwrap();
+    assert_eq!(nodes[1].saddr, payload_0.ip_port);
+
+    let request_1 = OnionRequest1 {
+        nonce: request.nonce,
+        temporary_pk: payload_0.temporary_pk,
+        payload: payload_0.inner,
+        onion_return: random_return(ONION_RETURN_1_SIZE),
+    };
+    let request_1 = OnionRequest1::from_bytes(&request_1.to_bytes()).unwrap();
+    assert_eq!(None, request_1.get_payload(&sks[0]));
+    let payload_1 = request_1.get_payload(&sks[1]).unwrap();
+    assert_eq!(nodes[2].saddr, payload_1.ip_port);
+
+    let request_2 = OnionRequest2 {
+        nonce: request.nonce,
+        temporary_pk: payload_1.temporary_pk,
+        payload: payload_1.inner,
+        onion_return: random_return(ONION_RETURN_2_SIZE),
+    };
+    let request_2 = OnionRequest2::from_bytes(&request_2.to_bytes()).unwrap();
+    let payload_2 = request_2.get_payload(&sks[2]).unwrap();
+    assert_eq!(destination, payload_2.ip_port);
+    assert_eq!(data, payload_2.data);
+}
+
+#[test]
+fn onion_path_create_request_test_temporary_keys() {
+    let (nodes, _) = random_path_nodes();
+    let path = OnionPath::new(&nodes);
+    let other = OnionPath::new(&nodes);
+    let destination: SocketAddr = "4.0.0.4:33445".parse().unwrap();
+    // different paths through the same nodes use different keys
+    assert!(path.create_request(destination, &[1]).temporary_pk
+            != other.create_request(destination, &[1]).temporary_pk);
+    // same path uses the same keys
+    assert_eq!(path.create_request(destination, &[1]).temporary_pk,
+               path.create_request(destination, &[1]).temporary_pk);
+}
+
+
+// OnionResponse3::from_bytes()
+
+#[test]
+fn onion_response_3_from_bytes_test() {
+    let response = OnionResponse3 {
+        onion_return: random_return(ONION_RETURN_3_SIZE),
+        payload: vec![42; 10],
+    };
+    let bytes = response.to_bytes();
+    assert_eq!(PacketKind::OnionResp3 as u8, bytes[0]);
+    assert_eq!(Some(response), OnionResponse3::from_bytes(&bytes));
+    assert_eq!(None, OnionResponse2::from_bytes(&bytes));
+    assert_eq!(None, OnionResponse3::from_bytes(&bytes[..ONION_RESPONSE_3_MIN_SIZE - 1]));
+}
+
+// OnionResponse2::from_bytes()
+
+#[test]
+fn onion_response_2_from_bytes_test() {
+    let response = OnionResponse2 {
+        onion_return: random_return(ONION_RETURN_2_SIZE),
+        payload: vec![42; 10],
+    };
+    let bytes = response.to_bytes();
+    assert_eq!(PacketKind::OnionResp2 as u8, bytes[0]);
+    assert_eq!(Some(response), OnionResponse2::from_bytes(&bytes));
+    assert_eq!(None, OnionResponse1::from_bytes(&bytes));
+    assert_eq!(None, OnionResponse2::from_bytes(&bytes[..ONION_RESPONSE_2_MIN_SIZE - 1]));
+}
+
+// OnionResponse1::from_bytes()
+
+#[test]
+fn onion_response_1_from_bytes_test() {
+    let response = OnionResponse1 {
+        onion_return: random_return(ONION_RETURN_1_SIZE),
+        payload: vec![42; 10],
+    };
+    let bytes = response.to_bytes();
+    assert_eq!(PacketKind::OnionResp1 as u8, bytes[0]);
+    assert_eq!(Some(response), OnionResponse1::from_bytes(&bytes));
+    assert_eq!(None, OnionResponse3::from_bytes(&bytes));
+    assert_eq!(None, OnionResponse1::from_bytes(&bytes[..ONION_RESPONSE_1_MIN_SIZE - 1]));
+}
+
+
+// OnionRelay::handle_response_1()
+
+#[test]
+fn onion_relay_handle_response_1_test_foreign_return() {
+    let relay = OnionRelay::new();
+    let other = OnionRelay::new();
+    let (nodes, sks) = random_path_nodes();
+    let path = OnionPath::new(&nodes);
+    let request = path.create_request("4.0.0.4:33445".parse().unwrap(), &[1]);
+    let sender: SocketAddr = "5.0.0.5:33445".parse().unwrap();
+    let (_, request_1) = other.handle_request_0(&sks[0], &request, sender).unwrap();
+
+    let response = OnionResponse1 {
+        onion_return: request_1.onion_return,
+        payload: vec![1],
+    };
+    assert_eq!(None, relay.handle_response_1(&response));
+    assert_eq!(Some((sender, vec![1])), other.handle_response_1(&response));
+}
+
+// OnionRelay::handle_packet()
+
+#[test]
+fn onion_relay_handle_packet_sim_test() {
+    let net = SimNetwork::new();
+    let sender = net.bind("5.0.0.5:33445");
+    let destination = net.bind("4.0.0.4:33445");
+    let (nodes, sks) = random_path_nodes();
+    let relays: Vec<OnionRelay> = (0..3).map(|_| OnionRelay::new()).collect();
+    let sockets: Vec<SimSocket> = nodes.iter()
+        .map(|n| net.bind(&n.saddr.to_string()))
+        .collect();
+
+    let relay_all = || {
+        let mut buf = [0; 2048];
+        for ((relay, socket), sk) in relays.iter().zip(&sockets).zip(&sks) {
+            while let Ok((len, addr)) = socket.recv_from(&mut buf) {
+                assert!(relay.handle_packet(socket, sk, &buf[..len], addr));
+            }
+        }
+    };
+
+    let path = OnionPath::new(&nodes);
+    let request = path.create_request(destination.addr, &[1, 2, 3]);
+    sender.send_to(&request.to_bytes(), nodes[0].saddr).unwrap();
+    // each relay passes it one hop further
+    for _ in 0..3 {
+        relay_all();
+    }
+
+    let mut buf = [0; 2048];
+    let (len, addr) = destination.recv_from(&mut buf).unwrap();
+    assert_eq!(nodes[2].saddr, addr);
+    assert_eq!(3 + ONION_RETURN_3_SIZE, len);
+    assert_eq!([1, 2, 3], buf[..3]);
+
+    let response = OnionResponse3 {
+        onion_return: OnionReturn::from_bytes(&buf[3..len]).unwrap(),
+        payload: vec![4, 5, 6],
+    };
+    destination.send_to(&response.to_bytes(), addr).unwrap();
+    for _ in 0..3 {
+        relay_all();
+    }
+
+    let (len, addr) = sender.recv_from(&mut buf).unwrap();
+    assert_eq!(nodes[0].saddr, addr);
+    assert_eq!([4, 5, 6], buf[..len]);
+}
+
+#[test]
+fn onion_relay_handle_packet_test_invalid() {
+    let net = SimNetwork::new();
+    let socket = net.bind("1.0.0.1:33445");
+    let relay = OnionRelay::new();
+    let (_, sk) = gen_keypair();
+    let addr = "1.0.0.2:33445".parse().unwrap();
+    assert_eq!(false, relay.handle_packet(&socket, &sk, &[0; 100], addr));
+
+    // can't be decrypted with our key
+    let (nodes, _) = random_path_nodes();
+    let request = OnionPath::new(&nodes).create_request(addr, &[1]);
+    assert_eq!(false, relay.handle_packet(&socket, &sk, &request.to_bytes(), addr));
+}
@@ -0,0 +1,311 @@
+/*
+    Copyright © 2016 Zetok Zalbavar <zexavexxe@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Simulated in-process UDP network, with hosts optionally put behind NATs.
+//!
+//! Packets are delivered immediately when sent, and can be received with
+//! [`SimSocket`](./struct.SimSocket.html), which implements `Transport`.
+//!
+//! Packets sent to `255.255.255.255` or `ff02::1` are delivered to all other
+//! sockets of the same IP version bound to the destination port, without
+//! passing through any NAT.
+//!
+//! Network can drop and reorder unicast packets, see
+//! [`set_impairment()`](./struct.SimNetwork.html#method.set_impairment).
+
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::rc::Rc;
+
+use toxcore::network::Transport;
+
+
+/// Type of simulated NAT.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum NatType {
+    /// Host's port is mapped to the same external port for all
+    /// destinations; anyone can send to the external port.
+    FullCone,
+    /// Like `FullCone`, but only IPs that host has sent to can send to it.
+    RestrictedCone,
+    /// Like `FullCone`, but only addresses (IP and port) that host has sent
+    /// to can send to it.
+    PortRestrictedCone,
+    /// Host's port is mapped to a new external port for each destination;
+    /// only that destination can send to it. External ports are allocated
+    /// sequentially.
+    Symmetric,
+}
+
+#[derive(Clone, Debug)]
+struct Mapping {
+    private: SocketAddr,
+    /// Destination of the mapping – only for symmetric NAT.
+    dst: Option<SocketAddr>,
+    external: SocketAddr,
+    /// Addresses that host has sent packets to through this mapping.
+    contacted: Vec<SocketAddr>,
+}
+
+#[derive(Clone, Debug)]
+struct Nat {
+    nat_type: NatType,
+    /// External address with port `0`.
+    external: SocketAddr,
+    next_port: u16,
+    mappings: Vec<Mapping>,
+}
+
+impl Nat {
+    /// Translate outgoing packet from `private` to `dst`, returning the
+    /// external source address.
+    fn outbound(&mut self, private: SocketAddr, dst: SocketAddr) -> SocketAddr {
+        let symmetric = self.nat_type == NatType::Symmetric;
+        let pos = self.mappings.iter().position(|m| m.private == private
+            && (!symmetric || m.dst == Some(dst)));
+        let pos = match pos {
+            Some(p) => p,
+            None => {
+                let mut external = self.external;
+                external.set_port(self.next_port);
+                self.next_port = self.next_port.wrapping_add(1);
+                self.mappings.push(Mapping {
+                    private: private,
+                    dst: if symmetric { Some(dst) } else { None },
+                    external: external,
+                    contacted: vec![],
+                });
+                self.mappings.len() - 1
+            },
+        };
+        let mapping = &mut self.mappings[pos];
+        if !mapping.contacted.contains(&dst) {
+            mapping.contacted.push(dst);
+        }
+        mapping.external
+    }
+
+    /// Translate incoming packet from `src` to the external address `dst`,
+    /// returning the private destination address, or `None` if NAT drops
+    /// the packet.
+    fn inbound(&self, src: SocketAddr, dst: SocketAddr) -> Option<SocketAddr> {
+        let mut src_ip = src;
+        src_ip.set_port(0);
+        for m in self.mappings.iter().filter(|m| m.external == dst) {
+            let allowed = match self.nat_type {
+                NatType::FullCone => true,
+                NatType::RestrictedCone => m.contacted.iter().any(|c| {
+                    let mut c = *c;
+                    c.set_port(0);
+                    c == src_ip
+                }),
+                NatType::PortRestrictedCone => m.contacted.contains(&src),
+                NatType::Symmetric => m.dst == Some(src),
+            };
+            if allowed {
+                return Some(m.private)
+            }
+        }
+        None
+    }
+}
+
+type Inbox = Rc<RefCell<VecDeque<(Vec<u8>, SocketAddr)>>>;
+
+#[derive(Default)]
+struct NetState {
+    /// Inboxes of sockets, by their local address.
+    sockets: HashMap<SocketAddr, Inbox>,
+    /// NATs, by their external address with port `0`.
+    nats: HashMap<SocketAddr, Nat>,
+    /// External address (port `0`) of the NAT that host with given local
+    /// address (port `0`) is behind.
+    behind_nat: HashMap<SocketAddr, SocketAddr>,
+    /// Percentage of dropped unicast packets.
+    loss: u8,
+    /// Percentage of unicast packets delivered before the waiting ones.
+    reorder: u8,
+    /// State of the pseudo-random generator.
+    rng: u64,
+}
+
+fn without_port(addr: &SocketAddr) -> SocketAddr {
+    let mut addr = *addr;
+    addr.set_port(0);
+    addr
+}
+
+fn is_broadcast(addr: &SocketAddr) -> bool {
+    addr.ip() == IpAddr::V4(Ipv4Addr::new(255, 255, 255, 255))
+        || addr.ip() == IpAddr::V6(Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1))
+}
+
+impl NetState {
+    /// Pseudo-random number in `0..100`.
+    fn roll(&mut self) -> u8 {
+        self.rng = self.rng.wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        ((self.rng >> 33) % 100) as u8
+    }
+
+    fn send(&mut self, from: SocketAddr, buf: &[u8], dst: SocketAddr) {
+        if is_broadcast(&dst) {
+            for (addr, inbox) in &self.sockets {
+                let same_version = match (*addr, dst) {
+                    (SocketAddr::V4(_), SocketAddr::V4(_)) |
+                    (SocketAddr::V6(_), SocketAddr::V6(_)) => true,
+                    _ => false,
+                };
+                if addr != &from && addr.port() == dst.port() && same_version {
+                    inbox.borrow_mut().push_back((buf.to_vec(), from));
+                }
+            }
+            return
+        }
+        let src = match self.behind_nat.get(&without_port(&from)) {
+            Some(nat) => self.nats.get_mut(nat).unwrap().outbound(from, dst),
+            None => from,
+        };
+        let local_dst = match self.nats.get(&without_port(&dst)) {
+            Some(nat) => match nat.inbound(src, dst) {
+                Some(d) => d,
+                None => return,
+            },
+            None => dst,
+        };
+        if self.loss > 0 && self.roll() < self.loss {
+            return
+        }
+        let reordered = self.reorder > 0 && self.roll() < self.reorder;
+        if let Some(inbox) = self.sockets.get(&local_dst) {
+            if reordered {
+                inbox.borrow_mut().push_front((buf.to_vec(), src));
+            } else {
+                inbox.borrow_mut().push_back((buf.to_vec(), src));
+            }
+        }
+    }
+}
+
+
+/// Simulated network.
+#[derive(Clone)]
+pub struct SimNetwork {
+    state: Rc<RefCell<NetState>>,
+}
+
+impl SimNetwork {
+    /// Create an empty network.
+    pub fn new() -> Self {
+        SimNetwork { state: Rc::new(RefCell::new(NetState::default())) }
+    }
+
+    /// Put host with IP of `private` behind NAT of `nat_type` with external
+    /// IP of `external`. Ports of supplied addresses are ignored.
+    ///
+    /// External ports are allocated sequentially, starting from
+    /// `first_port`.
+    pub fn add_nat(&self, nat_type: NatType, external: &str, private: &str,
+                   first_port: u16) {
+        let external = without_port(&external.parse().unwrap());
+        let private = without_port(&private.parse().unwrap());
+        let mut state = self.state.borrow_mut();
+        state.nats.insert(external, Nat {
+            nat_type: nat_type,
+            external: external,
+            next_port: first_port,
+            mappings: vec![],
+        });
+        state.behind_nat.insert(private, external);
+    }
+
+    /// Drop `loss` percent of unicast packets, and deliver `reorder`
+    /// percent of the rest before the packets that are already waiting to
+    /// be received. Packets are chosen pseudo-randomly, starting from
+    /// `seed`, so that tests are repeatable.
+    pub fn set_impairment(&self, loss: u8, reorder: u8, seed: u64) {
+        let mut state = self.state.borrow_mut();
+        state.loss = loss;
+        state.reorder = reorder;
+        state.rng = seed;
+    }
+
+    /// Bind socket to the local address `addr`.
+    pub fn bind(&self, addr: &str) -> SimSocket {
+        let addr: SocketAddr = addr.parse().unwrap();
+        let inbox = Rc::new(RefCell::new(VecDeque::new()));
+        self.state.borrow_mut().sockets.insert(addr, inbox.clone());
+        SimSocket { net: self.clone(), addr: addr, inbox: inbox }
+    }
+}
+
+
+/// Socket in the simulated network.
+pub struct SimSocket {
+    net: SimNetwork,
+    /// Local address of the socket.
+    pub addr: SocketAddr,
+    inbox: Inbox,
+}
+
+impl Transport for SimSocket {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        self.net.state.borrow_mut().send(self.addr, buf, addr);
+        Ok(buf.len())
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        match self.inbox.borrow_mut().pop_front() {
+            Some((data, src)) => {
+                let len = ::std::cmp::min(buf.len(), data.len());
+                buf[..len].copy_from_slice(&data[..len]);
+                Ok((len, src))
+            },
+            None => Err(io::Error::new(io::ErrorKind::WouldBlock,
+                                       "no packets to receive")),
+        }
+    }
+}
+
+
+/// Transport that only records sent packets, and never receives anything.
+pub struct RecordingTransport {
+    /// Sent packets with their destinations.
+    pub sent: RefCell<Vec<(Vec<u8>, SocketAddr)>>,
+}
+
+impl RecordingTransport {
+    /// Create a transport without sent packets.
+    pub fn new() -> Self {
+        RecordingTransport { sent: RefCell::new(vec![]) }
+    }
+}
+
+impl Transport for RecordingTransport {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        self.sent.borrow_mut().push((buf.to_vec(), addr));
+        Ok(buf.len())
+    }
+
+    fn recv_from(&self, _: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        Err(io::Error::new(io::ErrorKind::WouldBlock, "nothing to receive"))
+    }
+}